@@ -0,0 +1,145 @@
+//! Compares hashing the chunks of files with `BatchHasher`, which opens each file once
+//! for all its chunks, with opening the file again for every chunk.
+//! Also compares grouping duplicate files with the file handles kept open between
+//! the hashing stages and with every stage reopening the files.
+//!
+//! Before the measurements, prints the number of files opened by each variant.
+//! The difference in time is the cost of the `open` and `close` syscalls, so it grows
+//! with the number of chunks per file and is the largest on network file systems.
+
+use std::fs::File;
+use std::io::Write;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use fclones::config::GroupConfig;
+use fclones::file::{FileChunk, FileLen, FilePos};
+use fclones::log::Log;
+use fclones::path::Path;
+use fclones::{group_files_with_metrics, BatchHasher};
+use tempfile::TempDir;
+
+const FILE_COUNT: usize = 16;
+const FILE_LEN: u64 = 1024 * 1024;
+const CHUNK_LEN: u64 = 16 * 1024;
+const BUF_LEN: usize = 16 * 1024;
+const DUPLICATE_PAIR_COUNT: usize = 500;
+const DUPLICATE_LEN: usize = 64 * 1024;
+
+/// Creates files with different contents, so the page cache can hold all of them
+fn create_files() -> (TempDir, Vec<Path>) {
+    let root = tempfile::tempdir().unwrap();
+    let paths = (0..FILE_COUNT)
+        .map(|i| {
+            let path = root.path().join(format!("file{}", i));
+            let contents: Vec<u8> = (0..FILE_LEN).map(|j| (i as u64 + j) as u8).collect();
+            File::create(&path).unwrap().write_all(&contents).unwrap();
+            Path::from(path)
+        })
+        .collect();
+    (root, paths)
+}
+
+/// Returns all chunks of the files, with the chunks of each file next to each other
+fn chunks(paths: &[Path]) -> impl Iterator<Item = FileChunk<'_>> {
+    paths.iter().flat_map(|path| {
+        (0..FILE_LEN / CHUNK_LEN)
+            .map(move |i| FileChunk::new(path, FilePos(i * CHUNK_LEN), FileLen(CHUNK_LEN)))
+    })
+}
+
+/// Hashes the chunks with a new hasher per chunk, so each chunk opens its file.
+/// Returns the number of files opened.
+fn hash_separately(paths: &[Path]) -> u64 {
+    chunks(paths)
+        .map(|chunk| {
+            let mut hasher = BatchHasher::new(BUF_LEN);
+            hasher.hash([chunk], |_| {});
+            hasher.files_opened()
+        })
+        .sum()
+}
+
+/// Hashes the chunks with a single hasher, so each file is opened once.
+/// Returns the number of files opened.
+fn hash_batched(paths: &[Path]) -> u64 {
+    let mut hasher = BatchHasher::new(BUF_LEN);
+    hasher.hash(chunks(paths), |_| {});
+    hasher.files_opened()
+}
+
+fn hash_chunks(c: &mut Criterion) {
+    let (_root, paths) = create_files();
+    println!(
+        "Files opened for {} chunks: {} separately, {} batched",
+        chunks(&paths).count(),
+        hash_separately(&paths),
+        hash_batched(&paths)
+    );
+
+    c.bench_function("hash_chunks_separately", |b| {
+        b.iter(|| hash_separately(&paths))
+    });
+    c.bench_function("hash_chunks_batched", |b| b.iter(|| hash_batched(&paths)));
+}
+
+/// Creates pairs of identical files, so every file goes through all hashing stages
+fn create_duplicates() -> TempDir {
+    let root = tempfile::tempdir().unwrap();
+    for i in 0..DUPLICATE_PAIR_COUNT {
+        let mut contents = vec![0; DUPLICATE_LEN];
+        contents[..8].copy_from_slice(&(i as u64).to_le_bytes());
+        for copy in 0..2 {
+            let path = root.path().join(format!("file{}_{}", i, copy));
+            File::create(&path).unwrap().write_all(&contents).unwrap();
+        }
+    }
+    root
+}
+
+/// Groups the files and returns the number of files opened and the number of handles
+/// reused by all stages
+fn group(config: &GroupConfig, log: &Log) -> (u64, u64) {
+    let (_, _, metrics) = group_files_with_metrics(config, log).unwrap();
+    metrics
+        .stages
+        .iter()
+        .fold((0, 0), |(opened, reused), stage| {
+            (opened + stage.files_opened, reused + stage.handles_reused)
+        })
+}
+
+fn group_duplicates(c: &mut Criterion) {
+    let root = create_duplicates();
+    let mut log = Log::new();
+    log.no_progress = true;
+    let config = GroupConfig {
+        paths: vec![Path::from(root.path())],
+        ..Default::default()
+    };
+    // Too few open files to keep any handles between the stages
+    let no_reuse_config = GroupConfig {
+        paths: vec![Path::from(root.path())],
+        max_open_files: Some(3),
+        ..Default::default()
+    };
+
+    let (opened, reused) = group(&config, &log);
+    let (opened_without_reuse, _) = group(&no_reuse_config, &log);
+    println!(
+        "Files opened for {} files: {} with {} handles reused, {} without reusing handles",
+        2 * DUPLICATE_PAIR_COUNT,
+        opened,
+        reused,
+        opened_without_reuse
+    );
+
+    c.bench_function("group_files_reusing_handles", |b| {
+        b.iter(|| group(&config, &log))
+    });
+    c.bench_function("group_files_reopening", |b| {
+        b.iter(|| group(&no_reuse_config, &log))
+    });
+}
+
+criterion_group!(benches, hash_chunks, group_duplicates);
+criterion_main!(benches);
@@ -0,0 +1,52 @@
+//! Measures the time of scanning a directory tree and reading metadata of files.
+//!
+//! All files have different sizes, so no file contents are hashed and the result is dominated
+//! by the directory walk and the metadata stage. To see the effect of the page cache,
+//! drop the caches (`echo 3 > /proc/sys/vm/drop_caches`) before running the benchmark
+//! and increase `FILE_COUNT`.
+
+use std::fs::{create_dir, File};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use fclones::config::GroupConfig;
+use fclones::group_files;
+use fclones::log::Log;
+use fclones::path::Path;
+use tempfile::TempDir;
+
+const DIR_COUNT: usize = 100;
+const FILE_COUNT: usize = 10000;
+
+/// Creates a tree of empty-but-sized files. Files are sparse, so they don't take disk space.
+fn create_tree() -> TempDir {
+    let root = tempfile::tempdir().unwrap();
+    for d in 0..DIR_COUNT {
+        let dir = root.path().join(format!("dir{}", d));
+        create_dir(&dir).unwrap();
+        for f in 0..FILE_COUNT / DIR_COUNT {
+            let len = (d * FILE_COUNT / DIR_COUNT + f) as u64;
+            File::create(dir.join(format!("file{}", f)))
+                .unwrap()
+                .set_len(len)
+                .unwrap();
+        }
+    }
+    root
+}
+
+fn scan(c: &mut Criterion) {
+    let root = create_tree();
+    let mut log = Log::new();
+    log.no_progress = true;
+    let config = GroupConfig {
+        paths: vec![Path::from(root.path())],
+        ..Default::default()
+    };
+
+    c.bench_function("scan_files", |b| {
+        b.iter(|| group_files(&config, &log).unwrap())
+    });
+}
+
+criterion_group!(benches, scan);
+criterion_main!(benches);
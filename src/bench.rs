@@ -0,0 +1,410 @@
+//! Measuring read performance of storage devices.
+
+use std::cmp::max;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use crossbeam_utils::thread;
+use rand::{Rng, RngCore};
+use serde::Serialize;
+
+use crate::config::BenchConfig;
+use crate::device::DiskDevices;
+use crate::error::Error;
+use crate::file::{FileChunk, FileLen, FilePos};
+use crate::hasher::{evict_page_cache, file_hash};
+use crate::log::Log;
+use crate::path::Path;
+
+/// Name of the scratch file created in the benchmarked directory
+const SCRATCH_FILE_NAME: &str = ".fclones-bench.tmp";
+
+/// A measurement is considered as good as the best one if it achieves
+/// at least this fraction of its throughput
+const GOOD_ENOUGH: f64 = 0.9;
+
+/// Result of reading a file with a given block size and number of concurrent reads
+#[derive(Debug, Clone, Serialize)]
+pub struct Measurement {
+    pub block_size: FileLen,
+    pub queue_depth: usize,
+    /// Bytes read per second
+    pub throughput: f64,
+    /// Blocks read per second
+    pub iops: f64,
+}
+
+/// Settings recommended for the benchmarked device
+#[derive(Debug, Clone, Serialize)]
+pub struct Recommendation {
+    pub random_threads: usize,
+    pub sequential_threads: usize,
+    pub min_read_buffer: FileLen,
+}
+
+/// Results of the benchmark of a device
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchReport {
+    pub device: String,
+    pub disk_type: String,
+    pub file: Path,
+    pub file_len: FileLen,
+    pub sequential: Vec<Measurement>,
+    pub random: Vec<Measurement>,
+    pub recommendation: Recommendation,
+}
+
+impl BenchReport {
+    /// Returns the command line options applying the recommended settings
+    pub fn recommended_options(&self) -> String {
+        format!(
+            "--threads dev:{}:{},{} --min-read-buffer {}",
+            self.device,
+            self.recommendation.random_threads,
+            self.recommendation.sequential_threads,
+            self.recommendation.min_read_buffer.0
+        )
+    }
+
+    /// Writes the report in a human-readable form
+    pub fn write_as_text(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "Device: {} ({})", self.device, self.disk_type)?;
+        writeln!(out, "File: {} ({})", self.file.display(), self.file_len)?;
+        for (name, measurements) in [
+            ("Sequential reads", &self.sequential),
+            ("Random reads", &self.random),
+        ] {
+            writeln!(out)?;
+            writeln!(out, "{}:", name)?;
+            writeln!(
+                out,
+                "{:>12} {:>12} {:>14} {:>12}",
+                "block size", "queue depth", "throughput", "IOPS"
+            )?;
+            for m in measurements {
+                writeln!(
+                    out,
+                    "{:>12} {:>12} {:>12}/s {:>12.0}",
+                    m.block_size.to_string(),
+                    m.queue_depth,
+                    FileLen(m.throughput as u64).to_string(),
+                    m.iops
+                )?;
+            }
+        }
+        writeln!(out)?;
+        writeln!(out, "Recommended settings:")?;
+        writeln!(out, "    {}", self.recommended_options())
+    }
+
+    /// Writes the report in JSON
+    pub fn write_as_json(&self, out: &mut impl Write) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut *out, self)?;
+        writeln!(out)
+    }
+}
+
+/// A file used for the benchmark.
+/// If the file was created by the benchmark, it is removed when dropped.
+struct BenchFile {
+    path: Path,
+    len: FileLen,
+    remove: bool,
+}
+
+impl BenchFile {
+    /// Returns the file at `path`, or creates a new file filled with random data
+    /// if `path` is a directory.
+    fn open(path: &Path, len: FileLen, log: &Log) -> io::Result<BenchFile> {
+        let metadata = std::fs::metadata(path.to_path_buf())?;
+        if metadata.is_file() {
+            return Ok(BenchFile {
+                path: path.clone(),
+                len: FileLen(metadata.len()),
+                remove: false,
+            });
+        }
+        let file_path = path.clone().share().join(Path::from(SCRATCH_FILE_NAME));
+        let progress = log.bytes_progress_bar("Writing scratch file", len.0);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(file_path.to_path_buf())?;
+        let bench_file = BenchFile {
+            path: file_path,
+            len,
+            remove: true,
+        };
+        // Random data prevent filesystem compression from making the reads faster
+        let mut buf = vec![0; 1024 * 1024];
+        let mut written = 0;
+        while written < len.0 {
+            let to_write = (len.0 - written).min(buf.len() as u64) as usize;
+            rand::thread_rng().fill_bytes(&mut buf[..to_write]);
+            file.write_all(&buf[..to_write])?;
+            written += to_write as u64;
+            progress.inc(to_write);
+        }
+        file.sync_all()?;
+        Ok(bench_file)
+    }
+
+    /// Removes the file from the page cache, so the next reads have to access the device
+    fn evict_from_cache(&self) -> io::Result<()> {
+        let file = File::open(self.path.to_path_buf())?;
+        evict_page_cache(&file, FilePos(0), self.len);
+        Ok(())
+    }
+}
+
+impl Drop for BenchFile {
+    fn drop(&mut self) {
+        if self.remove {
+            let _ = std::fs::remove_file(self.path.to_path_buf());
+        }
+    }
+}
+
+/// Reads the whole file once, split into `queue_depth` ranges read concurrently.
+fn measure_sequential(
+    file: &BenchFile,
+    block_size: FileLen,
+    queue_depth: usize,
+) -> io::Result<Measurement> {
+    file.evict_from_cache()?;
+    let range_len = FileLen(max(1, file.len.0 / queue_depth as u64)).round_up_to(block_size);
+    let start = Instant::now();
+    let read_len = thread::scope(|s| {
+        let handles: Vec<_> = (0..queue_depth as u64)
+            .map(|i| FilePos(i * range_len.0))
+            .filter(|&pos| pos < file.len.as_pos())
+            .map(|pos| {
+                s.spawn(move |_| {
+                    let len = range_len.min(FileLen(file.len.0 - pos.0));
+                    let chunk = FileChunk::new(&file.path, pos, len);
+                    file_hash(&chunk, block_size.0 as usize, |_| {}).map(|_| len)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .sum::<io::Result<FileLen>>()
+    })
+    .unwrap()?;
+    Ok(measurement(
+        block_size,
+        queue_depth,
+        read_len,
+        read_len.0 / block_size.0,
+        start.elapsed(),
+    ))
+}
+
+/// Reads randomly selected blocks of the file by `queue_depth` threads, until
+/// the given time elapses.
+fn measure_random(
+    file: &BenchFile,
+    block_size: FileLen,
+    queue_depth: usize,
+    duration: Duration,
+) -> io::Result<Measurement> {
+    file.evict_from_cache()?;
+    let block_count = max(1, file.len.0 / block_size.0);
+    let start = Instant::now();
+    let read_count = thread::scope(|s| {
+        let handles: Vec<_> = (0..queue_depth)
+            .map(|_| {
+                s.spawn(move |_| {
+                    let mut rng = rand::thread_rng();
+                    let mut count = 0;
+                    while start.elapsed() < duration {
+                        let pos = FilePos(rng.gen_range(0..block_count) * block_size.0);
+                        let chunk = FileChunk::new(&file.path, pos, block_size);
+                        file_hash(&chunk, block_size.0 as usize, |_| {})?;
+                        count += 1;
+                    }
+                    Ok(count)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .sum::<io::Result<u64>>()
+    })
+    .unwrap()?;
+    Ok(measurement(
+        block_size,
+        queue_depth,
+        block_size * read_count,
+        read_count,
+        start.elapsed(),
+    ))
+}
+
+fn measurement(
+    block_size: FileLen,
+    queue_depth: usize,
+    read_len: FileLen,
+    read_count: u64,
+    elapsed: Duration,
+) -> Measurement {
+    let secs = elapsed.as_secs_f64().max(f64::MIN_POSITIVE);
+    Measurement {
+        block_size,
+        queue_depth,
+        throughput: read_len.as_f64() / secs,
+        iops: read_count as f64 / secs,
+    }
+}
+
+/// Returns the first measurement with the throughput close enough to the best one.
+/// Measurements should be sorted from the least demanding.
+fn first_good_enough(measurements: &[&Measurement]) -> Option<Measurement> {
+    let best = measurements
+        .iter()
+        .map(|m| m.throughput)
+        .fold(0.0, f64::max);
+    measurements
+        .iter()
+        .find(|m| m.throughput >= GOOD_ENOUGH * best)
+        .map(|&m| m.clone())
+}
+
+/// Measures the read performance of the device holding `config.path`
+/// and recommends the settings for it.
+pub fn bench(config: &BenchConfig, log: &Log) -> Result<BenchReport, Error> {
+    let path = dunce::canonicalize(&config.path)
+        .map_err(|e| Error::io("Cannot access", Some(&Path::from(&config.path)), e))?;
+    let path = Path::from(path);
+    let devices = DiskDevices::new(&HashMap::new());
+    let device = devices.get_by_path(&path);
+    let file = BenchFile::open(&path, config.size, log)
+        .map_err(|e| Error::io("Failed to prepare file for reading", None, e))?;
+
+    let mut block_sizes = config.block_sizes.clone();
+    block_sizes.sort();
+    block_sizes.dedup();
+    block_sizes.retain(|&b| b <= file.len);
+    if block_sizes.is_empty() {
+        return Err(Error::config(format!(
+            "File {} is smaller than the block sizes",
+            file.path.display()
+        )));
+    }
+    let mut queue_depths = config.queue_depths.clone();
+    queue_depths.sort_unstable();
+    queue_depths.dedup();
+
+    let count = block_sizes.len() * (1 + queue_depths.len()) + queue_depths.len();
+    let progress = log.progress_bar("Measuring", count as u64);
+    let read_error = |e: io::Error| Error::io("Failed to read", Some(&file.path), e);
+
+    // The best block size is determined for a single thread first
+    let mut sequential = Vec::new();
+    for &block_size in block_sizes.iter() {
+        sequential.push(measure_sequential(&file, block_size, 1).map_err(read_error)?);
+        progress.tick();
+    }
+    let min_read_buffer = first_good_enough(&sequential.iter().collect::<Vec<_>>())
+        .unwrap()
+        .block_size;
+    for &queue_depth in queue_depths.iter().filter(|&&q| q > 1) {
+        sequential
+            .push(measure_sequential(&file, min_read_buffer, queue_depth).map_err(read_error)?);
+        progress.tick();
+    }
+
+    let mut random = Vec::new();
+    for &block_size in block_sizes.iter() {
+        for &queue_depth in queue_depths.iter() {
+            random.push(
+                measure_random(&file, block_size, queue_depth, config.duration)
+                    .map_err(read_error)?,
+            );
+            progress.tick();
+        }
+    }
+    drop(progress);
+
+    // Thread counts are chosen by the throughput of reading the smallest blocks randomly,
+    // because this is how prefixes and suffixes of files are read:
+    let smallest_block = block_sizes[0];
+    let random_threads = first_good_enough(
+        &random
+            .iter()
+            .filter(|m| m.block_size == smallest_block)
+            .collect::<Vec<_>>(),
+    )
+    .unwrap()
+    .queue_depth;
+    let sequential_threads = first_good_enough(
+        &sequential
+            .iter()
+            .filter(|m| m.block_size == min_read_buffer)
+            .collect::<Vec<_>>(),
+    )
+    .unwrap()
+    .queue_depth;
+
+    Ok(BenchReport {
+        device: device.name.to_string_lossy().to_string(),
+        disk_type: device.disk_type_name().to_owned(),
+        file: file.path.clone(),
+        file_len: file.len,
+        sequential,
+        random,
+        recommendation: Recommendation {
+            random_threads,
+            sequential_threads,
+            min_read_buffer,
+        },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::test::with_dir;
+
+    #[test]
+    fn bench_recommends_settings() {
+        with_dir("bench/recommends_settings", |root| {
+            let config = BenchConfig {
+                path: root.clone(),
+                size: FileLen(1024 * 1024),
+                block_sizes: vec![FileLen(4096), FileLen(65536)],
+                queue_depths: vec![1, 2],
+                duration: Duration::from_millis(50),
+                json: false,
+            };
+            let mut log = Log::new();
+            log.no_progress = true;
+            let report = bench(&config, &log).unwrap();
+            assert_eq!(report.sequential.len(), 3);
+            assert_eq!(report.random.len(), 4);
+            assert!(report.random.iter().all(|m| m.iops > 0.0));
+            assert!([1, 2].contains(&report.recommendation.random_threads));
+            assert!([1, 2].contains(&report.recommendation.sequential_threads));
+            // The scratch file is removed after the benchmark
+            assert!(!root.join(SCRATCH_FILE_NAME).exists());
+        });
+    }
+
+    #[test]
+    fn first_good_enough_prefers_less_demanding_settings() {
+        let m = |queue_depth, throughput| Measurement {
+            block_size: FileLen(4096),
+            queue_depth,
+            throughput,
+            iops: throughput / 4096.0,
+        };
+        let measurements = [m(1, 100.0), m(2, 195.0), m(4, 200.0)];
+        let best = first_good_enough(&measurements.iter().collect::<Vec<_>>());
+        assert_eq!(best.unwrap().queue_depth, 2);
+    }
+}
@@ -0,0 +1,144 @@
+//! Detection of read-only btrfs subvolumes, such as snapshots.
+//!
+//! Files in a snapshot share their data with the files the snapshot was taken from,
+//! so they look like duplicates, but removing them reclaims no space.
+//! Read-only snapshots can't be modified at all.
+
+use crate::path::Path;
+
+/// Returns true if the file or directory is located in a read-only btrfs subvolume.
+/// Returns false if the path is on another file system or if its subvolume can't be checked.
+#[cfg(target_os = "linux")]
+pub(crate) fn is_in_read_only_subvolume(path: &Path) -> bool {
+    linux::is_in_read_only_subvolume(path)
+}
+
+/// Returns true if the directory is the root of a read-only btrfs subvolume.
+#[cfg(target_os = "linux")]
+pub(crate) fn is_read_only_subvolume_root(path: &Path) -> bool {
+    linux::is_subvolume_root(path) && linux::is_in_read_only_subvolume(path)
+}
+
+/// Btrfs is supported only on Linux.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn is_in_read_only_subvolume(_path: &Path) -> bool {
+    false
+}
+
+/// Btrfs is supported only on Linux.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn is_read_only_subvolume_root(_path: &Path) -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+
+    use dashmap::DashMap;
+    use lazy_static::lazy_static;
+    use nix::request_code_read;
+    use nix::sys::statfs::FsType;
+
+    use crate::path::Path;
+
+    // From /usr/include/linux/magic.h and /usr/include/linux/btrfs.h:
+    // #define BTRFS_SUPER_MAGIC 0x9123683E
+    // #define BTRFS_FIRST_FREE_OBJECTID 256ULL
+    // #define BTRFS_IOC_SUBVOL_GETFLAGS _IOR(BTRFS_IOCTL_MAGIC, 25, __u64)
+    // #define BTRFS_SUBVOL_RDONLY (1ULL << 1)
+    const BTRFS_SUPER_MAGIC: u32 = 0x9123683E;
+    const BTRFS_FIRST_FREE_OBJECTID: u64 = 256;
+    const BTRFS_IOCTL_MAGIC: u8 = 0x94;
+    const BTRFS_IOC_SUBVOL_GETFLAGS_NR: u8 = 25;
+    const BTRFS_SUBVOL_RDONLY: u64 = 1 << 1;
+
+    lazy_static! {
+        /// Every btrfs subvolume has a distinct device id, so the read-only flag
+        /// needs to be checked only once per device
+        static ref READ_ONLY_BY_DEVICE: DashMap<u64, bool> = DashMap::new();
+    }
+
+    pub fn is_in_read_only_subvolume(path: &Path) -> bool {
+        let metadata = match fs::symlink_metadata(path.to_path_buf()) {
+            Ok(metadata) => metadata,
+            Err(_) => return false,
+        };
+        let device = metadata.dev();
+        if let Some(read_only) = READ_ONLY_BY_DEVICE.get(&device) {
+            return *read_only;
+        }
+        let read_only = is_btrfs(path)
+            && subvolume_root(path, device)
+                .and_then(|root| subvolume_flags(&root))
+                .is_some_and(|flags| flags & BTRFS_SUBVOL_RDONLY != 0);
+        READ_ONLY_BY_DEVICE.insert(device, read_only);
+        read_only
+    }
+
+    /// Returns true if the path is the top directory of a btrfs subvolume.
+    /// The top directory of each subvolume has always the same inode number.
+    pub fn is_subvolume_root(path: &Path) -> bool {
+        fs::symlink_metadata(path.to_path_buf())
+            .is_ok_and(|m| m.is_dir() && m.ino() == BTRFS_FIRST_FREE_OBJECTID)
+            && is_btrfs(path)
+    }
+
+    fn is_btrfs(path: &Path) -> bool {
+        // The type of the magic number differs between architectures
+        nix::sys::statfs::statfs(&path.to_path_buf())
+            .is_ok_and(|s| s.filesystem_type() == FsType(BTRFS_SUPER_MAGIC as _))
+    }
+
+    /// Finds the top directory of the subvolume containing the given path,
+    /// by going up the directory tree until the device changes.
+    fn subvolume_root(path: &Path, device: u64) -> Option<Path> {
+        let mut current = path.clone();
+        loop {
+            let metadata = fs::symlink_metadata(current.to_path_buf()).ok()?;
+            if metadata.dev() != device {
+                return None;
+            }
+            if metadata.is_dir() && metadata.ino() == BTRFS_FIRST_FREE_OBJECTID {
+                return Some(current);
+            }
+            current = current.parent()?.as_ref().clone();
+        }
+    }
+
+    fn subvolume_flags(root: &Path) -> Option<u64> {
+        let dir = fs::File::open(root.to_path_buf()).ok()?;
+        let mut flags: u64 = 0;
+        let ret = unsafe {
+            libc::ioctl(
+                dir.as_raw_fd(),
+                request_code_read!(
+                    BTRFS_IOCTL_MAGIC,
+                    BTRFS_IOC_SUBVOL_GETFLAGS_NR,
+                    std::mem::size_of::<u64>()
+                ),
+                &mut flags,
+            )
+        };
+        (ret == 0).then_some(flags)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::util::test::{create_file, with_dir};
+
+    use super::*;
+
+    #[test]
+    fn regular_dir_is_not_read_only_subvolume() {
+        with_dir("btrfs/regular_dir", |root| {
+            let file = root.join("file");
+            create_file(&file);
+            assert!(!is_read_only_subvolume_root(&Path::from(root)));
+            assert!(!is_in_read_only_subvolume(&Path::from(&file)));
+        });
+    }
+}
@@ -14,6 +14,11 @@ use crate::path::Path;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Key {
+    /// Locates the file when the hashes are stored in its extended attributes.
+    /// Not a part of the database key, so moving a file doesn't lose its cached hashes.
+    #[cfg(all(unix, feature = "xattr-cache"))]
+    #[serde(skip)]
+    path: Option<Path>,
     file_id: u128,
     device_id: u64,
     chunk_pos: FilePos,
@@ -21,6 +26,17 @@ pub struct Key {
     algorithm: HashAlgorithm,
 }
 
+impl Key {
+    /// Returns the name of the extended attribute storing the hash of the chunk
+    #[cfg(all(unix, feature = "xattr-cache"))]
+    fn attr_name(&self) -> String {
+        format!(
+            "{}.{:?}.{}.{}",
+            HASH_ATTR_PREFIX, self.algorithm, self.chunk_pos.0, self.chunk_len.0
+        )
+    }
+}
+
 impl Display for Key {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}:{}", self.device_id, self.file_id)
@@ -34,13 +50,26 @@ struct CachedFileInfo {
     hash: FileHash,
 }
 
+/// Prefix of the names of the extended attributes storing the cached hashes
+#[cfg(all(unix, feature = "xattr-cache"))]
+const HASH_ATTR_PREFIX: &str = "user.fclones.hash";
+
+/// Where the cached hashes are kept
+enum Storage {
+    /// An embedded database in a directory of the user
+    Db(sled::Db),
+    /// Extended attributes of the hashed files
+    #[cfg(all(unix, feature = "xattr-cache"))]
+    Xattrs,
+}
+
 /// Caches file hashes to avoid repeated computations in subsequent runs of fclones.
 ///
 /// Most files don't change very frequently so their hashes don't change.
 /// Usually it is a lot faster to retrieve the hash from an embedded database that to compute
 /// them from file data.
 pub struct HashCache {
-    cache: sled::Db,
+    storage: Storage,
 }
 
 impl HashCache {
@@ -48,10 +77,10 @@ impl HashCache {
     /// If the database doesn't exist yet, creates a new one.
     pub fn open(database_path: &Path) -> Result<HashCache, Error> {
         create_dir_all(&database_path.to_path_buf()).map_err(|e| {
-            format!(
-                "Count not create hash database directory {}: {}",
-                database_path.to_escaped_string(),
-                e
+            Error::io(
+                "Count not create hash database directory",
+                Some(database_path),
+                e,
             )
         })?;
         let cache = sled::open(&database_path.to_path_buf()).map_err(|e| {
@@ -61,7 +90,9 @@ impl HashCache {
                 e
             )
         })?;
-        Ok(HashCache { cache })
+        Ok(HashCache {
+            storage: Storage::Db(cache),
+        })
     }
 
     /// Opens the file hash database located in `fclones` subdir of user cache directory.
@@ -73,12 +104,42 @@ impl HashCache {
         Self::open(&Path::from(hash_db_path))
     }
 
+    /// Creates a cache storing the hashes in extended attributes of the hashed files.
+    ///
+    /// The hashes stay with the files when they are moved or copied with their attributes,
+    /// and no database has to be kept in the user directory. Storing a hash requires
+    /// write access to the file, but doesn't change its modification time.
+    ///
+    /// Returns an error of [`ErrorKind::Unsupported`](crate::error::ErrorKind::Unsupported)
+    /// if fclones was built without the `xattr-cache` feature or the platform
+    /// doesn't support extended attributes.
+    #[cfg(all(unix, feature = "xattr-cache"))]
+    pub fn open_xattrs() -> Result<HashCache, Error> {
+        Ok(HashCache {
+            storage: Storage::Xattrs,
+        })
+    }
+
+    #[cfg(all(unix, not(feature = "xattr-cache")))]
+    pub fn open_xattrs() -> Result<HashCache, Error> {
+        Err(Error::unsupported(
+            "Caching hashes in extended attributes requires fclones built with the xattr-cache feature",
+        ))
+    }
+
+    #[cfg(not(unix))]
+    pub fn open_xattrs() -> Result<HashCache, Error> {
+        Err(Error::unsupported(
+            "Extended attributes are not supported on this platform",
+        ))
+    }
+
     /// Stores the file hash plus some file metadata in the cache.
     pub fn put(&self, key: &Key, file: &FileMetadata, hash: FileHash) -> Result<(), Error> {
         let value = CachedFileInfo {
             modified_timestamp_us: file
                 .modified()
-                .map_err(|e| format!("Unable to get file modification timestamp: {}", e))?
+                .map_err(|e| Error::io("Unable to get file modification timestamp", None, e))?
                 .duration_since(UNIX_EPOCH)
                 .unwrap_or(Duration::ZERO)
                 .as_micros(),
@@ -86,11 +147,21 @@ impl HashCache {
             hash,
         };
 
-        let key = bincode::serialize(&key).unwrap();
         let value = bincode::serialize(&value).unwrap();
-        self.cache
-            .insert(key, value)
-            .map_err(|e| format!("Failed to write entry to cache: {}", e))?;
+        match &self.storage {
+            Storage::Db(cache) => {
+                let key = bincode::serialize(&key).unwrap();
+                cache
+                    .insert(key, value)
+                    .map_err(|e| format!("Failed to write entry to cache: {}", e))?;
+            }
+            #[cfg(all(unix, feature = "xattr-cache"))]
+            Storage::Xattrs => {
+                let path = key.path.as_ref().expect("Key without path");
+                xattr::set(path.to_path_buf(), key.attr_name(), &value)
+                    .map_err(|e| Error::io("Failed to write hash attribute of", Some(path), e))?;
+            }
+        }
         Ok(())
     }
 
@@ -100,11 +171,21 @@ impl HashCache {
     /// or its current modification time do not match the file length and modification time
     /// recorded at insertion time.
     pub fn get(&self, key: &Key, metadata: &FileMetadata) -> Result<Option<FileHash>, Error> {
-        let key = bincode::serialize(&key).unwrap();
-        let value: Option<IVec> = self
-            .cache
-            .get(key)
-            .map_err(|e| format!("Failed to retrieve entry from cache: {}", e))?;
+        let value: Option<IVec> = match &self.storage {
+            Storage::Db(cache) => {
+                let key = bincode::serialize(&key).unwrap();
+                cache
+                    .get(key)
+                    .map_err(|e| format!("Failed to retrieve entry from cache: {}", e))?
+            }
+            #[cfg(all(unix, feature = "xattr-cache"))]
+            Storage::Xattrs => {
+                let path = key.path.as_ref().expect("Key without path");
+                xattr::get(path.to_path_buf(), key.attr_name())
+                    .map_err(|e| Error::io("Failed to read hash attribute of", Some(path), e))?
+                    .map(IVec::from)
+            }
+        };
         let value = match value {
             Some(v) => v,
             None => return Ok(None), // not found in cache
@@ -114,7 +195,7 @@ impl HashCache {
 
         let modified = metadata
             .modified()
-            .map_err(|e| format!("Unable to get file modification timestamp: {}", e))?
+            .map_err(|e| Error::io("Unable to get file modification timestamp", None, e))?
             .duration_since(UNIX_EPOCH)
             .unwrap_or(Duration::ZERO)
             .as_micros();
@@ -137,12 +218,14 @@ impl HashCache {
         algorithm: HashAlgorithm,
     ) -> Result<Key, Error> {
         let key = Key {
+            #[cfg(all(unix, feature = "xattr-cache"))]
+            path: Some(chunk.path.clone()),
             file_id: metadata
                 .inode_id()
-                .map_err(|e| format!("Unable to get file id: {}", e))?,
+                .map_err(|e| Error::io("Unable to get file id", None, e))?,
             device_id: metadata
                 .device_id()
-                .map_err(|e| format!("Unable to get device id: {}", e))?,
+                .map_err(|e| Error::io("Unable to get device id", None, e))?,
             chunk_pos: chunk.pos,
             chunk_len: chunk.len,
             algorithm,
@@ -156,6 +239,8 @@ mod test {
     use std::fs::OpenOptions;
 
     use crate::cache::HashCache;
+    #[cfg(not(feature = "xattr-cache"))]
+    use crate::error::ErrorKind;
     use crate::file::{FileChunk, FileHash, FileLen, FileMetadata, FilePos};
     use crate::hasher::HashAlgorithm;
     use crate::path::Path;
@@ -242,4 +327,37 @@ mod test {
             assert_eq!(cached_hash, None)
         });
     }
+
+    #[test]
+    #[cfg(all(unix, feature = "xattr-cache"))]
+    fn return_hash_cached_in_xattrs() {
+        with_dir("cache/return_hash_cached_in_xattrs", |root| {
+            let path = root.join("file");
+            create_file(&path);
+            let path = Path::from(&path);
+            let metadata = FileMetadata::new(&path).unwrap();
+            let chunk = FileChunk::new(&path, FilePos(0), FileLen(1000));
+
+            let cache = HashCache::open_xattrs().unwrap();
+            let key = cache
+                .key(&chunk, &metadata, HashAlgorithm::MetroHash128)
+                .unwrap();
+            assert_eq!(cache.get(&key, &metadata).unwrap(), None);
+            cache.put(&key, &metadata, FileHash(12345)).unwrap();
+            assert_eq!(cache.get(&key, &metadata).unwrap(), Some(FileHash(12345)));
+
+            let chunk = FileChunk::new(&path, FilePos(1000), FileLen(2000));
+            let key = cache
+                .key(&chunk, &metadata, HashAlgorithm::MetroHash128)
+                .unwrap();
+            assert_eq!(cache.get(&key, &metadata).unwrap(), None);
+        });
+    }
+
+    #[test]
+    #[cfg(not(feature = "xattr-cache"))]
+    fn xattr_cache_is_unsupported_without_feature() {
+        let result = HashCache::open_xattrs();
+        assert!(matches!(result, Err(e) if matches!(e.kind, ErrorKind::Unsupported)));
+    }
 }
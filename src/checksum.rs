@@ -0,0 +1,462 @@
+//! Writing checksum files in the format of `sha256sum` and verifying files against them.
+
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::{BufRead, ErrorKind, Write};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crossbeam_utils::thread;
+use rayon::prelude::*;
+use sysinfo::DiskType;
+
+use crate::device::{DiskDevice, DiskDevices};
+use crate::error::Error;
+use crate::file::FileInfo;
+use crate::hasher::file_sha256;
+use crate::log::Log;
+use crate::path::Path;
+
+/// Length of a SHA-256 digest written in hex
+const DIGEST_LEN: usize = 64;
+
+/// A file listed in a checksum file, together with its expected digest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChecksumEntry {
+    /// Expected SHA-256 digest in lowercase hex
+    pub digest: String,
+    /// File name as given in the checksum file, unescaped
+    pub name: String,
+}
+
+/// Outcome of verifying a single file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyStatus {
+    /// The digest of the file matches the expected one
+    Ok,
+    /// The digest doesn't match, or the file could not be read
+    Failed,
+    /// The file does not exist
+    Missing,
+}
+
+impl Display for VerifyStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            VerifyStatus::Ok => "OK",
+            VerifyStatus::Failed => "FAILED",
+            VerifyStatus::Missing => "MISSING",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VerifyResult {
+    pub name: String,
+    pub status: VerifyStatus,
+}
+
+/// Reverts the escaping of file names containing backslashes or newlines
+fn unescape(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                result.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                result.push('\\');
+                chars.next();
+            }
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Layout of the lines of a checksum file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChecksumFormat {
+    /// `<digest>  <path>`, as written by GNU `sha256sum`
+    #[default]
+    Gnu,
+    /// `SHA256 (<path>) = <digest>`, as written by BSD `sha256` and `sha256sum --tag`
+    Bsd,
+}
+
+impl ChecksumFormat {
+    pub fn variants() -> Vec<&'static str> {
+        vec!["gnu", "bsd"]
+    }
+}
+
+impl Display for ChecksumFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ChecksumFormat::Gnu => f.pad("gnu"),
+            ChecksumFormat::Bsd => f.pad("bsd"),
+        }
+    }
+}
+
+impl FromStr for ChecksumFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "gnu" => Ok(ChecksumFormat::Gnu),
+            "bsd" => Ok(ChecksumFormat::Bsd),
+            s => Err(format!("Unrecognized checksum format: {}", s)),
+        }
+    }
+}
+
+/// Formats a line of a checksum file.
+/// Like `sha256sum`, escapes backslashes and newlines in the file name
+/// and marks such lines with a leading backslash.
+fn checksum_line(digest: &str, path: &Path, format: ChecksumFormat) -> String {
+    let name = path.to_string_lossy();
+    let (prefix, name) = if name.contains(['\\', '\n']) {
+        ("\\", name.replace('\\', "\\\\").replace('\n', "\\n"))
+    } else {
+        ("", name)
+    };
+    match format {
+        ChecksumFormat::Gnu => format!("{}{}  {}", prefix, digest, name),
+        ChecksumFormat::Bsd => format!("{}SHA256 ({}) = {}", prefix, name, digest),
+    }
+}
+
+/// Parses a checksum file written by `sha256sum`.
+///
+/// Each line holds a hex digest, a space, a space or `*` denoting the binary mode,
+/// and a file name. Lines with file names containing backslashes or newlines
+/// start with a backslash and have these characters escaped.
+/// Blank lines and lines starting with `#` are skipped.
+pub fn parse_checksums(reader: impl BufRead) -> Result<Vec<ChecksumEntry>, Error> {
+    let mut entries = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line_no = index + 1;
+        let line = line.map_err(|e| Error::io("Failed to read checksums", None, e))?;
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let malformed = || {
+            Error::report_parse(
+                Some(line_no),
+                format!("Malformed checksum line {}", line_no),
+            )
+        };
+        let (escaped, line) = match line.strip_prefix('\\') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let digest = line.get(..DIGEST_LEN).ok_or_else(malformed)?;
+        if !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(malformed());
+        }
+        let name = line[DIGEST_LEN..]
+            .strip_prefix(' ')
+            .and_then(|s| s.strip_prefix(' ').or_else(|| s.strip_prefix('*')))
+            .filter(|s| !s.is_empty())
+            .ok_or_else(malformed)?;
+        entries.push(ChecksumEntry {
+            digest: digest.to_ascii_lowercase(),
+            name: if escaped {
+                unescape(name)
+            } else {
+                name.to_owned()
+            },
+        });
+    }
+    Ok(entries)
+}
+
+fn verify_file(
+    entry: &ChecksumEntry,
+    file: &FileInfo,
+    device: &DiskDevice,
+    log: &Log,
+) -> VerifyStatus {
+    match file_sha256(file.path(), device.read_buf_len()) {
+        Ok(digest) if to_hex(&digest) == entry.digest => VerifyStatus::Ok,
+        Ok(_) => VerifyStatus::Failed,
+        Err(e) if e.kind() == ErrorKind::NotFound => VerifyStatus::Missing,
+        Err(e) => {
+            log.warn(format!("Failed to read {}: {}", file.path().display(), e));
+            VerifyStatus::Failed
+        }
+    }
+}
+
+/// Computes the digests of the listed files and compares them with the expected ones.
+/// Returns the results in the same order as the entries.
+///
+/// Relative file names are resolved against `base_dir`.
+/// Files stored on different devices are read independently, each on the thread pool
+/// configured for sequential access on its device. Files on rotational drives are read
+/// in the order of their physical location.
+pub fn verify_checksums(
+    entries: &[ChecksumEntry],
+    base_dir: &Path,
+    devices: &DiskDevices,
+    log: &Log,
+) -> Vec<VerifyResult> {
+    let base_dir = Arc::new(base_dir.clone());
+    let mut statuses: Vec<_> = entries.iter().map(|_| VerifyStatus::Missing).collect();
+    let mut files: Vec<Vec<(usize, FileInfo)>> = devices.iter().map(|_| Vec::new()).collect();
+    for (i, entry) in entries.iter().enumerate() {
+        let path = base_dir.resolve(Path::from(entry.name.as_str()));
+        // Only the location of the file is used, to order the reads
+        match FileInfo::new_cached(path, devices) {
+            Ok(info) => files[info.get_device_index()].push((i, info)),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => {
+                log.warn(format!("Failed to read metadata of {}: {}", entry.name, e));
+                statuses[i] = VerifyStatus::Failed;
+            }
+        }
+    }
+
+    let file_count = files.iter().map(|f| f.len()).sum::<usize>();
+    let progress = log.progress_bar("Verifying checksums", file_count as u64);
+    let verified = process_on_devices(files, devices, |i, f, device| {
+        let status = verify_file(&entries[i], f, device, log);
+        progress.tick();
+        status
+    });
+
+    for (i, status) in verified {
+        statuses[i] = status;
+    }
+    entries
+        .iter()
+        .zip(statuses)
+        .map(|(e, status)| VerifyResult {
+            name: e.name.clone(),
+            status,
+        })
+        .collect()
+}
+
+/// Computes the SHA-256 digests of the given files and writes them to `out`,
+/// sorted by path. Files that can't be read are logged and left out.
+/// Returns the number of the written digests.
+///
+/// The files are read the same way as by [`verify_checksums`].
+pub fn write_checksums(
+    files: Vec<FileInfo>,
+    devices: &DiskDevices,
+    format: ChecksumFormat,
+    out: &mut dyn Write,
+    log: &Log,
+) -> io::Result<usize> {
+    let digests = compute_checksums(files, devices, log);
+    write_checksum_lines(&digests, format, out)?;
+    Ok(digests.len())
+}
+
+/// Computes the SHA-256 digests of the given files, sorted by path.
+/// Files that can't be read are logged and left out.
+pub(crate) fn compute_checksums(
+    files: Vec<FileInfo>,
+    devices: &DiskDevices,
+    log: &Log,
+) -> Vec<(Path, [u8; 32])> {
+    let mut by_device: Vec<Vec<(usize, FileInfo)>> = devices.iter().map(|_| Vec::new()).collect();
+    for (i, f) in files.into_iter().enumerate() {
+        by_device[f.get_device_index()].push((i, f));
+    }
+    let file_count = by_device.iter().map(|f| f.len()).sum::<usize>();
+    let progress = log.progress_bar("Computing checksums", file_count as u64);
+    let mut digests = process_on_devices(by_device, devices, |_, f, device| {
+        let digest = match file_sha256(f.path(), device.read_buf_len()) {
+            Ok(digest) => Some((f.path().clone(), digest)),
+            Err(e) => {
+                log.warn(format!("Failed to read {}: {}", f.path().display(), e));
+                None
+            }
+        };
+        progress.tick();
+        digest
+    })
+    .into_iter()
+    .filter_map(|(_, digest)| digest)
+    .collect::<Vec<_>>();
+    digests.sort_by(|(p1, _), (p2, _)| p1.cmp(p2));
+    digests
+}
+
+/// Writes the digests computed by [`compute_checksums`] to `out`, one per line
+pub(crate) fn write_checksum_lines(
+    digests: &[(Path, [u8; 32])],
+    format: ChecksumFormat,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    for (path, digest) in digests {
+        writeln!(out, "{}", checksum_line(&to_hex(digest), path, format))?;
+    }
+    Ok(())
+}
+
+/// Calls `f` on each file, on the thread pool configured for sequential access
+/// on the device of the file. Files on different devices are processed independently.
+/// Files on rotational drives are processed in the order of their physical location.
+/// Returns the results together with the indexes given to the files.
+fn process_on_devices<T, F>(
+    files: Vec<Vec<(usize, FileInfo)>>,
+    devices: &DiskDevices,
+    f: F,
+) -> Vec<(usize, T)>
+where
+    T: Send,
+    F: Fn(usize, &FileInfo, &DiskDevice) -> T + Sync,
+{
+    let f = &f;
+    thread::scope(|s| {
+        let handles: Vec<_> = files
+            .into_iter()
+            .zip(devices.iter())
+            .filter(|(files, _)| !files.is_empty())
+            .map(|(mut files, device)| {
+                s.spawn(move |_| {
+                    if device.disk_type != DiskType::SSD {
+                        fetch_physical_locations(&mut files);
+                        files.sort_by_key(|(_, f)| f.location);
+                    }
+                    device.seq_thread_pool().install(|| {
+                        files
+                            .par_iter()
+                            .map(|(i, file)| (*i, f(*i, file, device)))
+                            .collect::<Vec<_>>()
+                    })
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect()
+    })
+    .unwrap()
+}
+
+/// Replaces the inode numbers the files are initially ordered by with the physical
+/// locations of their data. Files whose extents can't be fetched keep their inode order.
+#[cfg(target_os = "linux")]
+fn fetch_physical_locations(files: &mut [(usize, FileInfo)]) {
+    for (_, f) in files.iter_mut() {
+        let _ = f.fetch_physical_location();
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn fetch_physical_locations(_files: &mut [(usize, FileInfo)]) {}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::fs::write;
+
+    use super::*;
+    use crate::util::test::with_dir;
+
+    const HELLO_DIGEST: &str = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+
+    #[test]
+    fn parse_text_and_binary_mode_lines() {
+        let input = format!(
+            "# comment\n{d}  foo\n\n{D} *bar baz\n\\{d}  a\\\\b\\nc\n",
+            d = HELLO_DIGEST,
+            D = HELLO_DIGEST.to_uppercase()
+        );
+        let entries = parse_checksums(input.as_bytes()).unwrap();
+        let names: Vec<_> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["foo", "bar baz", "a\\b\nc"]);
+        assert!(entries.iter().all(|e| e.digest == HELLO_DIGEST));
+    }
+
+    #[test]
+    fn parse_reports_malformed_line_number() {
+        let input = format!("{}  foo\nnot a checksum\n", HELLO_DIGEST);
+        let error = parse_checksums(input.as_bytes()).unwrap_err();
+        assert!(matches!(
+            error.kind,
+            crate::error::ErrorKind::ReportParse { line: Some(2) }
+        ));
+    }
+
+    #[test]
+    fn verify_reports_ok_failed_and_missing_files() {
+        with_dir("checksum/verify", |root| {
+            write(root.join("good"), b"hello\n").unwrap();
+            write(root.join("bad"), b"goodbye\n").unwrap();
+            let entry = |name: &str| ChecksumEntry {
+                digest: HELLO_DIGEST.to_owned(),
+                name: name.to_owned(),
+            };
+            let entries = [entry("good"), entry("bad"), entry("missing")];
+            let devices = DiskDevices::new(&HashMap::new());
+            let log = Log::new();
+            let results = verify_checksums(&entries, &Path::from(root), &devices, &log);
+            let statuses: Vec<_> = results.iter().map(|r| r.status).collect();
+            assert_eq!(
+                statuses,
+                [
+                    VerifyStatus::Ok,
+                    VerifyStatus::Failed,
+                    VerifyStatus::Missing
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn written_checksums_can_be_verified() {
+        with_dir("checksum/write", |root| {
+            write(root.join("b"), b"hello\n").unwrap();
+            write(root.join("a"), b"hello\n").unwrap();
+            let devices = DiskDevices::new(&HashMap::new());
+            let log = Log::new();
+            let files = ["b", "a"]
+                .iter()
+                .map(|name| FileInfo::new(Path::from(root.join(name)), &devices).unwrap())
+                .collect();
+            let mut out = Vec::new();
+            let count = write_checksums(files, &devices, ChecksumFormat::Gnu, &mut out, &log);
+            assert_eq!(count.unwrap(), 2);
+
+            let entries = parse_checksums(out.as_slice()).unwrap();
+            assert_eq!(entries[0].digest, HELLO_DIGEST);
+            assert_eq!(entries[0].name, root.join("a").to_string_lossy());
+            let results = verify_checksums(&entries, &Path::from(root), &devices, &log);
+            assert!(results.iter().all(|r| r.status == VerifyStatus::Ok));
+        });
+    }
+
+    #[test]
+    fn checksum_line_formats() {
+        let path = Path::from("/a b");
+        assert_eq!(
+            checksum_line("00ff", &path, ChecksumFormat::Gnu),
+            "00ff  /a b"
+        );
+        assert_eq!(
+            checksum_line("00ff", &path, ChecksumFormat::Bsd),
+            "SHA256 (/a b) = 00ff"
+        );
+        let path = Path::from("/a\nb");
+        assert_eq!(
+            checksum_line("00ff", &path, ChecksumFormat::Gnu),
+            "\\00ff  /a\\nb"
+        );
+    }
+}
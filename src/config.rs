@@ -8,17 +8,29 @@ use std::io::{stdin, BufRead, BufReader, ErrorKind};
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use chrono::{DateTime, FixedOffset, Local};
+#[cfg(feature = "cli")]
 use clap::AppSettings;
+#[cfg(feature = "cli")]
+use serde::Deserialize;
+#[cfg(feature = "cli")]
 use structopt::StructOpt;
+use sysinfo::DiskType;
 
-use crate::file::FileLen;
+use crate::checksum::ChecksumFormat;
+use crate::device::DiskDevices;
+use crate::error::Error;
+use crate::file::{FileLen, FileLenRange, HashEncoding};
 use crate::group::FileGroupFilter;
 use crate::group::Replication::{Overreplicated, Underreplicated};
-use crate::path::Path;
+use crate::import::ImportFormat;
+use crate::log::LogFormat;
+use crate::path::{Path, PathInterner};
 use crate::pattern::{Pattern, PatternError, PatternOpts};
-use crate::selector::PathSelector;
+use crate::report::SizeHistogram;
+use crate::selector::{ExtensionFilter, PathSelector};
 use crate::transform::Transform;
 
 #[derive(Debug, Clone, Copy)]
@@ -26,12 +38,54 @@ pub enum OutputFormat {
     Default,
     Fdupes,
     Csv,
+    Tsv,
     Json,
+    JsonLines,
+    Html,
+    Machine,
+    Report,
+    Sqlite,
 }
 
 impl OutputFormat {
     pub fn variants() -> Vec<&'static str> {
-        vec!["default", "fdupes", "csv", "json"]
+        vec![
+            "default", "fdupes", "csv", "tsv", "json", "ndjson", "jsonl", "html", "machine",
+            "report", "sqlite",
+        ]
+    }
+
+    /// Guesses the output format from the extension of the output file.
+    /// Returns `None` if the extension is not recognized.
+    /// The `.gz` extension of compressed files is skipped.
+    pub fn from_extension(path: &std::path::Path) -> Option<OutputFormat> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "gz" => Self::from_extension(std::path::Path::new(path.file_stem()?)),
+            "csv" => Some(OutputFormat::Csv),
+            "tsv" => Some(OutputFormat::Tsv),
+            "json" => Some(OutputFormat::Json),
+            "jsonl" | "ndjson" => Some(OutputFormat::JsonLines),
+            "html" | "htm" => Some(OutputFormat::Html),
+            "db" | "sqlite" | "sqlite3" => Some(OutputFormat::Sqlite),
+            _ => None,
+        }
+    }
+
+    /// Returns the usual extension of a file in this format, without the leading dot
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Csv => "csv",
+            OutputFormat::Tsv => "tsv",
+            OutputFormat::Json => "json",
+            OutputFormat::JsonLines => "ndjson",
+            OutputFormat::Html => "html",
+            OutputFormat::Sqlite => "db",
+            OutputFormat::Default
+            | OutputFormat::Fdupes
+            | OutputFormat::Machine
+            | OutputFormat::Report => "txt",
+        }
     }
 }
 
@@ -41,7 +95,13 @@ impl Display for OutputFormat {
             OutputFormat::Default => f.pad("default"),
             OutputFormat::Fdupes => f.pad("fdupes"),
             OutputFormat::Csv => f.pad("csv"),
+            OutputFormat::Tsv => f.pad("tsv"),
             OutputFormat::Json => f.pad("json"),
+            OutputFormat::JsonLines => f.pad("ndjson"),
+            OutputFormat::Html => f.pad("html"),
+            OutputFormat::Machine => f.pad("machine"),
+            OutputFormat::Report => f.pad("report"),
+            OutputFormat::Sqlite => f.pad("sqlite"),
         }
     }
 }
@@ -54,7 +114,13 @@ impl FromStr for OutputFormat {
             "default" => Ok(OutputFormat::Default),
             "fdupes" => Ok(OutputFormat::Fdupes),
             "csv" => Ok(OutputFormat::Csv),
+            "tsv" => Ok(OutputFormat::Tsv),
             "json" => Ok(OutputFormat::Json),
+            "ndjson" | "jsonl" => Ok(OutputFormat::JsonLines),
+            "html" => Ok(OutputFormat::Html),
+            "machine" => Ok(OutputFormat::Machine),
+            "report" => Ok(OutputFormat::Report),
+            "sqlite" => Ok(OutputFormat::Sqlite),
             s => Err(format!("Unrecognized output format: {}", s)),
         }
     }
@@ -67,6 +133,7 @@ impl Default for OutputFormat {
 }
 
 /// Parses date time string, accepts wide range of human-readable formats
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
 fn parse_date_time(s: &str) -> Result<DateTime<FixedOffset>, String> {
     match dtparse::parse(s) {
         Ok((dt, Some(offset))) => Ok(DateTime::from_utc(dt, offset)),
@@ -78,31 +145,195 @@ fn parse_date_time(s: &str) -> Result<DateTime<FixedOffset>, String> {
     }
 }
 
-/// Parses string with format: `<device>:<seq parallelism>[,<rand parallelism>]`
-fn parse_thread_count_option(s: &str) -> Result<(OsString, Parallelism), String> {
-    let (key, value) = if s.contains(':') {
-        let index = s.rfind(':').unwrap();
-        (&s[0..index], &s[(index + 1)..])
-    } else {
-        ("default", s)
+/// Parses duration string composed of numbers followed by units, e.g. `30m`, `2h`, `1h30m`.
+/// Accepted units are `ms`, `s`, `m`, `h`, `d`, `w` and `y`. A number without a unit means seconds.
+/// A year is 365.25 days, as in `humantime`.
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let err = || {
+        format!(
+            "Invalid duration: {}. Expected e.g. 90s, 30m, 2h or 1h30m",
+            s
+        )
+    };
+    let s = s.trim();
+    if s.is_empty() {
+        return Err(err());
+    }
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+    // In milliseconds:
+    let mut total: u64 = 0;
+    let mut number = String::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let multiplier = match c {
+            'm' if chars.next_if_eq(&'s').is_some() => 1,
+            's' => 1000,
+            'm' => 60 * 1000,
+            'h' => 60 * 60 * 1000,
+            'd' => 24 * 60 * 60 * 1000,
+            'w' => 7 * 24 * 60 * 60 * 1000,
+            'y' => 31_557_600 * 1000,
+            _ => return Err(err()),
+        };
+        let value: u64 = number.parse().map_err(|_| err())?;
+        total = value
+            .checked_mul(multiplier)
+            .and_then(|v| total.checked_add(v))
+            .ok_or_else(err)?;
+        number.clear();
+    }
+    if !number.is_empty() {
+        return Err(err());
+    }
+    Ok(Duration::from_millis(total))
+}
+
+/// Parses string with format: `[<name>:]<r>[,<s>][,<name>:<r>[,<s>]]...`
+///
+/// Each `<name>:<r>` item starts a specification of a new thread pool.
+/// A number without a name following such item sets the size of the sequential access pool.
+/// If the first item has no name, it refers to the `default` pool.
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+fn parse_thread_count_option(s: &str) -> Result<Vec<(OsString, Parallelism)>, String> {
+    let mut result: Vec<(OsString, Parallelism)> = Vec::new();
+    let mut expect_sequential = false;
+    for item in s.split(',') {
+        let (key, value) = match item.rfind(':') {
+            Some(index) => (Some(&item[0..index]), &item[(index + 1)..]),
+            None => (None, item),
+        };
+        let size = value
+            .parse::<usize>()
+            .map_err(|e| format!("{}: {}", e, value))?;
+        if size == 0 {
+            return Err(format!("Thread pool size must be greater than 0: {}", item));
+        }
+        match key {
+            Some(key) => {
+                let parallelism = Parallelism {
+                    random: size,
+                    sequential: size,
+                };
+                result.push((OsString::from(key), parallelism));
+                expect_sequential = true;
+            }
+            None if result.is_empty() => {
+                let parallelism = Parallelism {
+                    random: size,
+                    sequential: size,
+                };
+                result.push((OsString::from("default"), parallelism));
+                expect_sequential = true;
+            }
+            None if expect_sequential => {
+                result.last_mut().unwrap().1.sequential = size;
+                expect_sequential = false;
+            }
+            None => {
+                return Err(format!(
+                    "Missing thread pool name before {}. Expected <name>:<size>",
+                    item
+                ))
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Parses string with format: `<path>=<type>`, where type is one of the device types
+/// recognized by `DiskDevices`.
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+fn parse_device_type_option(s: &str) -> Result<(Path, DiskType), String> {
+    let (path, disk_type) = match s.rfind('=') {
+        Some(index) => (&s[0..index], &s[(index + 1)..]),
+        None => return Err(format!("Expected <path>=<type>: {}", s)),
     };
-    let key = OsString::from(key);
-    let value = value.to_string();
-    let mut pool_sizes = value
-        .split(',')
-        .map(|v| v.parse::<usize>().map_err(|e| format!("{}: {}", e, v)));
-
-    let random = match pool_sizes.next() {
-        Some(v) => v?,
-        None => return Err(String::from("Missing pool size specification")),
+    Ok((Path::from(path), parse_disk_type(disk_type)?))
+}
+
+/// Parses one of the device types recognized by `DiskDevices`, ignoring case.
+fn parse_disk_type(s: &str) -> Result<DiskType, String> {
+    match s.to_lowercase().as_str() {
+        "ssd" => Ok(DiskType::SSD),
+        "hdd" => Ok(DiskType::HDD),
+        "removable" => Ok(DiskType::Removable),
+        "unknown" => Ok(DiskType::Unknown(-1)),
+        _ => Err(format!(
+            "Unknown device type: {}. Valid types are: {}",
+            s,
+            DiskDevices::device_types().join(", ")
+        )),
+    }
+}
+
+/// Parses string with format: `<path>=<key>:<value>[,<key>:<value>...]`,
+/// e.g. `/mnt/raid=type:ssd,seq-threads:2,rand-threads:8,chunk:4M`.
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+fn parse_device_override_option(s: &str) -> Result<(Path, DeviceOverride), String> {
+    let (path, spec) = match s.rfind('=') {
+        Some(index) => (&s[0..index], &s[(index + 1)..]),
+        None => return Err(format!("Expected <path>=<key>:<value>,...: {}", s)),
     };
-    let sequential = match pool_sizes.next() {
-        Some(v) => v?,
-        None => random,
+    let parse_threads = |value: &str| match value.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("Not a positive integer: {}", value)),
     };
-    Ok((key, Parallelism { random, sequential }))
+    let mut result = DeviceOverride::default();
+    for item in spec.split(',') {
+        let (key, value) = match item.split_once(':') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => return Err(format!("Expected <key>:<value>: {}", item)),
+        };
+        let mut item_override = DeviceOverride::default();
+        match key {
+            "type" => item_override.disk_type = Some(parse_disk_type(value)?),
+            "seq-threads" => item_override.seq_threads = Some(parse_threads(value)?),
+            "rand-threads" => item_override.rand_threads = Some(parse_threads(value)?),
+            "chunk" => {
+                let chunk = FileLen::from_str(value)
+                    .map_err(|e| format!("Invalid chunk size: {}: {}", value, e))?;
+                if chunk.0 == 0 {
+                    return Err(format!("Chunk size must be greater than 0: {}", value));
+                }
+                item_override.chunk = Some(chunk)
+            }
+            _ => {
+                return Err(format!(
+                    "Unknown device property: {}. \
+                    Valid properties are: type, seq-threads, rand-threads, chunk",
+                    key
+                ))
+            }
+        }
+        result.merge(&item_override)?;
+    }
+    Ok((Path::from(path), result))
+}
+
+/// Parses string with format: `[<path>=]<bytes>[/s]`, e.g. `50MB/s` or `/mnt/hdd=50MB/s`.
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+fn parse_bandwidth_option(s: &str) -> Result<(Option<Path>, FileLen), String> {
+    let (path, bandwidth) = match s.rfind('=') {
+        Some(index) => (Some(Path::from(&s[0..index])), &s[(index + 1)..]),
+        None => (None, s),
+    };
+    let bandwidth = bandwidth.strip_suffix("/s").unwrap_or(bandwidth);
+    let bandwidth = FileLen::from_str(bandwidth.trim())
+        .map_err(|e| format!("Invalid bandwidth: {}: {}", s, e))?;
+    if bandwidth.0 == 0 {
+        return Err(format!("Bandwidth must be greater than 0: {}", s));
+    }
+    Ok((path, bandwidth))
 }
 
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
 fn is_positive_int(v: String) -> Result<(), String> {
     if let Ok(f) = v.parse::<u64>() {
         if f > 0 {
@@ -112,59 +343,568 @@ fn is_positive_int(v: String) -> Result<(), String> {
     return Err(format!("Not a positive integer: {}", &*v));
 }
 
+/// Deserializers of the option values set in the configuration file.
+/// The values are given as text parsed exactly like the command line arguments,
+/// but numbers and booleans are accepted as well.
+/// Options accepting multiple values on the command line take an array or a single value.
+#[cfg(feature = "cli")]
+pub(crate) mod de {
+    use serde::de::{Error, Visitor};
+    use serde::Deserializer;
+
+    use super::*;
+
+    /// Scalar value converted to text
+    struct Text(String);
+
+    impl<'de> Deserialize<'de> for Text {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            struct TextVisitor;
+            impl<'de> Visitor<'de> for TextVisitor {
+                type Value = Text;
+                fn expecting(&self, f: &mut Formatter) -> std::fmt::Result {
+                    write!(f, "a string, a number or a boolean")
+                }
+                fn visit_bool<E: Error>(self, v: bool) -> Result<Text, E> {
+                    Ok(Text(v.to_string()))
+                }
+                fn visit_i64<E: Error>(self, v: i64) -> Result<Text, E> {
+                    Ok(Text(v.to_string()))
+                }
+                fn visit_u64<E: Error>(self, v: u64) -> Result<Text, E> {
+                    Ok(Text(v.to_string()))
+                }
+                fn visit_f64<E: Error>(self, v: f64) -> Result<Text, E> {
+                    Ok(Text(v.to_string()))
+                }
+                fn visit_str<E: Error>(self, v: &str) -> Result<Text, E> {
+                    Ok(Text(v.to_owned()))
+                }
+            }
+            deserializer.deserialize_any(TextVisitor)
+        }
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(Text),
+        Many(Vec<Text>),
+    }
+
+    fn parse_with<'de, D, T, E>(d: D, parse: impl Fn(&str) -> Result<T, E>) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+        E: Display,
+    {
+        let text = Text::deserialize(d)?;
+        parse(&text.0).map_err(D::Error::custom)
+    }
+
+    fn parse_list_with<'de, D, T, E>(
+        d: D,
+        parse: impl Fn(&str) -> Result<T, E>,
+    ) -> Result<Vec<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        E: Display,
+    {
+        let texts = match OneOrMany::deserialize(d)? {
+            OneOrMany::One(text) => vec![text],
+            OneOrMany::Many(texts) => texts,
+        };
+        texts
+            .iter()
+            .map(|text| parse(&text.0).map_err(D::Error::custom))
+            .collect()
+    }
+
+    pub fn parsed<'de, D, T>(d: D) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: FromStr,
+        T::Err: Display,
+    {
+        parse_with(d, T::from_str)
+    }
+
+    pub fn parsed_opt<'de, D, T>(d: D) -> Result<Option<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: FromStr,
+        T::Err: Display,
+    {
+        parsed(d).map(Some)
+    }
+
+    pub fn parsed_list<'de, D, T>(d: D) -> Result<Vec<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: FromStr,
+        T::Err: Display,
+    {
+        parse_list_with(d, T::from_str)
+    }
+
+    /// Like `parsed_list`, but also splits the values at commas,
+    /// like the options with a value delimiter on the command line
+    pub fn delimited_list<'de, D, T>(d: D) -> Result<Vec<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: FromStr,
+        T::Err: Display,
+    {
+        let lists = parse_list_with(d, |s| {
+            s.split(',').map(T::from_str).collect::<Result<Vec<_>, _>>()
+        })?;
+        Ok(lists.into_iter().flatten().collect())
+    }
+
+    pub fn delimited_list_opt<'de, D, T>(d: D) -> Result<Option<Vec<T>>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: FromStr,
+        T::Err: Display,
+    {
+        delimited_list(d).map(Some)
+    }
+
+    pub fn positive_int_opt<'de, D: Deserializer<'de>>(d: D) -> Result<Option<usize>, D::Error> {
+        parse_with(d, |s| {
+            is_positive_int(s.to_owned()).and_then(|_| s.parse().map_err(|e| format!("{}", e)))
+        })
+        .map(Some)
+    }
+
+    pub fn path<'de, D: Deserializer<'de>>(d: D) -> Result<Path, D::Error> {
+        parse_with(d, |s| Ok::<_, String>(Path::from(s)))
+    }
+
+    pub fn paths<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Path>, D::Error> {
+        parse_list_with(d, |s| Ok::<_, String>(Path::from(s)))
+    }
+
+    pub fn duration<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        parse_with(d, parse_duration)
+    }
+
+    pub fn duration_opt<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+        duration(d).map(Some)
+    }
+
+    pub fn date_time_opt<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<Option<DateTime<FixedOffset>>, D::Error> {
+        parse_with(d, parse_date_time).map(Some)
+    }
+
+    pub fn thread_counts<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<Vec<Vec<(OsString, Parallelism)>>, D::Error> {
+        parse_list_with(d, parse_thread_count_option)
+    }
+
+    pub fn device_types<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<Vec<(Path, DiskType)>, D::Error> {
+        parse_list_with(d, parse_device_type_option)
+    }
+
+    pub fn device_overrides<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<Vec<(Path, DeviceOverride)>, D::Error> {
+        parse_list_with(d, parse_device_override_option)
+    }
+
+    pub fn bandwidths<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<Vec<(Option<Path>, FileLen)>, D::Error> {
+        parse_list_with(d, parse_bandwidth_option)
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct Parallelism {
     pub random: usize,
     pub sequential: usize,
 }
 
+/// Properties of a device set by the user, overriding the detected ones.
+/// Properties set to `None` are left as detected.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DeviceOverride {
+    pub disk_type: Option<DiskType>,
+    pub seq_threads: Option<usize>,
+    pub rand_threads: Option<usize>,
+    /// Fixed size of the buffer for reading whole files
+    pub chunk: Option<FileLen>,
+}
+
+impl DeviceOverride {
+    /// Combines the properties set by another override of the same device into this one.
+    /// Returns an error if both overrides set the same property to different values.
+    pub fn merge(&mut self, other: &DeviceOverride) -> Result<(), String> {
+        fn merge_property<T: PartialEq, D: Display>(
+            name: &str,
+            this: &mut Option<T>,
+            other: Option<T>,
+            display: impl Fn(&T) -> D,
+        ) -> Result<(), String> {
+            match (this.as_ref(), other) {
+                (Some(a), Some(b)) if *a != b => Err(format!(
+                    "Conflicting values of {}: {} and {}",
+                    name,
+                    display(a),
+                    display(&b)
+                )),
+                (_, Some(b)) => {
+                    *this = Some(b);
+                    Ok(())
+                }
+                (_, None) => Ok(()),
+            }
+        }
+        let disk_type = |t: &DiskType| format!("{:?}", t).to_lowercase();
+        merge_property("type", &mut self.disk_type, other.disk_type, disk_type)?;
+        merge_property(
+            "seq-threads",
+            &mut self.seq_threads,
+            other.seq_threads,
+            |n| *n,
+        )?;
+        merge_property(
+            "rand-threads",
+            &mut self.rand_threads,
+            other.rand_threads,
+            |n| *n,
+        )?;
+        merge_property("chunk", &mut self.chunk, other.chunk, |c| *c)
+    }
+}
+
+/// Default value of `GroupConfig::max_buffer_memory`
+const DEFAULT_MAX_BUFFER_MEMORY: u64 = 256 * 1024 * 1024;
+
+/// Default lower bound of the read buffer size
+const DEFAULT_MIN_READ_BUFFER: u64 = 64 * 1024;
+
+/// Default upper bound of the read buffer size
+const DEFAULT_MAX_READ_BUFFER: u64 = 16 * 1024 * 1024;
+
 // Configuration of the `group` subcommand
-#[derive(Debug, StructOpt, Default)]
-#[structopt(
-    setting(AppSettings::ColoredHelp),
-    setting(AppSettings::DeriveDisplayOrder),
-    setting(AppSettings::DisableVersion)
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "cli", derive(StructOpt, Deserialize))]
+#[cfg_attr(
+    feature = "cli",
+    serde(
+        default = "crate::config_file::command_line_values",
+        rename_all = "kebab-case"
+    )
+)]
+#[cfg_attr(
+    feature = "cli",
+    structopt(
+        setting(AppSettings::ColoredHelp),
+        setting(AppSettings::DeriveDisplayOrder),
+        setting(AppSettings::DisableVersion)
+    )
 )]
 pub struct GroupConfig {
     /// Writes the report to a file instead of the standard output
-    #[structopt(short = "o", long, value_name("path"))]
+    #[cfg_attr(feature = "cli", structopt(short = "o", long, value_name("path")))]
     pub output: Option<PathBuf>,
 
     /// Sets output file format
-    #[structopt(short = "f", long, possible_values = &OutputFormat::variants(),
-    case_insensitive = true, default_value="default")]
-    pub format: OutputFormat,
+    ///
+    /// If not given, the format is guessed from the extension of the output file:
+    /// `.csv`, `.tsv`, `.json`, `.jsonl` / `.ndjson` (JSON Lines), `.html` and `.db` / `.sqlite` are
+    /// recognized. Otherwise, the default text format is used.
+    ///
+    /// The `report` format is a verbose variant of the default format, listing
+    /// the modification time, the inode and the device of each file in a table.
+    /// It is meant for reading by humans and can't be passed to the other commands.
+    ///
+    /// The `sqlite` format writes the groups and files to tables of an SQLite database
+    /// and requires `--output`. It is available only if fclones was built
+    /// with the `sqlite` feature.
+    #[cfg_attr(feature = "cli", structopt(short = "f", long, possible_values = &OutputFormat::variants(),
+    case_insensitive = true))]
+    #[cfg_attr(feature = "cli", serde(deserialize_with = "de::parsed_opt"))]
+    pub format: Option<OutputFormat>,
+
+    /// Sets the encoding of the group hashes in the report
+    ///
+    /// Reports with hashes in any of the encodings can be read by `fclones remove`,
+    /// `fclones link` and other commands processing reports.
+    /// The sqlite format always uses hex.
+    #[cfg_attr(feature = "cli", structopt(long, value_name = "encoding", possible_values = &HashEncoding::variants(),
+    case_insensitive = true, default_value = "hex"))]
+    #[cfg_attr(feature = "cli", serde(deserialize_with = "de::parsed"))]
+    pub hash_encoding: HashEncoding,
+
+    /// Prints the version of the schema of the JSON report and the version of fclones
+    /// as a JSON object, e.g. `{"format_version":3,"fclones_version":"0.20.1"}`, and exits.
+    ///
+    /// The format version is incremented whenever the schema of the JSON report changes
+    /// in a backward-incompatible way.
+    #[cfg_attr(feature = "cli", structopt(long))]
+    #[cfg_attr(feature = "cli", serde(skip))]
+    pub report_format_version: bool,
+
+    /// Terminates each line of the `machine` format with a NUL character instead of
+    /// a newline and prints paths without escaping
+    #[cfg_attr(feature = "cli", structopt(long))]
+    pub print0: bool,
+
+    /// Writes the report in JSON to the given file.
+    ///
+    /// Shorthand for `--format json --output <path>`.
+    #[cfg_attr(feature = "cli", structopt(
+        long,
+        value_name("path"),
+        conflicts_with_all(&["output", "format", "export-csv", "export-fdupes"])
+    ))]
+    pub export_json: Option<PathBuf>,
+
+    /// Writes the report in CSV to the given file.
+    ///
+    /// Shorthand for `--format csv --output <path>`.
+    #[cfg_attr(feature = "cli", structopt(
+        long,
+        value_name("path"),
+        conflicts_with_all(&["output", "format", "export-json", "export-fdupes"])
+    ))]
+    pub export_csv: Option<PathBuf>,
+
+    /// Writes the report in the format of `fdupes` to the given file.
+    ///
+    /// Shorthand for `--format fdupes --output <path>`.
+    #[cfg_attr(feature = "cli", structopt(
+        long,
+        value_name("path"),
+        conflicts_with_all(&["output", "format", "export-json", "export-csv"])
+    ))]
+    pub export_fdupes: Option<PathBuf>,
+
+    /// Compresses the report file with gzip.
+    ///
+    /// Requires `--output` or one of the `--export-*` options.
+    /// The `.gz` extension is appended to the name of the output file, together with
+    /// the extension of the report format if the name has no extension,
+    /// unless the name already ends with `.gz`.
+    #[cfg_attr(feature = "cli", structopt(long))]
+    pub compress_output: bool,
+
+    /// Instead of the list of groups, reports how many bytes could be saved in each directory
+    /// by removing redundant files, sorted from the highest savings.
+    ///
+    /// Savings in a directory include the savings in all its subdirectories.
+    /// Requires the default text format.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, conflicts_with("report-largest-groups"))
+    )]
+    pub report_savings_by_directory: bool,
+
+    /// Instead of the list of groups, reports the directories which are empty or would become
+    /// empty after removing all redundant files, from the deepest.
+    ///
+    /// Removing the directories in this order never fails because of a directory not removed yet.
+    /// The input directories themselves are never reported.
+    /// Requires the default text format. Cannot be used with `--stdin`.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            conflicts_with_all(&["report-largest-groups", "report-savings-by-directory"])
+        )
+    )]
+    pub report_empty_dirs: bool,
+
+    /// Instead of the list of files, reports the given number of groups wasting
+    /// the most space, from the largest.
+    ///
+    /// Each line contains the hash, the file size, the number of files and the space taken
+    /// by all files of the group except one.
+    /// Requires the default text format.
+    #[cfg_attr(feature = "cli", structopt(long, value_name("n")))]
+    pub report_largest_groups: Option<usize>,
+
+    /// Instead of the list of groups, reports how the groups changed since an earlier run,
+    /// whose report is read from the given file.
+    ///
+    /// Groups are matched by their hash and file size, so both reports must be produced
+    /// with the same hash function. Reports the groups that are new, grew, shrunk or
+    /// disappeared, with the added members prefixed by `+` and the removed members by `-`.
+    /// Unchanged groups are not reported.
+    /// Requires the default text format.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            value_name("path"),
+            parse(from_os_str),
+            conflicts_with_all(&[
+                "report-largest-groups",
+                "report-savings-by-directory",
+                "report-empty-dirs"
+            ])
+        )
+    )]
+    pub diff: Option<PathBuf>,
+
+    /// Adds the total size of all scanned files, including the unique ones,
+    /// to the summary in the report header, together with the percentage of it
+    /// taken by redundant files.
+    #[cfg_attr(feature = "cli", structopt(long))]
+    pub report_total_size: bool,
+
+    /// Adds a histogram of the sizes of redundant files to the summary in the report header.
+    ///
+    /// For each range of file sizes, the histogram gives the number of redundant files and
+    /// the space they take. The default ranges end at 4 KiB, 64 KiB, 1 MiB, 16 MiB and 256 MiB.
+    #[cfg_attr(feature = "cli", structopt(long))]
+    pub size_histogram: bool,
+
+    /// Upper bounds of the size ranges of the histogram, separated by commas.
+    /// Implies `--size-histogram`.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, value_name = "bytes", use_delimiter = true)
+    )]
+    #[cfg_attr(feature = "cli", serde(deserialize_with = "de::delimited_list_opt"))]
+    pub histogram_buckets: Option<Vec<FileLen>>,
+
+    /// Adds the number of inodes that would be freed by replacing the duplicates with
+    /// hard links to the summary in the report header.
+    ///
+    /// This is the number of distinct inodes in each group minus the replicas retained
+    /// according to `--rf-over`, summed over all groups. Hard links to the same inode
+    /// are counted once.
+    #[cfg_attr(feature = "cli", structopt(long))]
+    pub report_inode_efficiency: bool,
+
+    /// Prints the time taken by each grouping stage and how many files it processed.
+    ///
+    /// The timings are also included in the report if `--format=json` is used.
+    /// Implied by `-vv`.
+    #[cfg_attr(feature = "cli", structopt(long))]
+    pub timings: bool,
 
     /// Reads the list of input paths from the standard input instead of the arguments.
     /// This flag is mostly useful together with Unix `find` utility.
-    #[structopt(long)]
+    #[cfg_attr(feature = "cli", structopt(long))]
     pub stdin: bool,
 
+    /// Reads additional input paths from the given file, one path per line.
+    ///
+    /// Reads the standard input if `-` is given. Surrounding whitespace is trimmed,
+    /// and blank lines and lines starting with `#` are skipped.
+    /// Relative paths are resolved against the base directory, like the path arguments.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, value_name("path"), parse(from_os_str), conflicts_with("stdin"))
+    )]
+    pub paths_file: Option<PathBuf>,
+
     /// Limits recursion depth.
     ///
     /// 0 disables descending into directories.
     /// 1 descends into directories specified explicitly as input paths,
     /// but does not descend into subdirectories.
-    #[structopt(short = "d", long)]
+    #[cfg_attr(feature = "cli", structopt(short = "d", long))]
     pub depth: Option<usize>,
 
     /// Skips hidden files
-    #[structopt(short = "A", long)]
+    #[cfg_attr(feature = "cli", structopt(short = "A", long))]
     pub skip_hidden: bool,
 
+    /// Skips read-only btrfs subvolumes, such as snapshots.
+    ///
+    /// Files in snapshots share their data with the files they were taken from,
+    /// so they can't be deduplicated to reclaim any space.
+    #[cfg_attr(feature = "cli", structopt(long))]
+    pub skip_ro_snapshots: bool,
+
     /// Follows symbolic links
-    #[structopt(short = "L", long)]
+    #[cfg_attr(feature = "cli", structopt(short = "L", long))]
     pub follow_links: bool,
 
     /// Treats files reachable from multiple paths through
     /// hard links as duplicates
-    #[structopt(short = "H", long)]
+    #[cfg_attr(feature = "cli", structopt(short = "H", long))]
     pub hard_links: bool,
 
+    /// Skips the groups consisting only of hard links to the same file.
+    ///
+    /// Such groups are found only with `--hard-links`. They are marked in the report
+    /// and don't count as reclaimable space, because removing a hard link doesn't free
+    /// the data while other links to it exist.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, overrides_with("include-hardlink-groups"))
+    )]
+    pub exclude_hardlink_groups: bool,
+
+    /// Reports the groups consisting only of hard links to the same file.
+    ///
+    /// This is the default. Overrides an earlier `--exclude-hardlink-groups`.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, overrides_with("exclude-hardlink-groups"))
+    )]
+    pub include_hardlink_groups: bool,
+
     /// Don't count matching files found within the same directory argument as duplicates.
-    #[structopt(short("I"), long, conflicts_with("follow-links"))]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(short("I"), long, conflicts_with("follow-links"))
+    )]
     pub isolate: bool,
 
+    /// Reports only groups with files under at least the given number of distinct
+    /// directory arguments.
+    ///
+    /// Files are attributed to the first directory argument that contains them.
+    /// Hard links under different arguments count for each of them.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, value_name("count"), conflicts_with("stdin"))
+    )]
+    pub min_roots: Option<usize>,
+
+    /// Reports only groups with files under every directory argument.
+    ///
+    /// Useful for verifying which files of a mirror are complete.
+    /// Same as `--min-roots` set to the number of directory arguments.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, conflicts_with_all(&["min-roots", "stdin"]))
+    )]
+    pub all_roots: bool,
+
+    /// Scans the given directory as a read-only baseline.
+    ///
+    /// Files under the baseline directories are hashed and grouped like the files
+    /// in the input paths, but groups consisting only of baseline files are not reported.
+    /// The `dedupe`, `link`, `move` and `remove` commands never select baseline files
+    /// for removal, so they only delete or link the copies of files already present
+    /// in the baseline. Can be given multiple times.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long = "baseline", value_name = "dir", parse(from_os_str))
+    )]
+    #[cfg_attr(feature = "cli", serde(deserialize_with = "de::paths"))]
+    pub baseline: Vec<Path>,
+
+    /// Reports only groups of files which all have the same file name.
+    ///
+    /// Groups of identical files are rejected after hashing if any of their files
+    /// has a different name than the others. Useful for finding copies of the same file
+    /// placed in different directories.
+    #[cfg_attr(feature = "cli", structopt(long))]
+    pub require_same_basename: bool,
+
     /// Before matching, transforms each file by the specified program.
     /// The value of this parameter should contain a command: the path to the program
     /// and optionally a list of space-separated arguments.
@@ -181,14 +921,14 @@ pub struct GroupConfig {
     /// specified by $OUT and will read output from there.
     /// If the program modifies the original file in-place without writing to the standard output
     /// nor a distinct file, use --in-place flag.
-    #[structopt(long, value_name("command"))]
+    #[cfg_attr(feature = "cli", structopt(long, value_name("command")))]
     pub transform: Option<String>,
 
     /// Set this flag if the command given to --transform transforms the file in-place,
     /// i.e. it modifies the original input file instead of writing to the standard output
     /// or to a new file. This flag tells fclones to read output from the original file
     /// after the transform command exited.
-    #[structopt(long)]
+    #[cfg_attr(feature = "cli", structopt(long))]
     pub in_place: bool,
 
     /// Doesn't copy the file to a temporary location before transforming,
@@ -197,50 +937,138 @@ pub struct GroupConfig {
     /// Caution:
     /// this option may speed up processing, but it may cause loss of data because it lets
     /// the transform command to work directly on the original file.
-    #[structopt(long)]
+    #[cfg_attr(feature = "cli", structopt(long))]
     pub no_copy: bool,
 
     /// Searches for over-replicated files with replication factor above the specified value.
     /// Specifying neither `--rf-over` nor `--rf-under` is equivalent to `--rf-over 1` which would
     /// report duplicate files.
-    #[structopt(short("n"), long, conflicts_with("rf-under"), value_name("count"))]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(short("n"), long, conflicts_with("rf-under"), value_name("count"))
+    )]
     pub rf_over: Option<usize>,
 
     /// Searches for under-replicated files with replication factor below the specified value.
     /// Specifying `--rf-under 2` will report unique files.
-    #[structopt(long, conflicts_with("rf-over"), value_name("count"))]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, conflicts_with("rf-over"), value_name("count"))
+    )]
     pub rf_under: Option<usize>,
 
     /// Instead of searching for duplicates, searches for unique files.
-    #[structopt(long, conflicts_with_all(&["rf-over", "rf-under"]))]
+    #[cfg_attr(feature = "cli", structopt(long, conflicts_with_all(&["rf-over", "rf-under"])))]
     pub unique: bool,
 
     /// Minimum file size in bytes. Units like KB, KiB, MB, MiB, GB, GiB are supported. Inclusive.
-    #[structopt(short = "s", long("min"), default_value = "1", value_name("bytes"))]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            name = "min",
+            short = "s",
+            long("min"),
+            default_value = "1",
+            value_name("bytes")
+        )
+    )]
+    #[cfg_attr(
+        feature = "cli",
+        serde(rename = "min", deserialize_with = "de::parsed")
+    )]
     pub min_size: FileLen,
 
     /// Maximum file size in bytes. Units like KB, KiB, MB, MiB, GB, GiB are supported. Inclusive.
-    #[structopt(long("max"), value_name("bytes"))]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(name = "max", long("max"), value_name("bytes"))
+    )]
+    #[cfg_attr(
+        feature = "cli",
+        serde(rename = "max", deserialize_with = "de::parsed_opt")
+    )]
     pub max_size: Option<FileLen>,
 
+    /// Range of file sizes, e.g. `1MiB..100MiB`, `..=4096` or `10GB..`.
+    ///
+    /// The upper bound is exclusive for `..` and inclusive for `..=`.
+    /// The lower bound is always inclusive. Units are the same as for `--min` and `--max`.
+    /// If combined with `--min` or `--max`, only files within all the bounds are included.
+    #[cfg_attr(feature = "cli", structopt(long, value_name("range")))]
+    #[cfg_attr(feature = "cli", serde(deserialize_with = "de::parsed_opt"))]
+    pub size: Option<FileLenRange>,
+
     /// Includes only file names matched fully by any of the given patterns.
-    #[structopt(long = "name", value_name("pattern"))]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(name = "name", long = "name", value_name("pattern"))
+    )]
+    #[cfg_attr(
+        feature = "cli",
+        serde(rename = "name", deserialize_with = "de::parsed_list")
+    )]
     pub name_patterns: Vec<String>,
 
     /// Includes only paths matched fully by any of the given patterns.
-    #[structopt(long = "path", value_name("pattern"))]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(name = "path", long = "path", value_name("pattern"))
+    )]
+    #[cfg_attr(
+        feature = "cli",
+        serde(rename = "path", deserialize_with = "de::parsed_list")
+    )]
     pub path_patterns: Vec<String>,
 
     /// Excludes paths matched fully by any of the given patterns.
-    #[structopt(long = "exclude", value_name("pattern"))]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(name = "exclude", long = "exclude", value_name("pattern"))
+    )]
+    #[cfg_attr(
+        feature = "cli",
+        serde(rename = "exclude", deserialize_with = "de::parsed_list")
+    )]
     pub exclude_patterns: Vec<String>,
 
+    /// Includes only files with any of the given extensions, e.g. `--ext jpg,jpeg,png`.
+    ///
+    /// Extensions are matched case-insensitively against the part of the file name
+    /// after the last dot. Use `-` to include files without an extension.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(name = "ext", long = "ext", value_name("ext"), use_delimiter = true)
+    )]
+    #[cfg_attr(
+        feature = "cli",
+        serde(rename = "ext", deserialize_with = "de::delimited_list")
+    )]
+    pub extensions: Vec<String>,
+
+    /// Excludes files with any of the given extensions, e.g. `--no-ext tmp,part`.
+    ///
+    /// Use `-` to exclude files without an extension.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            name = "no-ext",
+            long = "no-ext",
+            value_name("ext"),
+            use_delimiter = true
+        )
+    )]
+    #[cfg_attr(
+        feature = "cli",
+        serde(rename = "no-ext", deserialize_with = "de::delimited_list")
+    )]
+    pub excluded_extensions: Vec<String>,
+
     /// Makes pattern matching case-insensitive.
-    #[structopt(short = "i", long)]
+    #[cfg_attr(feature = "cli", structopt(short = "i", long))]
     pub caseless: bool,
 
     /// Expects patterns as Perl compatible regular expressions instead of Unix globs.
-    #[structopt(short = "g", long)]
+    #[cfg_attr(feature = "cli", structopt(short = "g", long))]
     pub regex: bool,
 
     /// Enables caching of file hashes.
@@ -249,12 +1077,173 @@ pub struct GroupConfig {
     /// recomputations of hashes of the files that haven't changed since the last scan.
     /// Beware though, that this option relies on file modification times recorded by the
     /// file system. It also increases memory and storage space consumption.
-    #[structopt(long)]
+    #[cfg_attr(feature = "cli", structopt(long))]
     pub cache: bool,
 
+    /// Stores the cached hashes in extended attributes of the files instead of the
+    /// database in the user cache directory. Implies `--cache`.
+    ///
+    /// The cached hashes follow the files when they are moved, also to other devices.
+    /// Storing a hash requires write access to the file. Requires fclones built with
+    /// the `xattr-cache` feature.
+    #[cfg_attr(feature = "cli", structopt(long))]
+    pub xattr_cache: bool,
+
+    /// Starts hashing files while the directory tree is still being scanned.
+    ///
+    /// As soon as two files of the same size are found, the prefixes of all files of that size
+    /// are hashed in the background, so the hashes are ready when the scan finishes.
+    /// Speeds up searching very large file systems, where scanning takes a long time.
+    /// Files with the same size as another file but removed by later stages of grouping,
+    /// e.g. hard links, may be read unnecessarily.
+    /// Prefixes are hashed with the length preferred by the slowest of all detected devices.
+    #[cfg_attr(feature = "cli", structopt(long, conflicts_with("transform")))]
+    pub scan_in_background: bool,
+
+    /// Writes the paths that couldn't be accessed due to insufficient permissions
+    /// to the given file, one path per line.
+    ///
+    /// Warnings about such paths are not logged individually, but summarized per
+    /// top-level directory at the end of the scan.
+    #[cfg_attr(feature = "cli", structopt(long, value_name("path")))]
+    pub list_denied: Option<PathBuf>,
+
+    /// Adds the number of files and directories skipped because of insufficient permissions
+    /// to the report header, so that they can be told apart from the files scanned.
+    ///
+    /// The JSON report lists the skipped paths in the `permission_errors` array
+    /// of the statistics.
+    #[cfg_attr(feature = "cli", structopt(long))]
+    pub report_permission_errors: bool,
+
+    /// Aborts the run after encountering more than the given number of I/O errors.
+    ///
+    /// Errors caused by insufficient permissions are not counted.
+    /// 0 means no limit.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, value_name = "count", default_value = "0")
+    )]
+    pub error_limit: usize,
+
+    /// Retries reading a file up to the given number of times after a transient I/O error.
+    ///
+    /// Only errors that are plausibly transient are retried: interrupted reads, timeouts
+    /// and, with `--io-retry-eio`, generic I/O errors. Files that still can't be read
+    /// are listed in the "Read errors" section of the report header.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, value_name = "count", default_value = "0")
+    )]
+    pub io_retries: usize,
+
+    /// Time to wait before the first retry of a failed read, e.g. `100ms`.
+    /// The time is doubled before each next retry.
+    #[cfg_attr(feature = "cli", structopt(long, value_name = "duration", default_value = "100ms",
+        parse(try_from_str = parse_duration)))]
+    #[cfg_attr(feature = "cli", serde(deserialize_with = "de::duration"))]
+    pub io_retry_delay: Duration,
+
+    /// Retries also reads failing with a generic I/O error (EIO).
+    ///
+    /// Flaky USB enclosures and cables cause sporadic EIO errors, but so does a failing disk,
+    /// so these errors are not retried by default.
+    #[cfg_attr(feature = "cli", structopt(long))]
+    pub io_retry_eio: bool,
+
+    /// Writes the SHA-256 digests of all scanned files to the given file.
+    ///
+    /// The file can be checked later with `sha256sum -c` or `fclones verify`.
+    /// All files matching the selection criteria are listed, not only the duplicates.
+    /// Computing the digests requires reading every file entirely, including the files
+    /// that would be otherwise skipped early because no other file has the same size,
+    /// so the run takes at least as long as reading all the scanned data.
+    /// The files are then grouped by their digests, so they are not read again.
+    #[cfg_attr(feature = "cli", structopt(long, value_name("path")))]
+    pub checksum_file: Option<PathBuf>,
+
+    /// Sets the format of the lines written to the checksum file.
+    ///
+    /// `gnu` writes `<digest>  <path>` lines like `sha256sum`.
+    /// `bsd` writes `SHA256 (<path>) = <digest>` lines like `sha256sum --tag`.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            value_name("format"),
+            possible_values = &ChecksumFormat::variants(),
+            default_value = "gnu"
+        )
+    )]
+    #[cfg_attr(feature = "cli", serde(deserialize_with = "de::parsed"))]
+    pub checksum_format: ChecksumFormat,
+
+    /// Keeps files whose size changed during the scan as candidates under their new size.
+    ///
+    /// Before hashing, the size of each file is compared with the size recorded when
+    /// the directory tree was scanned. By default, files that changed are skipped with
+    /// a warning. With this option, they are compared with the remaining candidates
+    /// of the new size instead. Files removed from the candidates in the earlier stages
+    /// are not compared again.
+    #[cfg_attr(feature = "cli", structopt(long))]
+    pub requeue_changed: bool,
+
+    /// Limits the number of files opened at the same time for hashing.
+    ///
+    /// When the limit is reached, further files wait until some of the open files are closed.
+    /// Lower it if hashing fails with "too many open files" errors.
+    /// By default, the limit is set to 75% of the maximum number of open file descriptors
+    /// allowed for the process (`ulimit -n`) on Unix systems, and to 4096 on Windows.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, value_name = "count", validator(is_positive_int))
+    )]
+    #[cfg_attr(feature = "cli", serde(deserialize_with = "de::positive_int_opt"))]
+    pub max_open_files: Option<usize>,
+
+    /// Limits the total size of buffers used for reading files by all threads at the same time.
+    /// Units like KB, KiB, MB, MiB, GB, GiB are supported.
+    ///
+    /// When the limit is reached, threads wait for buffers to be released before reading
+    /// more data. A single buffer larger than the limit is allowed if no other buffers are in use.
+    /// If not given, defaults to 256 MiB.
+    #[cfg_attr(feature = "cli", structopt(long, value_name = "bytes"))]
+    #[cfg_attr(feature = "cli", serde(deserialize_with = "de::parsed_opt"))]
+    pub max_buffer_memory: Option<FileLen>,
+
+    /// Sets the size of file system blocks used to compute the disk space taken by
+    /// redundant files. Units like KB, KiB, MB, MiB, GB, GiB are supported.
+    ///
+    /// File systems allocate space in whole blocks, so files usually take more space on disk
+    /// than their length. The space allocated for redundant files is reported
+    /// in the report header next to their total length.
+    /// If not given, the block size is read from the file system holding each group of files.
+    #[cfg_attr(feature = "cli", structopt(long, value_name = "bytes"))]
+    #[cfg_attr(feature = "cli", serde(deserialize_with = "de::parsed_opt"))]
+    pub block_size: Option<FileLen>,
+
+    /// Sets the lower bound of the size of the buffer used for reading file contents.
+    /// Units like KB, KiB, MB, MiB, GB, GiB are supported.
+    ///
+    /// When hashing whole files, the size of the buffer adapts to the read throughput measured
+    /// for each device, so that reading a single buffer takes roughly the same time on slow
+    /// and on fast devices. If not given, defaults to 64 KiB.
+    #[cfg_attr(feature = "cli", structopt(long, value_name = "bytes"))]
+    #[cfg_attr(feature = "cli", serde(deserialize_with = "de::parsed_opt"))]
+    pub min_read_buffer: Option<FileLen>,
+
+    /// Sets the upper bound of the size of the buffer used for reading file contents.
+    /// Units like KB, KiB, MB, MiB, GB, GiB are supported.
+    ///
+    /// If not given, defaults to 16 MiB.
+    #[cfg_attr(feature = "cli", structopt(long, value_name = "bytes"))]
+    #[cfg_attr(feature = "cli", serde(deserialize_with = "de::parsed_opt"))]
+    pub max_read_buffer: Option<FileLen>,
+
     /// Sets the sizes of thread-pools
     ///
     /// The spec has the following format: `[<name>:]<r>[,<s>]`.
+    /// Multiple comma-separated specs can be given at once, e.g. `main:8,ssd:16,hdd:1`.
     /// The name can be one of:
     /// (1) a physical block device when prefixed with `dev:` e.g. `dev:/dev/sda`;
     /// (2) a type of device - `ssd`, `hdd`, `removable` or `unknown`;
@@ -267,16 +1256,95 @@ pub struct GroupConfig {
     /// If `s` is not given, it is assumed to be the same as `r`.
     ///
     /// This parameter can be used multiple times to configure multiple thread pools.
-    #[structopt(
+    #[cfg_attr(feature = "cli", structopt(
       short,
       long,
       value_name = "spec",
       parse(try_from_str = parse_thread_count_option),
-      verbatim_doc_comment)]
-    pub threads: Vec<(OsString, Parallelism)>,
+      verbatim_doc_comment))]
+    #[cfg_attr(feature = "cli", serde(deserialize_with = "de::thread_counts"))]
+    pub threads: Vec<Vec<(OsString, Parallelism)>>,
+
+    /// Overrides the detected type of the device holding the given path.
+    ///
+    /// The spec has the following format: `<path>=<type>`, where type is one of
+    /// `ssd`, `hdd`, `removable` or `unknown`, e.g. `/mnt/backup=hdd`.
+    /// Use it when the device type is detected incorrectly, because the type determines
+    /// the default sizes of the thread pools used to access the device.
+    ///
+    /// This parameter can be used multiple times to configure multiple devices.
+    #[cfg_attr(feature = "cli", structopt(
+        long,
+        value_name = "path=type",
+        parse(try_from_str = parse_device_type_option)
+    ))]
+    #[cfg_attr(feature = "cli", serde(deserialize_with = "de::device_types"))]
+    pub device_type: Vec<(Path, DiskType)>,
+
+    /// Overrides the detected type and the tuning parameters of the device holding the given path.
+    ///
+    /// The spec has the following format: `<path>=<key>:<value>[,<key>:<value>...]`,
+    /// e.g. `/mnt/raid=type:ssd,seq-threads:2,rand-threads:8,chunk:4M`.
+    /// The keys are:
+    /// `type` - the type of the device: `ssd`, `hdd`, `removable` or `unknown`;
+    /// `seq-threads` - the size of the thread pool used for sequential I/O;
+    /// `rand-threads` - the size of the thread pool used for random access I/O;
+    /// `chunk` - the fixed size of the buffer for reading whole files.
+    /// Properties not given are left as detected or as set by the other options.
+    /// Run `fclones devices` to see the detected devices.
+    ///
+    /// This parameter can be used multiple times. Overriding the same property of a device
+    /// with different values, also by `--device-type`, is an error.
+    #[cfg_attr(feature = "cli", structopt(
+        long,
+        value_name = "path=spec",
+        parse(try_from_str = parse_device_override_option)
+    ))]
+    #[cfg_attr(feature = "cli", serde(deserialize_with = "de::device_overrides"))]
+    pub device_override: Vec<(Path, DeviceOverride)>,
+
+    /// Limits the number of bytes read per second from each device.
+    /// Units like KB, KiB, MB, MiB, GB, GiB are supported, e.g. `50MB/s`.
+    ///
+    /// The limit can be also set for the device holding the given path only,
+    /// e.g. `/mnt/hdd=50MB/s`. Limits set for paths take precedence over the limit set
+    /// for all devices. This parameter can be used multiple times.
+    #[cfg_attr(feature = "cli", structopt(
+        long,
+        value_name = "[path=]bytes/s",
+        parse(try_from_str = parse_bandwidth_option)
+    ))]
+    #[cfg_attr(feature = "cli", serde(deserialize_with = "de::bandwidths"))]
+    pub max_bandwidth: Vec<(Option<Path>, FileLen)>,
+
+    /// Limits the number of files hashed at the same time on each SSD.
+    ///
+    /// Unlike `--threads`, which sets the sizes of the thread pools, this limits
+    /// concurrent reads from a device regardless of which thread pool issues them.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, value_name = "n", validator(is_positive_int))
+    )]
+    #[cfg_attr(feature = "cli", serde(deserialize_with = "de::positive_int_opt"))]
+    pub ssd_concurrency: Option<usize>,
+
+    /// Limits the number of files hashed at the same time on each rotational drive.
+    ///
+    /// Setting it to 1 prevents head thrashing caused by concurrent reads
+    /// of files on the same drive.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, value_name = "n", validator(is_positive_int))
+    )]
+    #[cfg_attr(feature = "cli", serde(deserialize_with = "de::positive_int_opt"))]
+    pub hdd_concurrency: Option<usize>,
 
     /// Base directory to use when resolving relative input paths.
-    #[structopt(long, parse(from_os_str), default_value("."))]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, parse(from_os_str), default_value("."))
+    )]
+    #[cfg_attr(feature = "cli", serde(deserialize_with = "de::path"))]
     pub base_dir: Path,
 
     /// A list of input paths.
@@ -284,12 +1352,268 @@ pub struct GroupConfig {
     /// Accepts files and directories.
     /// By default descends into directories recursively, unless a recursion depth
     /// limit is specified with `--depth`.
-    #[structopt(parse(from_os_str), required_unless("stdin"))]
+    #[cfg_attr(feature = "cli", structopt(parse(from_os_str), required_unless_one(&["stdin", "paths-file", "report-format-version"])))]
+    #[cfg_attr(feature = "cli", serde(skip))]
     pub paths: Vec<Path>,
+
+    /// The command line recorded in the report header.
+    /// If empty, the arguments of the current process are recorded.
+    ///
+    /// Commands reading the report rebuild the configuration by parsing this command line,
+    /// so it must include the options set outside of the command line,
+    /// e.g. in the configuration file.
+    #[cfg_attr(feature = "cli", structopt(skip))]
+    #[cfg_attr(feature = "cli", serde(skip))]
+    pub command_line: Vec<OsString>,
 }
 
+/// Reads paths listed one per line, skipping blank lines and `#` comments.
+fn read_paths(reader: impl BufRead) -> io::Result<Vec<Path>> {
+    let mut paths = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            paths.push(Path::from(line));
+        }
+    }
+    Ok(paths)
+}
+
+/// How the two options named by an [`OptionRule`] relate to each other
+#[derive(Clone, Copy, Debug)]
+enum RuleKind {
+    /// The options cannot be used together and the given one should be dropped
+    Conflict { drop: &'static str },
+    /// The first option works only together with the second one
+    Requires,
+}
+
+/// A combination of options rejected by validation, because one of the options
+/// would be silently ignored or would make the command fail later with an unrelated error.
+struct OptionRule<C> {
+    option: &'static str,
+    other: &'static str,
+    kind: RuleKind,
+    /// Returns true if the configuration breaks the rule
+    broken: fn(&C) -> bool,
+}
+
+impl<C> OptionRule<C> {
+    const fn conflict(
+        option: &'static str,
+        other: &'static str,
+        drop: &'static str,
+        broken: fn(&C) -> bool,
+    ) -> Self {
+        OptionRule {
+            option,
+            other,
+            kind: RuleKind::Conflict { drop },
+            broken,
+        }
+    }
+
+    const fn requires(option: &'static str, other: &'static str, broken: fn(&C) -> bool) -> Self {
+        OptionRule {
+            option,
+            other,
+            kind: RuleKind::Requires,
+            broken,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self.kind {
+            RuleKind::Conflict { drop } => format!(
+                "The {} and {} options cannot be used together. Drop {}.",
+                self.option, self.other, drop
+            ),
+            RuleKind::Requires => format!(
+                "The {} option requires {}. Add {} or drop {}.",
+                self.option, self.other, self.other, self.option
+            ),
+        }
+    }
+}
+
+/// Returns the message of the first rule broken by the configuration
+fn check_rules<C>(config: &C, rules: &[OptionRule<C>]) -> Result<(), String> {
+    match rules.iter().find(|rule| (rule.broken)(config)) {
+        Some(rule) => Err(rule.message()),
+        None => Ok(()),
+    }
+}
+
+/// Most of these are rejected by the command line parser already,
+/// but the configuration can also be built in code or come from a configuration file.
+const GROUP_OPTION_RULES: &[OptionRule<GroupConfig>] = &[
+    OptionRule::conflict("--stdin", "<paths>", "the <paths> arguments", |c| {
+        c.stdin && !c.paths.is_empty()
+    }),
+    OptionRule::conflict("--stdin", "--paths-file", "--paths-file", |c| {
+        c.stdin && c.paths_file.is_some()
+    }),
+    OptionRule::conflict("--isolate", "--follow-links", "--follow-links", |c| {
+        c.isolate && c.follow_links
+    }),
+    OptionRule::conflict("--rf-over", "--rf-under", "--rf-under", |c| {
+        c.rf_over.is_some() && c.rf_under.is_some()
+    }),
+    OptionRule::conflict("--unique", "--rf-over", "--rf-over", |c| {
+        c.unique && c.rf_over.is_some()
+    }),
+    OptionRule::conflict("--unique", "--rf-under", "--rf-under", |c| {
+        c.unique && c.rf_under.is_some()
+    }),
+    OptionRule::conflict("--min-roots", "--stdin", "--stdin", |c| {
+        c.min_roots.is_some() && c.stdin
+    }),
+    OptionRule::conflict("--all-roots", "--stdin", "--stdin", |c| {
+        c.all_roots && c.stdin
+    }),
+    OptionRule::conflict("--all-roots", "--min-roots", "--min-roots", |c| {
+        c.all_roots && c.min_roots.is_some()
+    }),
+    OptionRule::conflict(
+        "--report-empty-dirs",
+        "--stdin",
+        "--report-empty-dirs",
+        |c| c.report_empty_dirs && c.stdin,
+    ),
+    OptionRule::conflict(
+        "--report-savings-by-directory",
+        "--report-largest-groups",
+        "--report-largest-groups",
+        |c| c.report_savings_by_directory && c.report_largest_groups.is_some(),
+    ),
+    OptionRule::conflict(
+        "--report-empty-dirs",
+        "--report-largest-groups",
+        "--report-largest-groups",
+        |c| c.report_empty_dirs && c.report_largest_groups.is_some(),
+    ),
+    OptionRule::conflict(
+        "--report-empty-dirs",
+        "--report-savings-by-directory",
+        "--report-savings-by-directory",
+        |c| c.report_empty_dirs && c.report_savings_by_directory,
+    ),
+    OptionRule::conflict(
+        "--diff",
+        "--report-largest-groups",
+        "--report-largest-groups",
+        |c| c.diff.is_some() && c.report_largest_groups.is_some(),
+    ),
+    OptionRule::conflict(
+        "--diff",
+        "--report-savings-by-directory",
+        "--report-savings-by-directory",
+        |c| c.diff.is_some() && c.report_savings_by_directory,
+    ),
+    OptionRule::conflict(
+        "--diff",
+        "--report-empty-dirs",
+        "--report-empty-dirs",
+        |c| c.diff.is_some() && c.report_empty_dirs,
+    ),
+    OptionRule::conflict(
+        "--scan-in-background",
+        "--transform",
+        "--scan-in-background",
+        |c| c.scan_in_background && c.transform.is_some(),
+    ),
+    OptionRule::conflict(
+        "--format sqlite",
+        "--compress-output",
+        "--compress-output",
+        |c| matches!(c.output_format(), OutputFormat::Sqlite) && c.compress_output,
+    ),
+    OptionRule::requires("--format sqlite", "--output", |c| {
+        matches!(c.output_format(), OutputFormat::Sqlite) && c.output.is_none()
+    }),
+    OptionRule::requires("--print0", "--format machine", |c| {
+        c.print0 && !matches!(c.output_format(), OutputFormat::Machine)
+    }),
+    OptionRule::requires("--compress-output", "--output", |c| {
+        c.compress_output && c.output.is_none()
+    }),
+    OptionRule::requires("--report-savings-by-directory", "--format default", |c| {
+        c.report_savings_by_directory && !matches!(c.output_format(), OutputFormat::Default)
+    }),
+    OptionRule::requires("--report-empty-dirs", "--format default", |c| {
+        c.report_empty_dirs && !matches!(c.output_format(), OutputFormat::Default)
+    }),
+    OptionRule::requires("--report-largest-groups", "--format default", |c| {
+        c.report_largest_groups.is_some() && !matches!(c.output_format(), OutputFormat::Default)
+    }),
+    OptionRule::requires("--diff", "--format default", |c| {
+        c.diff.is_some() && !matches!(c.output_format(), OutputFormat::Default)
+    }),
+    OptionRule::requires("--in-place", "--transform", |c| {
+        c.in_place && c.transform.is_none()
+    }),
+    OptionRule::requires("--no-copy", "--transform", |c| {
+        c.no_copy && c.transform.is_none()
+    }),
+];
+
+const DEDUPE_OPTION_RULES: &[OptionRule<DedupeConfig>] = &[OptionRule::requires(
+    "--no-verify",
+    "--import-format",
+    |c| c.no_verify && c.import_format.is_none(),
+)];
+
+const COMMAND_OPTION_RULES: &[OptionRule<Command>] = &[
+    OptionRule::conflict("--soft", "--link-then-verify", "--link-then-verify", |c| {
+        matches!(
+            c,
+            Command::Link {
+                soft: true,
+                link_then_verify: true,
+                ..
+            }
+        )
+    }),
+    OptionRule::conflict(
+        "--soft",
+        "--hardlink-posix-semantics-check",
+        "--hardlink-posix-semantics-check",
+        |c| {
+            matches!(
+                c,
+                Command::Link {
+                    soft: true,
+                    hardlink_posix_semantics_check: true,
+                    ..
+                }
+            )
+        },
+    ),
+    OptionRule::conflict(
+        "--sparse",
+        "--exec",
+        "--sparse",
+        |c| matches!(c, Command::Dedupe { sparse: true, exec, .. } if exec.exec.is_some()),
+    ),
+];
+
 impl GroupConfig {
     fn validate(&self) -> Result<(), String> {
+        if matches!(self.output_format(), OutputFormat::Sqlite) && !cfg!(feature = "sqlite") {
+            return Err("fclones was built without support for the sqlite format".to_owned());
+        }
+        check_rules(self, GROUP_OPTION_RULES)?;
+
+        if self.min_roots() > self.paths.len() + self.baseline.len() {
+            return Err(format!(
+                "The --min-roots value ({}) is larger than the number of input paths ({}). \
+                 No files would be reported.",
+                self.min_roots(),
+                self.paths.len() + self.baseline.len(),
+            ));
+        }
+
         if self.isolate && self.paths.len() <= self.rf_over() {
             return Err(format!(
                 "The --isolate flag requires that the number of input paths ({}) \
@@ -313,6 +1637,48 @@ impl GroupConfig {
             ));
         }
 
+        if self.size_range().is_none() {
+            return Err(format!(
+                "No file size satisfies all of the size constraints: --min {}{}{}",
+                self.min_size.0,
+                self.max_size
+                    .map(|s| format!(" --max {}", s.0))
+                    .unwrap_or_default(),
+                self.size
+                    .map(|r| format!(" --size {}", r))
+                    .unwrap_or_default(),
+            ));
+        }
+
+        let mut allowed_pool_names = DiskDevices::device_types();
+        allowed_pool_names.push("main");
+        allowed_pool_names.push("default");
+        for (name, _) in self.threads.iter().flatten() {
+            let name = name.to_string_lossy();
+            if !name.starts_with("dev:") && !allowed_pool_names.contains(&name.as_ref()) {
+                return Err(format!(
+                    "Unknown thread pool or device type: {}. Valid names are: {} or dev:<device>",
+                    name,
+                    allowed_pool_names.join(", ")
+                ));
+            }
+        }
+
+        if self.block_size == Some(FileLen(0)) {
+            return Err("The --block-size must be greater than zero".to_owned());
+        }
+
+        let (min_read_buffer, max_read_buffer) = self.read_buffer_bounds();
+        if min_read_buffer.0 == 0 {
+            return Err("The --min-read-buffer must be greater than zero".to_owned());
+        }
+        if min_read_buffer > max_read_buffer {
+            return Err(format!(
+                "The --min-read-buffer ({}) must not be greater than the --max-read-buffer ({})",
+                min_read_buffer, max_read_buffer
+            ));
+        }
+
         Ok(())
     }
 
@@ -352,6 +1718,38 @@ impl GroupConfig {
             .exclude_paths(exclude_paths?))
     }
 
+    pub fn extension_filter(&self) -> ExtensionFilter {
+        ExtensionFilter::new(&self.extensions, &self.excluded_extensions)
+    }
+
+    /// Returns an empty histogram of the sizes of redundant files,
+    /// or `None` if the histogram was not requested
+    pub fn size_histogram(&self) -> Option<SizeHistogram> {
+        match &self.histogram_buckets {
+            Some(bounds) => Some(SizeHistogram::new(bounds)),
+            None if self.size_histogram => Some(SizeHistogram::new(&SizeHistogram::DEFAULT_BOUNDS)),
+            None => None,
+        }
+    }
+
+    /// Returns the range of sizes of the files to include, combined from `--min`, `--max`
+    /// and `--size`, or `None` if no file size satisfies all of them.
+    pub fn size_range(&self) -> Option<FileLenRange> {
+        let range = FileLenRange {
+            min: self.min_size,
+            max: self.max_size.unwrap_or(FileLen::MAX),
+        };
+        range.intersect(&self.size.unwrap_or_else(FileLenRange::full))
+    }
+
+    /// Returns the size range to record in the report header,
+    /// or `None` if the sizes were not restricted beyond skipping empty files.
+    pub fn size_filter(&self) -> Option<FileLenRange> {
+        let restricted =
+            self.size.is_some() || self.max_size.is_some() || self.min_size > FileLen(1);
+        restricted.then(|| self.size_range()).flatten()
+    }
+
     pub fn group_filter(&self) -> FileGroupFilter {
         FileGroupFilter {
             replication: if self.unique {
@@ -366,6 +1764,23 @@ impl GroupConfig {
             } else {
                 vec![]
             },
+            baseline_paths: self.baseline_paths().collect(),
+            min_roots: self.min_roots(),
+            input_roots: if self.min_roots() > 1 {
+                self.input_paths().collect()
+            } else {
+                vec![]
+            },
+        }
+    }
+
+    /// Returns the minimum number of distinct directory arguments the files of a reported group
+    /// must be found under, given by `--min-roots` or `--all-roots`
+    pub fn min_roots(&self) -> usize {
+        if self.all_roots {
+            self.paths.len() + self.baseline.len()
+        } else {
+            self.min_roots.unwrap_or(0)
         }
     }
 
@@ -419,24 +1834,55 @@ impl GroupConfig {
 
     /// Returns an iterator over the absolute input paths.
     /// Input paths may be provided as arguments or from standard input.
+    /// The baseline directories are included after the input paths.
     pub fn input_paths(&self) -> Box<dyn Iterator<Item = Path> + Send> {
         let base_dir = Arc::new(self.base_dir.clone());
+        let baseline = self.baseline_paths();
         if self.stdin {
+            // Paths from the standard input may be many, so make them share the parents:
+            let interner = PathInterner::default();
             Box::new(
                 BufReader::new(stdin())
                     .lines()
-                    .map(move |s| base_dir.resolve(Path::from(s.unwrap().as_str()))),
+                    .map(move |s| {
+                        interner.intern(base_dir.resolve(Path::from(s.unwrap().as_str())))
+                    })
+                    .chain(baseline),
             )
         } else {
             Box::new(
                 self.paths
                     .clone()
                     .into_iter()
-                    .map(move |p| base_dir.resolve(p)),
+                    .map(move |p| base_dir.resolve(p))
+                    .chain(baseline),
             )
         }
     }
 
+    /// Returns the baseline directories resolved against the base directory
+    pub fn baseline_paths(&self) -> impl Iterator<Item = Path> + Send {
+        let base_dir = Arc::new(self.base_dir.clone());
+        self.baseline
+            .clone()
+            .into_iter()
+            .map(move |p| base_dir.resolve(p))
+    }
+
+    /// Appends the paths listed in the file given by `paths_file` to `paths`.
+    /// Returns the number of paths read.
+    /// The file is read only once; `paths_file` is cleared afterwards.
+    pub fn read_paths_file(&mut self) -> io::Result<usize> {
+        let paths = match self.paths_file.take() {
+            Some(file) if file.as_os_str() == "-" => read_paths(stdin().lock())?,
+            Some(file) => read_paths(BufReader::new(std::fs::File::open(file)?))?,
+            None => return Ok(0),
+        };
+        let count = paths.len();
+        self.paths.extend(paths);
+        Ok(count)
+    }
+
     fn build_transform(&self, command: &str) -> io::Result<Transform> {
         let mut tr = Transform::new(command.to_string(), self.in_place)?;
         if self.no_copy {
@@ -453,15 +1899,546 @@ impl GroupConfig {
             .map(|command| self.build_transform(command))
     }
 
+    /// Sets `format` and `output` according to the `--export-*` shorthand options,
+    /// as if they were given explicitly.
+    pub fn expand_shorthands(&mut self) {
+        let exports = [
+            (OutputFormat::Json, self.export_json.take()),
+            (OutputFormat::Csv, self.export_csv.take()),
+            (OutputFormat::Fdupes, self.export_fdupes.take()),
+        ];
+        for (format, path) in exports {
+            if let Some(path) = path {
+                self.format = Some(format);
+                self.output = Some(path);
+            }
+        }
+    }
+
+    /// Returns the format of the report.
+    /// If the format was not set explicitly, it is guessed from the extension of the output file.
+    pub fn output_format(&self) -> OutputFormat {
+        match (self.format, &self.output) {
+            (Some(format), _) => format,
+            (None, Some(output)) => OutputFormat::from_extension(output).unwrap_or_default(),
+            (None, None) => OutputFormat::Default,
+        }
+    }
+
+    /// Returns the path of the report file, including the `.gz` extension
+    /// appended if the report is compressed. Returns `None` for the standard output.
+    pub fn output_path(&self) -> Option<PathBuf> {
+        let output = self.output.as_ref()?;
+        let is_gz = output
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("gz"));
+        if !self.compress_output || is_gz {
+            return Some(output.clone());
+        }
+        let mut name = output.as_os_str().to_owned();
+        if output.extension().is_none() {
+            name.push(".");
+            name.push(self.output_format().extension());
+        }
+        name.push(".gz");
+        Some(PathBuf::from(name))
+    }
+
+    /// Returns the minimum and the maximum size of a buffer for reading files
+    pub fn read_buffer_bounds(&self) -> (FileLen, FileLen) {
+        (
+            self.min_read_buffer
+                .unwrap_or(FileLen(DEFAULT_MIN_READ_BUFFER)),
+            self.max_read_buffer
+                .unwrap_or(FileLen(DEFAULT_MAX_READ_BUFFER)),
+        )
+    }
+
+    /// Returns the maximum total size of buffers for reading files
+    pub fn max_buffer_memory(&self) -> FileLen {
+        self.max_buffer_memory
+            .unwrap_or(FileLen(DEFAULT_MAX_BUFFER_MEMORY))
+    }
+
     pub fn thread_pool_sizes(&self) -> HashMap<OsString, Parallelism> {
         let mut map = HashMap::new();
-        for (k, v) in self.threads.iter() {
+        for (k, v) in self.threads.iter().flatten() {
             map.insert(k.clone(), *v);
         }
         map
     }
 }
 
+/// Builds [`GroupConfig`] in code, without depending on the set of its fields.
+/// Options not set explicitly keep the default values of the command line,
+/// e.g. files shorter than 1 byte are skipped. This differs from [`GroupConfig::default`],
+/// whose numeric options are all zero. Relative input paths are not resolved against
+/// the current directory unless [`GroupConfigBuilder::base_dir`] is set.
+///
+/// # Example
+/// ```
+/// use fclones::config::GroupConfigBuilder;
+/// use fclones::file::FileLen;
+/// use fclones::ErrorKind;
+/// use fclones::path::Path;
+///
+/// let config = GroupConfigBuilder::new()
+///     .paths(vec![Path::from("/home"), Path::from("/mnt/backup")])
+///     .min_size(FileLen(1024))
+///     .isolate(true)
+///     .build()
+///     .unwrap();
+/// assert!(config.isolate);
+///
+/// let error = GroupConfigBuilder::new()
+///     .paths(vec![Path::from(".")])
+///     .rf_over(2)
+///     .rf_under(3)
+///     .build()
+///     .unwrap_err();
+/// assert!(matches!(error.kind, ErrorKind::Config));
+/// assert!(error.message.contains("--rf-over and --rf-under"));
+/// ```
+#[derive(Debug)]
+pub struct GroupConfigBuilder {
+    config: GroupConfig,
+}
+
+impl GroupConfigBuilder {
+    pub fn new() -> GroupConfigBuilder {
+        GroupConfigBuilder::default()
+    }
+
+    /// Sets the input files or directories
+    pub fn paths(mut self, paths: Vec<Path>) -> Self {
+        self.config.paths = paths;
+        self
+    }
+
+    /// Sets the directory relative input paths are resolved against
+    pub fn base_dir(mut self, base_dir: Path) -> Self {
+        self.config.base_dir = base_dir;
+        self
+    }
+
+    /// Sets the file the report is written to, instead of the standard output
+    pub fn output(mut self, output: PathBuf) -> Self {
+        self.config.output = Some(output);
+        self
+    }
+
+    /// Sets the format of the report
+    pub fn format(mut self, format: OutputFormat) -> Self {
+        self.config.format = Some(format);
+        self
+    }
+
+    /// Compresses the report file with gzip
+    pub fn compress_output(mut self, compress: bool) -> Self {
+        self.config.compress_output = compress;
+        self
+    }
+
+    /// Sets the maximum depth of directory recursion
+    pub fn depth(mut self, depth: usize) -> Self {
+        self.config.depth = Some(depth);
+        self
+    }
+
+    /// Skips hidden files
+    pub fn skip_hidden(mut self, skip_hidden: bool) -> Self {
+        self.config.skip_hidden = skip_hidden;
+        self
+    }
+
+    /// Skips read-only btrfs subvolumes, such as snapshots
+    pub fn skip_ro_snapshots(mut self, skip_ro_snapshots: bool) -> Self {
+        self.config.skip_ro_snapshots = skip_ro_snapshots;
+        self
+    }
+
+    /// Follows symbolic links
+    pub fn follow_links(mut self, follow_links: bool) -> Self {
+        self.config.follow_links = follow_links;
+        self
+    }
+
+    /// Treats files reachable through multiple hard links as duplicates
+    pub fn hard_links(mut self, hard_links: bool) -> Self {
+        self.config.hard_links = hard_links;
+        self
+    }
+
+    /// Doesn't count files found within the same input path as duplicates
+    pub fn isolate(mut self, isolate: bool) -> Self {
+        self.config.isolate = isolate;
+        self
+    }
+
+    /// Scans the given directories as a read-only baseline
+    pub fn baseline(mut self, dirs: Vec<Path>) -> Self {
+        self.config.baseline = dirs;
+        self
+    }
+
+    /// Searches for files with the replication factor above `count`
+    pub fn rf_over(mut self, count: usize) -> Self {
+        self.config.rf_over = Some(count);
+        self
+    }
+
+    /// Searches for files with the replication factor below `count`
+    pub fn rf_under(mut self, count: usize) -> Self {
+        self.config.rf_under = Some(count);
+        self
+    }
+
+    /// Searches for unique files instead of duplicates
+    pub fn unique(mut self, unique: bool) -> Self {
+        self.config.unique = unique;
+        self
+    }
+
+    /// Sets the minimum size of files, inclusive
+    pub fn min_size(mut self, min_size: FileLen) -> Self {
+        self.config.min_size = min_size;
+        self
+    }
+
+    /// Sets the maximum size of files, inclusive
+    pub fn max_size(mut self, max_size: FileLen) -> Self {
+        self.config.max_size = Some(max_size);
+        self
+    }
+
+    /// Sets the range of sizes of files
+    pub fn size(mut self, size: FileLenRange) -> Self {
+        self.config.size = Some(size);
+        self
+    }
+
+    /// Adds a glob or regex pattern file names must match
+    pub fn name_pattern(mut self, pattern: &str) -> Self {
+        self.config.name_patterns.push(pattern.to_owned());
+        self
+    }
+
+    /// Adds a glob or regex pattern paths must match
+    pub fn path_pattern(mut self, pattern: &str) -> Self {
+        self.config.path_patterns.push(pattern.to_owned());
+        self
+    }
+
+    /// Adds a glob or regex pattern of paths to exclude
+    pub fn exclude_pattern(mut self, pattern: &str) -> Self {
+        self.config.exclude_patterns.push(pattern.to_owned());
+        self
+    }
+
+    /// Treats the patterns as regular expressions instead of globs
+    pub fn regex(mut self, regex: bool) -> Self {
+        self.config.regex = regex;
+        self
+    }
+
+    /// Makes the patterns case-insensitive
+    pub fn caseless(mut self, caseless: bool) -> Self {
+        self.config.caseless = caseless;
+        self
+    }
+
+    /// Enables the persistent hash cache
+    pub fn cache(mut self, cache: bool) -> Self {
+        self.config.cache = cache;
+        self
+    }
+
+    /// Keeps the cached hashes in extended attributes of the files; implies `cache`
+    pub fn xattr_cache(mut self, xattr_cache: bool) -> Self {
+        self.config.xattr_cache = xattr_cache;
+        self
+    }
+
+    /// Aborts the run after encountering more than `limit` I/O errors; 0 means no limit
+    pub fn error_limit(mut self, limit: usize) -> Self {
+        self.config.error_limit = limit;
+        self
+    }
+
+    /// Retries reading a file up to `retries` times after a transient I/O error,
+    /// waiting `delay` before the first retry and twice as long before each next one
+    pub fn io_retries(mut self, retries: usize, delay: Duration) -> Self {
+        self.config.io_retries = retries;
+        self.config.io_retry_delay = delay;
+        self
+    }
+
+    /// Sets the parallelism of a thread pool.
+    /// See [`GroupConfig::threads`] for the allowed pool names.
+    pub fn threads(mut self, pool: &str, parallelism: Parallelism) -> Self {
+        self.config
+            .threads
+            .push(vec![(OsString::from(pool), parallelism)]);
+        self
+    }
+
+    /// Validates the options and returns the configuration.
+    /// Returns an error naming the conflicting options if the combination is not valid.
+    pub fn build(mut self) -> Result<GroupConfig, Error> {
+        self.config.expand_shorthands();
+        self.config.validate().map_err(Error::config)?;
+        Ok(self.config)
+    }
+}
+
+impl Default for GroupConfigBuilder {
+    fn default() -> Self {
+        GroupConfigBuilder {
+            config: GroupConfig {
+                min_size: FileLen(1),
+                io_retry_delay: Duration::from_millis(100),
+                ..GroupConfig::default()
+            },
+        }
+    }
+}
+
+impl From<GroupConfig> for GroupConfigBuilder {
+    fn from(config: GroupConfig) -> Self {
+        GroupConfigBuilder { config }
+    }
+}
+
+/// Configuration of the `verify` subcommand
+#[derive(Debug)]
+#[cfg_attr(feature = "cli", derive(StructOpt))]
+#[cfg_attr(
+    feature = "cli",
+    structopt(
+        setting(AppSettings::ColoredHelp),
+        setting(AppSettings::DeriveDisplayOrder),
+        setting(AppSettings::DisableVersion)
+    )
+)]
+pub struct VerifyConfig {
+    /// A file with the checksums, or `-` to read them from the standard input.
+    /// Files with the `.gz` extension are decompressed.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(parse(from_os_str), value_name = "checksum-file")
+    )]
+    pub checksum_file: PathBuf,
+}
+
+/// Configuration of running a user command instead of deduplicating files
+/// in the `dedupe` subcommand
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "cli", derive(StructOpt))]
+pub struct ExecConfig {
+    /// Runs the given command for each group instead of deduplicating the files.
+    ///
+    /// The command is split into arguments like by a Unix shell, but no shell is involved.
+    /// The following placeholders are replaced in the arguments:
+    /// `{keep}` with the path of the file to keep, `{dupes...}` with the paths of
+    /// the redundant files passed as separate arguments, `{hash}` with the hash of the contents
+    /// and `{size}` with the file size in bytes. The file to keep is selected by
+    /// the same options as for the other commands, e.g. `--priority`.
+    /// The command must not rely on the files being left intact by fclones.
+    #[cfg_attr(feature = "cli", structopt(long, value_name = "command"))]
+    pub exec: Option<String>,
+
+    /// Passes up to the given number of groups to each invocation of the `--exec` command.
+    ///
+    /// The arguments from the first one containing a placeholder to the end
+    /// are repeated for each group.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            value_name = "count",
+            default_value = "1",
+            validator(is_positive_int)
+        )
+    )]
+    pub exec_batch: usize,
+
+    /// Maximum number of `--exec` commands running at the same time
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            value_name = "count",
+            default_value = "1",
+            validator(is_positive_int)
+        )
+    )]
+    pub exec_concurrency: usize,
+}
+
+/// Configuration of the `diff-trees` subcommand
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "cli", derive(StructOpt))]
+#[cfg_attr(
+    feature = "cli",
+    structopt(
+        setting(AppSettings::ColoredHelp),
+        setting(AppSettings::DeriveDisplayOrder),
+        setting(AppSettings::DisableVersion)
+    )
+)]
+pub struct DiffTreesConfig {
+    /// The first directory tree to compare
+    #[cfg_attr(feature = "cli", structopt(parse(from_os_str), value_name = "left"))]
+    pub left: PathBuf,
+
+    /// The second directory tree to compare
+    #[cfg_attr(feature = "cli", structopt(parse(from_os_str), value_name = "right"))]
+    pub right: PathBuf,
+
+    /// Skips hidden files
+    #[cfg_attr(feature = "cli", structopt(short = "A", long))]
+    pub skip_hidden: bool,
+
+    /// Follows symbolic links
+    #[cfg_attr(feature = "cli", structopt(short = "L", long))]
+    pub follow_links: bool,
+
+    /// Prints the results in JSON
+    #[cfg_attr(feature = "cli", structopt(long))]
+    pub json: bool,
+}
+
+/// Configuration of the `watch` subcommand
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "cli", derive(StructOpt))]
+#[cfg_attr(
+    feature = "cli",
+    structopt(
+        setting(AppSettings::ColoredHelp),
+        setting(AppSettings::DeriveDisplayOrder),
+        setting(AppSettings::DisableVersion)
+    )
+)]
+pub struct WatchConfig {
+    /// Time to wait after the first change of a file before updating the report.
+    /// All changes made in this time are included in a single update.
+    #[cfg_attr(feature = "cli", structopt(long, value_name = "duration", default_value = "2s",
+        parse(try_from_str = parse_duration)))]
+    pub debounce: Duration,
+
+    /// Interval of full rescans, used if changes of files cannot be watched,
+    /// e.g. because of too many files or after the queue of file system events overflowed.
+    #[cfg_attr(feature = "cli", structopt(long, value_name = "duration", default_value = "10m",
+        parse(try_from_str = parse_duration)))]
+    pub rescan_interval: Duration,
+
+    #[cfg_attr(feature = "cli", structopt(flatten))]
+    pub group: GroupConfig,
+}
+
+impl WatchConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.group.output.is_none() {
+            return Err("The watch command requires --output".to_owned());
+        }
+        if self.group.stdin {
+            return Err(
+                "The watch command cannot read the paths from the standard input".to_owned(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Configuration of the `tag` and `untag` subcommands
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "cli", derive(StructOpt))]
+#[cfg_attr(
+    feature = "cli",
+    structopt(
+        setting(AppSettings::ColoredHelp),
+        setting(AppSettings::DeriveDisplayOrder),
+        setting(AppSettings::DisableVersion)
+    )
+)]
+pub struct TagConfig {
+    /// Doesn't change any attributes, but writes the equivalent `setfattr` commands
+    /// to the standard output.
+    #[cfg_attr(feature = "cli", structopt(long))]
+    pub dry_run: bool,
+}
+
+/// Configuration of the `bench` subcommand
+#[derive(Debug)]
+#[cfg_attr(feature = "cli", derive(StructOpt))]
+#[cfg_attr(
+    feature = "cli",
+    structopt(
+        setting(AppSettings::ColoredHelp),
+        setting(AppSettings::DeriveDisplayOrder),
+        setting(AppSettings::DisableVersion)
+    )
+)]
+pub struct BenchConfig {
+    /// A directory on the device to benchmark or an existing file to read.
+    ///
+    /// If a directory is given, a scratch file is created in it
+    /// and removed after the benchmark.
+    #[cfg_attr(feature = "cli", structopt(parse(from_os_str), value_name = "path"))]
+    pub path: PathBuf,
+
+    /// Size of the scratch file
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, value_name = "bytes", default_value = "256MiB")
+    )]
+    pub size: FileLen,
+
+    /// Sizes of blocks to read at once.
+    /// Blocks larger than the file are skipped.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            value_name = "bytes",
+            use_delimiter = true,
+            default_value = "4KiB,64KiB,1MiB,16MiB"
+        )
+    )]
+    pub block_sizes: Vec<FileLen>,
+
+    /// Numbers of reads issued concurrently
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            long,
+            value_name = "count",
+            use_delimiter = true,
+            default_value = "1,2,4,8,16",
+            validator(is_positive_int)
+        )
+    )]
+    pub queue_depths: Vec<usize>,
+
+    /// Duration of each random read measurement
+    #[cfg_attr(feature = "cli", structopt(long, value_name = "duration", default_value = "1s",
+        parse(try_from_str = parse_duration)))]
+    pub duration: Duration,
+
+    /// Prints the results in JSON
+    #[cfg_attr(feature = "cli", structopt(long))]
+    pub json: bool,
+}
+
+impl BenchConfig {
+    fn validate(&self) -> Result<(), String> {
+        if self.block_sizes.iter().any(|b| b.0 == 0) {
+            return Err("Block size must be greater than zero".to_owned());
+        }
+        Ok(())
+    }
+}
+
 /// Controls which files in a group should be removed / moved / replaced by links.
 #[derive(Clone, Debug)]
 pub enum Priority {
@@ -481,6 +2458,15 @@ pub enum Priority {
     MostNested,
     /// Gives higher priority to the files nested shallower in the directory tree.
     LeastNested,
+    /// Gives higher priority to the files with the highest number of hard links.
+    /// If the number of links is the same, gives higher priority to the files with
+    /// the most recent modification time.
+    MostHardLinked,
+    /// Gives higher priority to the files with the lowest number of hard links, so the file
+    /// with the most links is retained. Useful when each backup creates a new hard link.
+    /// If the number of links is the same, gives higher priority to the files with
+    /// the most recent modification time.
+    LeastHardLinked,
 }
 
 impl Priority {
@@ -494,6 +2480,8 @@ impl Priority {
             "least-recently-accessed",
             "most-nested",
             "least-nested",
+            "most-hard-linked",
+            "least-hard-linked",
         ]
     }
 }
@@ -510,63 +2498,153 @@ impl FromStr for Priority {
             "least-recently-accessed" | "lra" => Ok(Priority::LeastRecentlyAccessed),
             "most-nested" => Ok(Priority::MostNested),
             "least-nested" => Ok(Priority::LeastNested),
+            "most-hard-linked" | "most-hardlinked" => Ok(Priority::MostHardLinked),
+            "least-hard-linked" | "least-hardlinked" => Ok(Priority::LeastHardLinked),
             _ => Err(format!("Unrecognized priority: {}", s)),
         }
     }
 }
 
 /// Configures which files should be removed
-#[derive(Debug, Default, StructOpt)]
-#[structopt(
-    setting(AppSettings::DeriveDisplayOrder),
-    setting(AppSettings::DisableVersion),
-    setting(AppSettings::ColoredHelp)
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "cli", derive(StructOpt, Deserialize))]
+#[cfg_attr(
+    feature = "cli",
+    serde(
+        default = "crate::config_file::command_line_values",
+        rename_all = "kebab-case"
+    )
+)]
+#[cfg_attr(
+    feature = "cli",
+    structopt(
+        setting(AppSettings::DeriveDisplayOrder),
+        setting(AppSettings::DisableVersion),
+        setting(AppSettings::ColoredHelp)
+    )
 )]
 pub struct DedupeConfig {
     /// Doesn't perform any changes on the file-system, but writes a log of file operations
     /// to the standard output.
-    #[structopt(long)]
+    #[cfg_attr(feature = "cli", structopt(long))]
     pub dry_run: bool,
 
     /// Writes the `dry_run` report to a file instead of the standard output.
-    #[structopt(short = "o", long, value_name = "path")]
+    #[cfg_attr(feature = "cli", structopt(short = "o", long, value_name = "path"))]
     pub output: Option<PathBuf>,
 
     /// Deduplicates only the files that were modified before the given time.
     ///
     /// If any of the files in a group was modified later, the whole group is skipped.
-    #[structopt(long, short = "m", value_name = "timestamp", parse(try_from_str = parse_date_time))]
+    #[cfg_attr(feature = "cli", structopt(long, short = "m", value_name = "timestamp", parse(try_from_str = parse_date_time)))]
+    #[cfg_attr(feature = "cli", serde(deserialize_with = "de::date_time_opt"))]
     pub modified_before: Option<DateTime<FixedOffset>>,
 
     /// Keeps at least n replicas untouched.
     ///
     /// If not given, it is assumed to be the same as the
     /// `--rf-over` value in the earlier `fclones group` run.
-    #[structopt(short = "n", long, value_name = "count", validator(is_positive_int))]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(short = "n", long, value_name = "count", validator(is_positive_int))
+    )]
+    #[cfg_attr(feature = "cli", serde(deserialize_with = "de::positive_int_opt"))]
     pub rf_over: Option<usize>,
 
     /// Restricts the set of files that can be removed or replaced by links to files
     /// with the name matching any given patterns.
-    #[structopt(long = "name", value_name = "pattern")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(name = "name", long = "name", value_name = "pattern")
+    )]
+    #[cfg_attr(
+        feature = "cli",
+        serde(rename = "name", deserialize_with = "de::parsed_list")
+    )]
     pub name_patterns: Vec<Pattern>,
 
     /// Restricts the set of files that can be removed or replaced by links to files
     /// with the path matching any given patterns.
-    #[structopt(long = "path", value_name = "pattern")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(name = "path", long = "path", value_name = "pattern")
+    )]
+    #[cfg_attr(
+        feature = "cli",
+        serde(rename = "path", deserialize_with = "de::parsed_list")
+    )]
     pub path_patterns: Vec<Pattern>,
 
     /// Sets the priority for files to be removed or replaced by links.
-    #[structopt(long, value_name = "priority", possible_values = &Priority::variants())]
+    #[cfg_attr(feature = "cli", structopt(long, value_name = "priority", possible_values = &Priority::variants()))]
+    #[cfg_attr(feature = "cli", serde(deserialize_with = "de::parsed_list"))]
     pub priority: Vec<Priority>,
 
     /// Keeps files with names matching any given patterns untouched.
-    #[structopt(long = "keep-name", value_name = "pattern")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(name = "keep-name", long = "keep-name", value_name = "pattern")
+    )]
+    #[cfg_attr(
+        feature = "cli",
+        serde(rename = "keep-name", deserialize_with = "de::parsed_list")
+    )]
     pub keep_name_patterns: Vec<Pattern>,
 
     /// Keeps files with paths matching any given patterns untouched.
-    #[structopt(long = "keep-path", value_name = "pattern")]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(name = "keep-path", long = "keep-path", value_name = "pattern")
+    )]
+    #[cfg_attr(
+        feature = "cli",
+        serde(rename = "keep-path", deserialize_with = "de::parsed_list")
+    )]
     pub keep_path_patterns: Vec<Pattern>,
 
+    /// Keeps files last modified earlier than the given time ago untouched.
+    ///
+    /// Accepts a number followed by a unit: `s`, `m`, `h`, `d`, `w` or `y`, e.g. `1y` or `30d`.
+    /// The modification times are read from the file system when deduplicating.
+    /// Files with modification times in the future are treated as the newest.
+    #[cfg_attr(feature = "cli", structopt(long, value_name = "duration", parse(try_from_str = parse_duration)))]
+    #[cfg_attr(feature = "cli", serde(deserialize_with = "de::duration_opt"))]
+    pub keep_older_than: Option<Duration>,
+
+    /// Restricts the set of files that can be removed or replaced by links to files
+    /// last modified later than the given time ago.
+    ///
+    /// Accepts the same durations as `--keep-older-than`.
+    /// Files with modification times in the future are treated as the newest.
+    #[cfg_attr(feature = "cli", structopt(long, value_name = "duration", parse(try_from_str = parse_duration)))]
+    #[cfg_attr(feature = "cli", serde(deserialize_with = "de::duration_opt"))]
+    pub drop_newer_than: Option<Duration>,
+
+    /// Prefers keeping files located under the given directory.
+    ///
+    /// Files under any of the given prefixes are retained before files elsewhere,
+    /// so with the default `--rf-over` of 1, all duplicates outside of the prefixes
+    /// get removed or replaced by links. Unlike `--keep-path`, this doesn't protect
+    /// the redundant copies located under the prefix.
+    ///
+    /// Can be given multiple times. Prefixes given earlier take precedence over prefixes
+    /// given later, and all prefixes take precedence over `--priority`.
+    /// Relative paths are resolved against the current working directory.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            name = "prefer-path-prefix",
+            long = "prefer-path-prefix",
+            value_name = "path",
+            parse(from_os_str)
+        )
+    )]
+    #[cfg_attr(
+        feature = "cli",
+        serde(rename = "prefer-path-prefix", deserialize_with = "de::paths")
+    )]
+    pub preferred_path_prefixes: Vec<Path>,
+
     /// Specifies a list of path prefixes.
     /// If non-empty, all duplicates having the same path prefix (root) are treated as one.
     /// This also means that the files sharing the same root can be either all
@@ -574,11 +2652,110 @@ pub struct DedupeConfig {
     ///
     /// By default, it is set to the input paths given as arguments to the earlier
     /// `fclones group` command, if `--isolate` option was present.
-    #[structopt(long = "isolate", value_name = "path", parse(from_os_str))]
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            name = "isolate",
+            long = "isolate",
+            value_name = "path",
+            parse(from_os_str)
+        )
+    )]
+    #[cfg_attr(
+        feature = "cli",
+        serde(rename = "isolate", deserialize_with = "de::paths")
+    )]
     pub isolated_roots: Vec<Path>,
+
+    /// Skips the groups with files under fewer than the given number of distinct roots.
+    ///
+    /// By default, it is set to the value of `--min-roots` or `--all-roots` given to
+    /// the earlier `fclones group` command.
+    #[cfg_attr(feature = "cli", structopt(long, value_name("count")))]
+    pub min_roots: Option<usize>,
+
+    /// Specifies the roots counted by `--min-roots`.
+    ///
+    /// By default, it is set to the roots recorded in the report by the earlier
+    /// `fclones group --min-roots` or `fclones group --all-roots` command.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(name = "root", long = "root", value_name = "path", parse(from_os_str))
+    )]
+    #[cfg_attr(
+        feature = "cli",
+        serde(rename = "root", deserialize_with = "de::paths")
+    )]
+    pub roots: Vec<Path>,
+
+    /// Never removes or replaces files under the given read-only baseline directories.
+    ///
+    /// By default, it is set to the baseline directories given to the earlier
+    /// `fclones group` command with the `--baseline` option.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(
+            name = "baseline",
+            long = "baseline",
+            value_name = "dir",
+            parse(from_os_str)
+        )
+    )]
+    #[cfg_attr(
+        feature = "cli",
+        serde(rename = "baseline", deserialize_with = "de::paths")
+    )]
+    pub baseline_paths: Vec<Path>,
+
+    /// Attempts to process files protected by immutable, append-only, read-only
+    /// or system attributes.
+    ///
+    /// By default, such files are left intact and reported in the summary.
+    #[cfg_attr(feature = "cli", structopt(long))]
+    pub include_protected: bool,
+
+    /// After removing or moving the redundant files, removes the directories left empty.
+    ///
+    /// Directories are removed from the deepest, up to but excluding the input directories
+    /// given to the earlier `fclones group` command. If those are unknown, e.g. for imported
+    /// reports or paths read from the standard input, only the directories directly containing
+    /// the removed files are removed. Allowed only with the remove, trash and move commands.
+    #[cfg_attr(feature = "cli", structopt(long))]
+    pub prune_empty_dirs: bool,
+
+    /// Stops processing further groups after the given time.
+    ///
+    /// The groups being processed when the time limit is reached are completed,
+    /// and the statistics of the work done so far are printed.
+    /// Accepts a number followed by a unit: `s`, `m`, `h` or `d`, e.g. `30m` or `1h30m`.
+    #[cfg_attr(feature = "cli", structopt(long, value_name = "duration", parse(try_from_str = parse_duration)))]
+    #[cfg_attr(feature = "cli", serde(deserialize_with = "de::duration_opt"))]
+    pub dedup_timeout: Option<Duration>,
+
+    /// Reads the list of duplicate files written by another program from the standard input,
+    /// instead of a report produced by `fclones group`.
+    ///
+    /// Accepts the output of `fdupes` and `jdupes`, optionally with the sizes printed by `-S`,
+    /// and the JSON written by `rmlint -o json`.
+    /// The sizes of the files are always checked again and the contents of the files are
+    /// compared by hashing them, unless `--no-verify` is given. Files that differ from
+    /// the other files in their group are skipped.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, value_name = "format", possible_values = &ImportFormat::variants(), case_insensitive = true)
+    )]
+    #[cfg_attr(feature = "cli", serde(deserialize_with = "de::parsed_opt"))]
+    pub import_format: Option<ImportFormat>,
+
+    /// Doesn't compare the contents of the imported files before deduplicating them.
+    /// Only the sizes of the files are checked.
+    #[cfg_attr(feature = "cli", structopt(long, requires("import-format")))]
+    pub no_verify: bool,
 }
 
-#[derive(Debug, StructOpt)]
+#[derive(Debug)]
+#[cfg_attr(feature = "cli", derive(StructOpt))]
+#[allow(clippy::large_enum_variant)] // parsed once, not worth boxing
 pub enum Command {
     /// Produces a list of groups of identical files.
     ///
@@ -597,12 +2774,29 @@ pub enum Command {
     /// the same file system. Soft links are always created to link files between
     /// different file systems.
     Link {
-        #[structopt(flatten)]
+        #[cfg_attr(feature = "cli", structopt(flatten))]
         config: DedupeConfig,
 
         /// Creates soft links.
-        #[structopt(short, long)]
+        #[cfg_attr(feature = "cli", structopt(short, long))]
         soft: bool,
+
+        /// Verifies each hard link after creating it.
+        ///
+        /// Compares the contents of the link with the contents of its target
+        /// by hashing both files. If they differ, the link is removed and the original
+        /// file is restored.
+        #[cfg_attr(feature = "cli", structopt(long, conflicts_with("soft")))]
+        link_then_verify: bool,
+
+        /// Checks that the target file systems support real hard links before linking.
+        ///
+        /// Some file systems, e.g. CIFS or FAT32 mounted through some drivers, report
+        /// that a hard link was created, but store an independent copy of the file instead.
+        /// Before linking any files, creates a test file and a hard link to it on each
+        /// target device and aborts if they don't share the same inode.
+        #[cfg_attr(feature = "cli", structopt(long, conflicts_with("soft")))]
+        hardlink_posix_semantics_check: bool,
     },
 
     /// Deduplicates file data using native filesystem deduplication capabilities.
@@ -621,9 +2815,28 @@ pub enum Command {
     /// Not all file systems support deduplication.
     /// Not all metadata is preserved on macOS.
     /// Unsupported on Windows.
+    ///
+    /// With `--exec`, runs the given command for each group of files instead.
+    /// This works on all platforms.
     Dedupe {
-        #[structopt(flatten)]
+        #[cfg_attr(feature = "cli", structopt(flatten))]
         config: DedupeConfig,
+
+        #[cfg_attr(feature = "cli", structopt(flatten))]
+        exec: ExecConfig,
+
+        /// Deduplicates only the data extents of identical sparse files, e.g. VM disk images.
+        ///
+        /// Instead of replacing the whole file, asks the file system to share each data extent
+        /// of a redundant file with the extent at the same offset of the retained file.
+        /// Holes are left unallocated. The file system compares the data before sharing it,
+        /// so a file modified after the report was made is never changed.
+        /// Only groups of identical files from the report are processed; files that are
+        /// partially identical are not deduplicated.
+        /// Files are processed only if both the retained and the redundant file are sparse.
+        /// Requires the FIDEDUPERANGE ioctl, supported on Linux by e.g. Btrfs and XFS.
+        #[cfg_attr(feature = "cli", structopt(long, conflicts_with("exec")))]
+        sparse: bool,
     },
 
     /// Removes redundant files.
@@ -632,36 +2845,165 @@ pub enum Command {
     /// on the standard input.
     Remove(DedupeConfig),
 
+    /// Moves redundant files to the trash or the recycle bin of the operating system.
+    ///
+    /// The list of groups earlier produced by `fclones group` should be submitted
+    /// on the standard input.
+    ///
+    /// Unlike the files removed by `fclones remove`, the trashed files can be restored.
+    /// Their space is reclaimed after the trash is emptied.
+    /// Requires fclones built with the trash feature.
+    Trash(DedupeConfig),
+
     /// Moves redundant files to the given directory.
     ///
     /// The list of groups earlier produced by `fclones group` should be submitted
     /// on the standard input.
     Move {
-        #[structopt(flatten)]
+        #[cfg_attr(feature = "cli", structopt(flatten))]
         config: DedupeConfig,
 
         /// Target directory where the redundant files should be moved to.
-        #[structopt(parse(from_os_str))]
+        #[cfg_attr(feature = "cli", structopt(parse(from_os_str)))]
         target: PathBuf,
     },
+
+    /// Finds groups of identical files and keeps the report up to date as the files change.
+    ///
+    /// Writes the report like `fclones group`, then watches the input directories and rewrites
+    /// the report whenever files are created, modified or removed. The report file is replaced
+    /// atomically, so readers never see a partially written report.
+    /// The hash cache (`--cache`) is always enabled, so only the changed files are hashed again.
+    /// Runs until interrupted.
+    Watch(WatchConfig),
+
+    /// Marks the files in each group with an extended attribute holding the group hash.
+    ///
+    /// The list of groups earlier produced by `fclones group` should be submitted
+    /// on the standard input.
+    ///
+    /// All files of each group are tagged with the `user.fclones.group_hash` attribute.
+    /// The contents, names and locations of files are not changed.
+    /// Fails if the file system doesn't support extended attributes.
+    /// Unsupported on Windows.
+    Tag(TagConfig),
+
+    /// Removes the extended attribute set by `fclones tag`.
+    ///
+    /// The list of groups earlier produced by `fclones group` should be submitted
+    /// on the standard input.
+    Untag(TagConfig),
+
+    /// Measures read performance of a storage device.
+    ///
+    /// Reads a file with sequential and random access patterns, using different
+    /// block sizes and numbers of concurrent reads, and recommends the sizes of thread pools
+    /// and read buffers for the device. Files are read the same way as when they are hashed
+    /// by `fclones group`.
+    ///
+    /// The page cache is dropped before each measurement on Linux only.
+    /// On other systems, use a file larger than the available memory.
+    Bench(BenchConfig),
+
+    /// Verifies files against their SHA-256 checksums.
+    ///
+    /// Reads a checksum file in the format written by `sha256sum`, computes the digest of each
+    /// listed file and prints `OK`, `FAILED` or `MISSING` for it.
+    /// Exits with code 1 if any file is not `OK`.
+    /// Relative file names are resolved against the current working directory.
+    Verify(VerifyConfig),
+
+    /// Compares the contents of two directory trees.
+    ///
+    /// Groups the files of both trees by content and reports the contents found only
+    /// in the first tree, only in the second tree, and in both trees.
+    /// File names and locations are not compared, so a file moved or renamed within
+    /// a tree still counts as present in both if the other tree has a copy.
+    /// Empty files are included. Hard links are treated as separate files.
+    DiffTrees(DiffTreesConfig),
+
+    /// Lists the storage devices detected by fclones.
+    ///
+    /// Prints the name, the type, the file system, the sizes of the thread pools
+    /// and the mount points of each device. Use it to check how the devices are classified
+    /// before overriding their properties with `--device-override` or `--device-type`.
+    /// The `default` device holds the files not found on any of the detected mount points.
+    Devices,
+
+    /// Manages the configuration file.
+    ///
+    /// The configuration file holds the default values of options, with a table for each
+    /// command, e.g. `[group]`, and the same option names as the long command line flags.
+    Config(ConfigCommand),
+
+    /// Prints a shell completion script.
+    ///
+    /// The script completes the commands, the options and the values of options
+    /// accepting only a fixed set of values, e.g. `--format`.
+    /// For example, to enable completions in bash, run `source <(fclones complete bash)`.
+    Complete {
+        /// The shell to generate the completion script for
+        #[cfg_attr(
+            feature = "cli",
+            structopt(value_name = "shell", possible_values = &["bash", "zsh", "fish", "powershell"])
+        )]
+        shell: String,
+    },
+
+    /// Lists the options of all commands.
+    ///
+    /// Prints the name, the type of the value, the default value and the allowed values
+    /// of each option. The list is generated from the same definitions that parse
+    /// the command line, so it always matches the options accepted by this version of fclones.
+    Options {
+        /// Prints the options as JSON, with the help text of each option
+        #[cfg_attr(feature = "cli", structopt(long))]
+        json: bool,
+    },
+}
+
+/// Subcommands of `fclones config`
+#[derive(Debug)]
+#[cfg_attr(feature = "cli", derive(StructOpt))]
+pub enum ConfigCommand {
+    /// Prints the effective configuration.
+    ///
+    /// Lists the options set in the configuration file and the default values of other options,
+    /// in the format of the configuration file, each followed by a comment
+    /// telling where its value comes from.
+    Dump {
+        /// Prints only the options of the given command
+        #[cfg_attr(feature = "cli", structopt(value_name = "command"))]
+        command: Option<String>,
+    },
 }
 
 impl Command {
     pub fn validate(&self) -> Result<(), String> {
+        check_rules(self, COMMAND_OPTION_RULES)?;
         match self {
             Command::Group(c) => c.validate(),
+            Command::Bench(c) => c.validate(),
+            Command::Remove(config)
+            | Command::Trash(config)
+            | Command::Link { config, .. }
+            | Command::Dedupe { config, .. }
+            | Command::Move { config, .. } => check_rules(config, DEDUPE_OPTION_RULES),
             _ => Ok(()),
         }
     }
 }
 
 /// Finds and cleans up redundant files
-#[derive(Debug, StructOpt)]
-#[structopt(
-    name = "fclones",
-    setting(AppSettings::ColoredHelp),
-    setting(AppSettings::DeriveDisplayOrder),
-    after_help = r#"EXAMPLES:
+#[derive(Debug)]
+#[cfg_attr(feature = "cli", derive(StructOpt))]
+#[cfg_attr(
+    feature = "cli",
+    structopt(
+        name = "fclones",
+        setting(AppSettings::ColoredHelp),
+        setting(AppSettings::DeriveDisplayOrder),
+        after_help = r#"EXAMPLES:
 
 First call "fclones group -o duplicates.txt directory/" to find all duplicates
 in a directory. See "fclones group --help" on how to modify this search.
@@ -672,13 +3014,579 @@ for more information. All have a --dry-run flag to preview changes.
 
 It is also possible to combine the first and second step, e.g.:
 "fclones group dir1/ dir2/ | fclones SUBCOMMAND""#
+    )
 )]
 pub struct Config {
     /// Suppresses progress reporting
-    #[structopt(short("-q"), long)]
+    #[cfg_attr(feature = "cli", structopt(short("-q"), long))]
     pub quiet: bool,
 
+    /// Prints additional diagnostic messages, e.g. the effective sizes of thread pools.
+    /// Use twice (`-vv`) to print even more details, e.g. changes of read buffer sizes.
+    #[cfg_attr(feature = "cli", structopt(short("-v"), long, parse(from_occurrences)))]
+    pub verbose: u8,
+
+    /// Writes all timestamps in log messages and reports in UTC, as specified by RFC 3339,
+    /// e.g. `2024-01-15T10:30:00Z`.
+    ///
+    /// Makes the timestamps independent from the local time zone and easy to parse
+    /// by tools expecting strict ISO 8601.
+    #[cfg_attr(feature = "cli", structopt(long))]
+    pub strict_iso_8601: bool,
+
+    /// Sets the format of the log messages written to the standard error.
+    ///
+    /// With `json`, each message is written as a single-line JSON object with the
+    /// `timestamp`, `level` and `message` fields, and the `path`, `error_kind` and `count`
+    /// fields if known. Progress reporting is turned off. Useful when fclones runs
+    /// as a service and its logs are collected by journald or Kubernetes.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, value_name = "format", default_value = "text", possible_values = &LogFormat::variants(), case_insensitive = true)
+    )]
+    pub log_format: LogFormat,
+
+    /// Reads the default values of options from the given configuration file.
+    ///
+    /// If not given, `config.toml` in the `fclones` subdirectory of the user configuration
+    /// directory is read if it exists, e.g. `~/.config/fclones/config.toml` on Linux.
+    /// Options given on the command line take precedence over the configuration file.
+    #[cfg_attr(
+        feature = "cli",
+        structopt(long, value_name = "path", parse(from_os_str))
+    )]
+    pub config: Option<PathBuf>,
+
     /// Finds files
-    #[structopt(subcommand)]
+    #[cfg_attr(feature = "cli", structopt(subcommand))]
     pub command: Command,
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_bandwidth_option() {
+        let (path, bandwidth) = parse_bandwidth_option("50MB/s").unwrap();
+        assert!(path.is_none());
+        assert_eq!(bandwidth, FileLen(50_000_000));
+
+        let (path, bandwidth) = parse_bandwidth_option("/mnt/hdd=1MiB").unwrap();
+        assert_eq!(path, Some(Path::from("/mnt/hdd")));
+        assert_eq!(bandwidth, FileLen(1024 * 1024));
+
+        assert!(parse_bandwidth_option("0/s").is_err());
+        assert!(parse_bandwidth_option("/mnt/hdd=fast").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("45").unwrap(), Duration::from_secs(45));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("30m").unwrap(), Duration::from_secs(30 * 60));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(2 * 3600));
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(5400));
+        assert_eq!(parse_duration("100ms").unwrap(), Duration::from_millis(100));
+        assert_eq!(
+            parse_duration("1m30ms").unwrap(),
+            Duration::from_millis(60_030)
+        );
+        assert_eq!(parse_duration("1d").unwrap(), Duration::from_secs(86400));
+        assert_eq!(
+            parse_duration("2w").unwrap(),
+            Duration::from_secs(14 * 86400)
+        );
+        assert_eq!(
+            parse_duration("1y").unwrap(),
+            Duration::from_secs(31_557_600)
+        );
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("10x").is_err());
+        assert!(parse_duration("1h30").is_err());
+    }
+
+    #[test]
+    fn test_parse_thread_count_option() {
+        let specs = parse_thread_count_option("8").unwrap();
+        assert_eq!(specs.len(), 1);
+        assert_eq!(specs[0].0, OsString::from("default"));
+        assert_eq!(specs[0].1.random, 8);
+        assert_eq!(specs[0].1.sequential, 8);
+
+        let specs = parse_thread_count_option("ssd:16,4").unwrap();
+        assert_eq!(specs.len(), 1);
+        assert_eq!(specs[0].0, OsString::from("ssd"));
+        assert_eq!(specs[0].1.random, 16);
+        assert_eq!(specs[0].1.sequential, 4);
+    }
+
+    #[test]
+    fn test_parse_multiple_thread_pools() {
+        let specs = parse_thread_count_option("main:8,ssd:16,hdd:1,unknown:2,1").unwrap();
+        let names: Vec<_> = specs.iter().map(|(n, _)| n.to_string_lossy()).collect();
+        assert_eq!(names, vec!["main", "ssd", "hdd", "unknown"]);
+        assert_eq!(specs[1].1.random, 16);
+        assert_eq!(specs[2].1.sequential, 1);
+        assert_eq!(specs[3].1.random, 2);
+        assert_eq!(specs[3].1.sequential, 1);
+
+        let specs = parse_thread_count_option("dev:/dev/sda:2,main:4").unwrap();
+        assert_eq!(specs[0].0, OsString::from("dev:/dev/sda"));
+        assert_eq!(specs[1].0, OsString::from("main"));
+    }
+
+    #[test]
+    fn test_parse_thread_count_option_rejects_invalid_sizes() {
+        assert!(parse_thread_count_option("0").is_err());
+        assert!(parse_thread_count_option("ssd:0").is_err());
+        assert!(parse_thread_count_option("ssd:4,2,1").is_err());
+        assert!(parse_thread_count_option("ssd:foo").is_err());
+    }
+
+    #[test]
+    fn test_output_format_from_extension() {
+        let format = |format: Option<OutputFormat>, output: Option<&str>| {
+            let config = GroupConfig {
+                format,
+                output: output.map(PathBuf::from),
+                ..Default::default()
+            };
+            config.output_format().to_string()
+        };
+        assert_eq!(format(None, None), "default");
+        assert_eq!(format(None, Some("results.txt")), "default");
+        assert_eq!(format(None, Some("results")), "default");
+        assert_eq!(format(None, Some("results.csv")), "csv");
+        assert_eq!(format(None, Some("results.tsv")), "tsv");
+        assert_eq!(format(None, Some("results.JSON")), "json");
+        assert_eq!(format(None, Some("results.jsonl")), "ndjson");
+        assert_eq!(format(None, Some("results.ndjson")), "ndjson");
+        assert_eq!(format(None, Some("results.html")), "html");
+        assert_eq!(format(None, Some("results.db")), "sqlite");
+        assert_eq!(format(Some(OutputFormat::Fdupes), Some("a.json")), "fdupes");
+        assert_eq!(format(None, Some("results.csv.gz")), "csv");
+        assert_eq!(format(None, Some("results.gz")), "default");
+    }
+
+    #[test]
+    fn test_compressed_output_path() {
+        let path = |output: &str, format: Option<OutputFormat>| {
+            let config = GroupConfig {
+                output: Some(PathBuf::from(output)),
+                format,
+                compress_output: true,
+                ..Default::default()
+            };
+            config.output_path().unwrap()
+        };
+        assert_eq!(path("dupes.json", None), PathBuf::from("dupes.json.gz"));
+        assert_eq!(path("dupes.csv.gz", None), PathBuf::from("dupes.csv.gz"));
+        assert_eq!(path("dupes.GZ", None), PathBuf::from("dupes.GZ"));
+        assert_eq!(
+            path("dupes", Some(OutputFormat::Csv)),
+            PathBuf::from("dupes.csv.gz")
+        );
+        assert_eq!(path("dupes", None), PathBuf::from("dupes.txt.gz"));
+    }
+
+    #[test]
+    fn test_parse_device_type_option() {
+        let (path, disk_type) = parse_device_type_option("/mnt/backup=hdd").unwrap();
+        assert_eq!(path, Path::from("/mnt/backup"));
+        assert_eq!(disk_type, DiskType::HDD);
+        let (path, disk_type) = parse_device_type_option("C:\\=SSD").unwrap();
+        assert_eq!(path, Path::from("C:\\"));
+        assert_eq!(disk_type, DiskType::SSD);
+        assert!(parse_device_type_option("/mnt/backup").is_err());
+        assert!(parse_device_type_option("/mnt/backup=tape").is_err());
+    }
+
+    #[test]
+    fn test_parse_device_override_option() {
+        let (path, device_override) = parse_device_override_option(
+            "/mnt/raid=type:ssd,seq-threads:2,rand-threads:8,chunk:4M",
+        )
+        .unwrap();
+        assert_eq!(path, Path::from("/mnt/raid"));
+        assert_eq!(
+            device_override,
+            DeviceOverride {
+                disk_type: Some(DiskType::SSD),
+                seq_threads: Some(2),
+                rand_threads: Some(8),
+                chunk: Some(FileLen(4_000_000)),
+            }
+        );
+        assert!(parse_device_override_option("/mnt/raid").is_err());
+        assert!(parse_device_override_option("/mnt/raid=type:tape").is_err());
+        assert!(parse_device_override_option("/mnt/raid=seq-threads:0").is_err());
+        assert!(parse_device_override_option("/mnt/raid=speed:fast").is_err());
+        assert!(parse_device_override_option("/mnt/raid=type:ssd,type:hdd").is_err());
+    }
+
+    #[test]
+    fn test_merge_conflicting_device_overrides() {
+        let mut a = DeviceOverride {
+            disk_type: Some(DiskType::SSD),
+            ..Default::default()
+        };
+        let b = DeviceOverride {
+            disk_type: Some(DiskType::SSD),
+            seq_threads: Some(2),
+            ..Default::default()
+        };
+        a.merge(&b).unwrap();
+        assert_eq!(a, b);
+        let c = DeviceOverride {
+            disk_type: Some(DiskType::HDD),
+            ..Default::default()
+        };
+        assert_eq!(
+            a.merge(&c).unwrap_err(),
+            "Conflicting values of type: ssd and hdd"
+        );
+    }
+
+    #[test]
+    fn test_validate_rejects_conflicting_group_options() {
+        let paths = || vec![Path::from(".")];
+        let output = || Some(PathBuf::from("dupes.txt"));
+        let json = Some(OutputFormat::Json);
+        let mut cases: Vec<(GroupConfig, &str)> = vec![
+            (
+                GroupConfig { stdin: true, paths: paths(), ..Default::default() },
+                "The --stdin and <paths> options cannot be used together. \
+                 Drop the <paths> arguments.",
+            ),
+            (
+                GroupConfig { stdin: true, paths_file: output(), ..Default::default() },
+                "The --stdin and --paths-file options cannot be used together. Drop --paths-file.",
+            ),
+            (
+                GroupConfig { isolate: true, follow_links: true, paths: paths(), ..Default::default() },
+                "The --isolate and --follow-links options cannot be used together. \
+                 Drop --follow-links.",
+            ),
+            (
+                GroupConfig { rf_over: Some(1), rf_under: Some(3), paths: paths(), ..Default::default() },
+                "The --rf-over and --rf-under options cannot be used together. Drop --rf-under.",
+            ),
+            (
+                GroupConfig { unique: true, rf_over: Some(1), paths: paths(), ..Default::default() },
+                "The --unique and --rf-over options cannot be used together. Drop --rf-over.",
+            ),
+            (
+                GroupConfig { unique: true, rf_under: Some(3), paths: paths(), ..Default::default() },
+                "The --unique and --rf-under options cannot be used together. Drop --rf-under.",
+            ),
+            (
+                GroupConfig { min_roots: Some(2), stdin: true, ..Default::default() },
+                "The --min-roots and --stdin options cannot be used together. Drop --stdin.",
+            ),
+            (
+                GroupConfig { all_roots: true, stdin: true, ..Default::default() },
+                "The --all-roots and --stdin options cannot be used together. Drop --stdin.",
+            ),
+            (
+                GroupConfig { all_roots: true, min_roots: Some(2), paths: paths(), ..Default::default() },
+                "The --all-roots and --min-roots options cannot be used together. Drop --min-roots.",
+            ),
+            (
+                GroupConfig { report_empty_dirs: true, stdin: true, ..Default::default() },
+                "The --report-empty-dirs and --stdin options cannot be used together. \
+                 Drop --report-empty-dirs.",
+            ),
+            (
+                GroupConfig {
+                    report_savings_by_directory: true,
+                    report_largest_groups: Some(3),
+                    paths: paths(),
+                    ..Default::default()
+                },
+                "The --report-savings-by-directory and --report-largest-groups options \
+                 cannot be used together. Drop --report-largest-groups.",
+            ),
+            (
+                GroupConfig {
+                    report_empty_dirs: true,
+                    report_largest_groups: Some(3),
+                    paths: paths(),
+                    ..Default::default()
+                },
+                "The --report-empty-dirs and --report-largest-groups options \
+                 cannot be used together. Drop --report-largest-groups.",
+            ),
+            (
+                GroupConfig {
+                    report_empty_dirs: true,
+                    report_savings_by_directory: true,
+                    paths: paths(),
+                    ..Default::default()
+                },
+                "The --report-empty-dirs and --report-savings-by-directory options \
+                 cannot be used together. Drop --report-savings-by-directory.",
+            ),
+            (
+                GroupConfig {
+                    diff: output(),
+                    report_largest_groups: Some(3),
+                    paths: paths(),
+                    ..Default::default()
+                },
+                "The --diff and --report-largest-groups options cannot be used together. \
+                 Drop --report-largest-groups.",
+            ),
+            (
+                GroupConfig {
+                    diff: output(),
+                    report_savings_by_directory: true,
+                    paths: paths(),
+                    ..Default::default()
+                },
+                "The --diff and --report-savings-by-directory options cannot be used together. \
+                 Drop --report-savings-by-directory.",
+            ),
+            (
+                GroupConfig {
+                    diff: output(),
+                    report_empty_dirs: true,
+                    paths: paths(),
+                    ..Default::default()
+                },
+                "The --diff and --report-empty-dirs options cannot be used together. \
+                 Drop --report-empty-dirs.",
+            ),
+            (
+                GroupConfig {
+                    scan_in_background: true,
+                    transform: Some("cat".to_owned()),
+                    paths: paths(),
+                    ..Default::default()
+                },
+                "The --scan-in-background and --transform options cannot be used together. \
+                 Drop --scan-in-background.",
+            ),
+            (
+                GroupConfig { print0: true, paths: paths(), ..Default::default() },
+                "The --print0 option requires --format machine. \
+                 Add --format machine or drop --print0.",
+            ),
+            (
+                GroupConfig { compress_output: true, paths: paths(), ..Default::default() },
+                "The --compress-output option requires --output. \
+                 Add --output or drop --compress-output.",
+            ),
+            (
+                GroupConfig {
+                    report_savings_by_directory: true,
+                    format: json,
+                    paths: paths(),
+                    ..Default::default()
+                },
+                "The --report-savings-by-directory option requires --format default. \
+                 Add --format default or drop --report-savings-by-directory.",
+            ),
+            (
+                GroupConfig { report_empty_dirs: true, format: json, paths: paths(), ..Default::default() },
+                "The --report-empty-dirs option requires --format default. \
+                 Add --format default or drop --report-empty-dirs.",
+            ),
+            (
+                GroupConfig {
+                    report_largest_groups: Some(3),
+                    format: json,
+                    paths: paths(),
+                    ..Default::default()
+                },
+                "The --report-largest-groups option requires --format default. \
+                 Add --format default or drop --report-largest-groups.",
+            ),
+            (
+                GroupConfig { diff: output(), format: json, paths: paths(), ..Default::default() },
+                "The --diff option requires --format default. \
+                 Add --format default or drop --diff.",
+            ),
+            (
+                GroupConfig { in_place: true, paths: paths(), ..Default::default() },
+                "The --in-place option requires --transform. Add --transform or drop --in-place.",
+            ),
+            (
+                GroupConfig { no_copy: true, paths: paths(), ..Default::default() },
+                "The --no-copy option requires --transform. Add --transform or drop --no-copy.",
+            ),
+        ];
+        if cfg!(feature = "sqlite") {
+            let sqlite = Some(OutputFormat::Sqlite);
+            cases.push((
+                GroupConfig {
+                    format: sqlite,
+                    output: output(),
+                    compress_output: true,
+                    paths: paths(),
+                    ..Default::default()
+                },
+                "The --format sqlite and --compress-output options cannot be used together. \
+                 Drop --compress-output.",
+            ));
+            cases.push((
+                GroupConfig {
+                    format: sqlite,
+                    paths: paths(),
+                    ..Default::default()
+                },
+                "The --format sqlite option requires --output. \
+                 Add --output or drop --format sqlite.",
+            ));
+        }
+
+        for (config, expected) in &cases {
+            assert_eq!(config.validate().unwrap_err(), *expected);
+        }
+        let tested: HashSet<&str> = cases.iter().map(|(_, message)| *message).collect();
+        for rule in GROUP_OPTION_RULES {
+            if rule.option == "--format sqlite" && !cfg!(feature = "sqlite") {
+                continue;
+            }
+            assert!(
+                tested.contains(rule.message().as_str()),
+                "{}",
+                rule.message()
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate_rejects_conflicting_command_options() {
+        let link = |soft, link_then_verify, hardlink_posix_semantics_check| Command::Link {
+            config: DedupeConfig::default(),
+            soft,
+            link_then_verify,
+            hardlink_posix_semantics_check,
+        };
+        let cases: Vec<(Command, &str)> = vec![
+            (
+                link(true, true, false),
+                "The --soft and --link-then-verify options cannot be used together. \
+                 Drop --link-then-verify.",
+            ),
+            (
+                link(true, false, true),
+                "The --soft and --hardlink-posix-semantics-check options cannot be used together. \
+                 Drop --hardlink-posix-semantics-check.",
+            ),
+            (
+                Command::Dedupe {
+                    config: DedupeConfig::default(),
+                    exec: ExecConfig {
+                        exec: Some("echo {keep}".to_owned()),
+                        ..Default::default()
+                    },
+                    sparse: true,
+                },
+                "The --sparse and --exec options cannot be used together. Drop --sparse.",
+            ),
+            (
+                Command::Remove(DedupeConfig {
+                    no_verify: true,
+                    ..Default::default()
+                }),
+                "The --no-verify option requires --import-format. \
+                 Add --import-format or drop --no-verify.",
+            ),
+        ];
+        for (command, expected) in &cases {
+            assert_eq!(command.validate().unwrap_err(), *expected);
+        }
+        let tested: HashSet<&str> = cases.iter().map(|(_, message)| *message).collect();
+        let messages = COMMAND_OPTION_RULES
+            .iter()
+            .map(|r| r.message())
+            .chain(DEDUPE_OPTION_RULES.iter().map(|r| r.message()));
+        for message in messages {
+            assert!(tested.contains(message.as_str()), "{}", message);
+        }
+        assert!(link(false, true, true).validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_rejects_unknown_pool_names() {
+        let config = GroupConfig {
+            paths: vec![Path::from(".")],
+            threads: vec![parse_thread_count_option("ssd:4,nvme:8").unwrap()],
+            ..Default::default()
+        };
+        let err = config.validate().unwrap_err();
+        assert!(err.contains("nvme"));
+        assert!(err.contains("ssd, hdd, removable, unknown, main, default"));
+    }
+
+    #[test]
+    #[cfg(feature = "cli")]
+    fn test_builder_starts_from_command_line_defaults() {
+        let mut cli = GroupConfig::from_iter_safe(["fclones", "."]).unwrap();
+        let built = GroupConfigBuilder::new()
+            .paths(vec![Path::from(".")])
+            .build()
+            .unwrap();
+        // The base directory is resolved by the command line tool only
+        cli.base_dir = built.base_dir.clone();
+        assert_eq!(format!("{:?}", built), format!("{:?}", cli));
+    }
+
+    #[test]
+    #[cfg(feature = "cli")]
+    fn test_export_shorthands_set_format_and_output() {
+        let mut config =
+            GroupConfig::from_iter_safe(["fclones", "--export-fdupes", "dupes.txt", "."]).unwrap();
+        config.expand_shorthands();
+        assert_eq!(config.output, Some(PathBuf::from("dupes.txt")));
+        assert!(matches!(config.output_format(), OutputFormat::Fdupes));
+
+        let mut config =
+            GroupConfig::from_iter_safe(["fclones", "--export-json", "dupes.txt", "."]).unwrap();
+        config.expand_shorthands();
+        assert!(matches!(config.output_format(), OutputFormat::Json));
+
+        assert!(GroupConfig::from_iter_safe([
+            "fclones",
+            "--export-csv",
+            "dupes.csv",
+            "-o",
+            "out.txt",
+            "."
+        ])
+        .is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "cli")]
+    fn test_size_range_combines_with_min_and_max() {
+        let config =
+            GroupConfig::from_iter_safe(["fclones", "--size", "1KiB..1MiB", "--max", "4KiB", "."])
+                .unwrap();
+        let range = config.size_range().unwrap();
+        assert_eq!(range.min, FileLen(1024));
+        assert_eq!(range.max, FileLen(4096));
+        assert_eq!(config.size_filter(), Some(range));
+
+        let config =
+            GroupConfig::from_iter_safe(["fclones", "--size", "..1KiB", "--min", "2KiB", "."])
+                .unwrap();
+        assert!(config.validate().unwrap_err().contains("--size 0..=1023"));
+
+        assert!(GroupConfig::from_iter_safe(["fclones", "--size", "1MiB..1KiB", "."]).is_err());
+        let config = GroupConfig::from_iter_safe(["fclones", "."]).unwrap();
+        assert_eq!(config.size_filter(), None);
+    }
+
+    #[test]
+    fn test_read_paths_skips_blank_lines_and_comments() {
+        let input = "# input paths\n  /home/foo  \n\n\t\nbar baz\n#/home/skipped\n";
+        let paths = read_paths(input.as_bytes()).unwrap();
+        assert_eq!(paths, [Path::from("/home/foo"), Path::from("bar baz")]);
+    }
+}
@@ -0,0 +1,861 @@
+//! Reading the values of command line options from a configuration file
+//! and from environment variables.
+//!
+//! The configuration file is a TOML file with a table per command, e.g. `[group]`,
+//! holding the options of the command under the names of their long command line flags,
+//! without the leading dashes. Top-level keys set the global options, e.g. `quiet = true`.
+//! Only the options of the `group`, `link`, `dedupe`, `remove`, `trash` and `move` commands
+//! can be set.
+//!
+//! The environment variables are named after the options, with the `FCLONES_` prefix,
+//! e.g. `FCLONES_MIN` for `--min`, and apply to every command having the option.
+//! `FCLONES_MIN_SIZE` and `FCLONES_MAX_SIZE` are accepted as aliases of `FCLONES_MIN`
+//! and `FCLONES_MAX`.
+//!
+//! The settings are deserialized with serde into the configuration parsed from the command line,
+//! one option at a time, so the options not set keep the values parsed from the command line.
+//! Options given on the command line take precedence over the environment variables,
+//! which take precedence over the configuration file.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::env;
+use std::ffi::OsString;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::PathBuf;
+
+use clap::ArgMatches;
+use serde::de::Visitor;
+use serde::{Deserialize, Deserializer};
+use structopt::StructOpt;
+use toml::value::Table;
+use toml::Value;
+
+use crate::config::{de, Command, Config, DedupeConfig, GroupConfig};
+use crate::error::Error;
+use crate::log::LogFormat;
+use crate::path::Path;
+
+const ENV_PREFIX: &str = "FCLONES_";
+/// Environment variable holding the path of the configuration file
+const CONFIG_ENV_VAR: &str = "FCLONES_CONFIG";
+/// Alternative names of the environment variables, with the options they set
+const ENV_ALIASES: &[(&str, &str)] = &[("FCLONES_MIN_SIZE", "min"), ("FCLONES_MAX_SIZE", "max")];
+
+/// Commands which options can be set, with the arguments required by their command line
+const COMMANDS: &[(&str, &[&str])] = &[
+    ("group", &["--stdin"]),
+    ("link", &[]),
+    ("dedupe", &[]),
+    ("remove", &[]),
+    ("trash", &[]),
+    ("move", &["target"]),
+];
+
+/// Global options that can be set
+#[derive(Debug, Default, Deserialize)]
+#[serde(default = "command_line_values", rename_all = "kebab-case")]
+struct GlobalOptions {
+    quiet: bool,
+    verbose: u8,
+    strict_iso_8601: bool,
+    #[serde(deserialize_with = "de::parsed")]
+    log_format: LogFormat,
+}
+
+/// Returns the names of the options of the command, if they can be set
+fn command_options(command: &str) -> Option<&'static [&'static str]> {
+    match command {
+        "group" => Some(option_names::<GroupConfig>()),
+        "link" | "dedupe" | "remove" | "trash" | "move" => Some(option_names::<DedupeConfig>()),
+        _ => None,
+    }
+}
+
+/// Returns the name of the environment variable setting the option, e.g. `FCLONES_MIN`
+fn env_var(option: &str) -> String {
+    format!("{}{}", ENV_PREFIX, option.to_uppercase().replace('-', "_"))
+}
+
+/// Returns the names of the environment variables setting the option,
+/// starting from the one named after the option, followed by its aliases
+fn env_vars(option: &str) -> impl Iterator<Item = String> + '_ {
+    let aliases = ENV_ALIASES
+        .iter()
+        .filter(move |(_, o)| *o == option)
+        .map(|(var, _)| var.to_string());
+    std::iter::once(env_var(option)).chain(aliases)
+}
+
+/// Returns the names of the fields of a struct deserialized by serde,
+/// which are the names of the options in the configuration file.
+fn option_names<T: for<'de> Deserialize<'de>>() -> &'static [&'static str] {
+    /// Deserializer recording the names of the fields requested by the deserialized struct
+    struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de> Deserializer<'de> for FieldNames<'_> {
+        type Error = serde::de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+            Err(serde::de::Error::custom("expected a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(serde::de::Error::custom(
+                "only the names of the fields are read",
+            ))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+            option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+            ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldNames(&mut fields));
+    fields
+}
+
+thread_local! {
+    /// Configuration the setting is applied to, see `apply_setting`
+    static COMMAND_LINE_VALUES: RefCell<Option<Box<dyn Any>>> = RefCell::new(None);
+}
+
+/// Returns the configuration a setting is being applied to, or the default configuration
+/// if no setting is being applied.
+/// It gives the values of the options missing in the deserialized settings.
+pub(crate) fn command_line_values<T: Default + 'static>() -> T {
+    COMMAND_LINE_VALUES
+        .with(|values| values.borrow_mut().take())
+        .and_then(|values| values.downcast().ok())
+        .map(|values| *values)
+        .unwrap_or_default()
+}
+
+/// Deserializes the value of a single option into the configuration,
+/// keeping the values of other options.
+fn apply_setting<T>(config: T, option: &str, value: &Value) -> Result<T, toml::de::Error>
+where
+    T: for<'de> Deserialize<'de> + Default + 'static,
+{
+    let mut table = Table::new();
+    table.insert(option.to_owned(), value.clone());
+    COMMAND_LINE_VALUES.with(|values| *values.borrow_mut() = Some(Box::new(config)));
+    let result = T::deserialize(Value::Table(table));
+    COMMAND_LINE_VALUES.with(|values| values.borrow_mut().take());
+    result
+}
+
+/// Where the value of an option comes from, if not from the command line
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Source {
+    Default,
+    ConfigFile,
+    Env(String),
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::ConfigFile => write!(f, "configuration file"),
+            Source::Env(var) => write!(f, "environment variable {}", var),
+        }
+    }
+}
+
+/// Value of an option set outside of the command line
+#[derive(Clone, Debug)]
+struct Setting {
+    option: &'static str,
+    value: Value,
+    source: Source,
+}
+
+/// Parsed configuration file
+#[derive(Debug)]
+pub struct ConfigFile {
+    pub path: PathBuf,
+    /// Settings of the global options, given by the top-level keys
+    global: Table,
+    /// Settings of the options of commands, by the name of the command
+    sections: Vec<(String, Table)>,
+}
+
+impl ConfigFile {
+    /// Returns the path of the configuration file read if `--config` is not given,
+    /// e.g. `~/.config/fclones/config.toml` on Linux.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|d| d.join("fclones").join("config.toml"))
+    }
+
+    /// Reads the configuration file given by the `--config` option of the command line
+    /// or the `FCLONES_CONFIG` environment variable.
+    /// If neither is given, reads the configuration file at the default path if it exists.
+    pub fn find(matches: &ArgMatches<'_>) -> Result<Option<ConfigFile>, Error> {
+        let explicit_path = matches
+            .value_of_os("config")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os(CONFIG_ENV_VAR).map(PathBuf::from));
+        match explicit_path {
+            Some(path) => Ok(Some(Self::load(path)?)),
+            None => match Self::default_path() {
+                Some(path) if path.is_file() => Ok(Some(Self::load(path)?)),
+                _ => Ok(None),
+            },
+        }
+    }
+
+    pub fn load(path: PathBuf) -> Result<ConfigFile, Error> {
+        let text = fs::read_to_string(&path).map_err(|e| {
+            Error::io(
+                "Failed to read configuration file",
+                Some(&Path::from(&path)),
+                e,
+            )
+        })?;
+        Self::parse(path, &text)
+    }
+
+    pub fn parse(path: PathBuf, text: &str) -> Result<ConfigFile, Error> {
+        let table: Table = toml::from_str(text).map_err(|e| {
+            Error::config(format!(
+                "Invalid configuration file {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        let mut global = Table::new();
+        let mut sections = Vec::new();
+        for (key, value) in table {
+            match value {
+                Value::Table(section) => sections.push((key, section)),
+                value => {
+                    global.insert(key, value);
+                }
+            }
+        }
+        Ok(ConfigFile {
+            path,
+            global,
+            sections,
+        })
+    }
+
+    /// Returns the settings of the given command, or the top-level settings if `command` is `None`
+    fn section(&self, command: Option<&str>) -> Option<&Table> {
+        match command {
+            Some(command) => self
+                .sections
+                .iter()
+                .find(|(name, _)| name == command)
+                .map(|(_, section)| section),
+            None => Some(&self.global),
+        }
+    }
+}
+
+/// Values of options set outside of the command line,
+/// in the environment variables and in the configuration file
+#[derive(Debug, Default)]
+pub struct Settings {
+    pub file: Option<ConfigFile>,
+    /// Environment variables with the `FCLONES_` prefix
+    env: Vec<(String, String)>,
+}
+
+impl Settings {
+    /// Creates the settings from the configuration file and the environment variables.
+    /// Variables without the `FCLONES_` prefix are ignored.
+    pub fn new(file: Option<ConfigFile>, env: Vec<(String, String)>) -> Settings {
+        let env = env
+            .into_iter()
+            .filter(|(var, _)| var.starts_with(ENV_PREFIX))
+            .collect();
+        Settings { file, env }
+    }
+
+    /// Reads the configuration file and the environment variables of this process
+    pub fn load(matches: &ArgMatches<'_>) -> Result<Settings, Error> {
+        let mut vars = Vec::new();
+        for (var, value) in env::vars_os() {
+            let var = var.to_string_lossy().to_string();
+            if !var.starts_with(ENV_PREFIX) {
+                continue;
+            }
+            let value = value.into_string().map_err(|_| {
+                Error::config(format!(
+                    "Invalid value of environment variable {}: not valid UTF-8",
+                    var
+                ))
+            })?;
+            vars.push((var, value));
+        }
+        Ok(Settings::new(ConfigFile::find(matches)?, vars))
+    }
+
+    /// Returns the options of the given command, or the global options if `command` is `None`,
+    /// set by the environment variables or in the configuration file.
+    /// The environment variables take precedence.
+    fn settings(&self, command: Option<&str>, options: &'static [&'static str]) -> Vec<Setting> {
+        let section = self.file.as_ref().and_then(|f| f.section(command));
+        options
+            .iter()
+            .filter_map(|&option| {
+                for var in env_vars(option) {
+                    if let Some((_, value)) = self.env.iter().find(|(v, _)| *v == var) {
+                        return Some(Setting {
+                            option,
+                            value: env_value(value),
+                            source: Source::Env(var),
+                        });
+                    }
+                }
+                Some(Setting {
+                    option,
+                    value: section?.get(option)?.clone(),
+                    source: Source::ConfigFile,
+                })
+            })
+            .collect()
+    }
+
+    /// Sets the options of the configuration parsed from the command line
+    /// to the values set by the environment variables and in the configuration file,
+    /// unless they are given on the command line.
+    ///
+    /// The values are parsed like the command line arguments, but the constraints between
+    /// options are not checked, so the configuration must be validated afterwards.
+    pub fn apply(&self, mut config: Config, matches: &ArgMatches<'_>) -> Result<Config, Error> {
+        let global = GlobalOptions {
+            quiet: config.quiet,
+            verbose: config.verbose,
+            strict_iso_8601: config.strict_iso_8601,
+            log_format: config.log_format,
+        };
+        let global = self.apply_section(None, global, matches)?;
+        config.quiet = global.quiet;
+        config.verbose = global.verbose;
+        config.strict_iso_8601 = global.strict_iso_8601;
+        config.log_format = global.log_format;
+
+        let (command, command_matches) = match matches.subcommand() {
+            (command, Some(command_matches)) => (Some(command), command_matches),
+            _ => return Ok(config),
+        };
+        match &mut config.command {
+            Command::Group(c) => {
+                *c = self.apply_section(command, std::mem::take(c), command_matches)?;
+            }
+            Command::Link { config: c, .. }
+            | Command::Dedupe { config: c, .. }
+            | Command::Remove(c)
+            | Command::Trash(c)
+            | Command::Move { config: c, .. } => {
+                *c = self.apply_section(command, std::mem::take(c), command_matches)?;
+            }
+            _ => {}
+        }
+        Ok(config)
+    }
+
+    fn apply_section<T>(
+        &self,
+        command: Option<&str>,
+        mut config: T,
+        matches: &ArgMatches<'_>,
+    ) -> Result<T, Error>
+    where
+        T: for<'de> Deserialize<'de> + Default + 'static,
+    {
+        for setting in self.settings(command, option_names::<T>()) {
+            if matches.occurrences_of(setting.option) > 0 {
+                continue;
+            }
+            config = apply_setting(config, setting.option, &setting.value).map_err(|e| {
+                // The key is already a part of our message:
+                let key_suffix = format!(" for key `{}`", setting.option);
+                let e = e.to_string();
+                Error::config(format!(
+                    "Invalid value of '{}' in {}: {}",
+                    setting.option,
+                    self.describe(&setting.source),
+                    e.strip_suffix(&key_suffix).unwrap_or(&e)
+                ))
+            })?;
+        }
+        Ok(config)
+    }
+
+    /// Returns the command line `args` extended with the options set by the environment
+    /// variables and in the configuration file that are not given on the command line,
+    /// so that parsing the returned command line gives the same configuration as [`apply`].
+    ///
+    /// The global options are added before the command and the options of the command
+    /// are added after its arguments, each in the `--option=value` form.
+    ///
+    /// [`apply`]: Settings::apply
+    pub fn command_line(&self, args: Vec<OsString>, matches: &ArgMatches<'_>) -> Vec<OsString> {
+        let mut args = args.into_iter();
+        let mut result: Vec<OsString> = args.next().into_iter().collect();
+        let global = option_names::<GlobalOptions>();
+        result.extend(self.unset_args(None, global, matches));
+        let mut args: Vec<OsString> = args.collect();
+        if let (command, Some(command_matches)) = matches.subcommand() {
+            if let Some(options) = command_options(command) {
+                // Arguments after `--` are positional, so the options must precede it:
+                let end = args.iter().position(|a| a == "--").unwrap_or(args.len());
+                let added = self.unset_args(Some(command), options, command_matches);
+                args.splice(end..end, added);
+            }
+        }
+        result.extend(args);
+        result
+    }
+
+    /// Returns the command line arguments for the settings of the options not given
+    /// on the command line
+    fn unset_args(
+        &self,
+        command: Option<&str>,
+        options: &'static [&'static str],
+        matches: &ArgMatches<'_>,
+    ) -> Vec<OsString> {
+        self.settings(command, options)
+            .into_iter()
+            .filter(|setting| matches.occurrences_of(setting.option) == 0)
+            .flat_map(|setting| option_args(setting.option, &setting.value))
+            .collect()
+    }
+
+    fn describe(&self, source: &Source) -> String {
+        match (source, &self.file) {
+            (Source::ConfigFile, Some(file)) => format!("{} {}", source, file.path.display()),
+            _ => source.to_string(),
+        }
+    }
+
+    /// Returns warnings about the sections and options of the configuration file
+    /// and the `FCLONES_` environment variables that don't match any command or option.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if let Some(file) = &self.file {
+            let path = file.path.display();
+            let global_options = option_names::<GlobalOptions>();
+            for key in file.global.keys() {
+                if !global_options.contains(&key.as_str()) {
+                    warnings.push(format!(
+                        "Unknown option '{}' in configuration file {}",
+                        key, path
+                    ));
+                }
+            }
+            for (command, section) in &file.sections {
+                match command_options(command) {
+                    Some(options) => {
+                        for key in section.keys() {
+                            if !options.contains(&key.as_str()) {
+                                warnings.push(format!(
+                                    "Unknown option '{}' in section [{}] of configuration file {}",
+                                    key, command, path
+                                ))
+                            }
+                        }
+                    }
+                    None => warnings.push(format!(
+                        "Unknown section [{}] in configuration file {}. \
+                        Only the group, link, dedupe, remove, trash and move commands \
+                        can be configured",
+                        command, path
+                    )),
+                }
+            }
+        }
+
+        let mut known_vars: Vec<String> = option_names::<GlobalOptions>()
+            .iter()
+            .chain(option_names::<GroupConfig>())
+            .chain(option_names::<DedupeConfig>())
+            .flat_map(|option| env_vars(option))
+            .collect();
+        known_vars.push(CONFIG_ENV_VAR.to_owned());
+        for (var, _) in &self.env {
+            if !known_vars.contains(var) {
+                warnings.push(format!("Unknown environment variable {}", var));
+            }
+        }
+        warnings
+    }
+
+    /// Formats the effective configuration of the given command, or of all commands if not given,
+    /// in the format of the configuration file.
+    /// Lists the options set by the environment variables, in the configuration file
+    /// and the options having default values.
+    /// Each option is followed by a comment telling where its value comes from.
+    pub fn dump(&self, command: Option<&str>) -> Result<String, Error> {
+        let commands: Vec<_> = match command {
+            Some(command) => match COMMANDS.iter().find(|(name, _)| *name == command) {
+                Some(c) => vec![c],
+                None => {
+                    return Err(Error::config(format!(
+                        "Unknown command or command without configurable options: {}",
+                        command
+                    )))
+                }
+            },
+            None => COMMANDS.iter().collect(),
+        };
+
+        let mut result = match &self.file {
+            Some(file) => format!("# Configuration file: {}\n", file.path.display()),
+            None => "# No configuration file\n".to_owned(),
+        };
+        for (name, args) in commands {
+            // The defaults are the values parsed from a command line not giving the options:
+            let args = ["fclones", name].into_iter().chain(args.iter().copied());
+            let matches = Config::clap()
+                .get_matches_from_safe(args)
+                .map_err(|e| Error::config(e.message))?;
+            if command.is_none() && *name == COMMANDS[0].0 {
+                let options = option_names::<GlobalOptions>();
+                for line in self.dump_section(None, options, &matches) {
+                    result.push_str(&line);
+                    result.push('\n');
+                }
+            }
+            let options = command_options(name).unwrap();
+            let command_matches = matches.subcommand_matches(name).unwrap();
+            result.push_str(&format!("\n[{}]\n", name));
+            for line in self.dump_section(Some(name), options, command_matches) {
+                result.push_str(&line);
+                result.push('\n');
+            }
+        }
+        Ok(result)
+    }
+
+    fn dump_section(
+        &self,
+        command: Option<&str>,
+        options: &'static [&'static str],
+        defaults: &ArgMatches<'_>,
+    ) -> Vec<String> {
+        let settings = self.settings(command, options);
+        options
+            .iter()
+            .filter_map(|&option| {
+                let (value, source) = match settings.iter().find(|s| s.option == option) {
+                    Some(setting) => (setting.value.clone(), setting.source.clone()),
+                    None => (text_value(defaults.value_of(option)?), Source::Default),
+                };
+                Some(format!("{} = {}  # {}", option, value, source))
+            })
+            .collect()
+    }
+}
+
+/// Converts the value of an option to the command line arguments setting it.
+/// Flags set to false are left out, and each element of an array becomes a separate argument.
+/// The verbosity level is given by repeating the `--verbose` flag.
+fn option_args(option: &str, value: &Value) -> Vec<OsString> {
+    match value {
+        Value::Integer(level) if option == "verbose" => {
+            vec!["--verbose".into(); usize::try_from(*level).unwrap_or(0)]
+        }
+        Value::Boolean(true) => vec![format!("--{}", option).into()],
+        Value::Boolean(false) => vec![],
+        Value::String(s) => vec![format!("--{}={}", option, s).into()],
+        Value::Array(values) => values
+            .iter()
+            .flat_map(|value| option_args(option, value))
+            .collect(),
+        value => vec![format!("--{}={}", option, value).into()],
+    }
+}
+
+/// Converts the value of an environment variable to a value of the configuration file
+fn env_value(value: &str) -> Value {
+    match value.to_lowercase().as_str() {
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        _ => text_value(value),
+    }
+}
+
+/// Converts the text of a command line argument to a value of the configuration file
+fn text_value(value: &str) -> Value {
+    match value.parse::<i64>() {
+        Ok(i) => Value::Integer(i),
+        Err(_) => Value::String(value.to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::file::FileLen;
+
+    fn config_file(text: &str) -> ConfigFile {
+        ConfigFile::parse(PathBuf::from("config.toml"), text).unwrap()
+    }
+
+    fn settings(text: &str) -> Settings {
+        Settings::new(Some(config_file(text)), vec![])
+    }
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(var, value)| (var.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn parse(settings: &Settings, args: &[&str]) -> Result<Config, Error> {
+        let matches = Config::clap().get_matches_from_safe(args).unwrap();
+        settings.apply(Config::from_clap(&matches), &matches)
+    }
+
+    fn group_config(config: Config) -> GroupConfig {
+        match config.command {
+            Command::Group(c) => c,
+            _ => panic!("Expected group command"),
+        }
+    }
+
+    #[test]
+    fn command_line_includes_settings() {
+        let settings = Settings::new(
+            Some(config_file(
+                r#"
+                verbose = 2
+
+                [group]
+                rf-over = 2
+                cache = true
+                name = ["*.jpg", "*.png"]
+                ext = "jpg,png"
+                "#,
+            )),
+            env(&[("FCLONES_MIN", "1MB"), ("FCLONES_HARD_LINKS", "false")]),
+        );
+        let args = ["fclones", "group", "--cache", "--", "dir"];
+        let matches = Config::clap().get_matches_from_safe(args).unwrap();
+        let command_line = settings.command_line(args.map(OsString::from).to_vec(), &matches);
+        assert_eq!(
+            command_line,
+            [
+                "fclones",
+                "--verbose",
+                "--verbose",
+                "group",
+                "--cache",
+                "--rf-over=2",
+                "--min=1MB",
+                "--name=*.jpg",
+                "--name=*.png",
+                "--ext=jpg,png",
+                "--",
+                "dir"
+            ]
+        );
+
+        let expected = parse(&settings, &args).unwrap();
+        let matches = Config::clap().get_matches_from_safe(&command_line).unwrap();
+        let parsed = Config::from_clap(&matches);
+        assert_eq!(parsed.verbose, expected.verbose);
+        assert_eq!(
+            format!("{:?}", group_config(parsed)),
+            format!("{:?}", group_config(expected))
+        );
+    }
+
+    #[test]
+    fn merges_options_not_given_on_command_line() {
+        let settings = settings(
+            r#"
+            quiet = true
+
+            [group]
+            min = "1MB"
+            threads = "4"
+            cache = true
+            name = ["*.jpg", "*.png"]
+            ext = "jpg,png"
+            io-retry-delay = "1s"
+
+            [remove]
+            priority = "oldest"
+            keep-older-than = "1d"
+            "#,
+        );
+        let config = parse(&settings, &["fclones", "group", "dir", "--threads", "8"]).unwrap();
+        assert!(config.quiet);
+        let c = group_config(config);
+        assert_eq!(c.min_size, FileLen(1_000_000));
+        assert_eq!(c.threads.len(), 1);
+        assert_eq!(c.threads[0][0].1.random, 8);
+        assert!(c.cache);
+        assert_eq!(c.name_patterns, vec!["*.jpg", "*.png"]);
+        assert_eq!(c.extensions, vec!["jpg", "png"]);
+        assert_eq!(c.io_retry_delay, std::time::Duration::from_secs(1));
+        assert_eq!(c.hash_encoding, crate::file::HashEncoding::Hex);
+        assert_eq!(c.paths.len(), 1);
+
+        let config = parse(&settings, &["fclones", "remove"]).unwrap();
+        match config.command {
+            Command::Remove(c) => {
+                assert_eq!(c.priority.len(), 1);
+                assert!(c.keep_older_than.is_some());
+            }
+            _ => panic!("Expected remove command"),
+        }
+    }
+
+    #[test]
+    fn environment_overrides_config_file() {
+        let file = config_file("[group]\nmin = \"1MB\"\nmax = \"2MB\"\n");
+        let vars = env(&[
+            ("FCLONES_MIN", "10"),
+            ("FCLONES_MAX", "20"),
+            ("FCLONES_VERBOSE", "2"),
+            ("HOME", "/home/user"),
+        ]);
+        let settings = Settings::new(Some(file), vars);
+        let config = parse(&settings, &["fclones", "group", "dir", "--max", "30"]).unwrap();
+        assert_eq!(config.verbose, 2);
+        let c = group_config(config);
+        assert_eq!(c.min_size, FileLen(10));
+        assert_eq!(c.max_size, Some(FileLen(30)));
+        let dump = settings.dump(Some("group")).unwrap();
+        assert!(dump.contains("min = 10  # environment variable FCLONES_MIN\n"));
+    }
+
+    #[test]
+    fn size_variables_accept_aliases() {
+        let vars = env(&[("FCLONES_MIN_SIZE", "10"), ("FCLONES_MAX_SIZE", "20")]);
+        let settings = Settings::new(None, vars);
+        let c = group_config(parse(&settings, &["fclones", "group", "dir"]).unwrap());
+        assert_eq!(c.min_size, FileLen(10));
+        assert_eq!(c.max_size, Some(FileLen(20)));
+        assert!(settings.warnings().is_empty());
+
+        // The variable named after the option takes precedence:
+        let vars = env(&[("FCLONES_MIN_SIZE", "10"), ("FCLONES_MIN", "30")]);
+        let settings = Settings::new(None, vars);
+        let c = group_config(parse(&settings, &["fclones", "group", "dir"]).unwrap());
+        assert_eq!(c.min_size, FileLen(30));
+    }
+
+    #[test]
+    fn values_are_parsed_like_command_line() {
+        let settings = Settings::new(None, env(&[("FCLONES_MIN", "abc")]));
+        let err = parse(&settings, &["fclones", "group", "dir"]).unwrap_err();
+        assert!(err.message.contains("FCLONES_MIN"));
+        let cli_err = Config::from_iter_safe(["fclones", "group", "dir", "--min=abc"]).unwrap_err();
+        let parse_err = "abc".parse::<FileLen>().unwrap_err().to_string();
+        assert!(cli_err.message.contains(&parse_err));
+        assert!(err.message.contains(&parse_err));
+
+        let settings = Settings::new(None, env(&[("FCLONES_CACHE", "maybe")]));
+        assert!(parse(&settings, &["fclones", "group", "dir"]).is_err());
+        let settings = Settings::new(None, env(&[("FCLONES_MAX_OPEN_FILES", "0")]));
+        assert!(parse(&settings, &["fclones", "group", "dir"]).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let invalid_flag = settings("[group]\ncache = \"yes\"\n");
+        let err = parse(&invalid_flag, &["fclones", "group", "dir"]).unwrap_err();
+        assert!(err.message.contains("'cache'"));
+        assert!(err.message.contains("config.toml"));
+        let invalid_threads = settings("[group]\nthreads = \"0\"\n");
+        assert!(parse(&invalid_threads, &["fclones", "group", "dir"]).is_err());
+        assert!(ConfigFile::parse(PathBuf::from("config.toml"), "[group\n").is_err());
+    }
+
+    /// The options must be named like the command line arguments, because the names are used
+    /// to check if the options are given on the command line.
+    #[test]
+    fn options_are_named_after_command_line_arguments() {
+        // Arguments following the option flag if it takes a value:
+        let value_args = |option: &str| -> &[&str] {
+            match option {
+                "format" => &["json"],
+                "size" => &["1..2"],
+                "hash-encoding" => &["hex"],
+                "checksum-format" => &["gnu"],
+                "device-type" => &["/=ssd"],
+                "device-override" => &["/=type:ssd"],
+                "io-retry-delay" | "keep-older-than" | "drop-newer-than" | "dedup-timeout" => {
+                    &["1s"]
+                }
+                "modified-before" => &["2020-01-01"],
+                "priority" => &["newest"],
+                "import-format" => &["fdupes"],
+                "no-verify" => &["--import-format", "fdupes"],
+                _ => &["1"],
+            }
+        };
+        for (command, _) in COMMANDS {
+            let positional: &[&str] = match *command {
+                "group" => &["."],
+                "move" => &["target"],
+                _ => &[],
+            };
+            for &option in command_options(command).unwrap() {
+                let flag = format!("--{}", option);
+                let parse = |args: &[&str]| {
+                    let args = ["fclones", command]
+                        .into_iter()
+                        .chain(positional.iter().copied())
+                        .chain(args.iter().copied());
+                    Config::clap().get_matches_from_safe(args)
+                };
+                let with_value: Vec<&str> = [flag.as_str()]
+                    .iter()
+                    .chain(value_args(option))
+                    .copied()
+                    .collect();
+                let matches = parse(&[&flag])
+                    .or_else(|_| parse(&with_value))
+                    .unwrap_or_else(|e| panic!("{} {}: {}", command, flag, e));
+                let matches = matches.subcommand_matches(command).unwrap();
+                assert_eq!(matches.occurrences_of(option), 1, "{} {}", command, flag);
+            }
+        }
+    }
+
+    #[test]
+    fn reports_unknown_settings() {
+        let file = config_file("foo = 1\n[group]\ncache = true\nbar = 2\n[baz]\n");
+        let vars = env(&[("FCLONES_THREADS", "4"), ("FCLONES_QUX", "1")]);
+        let warnings = Settings::new(Some(file), vars).warnings();
+        assert_eq!(warnings.len(), 4);
+        assert!(warnings[0].contains("'foo'"));
+        assert!(warnings[1].contains("[baz]"));
+        assert!(warnings[2].contains("'bar'"));
+        assert!(warnings[2].contains("[group]"));
+        assert!(warnings[3].contains("FCLONES_QUX"));
+    }
+
+    #[test]
+    fn dumps_effective_configuration() {
+        let settings = settings("[group]\nhash-encoding = \"base64\"\n");
+        let dump = settings.dump(Some("group")).unwrap();
+        assert!(dump.contains("[group]\n"));
+        assert!(dump.contains("hash-encoding = \"base64\"  # configuration file\n"));
+        assert!(dump.contains("io-retries = 0  # default\n"));
+        let dump = settings.dump(None).unwrap();
+        assert!(dump.contains("log-format = \"text\"  # default\n"));
+        assert!(dump.contains("[move]\n"));
+        assert!(Settings::default().dump(Some("foo")).is_err());
+    }
+}
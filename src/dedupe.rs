@@ -1,30 +1,35 @@
 //! Removing redundant files.
 
 use std::cmp::{max, min, Reverse};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Formatter};
 use std::io::{BufWriter, ErrorKind, Write};
 use std::ops::{Add, AddAssign};
 use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use std::{fmt, fs, io};
 
 use chrono::{DateTime, FixedOffset, Local};
 use crossbeam_utils::atomic::AtomicCell;
+use itertools::Itertools;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
 use rayon::iter::IntoParallelIterator;
 use rayon::iter::ParallelIterator;
 
+use crate::btrfs::is_in_read_only_subvolume;
 use crate::config::{DedupeConfig, Priority};
 use crate::device::DiskDevices;
-use crate::file::{AsPath, FileLen, FileMetadata};
+use crate::exec::ExecGroup;
+use crate::extent::is_sparse;
+use crate::file::{AsPath, FileChunk, FileId, FileLen, FileMetadata, FilePos};
 use crate::group::{FileGroup, FileSubGroup};
+use crate::hasher::file_hash;
 use crate::lock::FileLock;
 use crate::log::Log;
 use crate::path::Path;
 use crate::util::{max_result, min_result, try_sort_by_key};
-use crate::{Error, TIMESTAMP_FMT};
+use crate::{format_timestamp, Error};
 
 /// Defines what to do with redundant files
 #[derive(Clone, PartialEq, Eq)]
@@ -33,12 +38,21 @@ pub enum DedupeOp {
     Remove,
     /// Moves redundant files to a different dir
     Move(Arc<Path>),
+    /// Moves redundant files to the trash or the recycle bin of the operating system.
+    Trash,
     /// Replaces redundant files with soft-links (ln -s on Unix).
     SoftLink,
     /// Replaces redundant files with hard-links (ln on Unix).
-    HardLink,
+    /// If `verify` is set, the contents of each link are compared with its target
+    /// after linking and the operation is rolled back if they differ.
+    /// If `check_semantics` is set, hard links are first tested once on each target device
+    /// and nothing is linked if the file system doesn't really create them.
+    HardLink { verify: bool, check_semantics: bool },
     /// Reflink redundant files (cp --reflink=always, only some filesystems).
     RefLink,
+    /// Shares only the data extents of sparse redundant files with the retained file,
+    /// leaving their holes unallocated. Files that are not sparse are left intact.
+    SparseRefLink,
 }
 
 /// Convenience struct for holding a path to a file and its metadata together
@@ -60,6 +74,83 @@ impl PathAndMetadata {
     }
 }
 
+impl PathAndMetadata {
+    /// Returns true if the file is marked immutable or append-only,
+    /// and therefore can be neither removed nor replaced by a link.
+    /// If the attributes cannot be read, the file is assumed to not be protected.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn is_protected(&self) -> bool {
+        use nix::request_code_read;
+        use std::os::unix::fs::OpenOptionsExt;
+        use std::os::unix::io::AsRawFd;
+
+        // From /usr/include/linux/fs.h:
+        // #define FS_IOC_GETFLAGS _IOR('f', 1, long)
+        // #define FS_IMMUTABLE_FL 0x00000010
+        // #define FS_APPEND_FL    0x00000020
+        const FS_IOC_GETFLAGS_TYPE: u8 = b'f';
+        const FS_IOC_GETFLAGS_NR: u8 = 1;
+        const FS_IOC_GETFLAGS_SIZE: usize = std::mem::size_of::<libc::c_long>();
+        const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+        const FS_APPEND_FL: libc::c_int = 0x20;
+
+        let file = match fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(self.path.to_path_buf())
+        {
+            Ok(file) => file,
+            Err(_) => return false,
+        };
+        let mut flags: libc::c_int = 0;
+        let ret = unsafe {
+            libc::ioctl(
+                file.as_raw_fd(),
+                request_code_read!(
+                    FS_IOC_GETFLAGS_TYPE,
+                    FS_IOC_GETFLAGS_NR,
+                    FS_IOC_GETFLAGS_SIZE
+                ),
+                &mut flags,
+            )
+        };
+        ret == 0 && flags & (FS_IMMUTABLE_FL | FS_APPEND_FL) != 0
+    }
+
+    /// Returns true if the file is marked immutable or append-only,
+    /// and therefore can be neither removed nor replaced by a link.
+    #[cfg(target_os = "macos")]
+    pub fn is_protected(&self) -> bool {
+        use std::os::macos::fs::MetadataExt;
+        const UF_IMMUTABLE: u32 = 0x0000_0002;
+        const UF_APPEND: u32 = 0x0000_0004;
+        const SF_IMMUTABLE: u32 = 0x0002_0000;
+        const SF_APPEND: u32 = 0x0004_0000;
+        self.metadata.st_flags() & (UF_IMMUTABLE | UF_APPEND | SF_IMMUTABLE | SF_APPEND) != 0
+    }
+
+    /// Returns true if the file has the read-only or system attribute set,
+    /// and therefore shouldn't be removed nor replaced by a link.
+    #[cfg(windows)]
+    pub fn is_protected(&self) -> bool {
+        use std::os::windows::fs::MetadataExt;
+        const FILE_ATTRIBUTE_READONLY: u32 = 0x1;
+        const FILE_ATTRIBUTE_SYSTEM: u32 = 0x4;
+        self.metadata.file_attributes() & (FILE_ATTRIBUTE_READONLY | FILE_ATTRIBUTE_SYSTEM) != 0
+    }
+
+    /// Protection attributes are not supported on this platform.
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        windows
+    )))]
+    pub fn is_protected(&self) -> bool {
+        false
+    }
+}
+
 impl AsPath for PathAndMetadata {
     fn path(&self) -> &Path {
         &self.path
@@ -82,6 +173,9 @@ pub enum FsCommand {
         target: Path,
         use_rename: bool, // try to move the file directly by issuing fs rename command
     },
+    Trash {
+        file: PathAndMetadata,
+    },
     SoftLink {
         target: Arc<PathAndMetadata>,
         link: PathAndMetadata,
@@ -89,11 +183,21 @@ pub enum FsCommand {
     HardLink {
         target: Arc<PathAndMetadata>,
         link: PathAndMetadata,
+        verify: bool, // compare contents of the link and the target after linking
     },
     RefLink {
         target: Arc<PathAndMetadata>,
         link: PathAndMetadata,
     },
+    SparseRefLink {
+        target: Arc<PathAndMetadata>,
+        link: PathAndMetadata,
+    },
+    /// Leaves a redundant file intact, because it is protected
+    /// by an immutable, append-only, read-only or system attribute.
+    Skip {
+        file: PathAndMetadata,
+    },
 }
 
 impl FsCommand {
@@ -145,6 +249,35 @@ impl FsCommand {
         })
     }
 
+    /// Creates a temporary file next to `file` and a hard link to it, and checks if both
+    /// paths refer to the same inode. Both temporary files are removed afterwards.
+    /// Returns false if the file system reported success of linking,
+    /// but created an independent file.
+    pub fn check_hard_link_semantics(file: &Path) -> io::Result<bool> {
+        let probe = Self::temp_file(file);
+        let probe_link = Self::temp_file(file);
+        let result = (|| {
+            fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(probe.to_path_buf())?;
+            Self::hardlink(&probe, &probe_link)?;
+            Ok(FileId::new(&probe)? == FileId::new(&probe_link)?)
+        })();
+        let _ = fs::remove_file(probe_link.to_path_buf());
+        let _ = fs::remove_file(probe.to_path_buf());
+        result.map_err(|e: io::Error| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "Failed to test hard links in the directory of {}: {}",
+                    file.display(),
+                    e
+                ),
+            )
+        })
+    }
+
     fn check_can_rename(source: &Path, target: &Path) -> io::Result<()> {
         if target.to_path_buf().exists() {
             return Err(io::Error::new(
@@ -240,6 +373,30 @@ impl FsCommand {
         }
     }
 
+    /// Hashes the contents of a newly created link and its target and checks if they are equal.
+    /// If they differ, removes the link, logs an error and returns an error,
+    /// so the original file can be restored by the caller.
+    fn verify_link(target: &Path, link: &Path, log: &Log) -> io::Result<()> {
+        let hash = |path: &Path| {
+            file_hash(
+                &FileChunk::new(path, FilePos(0), FileLen::MAX),
+                65536,
+                |_| {},
+            )
+        };
+        if hash(target)? == hash(link)? {
+            return Ok(());
+        }
+        let _ = fs::remove_file(link.to_path_buf());
+        let message = format!(
+            "Contents of link {} differ from its target {}. The link has been rolled back.",
+            link.display(),
+            target.display()
+        );
+        log.err(&message);
+        Err(io::Error::new(ErrorKind::InvalidData, message))
+    }
+
     /// Safely moves the file to a different location and invokes the function.
     /// If the function fails, moves the file back to the original location.
     /// If the function succeeds, removes the file permanently.
@@ -284,18 +441,57 @@ impl FsCommand {
                 Self::remove(&file.path)?;
                 Ok(file.metadata.len())
             }
+            #[cfg(feature = "trash")]
+            FsCommand::Trash { file } => {
+                crate::trash::move_to_trash(&file.path)?;
+                Ok(file.metadata.len())
+            }
+            #[cfg(not(feature = "trash"))]
+            FsCommand::Trash { file } => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "Failed to move {} to trash: fclones was built without the trash feature",
+                    file.path
+                ),
+            )),
             FsCommand::SoftLink { target, link } => {
                 Self::safe_remove(&link.path, |link| Self::symlink(&target.path, link), log)?;
                 Ok(link.metadata.len())
             }
-            FsCommand::HardLink { target, link } => {
-                Self::safe_remove(&link.path, |link| Self::hardlink(&target.path, link), log)?;
+            FsCommand::HardLink {
+                target,
+                link,
+                verify,
+            } => {
+                Self::safe_remove(
+                    &link.path,
+                    |link| {
+                        Self::hardlink(&target.path, link)?;
+                        if *verify {
+                            Self::verify_link(&target.path, link, log)?;
+                        }
+                        Ok(())
+                    },
+                    log,
+                )?;
                 Ok(link.metadata.len())
             }
+            #[cfg(feature = "reflink")]
             FsCommand::RefLink { target, link } => {
                 crate::reflink::reflink(target, link, log)?;
                 Ok(link.metadata.len())
             }
+            #[cfg(not(feature = "reflink"))]
+            FsCommand::RefLink { link, .. } => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "Failed to deduplicate {}: fclones was built without the reflink feature",
+                    link
+                ),
+            )),
+            FsCommand::SparseRefLink { target, link } => {
+                crate::extent::dedupe_extents(&target.path, &link.path)
+            }
             FsCommand::Move {
                 source,
                 target,
@@ -308,6 +504,22 @@ impl FsCommand {
                 Self::move_copy(&source.path, target)?;
                 Ok(len)
             }
+            FsCommand::Skip { .. } => Ok(FileLen(0)),
+        }
+    }
+
+    /// Returns the result of running this command, given the amount of reclaimed space
+    fn dedupe_result(&self, reclaimed_space: FileLen) -> DedupeResult {
+        match self {
+            FsCommand::Skip { .. } => DedupeResult {
+                protected_count: 1,
+                ..Default::default()
+            },
+            _ => DedupeResult {
+                processed_count: 1,
+                reclaimed_space,
+                protected_count: 0,
+            },
         }
     }
 
@@ -315,10 +527,27 @@ impl FsCommand {
     pub fn space_to_reclaim(&self) -> FileLen {
         match self {
             FsCommand::Remove { file, .. }
+            | FsCommand::Trash { file, .. }
             | FsCommand::SoftLink { link: file, .. }
             | FsCommand::HardLink { link: file, .. }
             | FsCommand::RefLink { link: file, .. }
             | FsCommand::Move { source: file, .. } => file.metadata.len(),
+            FsCommand::SparseRefLink { link, .. } => crate::extent::allocated_len(&link.metadata),
+            FsCommand::Skip { .. } => FileLen(0),
+        }
+    }
+
+    /// Returns the path of the file this command removes from its directory, if any.
+    /// Files replaced by links are not removed.
+    pub fn removed_file(&self) -> Option<&Path> {
+        match self {
+            FsCommand::Remove { file } | FsCommand::Trash { file } => Some(&file.path),
+            FsCommand::Move { source, .. } => Some(&source.path),
+            FsCommand::SoftLink { .. }
+            | FsCommand::HardLink { .. }
+            | FsCommand::RefLink { .. }
+            | FsCommand::SparseRefLink { .. }
+            | FsCommand::Skip { .. } => None,
         }
     }
 
@@ -331,6 +560,9 @@ impl FsCommand {
                 let path = file.path.quote();
                 result.push(format!("rm {}", path));
             }
+            FsCommand::Trash { file, .. } => {
+                result.push(format!("gio trash {}", file.path.quote()));
+            }
             FsCommand::SoftLink { target, link, .. } => {
                 let tmp = Self::temp_file(&link.path);
                 let target = target.path.quote();
@@ -356,6 +588,11 @@ impl FsCommand {
                 result.push(format!("cp --reflink=always {} {}", target, link));
                 result.push(format!("rm {}", tmp.quote()));
             }
+            FsCommand::SparseRefLink { target, link } => {
+                let target = target.path.quote();
+                let link = link.path.quote();
+                result.push(format!("duperemove -d {} {}", target, link));
+            }
             FsCommand::Move {
                 source,
                 target,
@@ -370,6 +607,9 @@ impl FsCommand {
                     result.push(format!("rm {}", &source));
                 }
             }
+            FsCommand::Skip { file } => {
+                result.push(format!("# skip protected file {}", file.path.quote()));
+            }
         }
         result
     }
@@ -382,6 +622,9 @@ impl FsCommand {
                 let path = file.path.quote();
                 result.push(format!("del {}", path));
             }
+            FsCommand::Trash { file, .. } => {
+                result.push(format!(":: move to recycle bin {}", file.path.quote()));
+            }
             FsCommand::SoftLink { target, link, .. } => {
                 let tmp = Self::temp_file(&link.path);
                 let target = target.path.quote();
@@ -398,7 +641,7 @@ impl FsCommand {
                 result.push(format!("mklink /H {} {}", target, link));
                 result.push(format!("del {}", tmp.quote()));
             }
-            FsCommand::RefLink { target, link, .. } => {
+            FsCommand::RefLink { target, link, .. } | FsCommand::SparseRefLink { target, link } => {
                 result.push(format!(":: deduplicate {} {}", link, target));
             }
             FsCommand::Move {
@@ -415,6 +658,9 @@ impl FsCommand {
                     result.push(format!("del {}", &source));
                 }
             }
+            FsCommand::Skip { file } => {
+                result.push(format!(":: skip protected file {}", file.path.quote()));
+            }
         }
         result
     }
@@ -425,6 +671,8 @@ impl FsCommand {
 pub struct DedupeResult {
     pub processed_count: u64,
     pub reclaimed_space: FileLen,
+    /// Number of redundant files left intact because they were protected from modification
+    pub protected_count: u64,
 }
 
 impl Add<DedupeResult> for DedupeResult {
@@ -434,6 +682,7 @@ impl Add<DedupeResult> for DedupeResult {
         DedupeResult {
             processed_count: self.processed_count + rhs.processed_count,
             reclaimed_space: self.reclaimed_space + rhs.reclaimed_space,
+            protected_count: self.protected_count + rhs.protected_count,
         }
     }
 }
@@ -442,6 +691,7 @@ impl AddAssign for DedupeResult {
     fn add_assign(&mut self, rhs: Self) {
         self.processed_count += rhs.processed_count;
         self.reclaimed_space += rhs.reclaimed_space;
+        self.protected_count += rhs.protected_count;
     }
 }
 
@@ -463,8 +713,8 @@ fn was_modified(files: &[PathAndMetadata], after: DateTime<FixedOffset>, log: &L
                     log.warn(format!(
                         "File {} was updated after {} (at {})",
                         p.display(),
-                        after.format(TIMESTAMP_FMT),
-                        file_timestamp.format(TIMESTAMP_FMT)
+                        format_timestamp(&after, log.strict_iso_8601),
+                        format_timestamp(&file_timestamp, log.strict_iso_8601)
                     ));
                     result = true;
                 }
@@ -483,7 +733,12 @@ fn was_modified(files: &[PathAndMetadata], after: DateTime<FixedOffset>, log: &L
 }
 
 /// Returns true if given path matches any of the `keep` patterns
+/// or is located in any of the baseline directories
 fn should_keep(path: &Path, config: &DedupeConfig) -> bool {
+    if config.baseline_paths.iter().any(|b| b.is_prefix_of(path)) {
+        return true;
+    }
+
     let matches_any_name = config
         .keep_name_patterns
         .iter()
@@ -525,17 +780,21 @@ fn may_drop(path: &Path, config: &DedupeConfig) -> bool {
         || matches_any_path()
 }
 
+/// Returns the time elapsed since the last modification of the file,
+/// or `None` if the modification time can't be read.
+/// Modification times later than `now` give zero age, so such files count as the newest.
+fn modification_age(file: &PathAndMetadata, now: SystemTime) -> Option<Duration> {
+    let modified = file.metadata.modified().ok()?;
+    Some(now.duration_since(modified).unwrap_or(Duration::ZERO))
+}
+
 impl FileSubGroup<PathAndMetadata> {
     /// Returns the time of the earliest creation of a file in the subgroup
     pub fn created(&self) -> Result<SystemTime, Error> {
         Ok(min_result(self.files.iter().map(|f| {
-            f.metadata.created().map_err(|e| {
-                format!(
-                    "Failed to read creation time of file {}: {}",
-                    f.path.display(),
-                    e
-                )
-            })
+            f.metadata
+                .created()
+                .map_err(|e| Error::io("Failed to read creation time of file", Some(&f.path), e))
         }))?
         .unwrap())
     }
@@ -544,10 +803,20 @@ impl FileSubGroup<PathAndMetadata> {
     pub fn modified(&self) -> Result<SystemTime, Error> {
         Ok(max_result(self.files.iter().map(|f| {
             f.metadata.modified().map_err(|e| {
-                format!(
-                    "Failed to read modification time of file {}: {}",
-                    f.path.display(),
-                    e
+                Error::io("Failed to read modification time of file", Some(&f.path), e)
+            })
+        }))?
+        .unwrap())
+    }
+
+    /// Returns the highest number of hard links of a file in the subgroup
+    pub fn hard_link_count(&self) -> Result<u64, Error> {
+        Ok(max_result(self.files.iter().map(|f| {
+            f.metadata.hard_link_count().map_err(|e| {
+                Error::io(
+                    "Failed to read the number of hard links of file",
+                    Some(&f.path),
+                    e,
                 )
             })
         }))?
@@ -557,13 +826,9 @@ impl FileSubGroup<PathAndMetadata> {
     /// Returns the time of the latest access of a file in the subgroup
     pub fn accessed(&self) -> Result<SystemTime, Error> {
         Ok(max_result(self.files.iter().map(|f| {
-            f.metadata.accessed().map_err(|e| {
-                format!(
-                    "Failed to read access time of file {}: {}",
-                    f.path.display(),
-                    e
-                )
-            })
+            f.metadata
+                .accessed()
+                .map_err(|e| Error::io("Failed to read access time of file", Some(&f.path), e))
         }))?
         .unwrap())
     }
@@ -578,6 +843,23 @@ impl FileSubGroup<PathAndMetadata> {
         self.files.iter().all(|f| may_drop(&f.path, config))
     }
 
+    /// Returns true if any of the files in the subgroup was last modified more than `age`
+    /// before `now`. Files with unreadable modification time are assumed to be old.
+    pub fn is_older_than(&self, age: Duration, now: SystemTime) -> bool {
+        self.files.iter().any(|f| match modification_age(f, now) {
+            Some(a) => a > age,
+            None => true,
+        })
+    }
+
+    /// Returns true if all files in the subgroup were last modified less than `age`
+    /// before `now`. Files with unreadable modification time are assumed to be old.
+    pub fn is_newer_than(&self, age: Duration, now: SystemTime) -> bool {
+        self.files
+            .iter()
+            .all(|f| modification_age(f, now).is_some_and(|a| a < age))
+    }
+
     /// Returns the number of components of the least nested path
     pub fn min_nesting(&self) -> usize {
         self.files
@@ -620,15 +902,163 @@ fn sort_by_priority(
             files.sort_by_key(|m| Reverse(m.min_nesting()));
             vec![]
         }
+        // Among the files with the same number of links, the least recently modified ones
+        // are retained:
+        Priority::MostHardLinked => try_sort_by_key(files, |m| {
+            Ok::<_, Error>((m.hard_link_count()?, m.modified()?))
+        }),
+        Priority::LeastHardLinked => try_sort_by_key(files, |m| {
+            Ok::<_, Error>((Reverse(m.hard_link_count()?), m.modified()?))
+        }),
+    }
+}
+
+/// Sorts files so that the files located under the prefixes listed earlier come first,
+/// and the files not located under any of the prefixes come last.
+/// A subgroup is ranked by its best placed file.
+fn sort_by_path_prefix(files: &mut [FileSubGroup<PathAndMetadata>], prefixes: &[Path]) {
+    if prefixes.is_empty() {
+        return;
+    }
+    files.sort_by_key(|g| {
+        g.files
+            .iter()
+            .filter_map(|f| prefixes.iter().position(|p| p.is_prefix_of(&f.path)))
+            .min()
+            .unwrap_or(prefixes.len())
+    });
+}
+
+/// Result of choosing which files of a group to keep and which to drop.
+///
+/// Files in `to_drop` are going to be removed, moved or replaced by links
+/// pointing to the first file in `to_keep`.
+#[derive(Debug, Default)]
+pub struct Selection {
+    /// Files that must be left intact
+    pub to_keep: Vec<PathAndMetadata>,
+    /// Redundant files that can be removed or replaced by links
+    pub to_drop: Vec<PathAndMetadata>,
+}
+
+/// Decides which files of a group of identical files should be retained.
+///
+/// Implement this trait to plug a custom retention policy into [`dedupe_with_selector`].
+/// The selector is given only the files that passed the safety checks,
+/// i.e. regular files whose metadata could be read and whose length matches the group.
+/// Every file of the group should be placed either in `to_keep` or in `to_drop`.
+/// If a selector leaves no file to keep while dropping some, or selects a file that is not
+/// a member of the group or selects a file more than once, the group is skipped.
+///
+/// # Example
+/// ```
+/// use fclones::{Error, FileGroup, PathAndMetadata, ReplicaSelector, Selection};
+///
+/// /// Keeps the file with the shortest path and drops all other files.
+/// struct ShortestPath;
+///
+/// impl ReplicaSelector for ShortestPath {
+///     fn select(&self, group: FileGroup<PathAndMetadata>) -> Result<Selection, Error> {
+///         let mut files = group.files;
+///         files.sort_by_key(|f| f.path.to_escaped_string().len());
+///         let to_drop = files.split_off(1.min(files.len()));
+///         Ok(Selection { to_keep: files, to_drop })
+///     }
+/// }
+/// ```
+pub trait ReplicaSelector: Send + Sync {
+    /// Splits the files of the group into files to keep and files to drop.
+    fn select(&self, group: FileGroup<PathAndMetadata>) -> Result<Selection, Error>;
+}
+
+/// The default [`ReplicaSelector`] driven by the [`DedupeConfig`].
+///
+/// Orders files by the configured priorities, retains files matching the `keep` patterns
+/// or not matching the `drop` patterns and keeps at least `rf_over` replicas.
+///
+/// The rules are applied in the following order:
+/// 1. files in the baseline directories are always kept,
+/// 2. files matching `--keep-name`, `--keep-path` or `--keep-older-than` are kept,
+/// 3. files not matching `--name`, `--path` or `--drop-newer-than` are kept,
+/// 4. of the remaining files, the ones preferred by `--prefer-path-prefix` and then by
+///    `--priority` are kept until at least `rf_over` replicas are retained.
+pub struct PrioritySelector<'a> {
+    config: &'a DedupeConfig,
+    /// The reference time for the age of files
+    now: SystemTime,
+}
+
+impl<'a> PrioritySelector<'a> {
+    pub fn new(config: &'a DedupeConfig) -> PrioritySelector<'a> {
+        PrioritySelector {
+            config,
+            now: SystemTime::now(),
+        }
+    }
+
+    /// Returns true if any of the files in the subgroup must be kept
+    fn should_keep(&self, files: &FileSubGroup<PathAndMetadata>) -> bool {
+        files.should_keep(self.config)
+            || self
+                .config
+                .keep_older_than
+                .is_some_and(|age| files.is_older_than(age, self.now))
+    }
+
+    /// Returns true if all files in the subgroup can be dropped
+    fn may_drop(&self, files: &FileSubGroup<PathAndMetadata>) -> bool {
+        files.may_drop(self.config)
+            && self
+                .config
+                .drop_newer_than
+                .is_none_or(|age| files.is_newer_than(age, self.now))
     }
 }
 
-struct PartitionedFileGroup {
-    to_keep: Vec<PathAndMetadata>,
-    to_drop: Vec<PathAndMetadata>,
+impl ReplicaSelector for PrioritySelector<'_> {
+    fn select(&self, group: FileGroup<PathAndMetadata>) -> Result<Selection, Error> {
+        let config = self.config;
+        let mut file_sub_groups = FileSubGroup::group(group.files, &config.isolated_roots);
+
+        // Sort files to remove in user selected order.
+        // The priorities at the beginning of the argument list have precedence over
+        // the priorities given at the end of the argument list, therefore we're applying
+        // them in reversed order.
+        let mut sort_errors = Vec::new();
+        for priority in config.priority.iter().rev() {
+            sort_errors.extend(sort_by_priority(&mut file_sub_groups, priority));
+        }
+        // Sorting is stable, so the prefixes sorted last take precedence over the priorities:
+        sort_by_path_prefix(&mut file_sub_groups, &config.preferred_path_prefixes);
+
+        if !sort_errors.is_empty() {
+            return Err(Error::failed(format!(
+                "Metadata of some files could not be read: {}",
+                sort_errors.into_iter().map(|e| e.message).join("; ")
+            )));
+        }
+
+        // Split the set of file subgroups into two sets - a set that we want to keep intact and a set
+        // that we can remove or replace with links:
+        let (mut to_retain, mut to_drop): (Vec<_>, Vec<_>) = file_sub_groups
+            .into_iter()
+            .partition(|m| self.should_keep(m) || !self.may_drop(m));
+
+        // If the set to retain is smaller than the number of files we must keep (rf), then
+        // move some higher priority files from `to_drop` and append them to `to_retain`.
+        let n = max(1, config.rf_over.unwrap_or(1));
+        let missing_count = min(to_drop.len(), n.saturating_sub(to_retain.len()));
+        to_retain.extend(to_drop.drain(0..missing_count));
+
+        assert!(to_retain.len() >= n || to_drop.is_empty());
+        Ok(Selection {
+            to_keep: to_retain.into_iter().flat_map(|g| g.files).collect(),
+            to_drop: to_drop.into_iter().flat_map(|g| g.files).collect(),
+        })
+    }
 }
 
-impl PartitionedFileGroup {
+impl Selection {
     /// Returns the destination path where the file should be moved when the
     /// dedupe mode was selected to move
     fn move_target(target_dir: &Arc<Path>, source_path: &Path) -> Path {
@@ -653,7 +1083,14 @@ impl PartitionedFileGroup {
     }
 
     /// Returns a list of commands that would remove redundant files in this group when executed.
-    fn dedupe_script(mut self, strategy: &DedupeOp, devices: &DiskDevices) -> Vec<FsCommand> {
+    /// Redundant files protected from modification are skipped,
+    /// unless `include_protected` is set.
+    fn dedupe_script(
+        mut self,
+        strategy: &DedupeOp,
+        include_protected: bool,
+        devices: &DiskDevices,
+    ) -> Vec<FsCommand> {
         if self.to_drop.is_empty() {
             return vec![];
         }
@@ -664,6 +1101,10 @@ impl PartitionedFileGroup {
         let mut commands = Vec::new();
         let retained_file = Arc::new(self.to_keep.swap_remove(0));
         for dropped_file in self.to_drop {
+            if !include_protected && dropped_file.is_protected() {
+                commands.push(FsCommand::Skip { file: dropped_file });
+                continue;
+            }
             let devices_differ =
                 retained_file.metadata.device_id().ok() != dropped_file.metadata.device_id().ok();
             match strategy {
@@ -672,19 +1113,29 @@ impl PartitionedFileGroup {
                     link: dropped_file,
                 }),
                 // hard links are not supported between files on different file systems
-                DedupeOp::HardLink if devices_differ => commands.push(FsCommand::SoftLink {
+                DedupeOp::HardLink { .. } if devices_differ => commands.push(FsCommand::SoftLink {
                     target: retained_file.clone(),
                     link: dropped_file,
                 }),
-                DedupeOp::HardLink => commands.push(FsCommand::HardLink {
+                DedupeOp::HardLink { verify, .. } => commands.push(FsCommand::HardLink {
                     target: retained_file.clone(),
                     link: dropped_file,
+                    verify: *verify,
                 }),
                 DedupeOp::RefLink => commands.push(FsCommand::RefLink {
                     target: retained_file.clone(),
                     link: dropped_file,
                 }),
+                // only the extents of sparse files are deduplicated
+                DedupeOp::SparseRefLink
+                    if !is_sparse(&retained_file.metadata)
+                        || !is_sparse(&dropped_file.metadata) => {}
+                DedupeOp::SparseRefLink => commands.push(FsCommand::SparseRefLink {
+                    target: retained_file.clone(),
+                    link: dropped_file,
+                }),
                 DedupeOp::Remove => commands.push(FsCommand::Remove { file: dropped_file }),
+                DedupeOp::Trash => commands.push(FsCommand::Trash { file: dropped_file }),
                 DedupeOp::Move(target_dir) => {
                     let source = dropped_file;
                     let source_path = &source.path;
@@ -726,16 +1177,29 @@ fn files_metadata(group: FileGroup<Path>, log: &Log) -> Option<Vec<PathAndMetada
 }
 
 /// Partitions a group of files into files to keep and files that can be safely dropped
-/// (or linked).
-fn partition(
+/// (or linked), using the default [`PrioritySelector`].
+#[cfg(test)]
+fn partition(group: FileGroup<Path>, config: &DedupeConfig, log: &Log) -> Result<Selection, Error> {
+    partition_with(group, config, &PrioritySelector::new(config), true, log)
+}
+
+/// Partitions a group of files into files to keep and files that can be safely dropped
+/// (or linked). Files that fail the safety checks are excluded, and the remaining
+/// ones are split by the given `selector`.
+///
+/// If `check_writable` is set and `include_protected` is not set in the `config`,
+/// the group is rejected when none of the files to drop can be replaced.
+fn partition_with(
     group: FileGroup<Path>,
     config: &DedupeConfig,
+    selector: &dyn ReplicaSelector,
+    check_writable: bool,
     log: &Log,
-) -> Result<PartitionedFileGroup, Error> {
+) -> Result<Selection, Error> {
     let file_len = group.file_len;
     let file_hash = group.file_hash;
     let error = |msg: &str| {
-        Err(Error::from(format!(
+        Err(Error::failed(format!(
             "Could not determine files to drop in group with hash {} and len {}: {}",
             file_hash, file_len.0, msg
         )))
@@ -758,6 +1222,20 @@ fn partition(
         is_file
     });
 
+    // Files in read-only snapshots share the data with the files they were taken from,
+    // so dropping them wouldn't reclaim any space; also they can't be modified anyway.
+    // They are also not good candidates to keep, because snapshots are often deleted.
+    files.retain(|m| {
+        let in_snapshot = is_in_read_only_subvolume(&m.path);
+        if in_snapshot {
+            log.debug(format!(
+                "Skipping file {}: Located in a read-only btrfs snapshot",
+                m.path.display()
+            ));
+        }
+        !in_snapshot
+    });
+
     // If file has a different length, then we really know it has been modified.
     // Therefore, it does not belong to the group and we can safely skip it.
     files.retain(|m| {
@@ -781,41 +1259,41 @@ fn partition(
         }
     }
 
-    let mut file_sub_groups = FileSubGroup::group(files, &config.isolated_roots);
-
-    // Sort files to remove in user selected order.
-    // The priorities at the beginning of the argument list have precedence over
-    // the priorities given at the end of the argument list, therefore we're applying
-    // them in reversed order.
-    let mut sort_errors = Vec::new();
-    for priority in config.priority.iter().rev() {
-        sort_errors.extend(sort_by_priority(&mut file_sub_groups, priority));
-    }
-
-    if !sort_errors.is_empty() {
-        for e in sort_errors {
-            log.warn(e);
+    // A custom selector could return files that didn't pass the checks above
+    let mut members: HashSet<Path> = files.iter().map(|m| m.path.clone()).collect();
+    let group = FileGroup {
+        file_len,
+        file_hash,
+        files,
+    };
+    let selection = match selector.select(group) {
+        Ok(selection) => selection,
+        Err(e) => return error(&e.message),
+    };
+    for m in selection.to_keep.iter().chain(selection.to_drop.iter()) {
+        if !members.remove(&m.path) {
+            return error(&format!(
+                "File {} was selected more than once or is not a member of the group",
+                m.path.display()
+            ));
         }
-        return error("Metadata of some files could not be read.");
     }
-
-    // Split the set of file subgroups into two sets - a set that we want to keep intact and a set
-    // that we can remove or replace with links:
-    let (mut to_retain, mut to_drop): (Vec<_>, Vec<_>) = file_sub_groups
-        .into_iter()
-        .partition(|m| m.should_keep(config) || !m.may_drop(config));
-
-    // If the set to retain is smaller than the number of files we must keep (rf), then
-    // move some higher priority files from `to_drop` and append them to `to_retain`.
-    let n = max(1, config.rf_over.unwrap_or(1));
-    let missing_count = min(to_drop.len(), n.saturating_sub(to_retain.len()));
-    to_retain.extend(to_drop.drain(0..missing_count));
-
-    assert!(to_retain.len() >= n || to_drop.is_empty());
-    Ok(PartitionedFileGroup {
-        to_keep: to_retain.into_iter().flat_map(|g| g.files).collect(),
-        to_drop: to_drop.into_iter().flat_map(|g| g.files).collect(),
-    })
+    if selection.to_keep.is_empty() && !selection.to_drop.is_empty() {
+        return error("No files would be left after deduplicating");
+    }
+    let to_drop: FileGroup<&PathAndMetadata> = FileGroup {
+        file_len,
+        file_hash,
+        files: selection.to_drop.iter().collect(),
+    };
+    if check_writable
+        && !config.include_protected
+        && !to_drop.files.is_empty()
+        && !to_drop.has_writable_member()
+    {
+        return error("None of the redundant files is writable");
+    }
+    Ok(selection)
 }
 
 /// Generates a list of commands that will remove the redundant files in the groups provided
@@ -827,10 +1305,14 @@ fn partition(
 /// It rejects a group of files if:
 /// - metadata of any files in the group cannot be read,
 /// - any file in the group was modified after the `modified_before` configuration property
+/// - none of the files selected for moving or replacing with links can be replaced,
+///   unless `include_protected` is set
 ///
 /// Additionally it will never emit commands to remove a file which:
 /// - has length that does not match the file length recorded in the group metadata
+/// - is located in a read-only btrfs subvolume, e.g. a snapshot
 /// - was matched by any of the `retain_path` or `retain_name` patterns
+/// - is located in any of the `baseline_paths`
 /// - was not matched by all `drop_path` and `drop_name` patterns
 ///
 /// # Parameters
@@ -844,19 +1326,75 @@ pub fn dedupe<'a, I>(
     config: &'a DedupeConfig,
     log: &'a Log,
 ) -> impl ParallelIterator<Item = FsCommand> + 'a
+where
+    I: IntoParallelIterator<Item = FileGroup<Path>> + 'a,
+{
+    let selector = Box::new(PrioritySelector::new(config));
+    dedupe_with_selector(groups, op, config, selector, log)
+}
+
+/// Like [`dedupe`], but lets the caller decide which files to keep with
+/// a custom [`ReplicaSelector`].
+///
+/// The safety checks described in [`dedupe`] are still performed before
+/// the files are passed to the selector. The keep and drop patterns, priorities
+/// and `rf_over` settings of the `config` are ignored - it is the job of the selector
+/// to apply them if needed.
+pub fn dedupe_with_selector<'a, I>(
+    groups: I,
+    op: DedupeOp,
+    config: &'a DedupeConfig,
+    selector: Box<dyn ReplicaSelector + 'a>,
+    log: &'a Log,
+) -> impl ParallelIterator<Item = FsCommand> + 'a
 where
     I: IntoParallelIterator<Item = FileGroup<Path>> + 'a,
 {
     let devices = DiskDevices::new(&HashMap::new());
-    groups
-        .into_par_iter()
-        .flat_map(move |group| match partition(group, config, log) {
-            Ok(group) => group.dedupe_script(&op, &devices),
+    // Removing a file fails cleanly without modifying anything, so there is no need to check
+    // permissions in advance:
+    let check_writable = !matches!(op, DedupeOp::Remove | DedupeOp::Trash);
+    groups.into_par_iter().flat_map(move |group| {
+        match partition_with(group, config, selector.as_ref(), check_writable, log) {
+            Ok(group) => group.dedupe_script(&op, config.include_protected, &devices),
             Err(e) => {
                 log.warn(e);
                 Vec::new()
             }
-        })
+        }
+    })
+}
+
+/// Selects the file to keep and the redundant files in each group, performing the same
+/// safety checks as [`dedupe`], for running a user command on them.
+/// Groups failing the checks are skipped with a warning.
+/// Groups without redundant files are skipped.
+pub fn select_for_exec<'a, I>(
+    groups: I,
+    config: &'a DedupeConfig,
+    log: &'a Log,
+) -> impl ParallelIterator<Item = ExecGroup> + 'a
+where
+    I: IntoParallelIterator<Item = FileGroup<Path>> + 'a,
+{
+    let selector = PrioritySelector::new(config);
+    groups.into_par_iter().filter_map(move |group| {
+        let file_len = group.file_len;
+        let file_hash = group.file_hash;
+        match partition_with(group, config, &selector, true, log) {
+            Ok(selection) if !selection.to_drop.is_empty() => Some(ExecGroup {
+                file_len,
+                file_hash,
+                keep: selection.to_keep.into_iter().next().unwrap().path,
+                dupes: selection.to_drop.into_iter().map(|f| f.path).collect(),
+            }),
+            Ok(_) => None,
+            Err(e) => {
+                log.warn(e);
+                None
+            }
+        }
+    })
 }
 
 /// Runs a deduplication script generated by [`dedupe`].
@@ -870,20 +1408,45 @@ where
 pub fn run_script(script: impl IntoParallelIterator<Item = FsCommand>, log: &Log) -> DedupeResult {
     script
         .into_par_iter()
-        .map(|cmd| cmd.execute(log))
+        .map(|cmd| cmd.execute(log).map(|len| cmd.dedupe_result(len)))
         .inspect(|res| {
             if let Err(e) = res {
                 log.warn(e);
             }
         })
         .filter_map(|res| res.ok())
-        .map(|len| DedupeResult {
-            processed_count: 1,
-            reclaimed_space: len,
-        })
         .reduce(DedupeResult::default, |a, b| a + b)
 }
 
+/// Checks that the file systems targeted by the hard link commands of the script
+/// create real hard links, i.e. links sharing the inode with their targets.
+///
+/// Some file systems, e.g. CIFS or FAT32 mounted through some drivers, report success
+/// of `link(2)` but create an independent copy of the file instead.
+/// The check runs once per device, in the directory of the first link on that device.
+/// Returns an error for the first device that fails the check.
+pub fn check_hard_links(script: &[FsCommand]) -> Result<(), Error> {
+    let mut checked_devices = HashSet::new();
+    for cmd in script {
+        if let FsCommand::HardLink { link, .. } = cmd {
+            if !checked_devices.insert(link.metadata.device_id().ok()) {
+                continue;
+            }
+            let shares_inode = FsCommand::check_hard_link_semantics(&link.path)
+                .map_err(|e| Error::io("Hard link check failed", None, e))?;
+            if !shares_inode {
+                return Err(Error::unsupported(format!(
+                    "The file system holding {} doesn't support POSIX hard links: \
+                    a test link was created, but it doesn't share the inode with its target. \
+                    No files were linked.",
+                    link.path.display()
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
 /// Prints a script generated by [`dedupe`] to stdout.
 ///
 /// Does not perform any filesystem changes.
@@ -905,10 +1468,7 @@ pub fn log_script(
                     return None;
                 }
             }
-            Some(DedupeResult {
-                processed_count: 1,
-                reclaimed_space: cmd.space_to_reclaim(),
-            })
+            Some(cmd.dedupe_result(cmd.space_to_reclaim()))
         })
         .while_some()
         .reduce(DedupeResult::default, |a, b| a + b);
@@ -1057,6 +1617,7 @@ mod test {
             let cmd = FsCommand::HardLink {
                 target: Arc::new(file_1),
                 link: file_2,
+                verify: false,
             };
             cmd.execute(&log).unwrap();
 
@@ -1066,6 +1627,99 @@ mod test {
         })
     }
 
+    #[test]
+    fn test_verified_hard_link_command_replaces_file_with_a_link() {
+        with_dir("dedupe/verified_hard_link_cmd", |root| {
+            let file_path_1 = root.join("file_1");
+            let file_path_2 = root.join("file_2");
+
+            write_file(&file_path_1, "foo");
+            write_file(&file_path_2, "foo");
+
+            let file_1 = PathAndMetadata::new(Path::from(&file_path_1)).unwrap();
+            let file_2 = PathAndMetadata::new(Path::from(&file_path_2)).unwrap();
+            let cmd = FsCommand::HardLink {
+                target: Arc::new(file_1),
+                link: file_2,
+                verify: true,
+            };
+            cmd.execute(&Log::new()).unwrap();
+
+            let metadata_1 = FileMetadata::new(&Path::from(&file_path_1)).unwrap();
+            let metadata_2 = FileMetadata::new(&Path::from(&file_path_2)).unwrap();
+            assert_eq!(
+                metadata_1.inode_id().unwrap(),
+                metadata_2.inode_id().unwrap()
+            );
+            assert_eq!(read_file(&file_path_2), "foo");
+        })
+    }
+
+    #[test]
+    fn test_hard_link_check_passes_and_leaves_no_files() {
+        with_dir("dedupe/hard_link_check", |root| {
+            let file_path_1 = root.join("file_1");
+            let file_path_2 = root.join("file_2");
+            write_file(&file_path_1, "foo");
+            write_file(&file_path_2, "foo");
+
+            let file_1 = PathAndMetadata::new(Path::from(&file_path_1)).unwrap();
+            let file_2 = PathAndMetadata::new(Path::from(&file_path_2)).unwrap();
+            let script = vec![FsCommand::HardLink {
+                target: Arc::new(file_1),
+                link: file_2,
+                verify: false,
+            }];
+            check_hard_links(&script).unwrap();
+
+            assert_eq!(root.read_dir().unwrap().count(), 2);
+            assert_eq!(read_file(&file_path_2), "foo");
+        })
+    }
+
+    #[test]
+    fn test_verify_link_removes_link_with_different_contents() {
+        with_dir("dedupe/verify_link", |root| {
+            let file_path_1 = root.join("file_1");
+            let file_path_2 = root.join("file_2");
+            write_file(&file_path_1, "foo");
+            write_file(&file_path_2, "bar");
+
+            let mut log = Log::new();
+            log.no_progress = true;
+            let result =
+                FsCommand::verify_link(&Path::from(&file_path_1), &Path::from(&file_path_2), &log);
+            assert!(result.is_err());
+            assert!(file_path_1.exists());
+            assert!(!file_path_2.exists());
+        })
+    }
+
+    #[test]
+    fn test_regular_file_is_not_protected() {
+        with_dir("dedupe/not_protected", |root| {
+            let file_path = root.join("file");
+            create_file(&file_path);
+            let file = PathAndMetadata::new(Path::from(&file_path)).unwrap();
+            assert!(!file.is_protected());
+        })
+    }
+
+    #[test]
+    fn test_skip_command_leaves_file_intact() {
+        with_dir("dedupe/skip", |root| {
+            let file_path = root.join("file");
+            write_file(&file_path, "foo");
+            let file = PathAndMetadata::new(Path::from(&file_path)).unwrap();
+            let log = Log::new();
+            let result = run_script(vec![FsCommand::Skip { file }], &log);
+            assert_eq!(result.processed_count, 0);
+            assert_eq!(result.protected_count, 1);
+            assert_eq!(result.reclaimed_space, FileLen(0));
+            assert_eq!(read_file(&file_path), "foo");
+        })
+    }
+
     /// Creates 3 empty files with different creation time and returns a FileGroup describing them
     fn make_group(root: &PathBuf) -> FileGroup<Path> {
         let file_1 = root.join("file_1");
@@ -1133,10 +1787,75 @@ mod test {
         })
     }
 
+    #[test]
+    #[cfg(unix)]
+    fn test_partition_bails_out_if_no_redundant_file_is_writable() {
+        with_dir("dedupe/partition/read_only", |root| {
+            let group = make_group(root);
+            let set_readonly = |path: &std::path::Path, readonly: bool| {
+                let mut permissions = fs::metadata(path).unwrap().permissions();
+                permissions.set_readonly(readonly);
+                fs::set_permissions(path, permissions).unwrap();
+            };
+            // Read-only files can be replaced as long as their directory is writable:
+            for f in &group.files {
+                set_readonly(&f.to_path_buf(), true);
+            }
+            let config = DedupeConfig::default();
+            assert!(partition(group.clone(), &config, &Log::new()).is_ok());
+
+            // Permission checks don't apply to root:
+            if nix::unistd::geteuid().is_root() {
+                return;
+            }
+            set_readonly(root, true);
+            let partitioned = partition(group.clone(), &config, &Log::new());
+            let config = DedupeConfig {
+                include_protected: true,
+                ..DedupeConfig::default()
+            };
+            let partitioned_protected = partition(group, &config, &Log::new());
+            set_readonly(root, false);
+            assert!(partitioned.is_err());
+            assert!(partitioned_protected.is_ok());
+        })
+    }
+
     fn path_set(v: &Vec<PathAndMetadata>) -> HashSet<&Path> {
         v.iter().map(|f| &f.path).collect()
     }
 
+    #[test]
+    #[cfg(unix)]
+    fn test_partition_keeps_most_hard_linked_file() {
+        with_dir("dedupe/partition/hard_link_priority", |root| {
+            let group = make_group(root);
+            let config = DedupeConfig {
+                priority: vec![Priority::LeastHardLinked],
+                ..Default::default()
+            };
+
+            fs::hard_link(root.join("file_3"), root.join("link_3")).unwrap();
+            let partitioned = partition(group.clone(), &config, &Log::new()).unwrap();
+            let kept = partitioned
+                .to_keep
+                .iter()
+                .map(|f| f.path.clone())
+                .collect_vec();
+            assert_eq!(kept, [Path::from(root.join("file_3"))]);
+
+            // The same number of links, so the least recently modified file is kept:
+            fs::hard_link(root.join("file_2"), root.join("link_2")).unwrap();
+            let partitioned = partition(group, &config, &Log::new()).unwrap();
+            let kept = partitioned
+                .to_keep
+                .iter()
+                .map(|f| f.path.clone())
+                .collect_vec();
+            assert_eq!(kept, [Path::from(root.join("file_2"))]);
+        });
+    }
+
     #[test]
     fn test_partition_respects_creation_time_priority() {
         with_dir("dedupe/partition/ctime_priority", |root| {
@@ -1227,6 +1946,89 @@ mod test {
         })
     }
 
+    #[test]
+    fn test_partition_prefers_files_under_path_prefix() {
+        with_dir("dedupe/partition/prefer_path_prefix", |root| {
+            let original = root.join("original");
+            let copies = root.join("copies");
+            create_dir(&original).unwrap();
+            create_dir(&copies).unwrap();
+
+            let group1 = make_group(&copies);
+            let group2 = make_group(&original);
+            let group = FileGroup {
+                file_len: group1.file_len,
+                file_hash: group1.file_hash,
+                files: group1.files.into_iter().chain(group2.files).collect(),
+            };
+
+            let config = DedupeConfig {
+                preferred_path_prefixes: vec![Path::from(&original)],
+                priority: vec![Priority::MostRecentlyModified],
+                ..DedupeConfig::default()
+            };
+            let p = partition(group, &config, &Log::new()).unwrap();
+            assert_eq!(p.to_keep.len(), 1);
+            assert_eq!(p.to_keep[0].path, Path::from(original.join("file_1")));
+            assert_eq!(p.to_drop.len(), 5);
+        })
+    }
+
+    /// Creates three empty files modified 2 years ago, 10 days ago and 1 day in the future
+    fn make_group_of_different_age(root: &std::path::Path) -> FileGroup<Path> {
+        let now = SystemTime::now();
+        let day = time::Duration::from_secs(24 * 3600);
+        let times = [now - 730 * day, now - 10 * day, now + day];
+        let files: Vec<_> = times
+            .iter()
+            .enumerate()
+            .map(|(i, &time)| {
+                let path = root.join(format!("file_{}", i + 1));
+                create_file(&path);
+                filetime::set_file_mtime(&path, filetime::FileTime::from_system_time(time))
+                    .unwrap();
+                Path::from(&path)
+            })
+            .collect();
+        FileGroup {
+            file_len: FileLen(0),
+            file_hash: FileHash(0),
+            files,
+        }
+    }
+
+    #[test]
+    fn test_partition_respects_keep_older_than() {
+        with_dir("dedupe/partition/keep_older_than", |root| {
+            let group = make_group_of_different_age(root);
+            let config = DedupeConfig {
+                keep_older_than: Some(time::Duration::from_secs(365 * 24 * 3600)),
+                priority: vec![Priority::MostRecentlyModified],
+                ..DedupeConfig::default()
+            };
+            let p = partition(group, &config, &Log::new()).unwrap();
+            assert_eq!(p.to_keep.len(), 1);
+            assert_eq!(p.to_keep[0].path, Path::from(root.join("file_1")));
+            assert_eq!(p.to_drop.len(), 2);
+        })
+    }
+
+    #[test]
+    fn test_partition_respects_drop_newer_than() {
+        with_dir("dedupe/partition/drop_newer_than", |root| {
+            let group = make_group_of_different_age(root);
+            let config = DedupeConfig {
+                drop_newer_than: Some(time::Duration::from_secs(30 * 24 * 3600)),
+                ..DedupeConfig::default()
+            };
+            let p = partition(group, &config, &Log::new()).unwrap();
+            // The file modified in the future counts as the newest, so it can be dropped:
+            assert_eq!(p.to_keep.len(), 1);
+            assert_eq!(p.to_keep[0].path, Path::from(root.join("file_1")));
+            assert_eq!(p.to_drop.len(), 2);
+        })
+    }
+
     #[test]
     fn test_partition_respects_isolated_roots() {
         with_dir("dedupe/partition/isolated_roots", |root| {
@@ -1260,6 +2062,153 @@ mod test {
         })
     }
 
+    #[test]
+    fn test_partition_never_drops_baseline_files() {
+        with_dir("dedupe/partition/baseline", |root| {
+            let library = root.join("library");
+            let incoming = root.join("incoming");
+            create_dir(&library).unwrap();
+            create_dir(&incoming).unwrap();
+
+            let group1 = make_group(&library);
+            let group2 = make_group(&incoming);
+            let group = FileGroup {
+                file_len: group1.file_len,
+                file_hash: group1.file_hash,
+                files: group1.files.into_iter().chain(group2.files).collect(),
+            };
+
+            let config = DedupeConfig {
+                baseline_paths: vec![Path::from(&library)],
+                // Without the baseline, the newest files in incoming would be kept:
+                priority: vec![Priority::LeastRecentlyModified],
+                ..DedupeConfig::default()
+            };
+            let p = partition(group, &config, &Log::new()).unwrap();
+            assert_eq!(p.to_keep.len(), 3);
+            assert!(p
+                .to_keep
+                .iter()
+                .all(|f| f.path.to_path_buf().starts_with(&library)));
+            assert_eq!(p.to_drop.len(), 3);
+        })
+    }
+
+    /// Keeps only the files with the names ending with `_2`
+    struct KeepSecond;
+
+    impl ReplicaSelector for KeepSecond {
+        fn select(&self, group: FileGroup<PathAndMetadata>) -> Result<Selection, Error> {
+            let (to_keep, to_drop) =
+                group
+                    .files
+                    .into_iter()
+                    .partition(|f| match f.path.file_name_cstr() {
+                        Some(name) => name.to_string_lossy().ends_with("_2"),
+                        None => false,
+                    });
+            Ok(Selection { to_keep, to_drop })
+        }
+    }
+
+    /// Keeps the first file and drops the first file again
+    struct DropKept;
+
+    impl ReplicaSelector for DropKept {
+        fn select(&self, group: FileGroup<PathAndMetadata>) -> Result<Selection, Error> {
+            let first = group.files.into_iter().next().unwrap();
+            let again = PathAndMetadata::new(first.path.clone()).unwrap();
+            Ok(Selection {
+                to_keep: vec![first],
+                to_drop: vec![again],
+            })
+        }
+    }
+
+    /// Keeps the group and drops the given file from outside of the group
+    struct DropOutsider(Path);
+
+    impl ReplicaSelector for DropOutsider {
+        fn select(&self, group: FileGroup<PathAndMetadata>) -> Result<Selection, Error> {
+            Ok(Selection {
+                to_keep: group.files,
+                to_drop: vec![PathAndMetadata::new(self.0.clone()).unwrap()],
+            })
+        }
+    }
+
+    /// Drops everything
+    struct DropAll;
+
+    impl ReplicaSelector for DropAll {
+        fn select(&self, group: FileGroup<PathAndMetadata>) -> Result<Selection, Error> {
+            Ok(Selection {
+                to_keep: vec![],
+                to_drop: group.files,
+            })
+        }
+    }
+
+    #[test]
+    fn test_partition_uses_custom_selector() {
+        with_dir("dedupe/partition/custom_selector", |root| {
+            let group = make_group(root);
+            let config = DedupeConfig::default();
+            let p = partition_with(group.clone(), &config, &KeepSecond, true, &Log::new()).unwrap();
+            assert_eq!(p.to_keep.len(), 1);
+            assert_eq!(&p.to_keep[0].path, &group.files[1]);
+            assert_eq!(p.to_drop.len(), 2);
+        })
+    }
+
+    #[test]
+    fn test_partition_rejects_file_selected_twice() {
+        with_dir("dedupe/partition/drop_kept_selector", |root| {
+            let group = make_group(root);
+            let config = DedupeConfig::default();
+            let p = partition_with(group, &config, &DropKept, true, &Log::new());
+            assert!(p.is_err());
+        })
+    }
+
+    #[test]
+    fn test_partition_rejects_file_outside_of_group() {
+        with_dir("dedupe/partition/drop_outsider_selector", |root| {
+            let mut group = make_group(root);
+            let outsider = group.files.pop().unwrap();
+            let config = DedupeConfig::default();
+            let p = partition_with(group, &config, &DropOutsider(outsider), true, &Log::new());
+            assert!(p.is_err());
+        })
+    }
+
+    #[test]
+    fn test_partition_rejects_selection_without_files_to_keep() {
+        with_dir("dedupe/partition/drop_all_selector", |root| {
+            let group = make_group(root);
+            let config = DedupeConfig::default();
+            let p = partition_with(group, &config, &DropAll, true, &Log::new());
+            assert!(p.is_err());
+        })
+    }
+
+    #[test]
+    fn test_run_dedupe_script_with_custom_selector() {
+        with_dir("dedupe/partition/custom_selector_script", |root| {
+            let group = make_group(root);
+            let config = DedupeConfig::default();
+            let log = Log::new();
+            let selector = Box::new(KeepSecond);
+            let script =
+                dedupe_with_selector(vec![group], DedupeOp::Remove, &config, selector, &log);
+            let dedupe_result = run_script(script, &log);
+            assert_eq!(dedupe_result.processed_count, 2);
+            assert!(!root.join("file_1").exists());
+            assert!(root.join("file_2").exists());
+            assert!(!root.join("file_3").exists());
+        });
+    }
+
     #[test]
     fn test_run_dedupe_script() {
         with_dir("dedupe/partition/dedupe_script", |root| {
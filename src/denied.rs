@@ -0,0 +1,245 @@
+//! Collecting the paths that couldn't be accessed due to insufficient permissions.
+//!
+//! Scanning system directories as a regular user can fail on thousands of entries.
+//! Instead of logging a warning for each of them, the failures are collected and
+//! summarized per top-level directory at the end of the run.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::log::{LogLevel, LogMessage, LogSink};
+use crate::path::Path;
+
+/// Maximum number of directories listed in the summary
+const MAX_DIRS_TO_LOG: usize = 20;
+
+/// Percentage of inaccessible entries above which running with elevated privileges is suggested
+const PRIVILEGE_HINT_THRESHOLD_PERCENT: usize = 10;
+
+/// Forwards messages to another sink, except warnings about denied access to a file,
+/// which are recorded and forwarded at the debug level.
+pub(crate) struct DeniedAccess<'a> {
+    inner: &'a dyn LogSink,
+    paths: Mutex<Vec<Path>>,
+    /// Number of entries visited by the directory walk
+    visited: AtomicUsize,
+}
+
+impl<'a> DeniedAccess<'a> {
+    pub fn new(inner: &'a dyn LogSink) -> DeniedAccess<'a> {
+        DeniedAccess {
+            inner,
+            paths: Mutex::new(Vec::new()),
+            visited: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sets the number of entries visited by the directory walk,
+    /// used to decide if the number of inaccessible entries is significant
+    pub fn set_visited(&self, count: usize) {
+        self.visited.store(count, Ordering::Relaxed)
+    }
+
+    /// Returns the recorded paths, sorted and without duplicates
+    pub fn paths(&self) -> Vec<Path> {
+        let mut paths = self.paths.lock().unwrap().clone();
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+
+    /// Logs the number of inaccessible paths in each of the top-level directories
+    /// of the given roots. Paths outside of the roots are counted in their parent directories.
+    pub fn log_summary(&self, roots: &[Path]) {
+        let paths = self.paths();
+        if paths.is_empty() {
+            return;
+        }
+        let counts = count_by_top_dir(&paths, roots);
+        for (dir, count) in counts.iter().take(MAX_DIRS_TO_LOG) {
+            let dir = Path::from(dir);
+            self.inner.warn(
+                LogMessage::new(format!(
+                    "Permission denied to {} {} in {}",
+                    count,
+                    if *count == 1 { "entry" } else { "entries" },
+                    dir.display()
+                ))
+                .with_path(&dir)
+                .with_error_kind(ErrorKind::PermissionDenied)
+                .with_count(*count),
+            );
+        }
+        if counts.len() > MAX_DIRS_TO_LOG {
+            let skipped: usize = counts.iter().skip(MAX_DIRS_TO_LOG).map(|(_, c)| *c).sum();
+            self.inner.warn(
+                LogMessage::new(format!(
+                    "Permission denied to entries in {} more directories",
+                    counts.len() - MAX_DIRS_TO_LOG
+                ))
+                .with_error_kind(ErrorKind::PermissionDenied)
+                .with_count(skipped),
+            );
+        }
+        let visited = self.visited.load(Ordering::Relaxed);
+        if paths.len() * 100 > visited * PRIVILEGE_HINT_THRESHOLD_PERCENT {
+            self.inner.warn(
+                LogMessage::new(format!(
+                    "{} of {} scanned entries couldn't be accessed. \
+                    Consider running fclones with elevated privileges.",
+                    paths.len(),
+                    visited
+                ))
+                .with_error_kind(ErrorKind::PermissionDenied)
+                .with_count(paths.len()),
+            );
+        }
+    }
+
+    /// Writes the inaccessible paths to the given file, one path per line
+    pub fn write_list(&self, file: &std::path::Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(file)?);
+        for path in self.paths() {
+            writeln!(out, "{}", path.to_escaped_string())?;
+        }
+        out.flush()
+    }
+}
+
+impl LogSink for DeniedAccess<'_> {
+    fn log(&self, level: LogLevel, msg: LogMessage) {
+        match &msg.path {
+            Some(path)
+                if level == LogLevel::Warn
+                    && msg.error_kind == Some(ErrorKind::PermissionDenied) =>
+            {
+                self.paths.lock().unwrap().push(path.clone());
+                self.inner.log(LogLevel::Debug, msg)
+            }
+            _ => self.inner.log(level, msg),
+        }
+    }
+}
+
+/// Counts the paths by the directory directly below the root they were found in.
+/// Returns the directories sorted by decreasing count.
+fn count_by_top_dir(paths: &[Path], roots: &[Path]) -> Vec<(PathBuf, usize)> {
+    let mut counts: BTreeMap<PathBuf, usize> = BTreeMap::new();
+    for path in paths {
+        let dir = match roots.iter().find(|root| root.is_prefix_of(path)) {
+            Some(root) => {
+                let root = root.to_path_buf();
+                let relative = path.to_path_buf();
+                let first = relative
+                    .strip_prefix(&root)
+                    .ok()
+                    .and_then(|r| r.components().next());
+                match first {
+                    Some(first) => root.join(first),
+                    None => root,
+                }
+            }
+            None => path
+                .parent()
+                .map(|p| p.to_path_buf())
+                .unwrap_or_else(|| path.to_path_buf()),
+        };
+        *counts.entry(dir).or_default() += 1;
+    }
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by(|(d1, c1), (d2, c2)| c2.cmp(c1).then_with(|| d1.cmp(d2)));
+    counts
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::ErrorKind;
+
+    use tempfile::NamedTempFile;
+
+    use crate::log::VecSink;
+
+    use super::*;
+
+    fn denied(path: &str) -> LogMessage {
+        let e = io::Error::from(ErrorKind::PermissionDenied);
+        LogMessage::new(format!("Permission denied: {}", path))
+            .with_path(&Path::from(path))
+            .with_error(&e)
+    }
+
+    #[test]
+    fn permission_denied_warnings_are_collected() {
+        let sink = VecSink::new();
+        let access = DeniedAccess::new(&sink);
+        access.warn(denied("/var/cache/a"));
+        access.warn(LogMessage::new("other warning"));
+        access.warn(denied("/var/cache/b"));
+        assert_eq!(
+            access.paths(),
+            vec![Path::from("/var/cache/a"), Path::from("/var/cache/b")]
+        );
+        let levels: Vec<_> = sink.messages().into_iter().map(|(l, _)| l).collect();
+        assert_eq!(levels, [LogLevel::Debug, LogLevel::Warn, LogLevel::Debug]);
+    }
+
+    #[test]
+    fn summary_counts_paths_by_top_level_directory() {
+        let sink = VecSink::new();
+        let access = DeniedAccess::new(&sink);
+        access.warn(denied("/var/cache/apt/a"));
+        access.warn(denied("/var/cache/apt/b"));
+        access.warn(denied("/var/lib/c"));
+        access.warn(denied("/tmp/d"));
+        access.set_visited(100);
+        access.log_summary(&[Path::from("/var")]);
+
+        let warnings: Vec<_> = sink
+            .messages()
+            .into_iter()
+            .filter(|(l, _)| *l == LogLevel::Warn)
+            .map(|(_, m)| m)
+            .collect();
+        let messages: Vec<_> = warnings.iter().map(|m| m.text.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "Permission denied to 2 entries in /var/cache",
+                "Permission denied to 1 entry in /tmp",
+                "Permission denied to 1 entry in /var/lib",
+            ]
+        );
+        assert_eq!(warnings[0].path, Some(Path::from("/var/cache")));
+        assert_eq!(warnings[0].error_kind, Some(ErrorKind::PermissionDenied));
+        let counts: Vec<_> = warnings.iter().map(|m| m.count).collect();
+        assert_eq!(counts, [Some(2), Some(1), Some(1)]);
+    }
+
+    #[test]
+    fn summary_suggests_elevated_privileges() {
+        let sink = VecSink::new();
+        let access = DeniedAccess::new(&sink);
+        access.warn(denied("/root/a"));
+        access.set_visited(2);
+        access.log_summary(&[Path::from("/")]);
+        let last = sink.messages().pop().unwrap().1.text;
+        assert!(last.contains("elevated privileges"), "{}", last);
+    }
+
+    #[test]
+    fn write_list_of_denied_paths() {
+        let sink = VecSink::new();
+        let access = DeniedAccess::new(&sink);
+        access.warn(denied("/b"));
+        access.warn(denied("/a"));
+        let file = NamedTempFile::new().unwrap();
+        access.write_list(file.path()).unwrap();
+        let contents = std::fs::read_to_string(file.path()).unwrap();
+        assert_eq!(contents, "/a\n/b\n");
+    }
+}
@@ -2,15 +2,24 @@ use core::cmp;
 use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::ops::Index;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::{fs, io};
 
 use itertools::Itertools;
 use lazy_init::Lazy;
 use rayon::{ThreadPool, ThreadPoolBuilder};
-use sysinfo::{DiskExt, DiskType, System, SystemExt};
+use sysinfo::DiskType;
+#[cfg(not(target_os = "linux"))]
+use sysinfo::{Disk, DiskExt, System, SystemExt};
 
-use crate::config::Parallelism;
+use crate::config::{DeviceOverride, Parallelism};
 use crate::file::FileLen;
+use crate::hasher::{AdaptiveBufLen, DEFAULT_BUF_LEN};
+use crate::log::{LogMessage, LogSink};
 use crate::path::Path;
+use crate::semaphore::{Semaphore, SemaphoreGuard};
+use crate::throttle::TokenBucket;
 
 impl Parallelism {
     pub fn default_for(disk_type: DiskType) -> Parallelism {
@@ -52,6 +61,10 @@ pub struct DiskDevice {
     pub disk_type: DiskType,
     pub file_system: String,
     pub parallelism: Parallelism,
+    read_buf_len: AdaptiveBufLen,
+    throttle: Option<TokenBucket>,
+    /// Limits the number of reads from this device running at the same time
+    concurrency_limit: Option<(usize, Semaphore)>,
     seq_thread_pool: Lazy<ThreadPool>,
     rand_thread_pool: Lazy<ThreadPool>,
 }
@@ -70,6 +83,9 @@ impl DiskDevice {
             disk_type,
             file_system,
             parallelism,
+            read_buf_len: AdaptiveBufLen::new(DEFAULT_BUF_LEN, DEFAULT_BUF_LEN),
+            throttle: None,
+            concurrency_limit: None,
             seq_thread_pool: Lazy::new(),
             rand_thread_pool: Lazy::new(),
         }
@@ -92,6 +108,59 @@ impl DiskDevice {
             .get_or_create(|| Self::build_thread_pool(self.parallelism.random))
     }
 
+    /// Returns the length of the buffer for reading whole files from this device,
+    /// adapted to the throughput of the device measured so far.
+    pub fn read_buf_len(&self) -> usize {
+        self.read_buf_len.get()
+    }
+
+    /// Records that reading `len` bytes from this device took `duration`.
+    /// Adapts the length of the read buffer and logs its change at the trace level.
+    pub(crate) fn record_read(&self, len: FileLen, duration: Duration, log: &dyn LogSink) {
+        if let Some((buf_len, throughput)) = self.read_buf_len.record(len, duration) {
+            log.trace(LogMessage::new(format!(
+                "Read buffer size for device {} changed to {} (throughput: {}/s)",
+                self.name.to_string_lossy(),
+                FileLen(buf_len as u64),
+                FileLen(throughput as u64)
+            )));
+        }
+    }
+
+    /// Returns the maximum number of bytes per second read from this device,
+    /// or `None` if reading is not limited.
+    pub fn max_bandwidth(&self) -> Option<FileLen> {
+        self.throttle.as_ref().map(|t| FileLen(t.rate()))
+    }
+
+    /// Returns the token bucket limiting the rate of reading from this device.
+    pub(crate) fn throttle(&self) -> Option<&TokenBucket> {
+        self.throttle.as_ref()
+    }
+
+    /// Returns the maximum number of reads from this device running at the same time,
+    /// or `None` if it is limited only by the size of the thread pools.
+    pub fn max_concurrency(&self) -> Option<usize> {
+        self.concurrency_limit.as_ref().map(|(n, _)| *n)
+    }
+
+    /// Blocks until a read from this device can be started without exceeding the limit
+    /// of concurrent reads. The read must be finished before the returned guard is dropped.
+    /// Returns `None` immediately if the concurrency is not limited.
+    pub(crate) fn read_permit(&self) -> Option<SemaphoreGuard<'_>> {
+        self.concurrency_limit.as_ref().map(|(_, s)| s.access())
+    }
+
+    /// Returns a short human-readable name of the type of this device
+    pub fn disk_type_name(&self) -> &'static str {
+        match self.disk_type {
+            DiskType::SSD => "ssd",
+            DiskType::HDD => "hdd",
+            DiskType::Removable => "removable",
+            DiskType::Unknown(_) => "unknown",
+        }
+    }
+
     pub fn min_prefix_len(&self) -> FileLen {
         FileLen(match self.disk_type {
             DiskType::SSD => 4 * 1024,
@@ -124,6 +193,90 @@ impl DiskDevice {
     }
 }
 
+/// File system mounted at a mount point, as listed in a mount table, e.g. `/proc/mounts`
+#[derive(Debug, PartialEq, Eq)]
+struct MountEntry {
+    device: OsString,
+    mount_point: PathBuf,
+    file_system: String,
+}
+
+/// Decodes the octal escapes of the special characters, e.g. `\040` for a space,
+/// used by the mount tables.
+fn unescape_mount_field(field: &str) -> String {
+    let mut result = Vec::with_capacity(field.len());
+    let bytes = field.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let code = bytes
+            .get(i + 1..i + 4)
+            .filter(|_| bytes[i] == b'\\')
+            .and_then(|code| std::str::from_utf8(code).ok())
+            .and_then(|code| u8::from_str_radix(code, 8).ok());
+        match code {
+            Some(c) => {
+                result.push(c);
+                i += 4;
+            }
+            None => {
+                result.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&result).to_string()
+}
+
+/// Parses a mount table in the format of `/proc/mounts` or `/etc/fstab`.
+/// Skips the pseudo file systems, which don't hold regular files, e.g. `proc` or `tmpfs`,
+/// and the mount points below `/proc`, `/sys` and `/run`, except `/run/media`.
+fn parse_mount_table(content: &str) -> Vec<MountEntry> {
+    const PSEUDO_FILE_SYSTEMS: [&str; 17] = [
+        "autofs",
+        "binfmt_misc",
+        "bpf",
+        "cgroup",
+        "cgroup2",
+        "configfs",
+        "debugfs",
+        "devpts",
+        "devtmpfs",
+        "iso9660",
+        "mqueue",
+        "proc",
+        "pstore",
+        "rpc_pipefs",
+        "squashfs",
+        "sysfs",
+        "tmpfs",
+    ];
+    content
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let device = unescape_mount_field(fields.next()?);
+            let mount_point = unescape_mount_field(fields.next()?);
+            let file_system = fields.next()?.to_owned();
+            Some(MountEntry {
+                device: OsString::from(device),
+                mount_point: PathBuf::from(mount_point),
+                file_system,
+            })
+        })
+        .filter(|e| {
+            let mount_point = &e.mount_point;
+            let ignored_mount_point = mount_point.starts_with("/proc")
+                || mount_point.starts_with("/sys")
+                || (mount_point.starts_with("/run") && !mount_point.starts_with("/run/media"));
+            !ignored_mount_point
+                && !PSEUDO_FILE_SYSTEMS.contains(&e.file_system.as_str())
+                && !e.device.to_string_lossy().starts_with("sunrpc")
+        })
+        .collect()
+}
+
 /// Finds disk devices by file paths
 pub struct DiskDevices {
     devices: Vec<DiskDevice>,
@@ -227,28 +380,231 @@ impl DiskDevices {
         name.to_os_string()
     }
 
-    /// Reads the list of partitions and disks from the system and builds the `DiskDevices`
-    /// structure from that information.
-    pub fn new(pool_sizes: &HashMap<OsString, Parallelism>) -> DiskDevices {
-        let mut sys = System::new();
-        sys.refresh_disks_list();
+    /// Returns true if the volume mounted at given mount point incurs a seek penalty,
+    /// which is the case for rotational drives.
+    /// Returns `None` if the volume could not be queried.
+    #[cfg(windows)]
+    fn incurs_seek_penalty(mount_point: &std::path::Path) -> Option<bool> {
+        use std::iter::once;
+        use std::mem::size_of;
+        use std::os::windows::ffi::OsStrExt;
+        use std::ptr::null_mut;
+        use winapi::ctypes::c_void;
+        use winapi::um::fileapi::{CreateFileW, OPEN_EXISTING};
+        use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+        use winapi::um::ioapiset::DeviceIoControl;
+        use winapi::um::winnt::{FILE_SHARE_READ, FILE_SHARE_WRITE};
+
+        // From winioctl.h:
+        // #define IOCTL_STORAGE_QUERY_PROPERTY \
+        //     CTL_CODE(IOCTL_STORAGE_BASE, 0x0500, METHOD_BUFFERED, FILE_ANY_ACCESS)
+        const IOCTL_STORAGE_QUERY_PROPERTY: u32 = 0x002D_1400;
+        const STORAGE_DEVICE_SEEK_PENALTY_PROPERTY: i32 = 7;
+        const PROPERTY_STANDARD_QUERY: i32 = 0;
+
+        #[repr(C)]
+        struct StoragePropertyQuery {
+            property_id: i32,
+            query_type: i32,
+            additional_parameters: [u8; 1],
+        }
+
+        #[repr(C)]
+        #[derive(Default)]
+        struct DeviceSeekPenaltyDescriptor {
+            version: u32,
+            size: u32,
+            incurs_seek_penalty: u8,
+        }
+
+        let drive_letter = mount_point.to_str()?.split(':').next()?;
+        let volume = format!("\\\\.\\{}:", drive_letter);
+        let volume: Vec<u16> = OsStr::new(&volume).encode_wide().chain(once(0)).collect();
+        let mut query = StoragePropertyQuery {
+            property_id: STORAGE_DEVICE_SEEK_PENALTY_PROPERTY,
+            query_type: PROPERTY_STANDARD_QUERY,
+            additional_parameters: [0],
+        };
+        let mut descriptor = DeviceSeekPenaltyDescriptor::default();
+        let mut returned_len: u32 = 0;
+        unsafe {
+            let handle = CreateFileW(
+                volume.as_ptr(),
+                0,
+                FILE_SHARE_READ | FILE_SHARE_WRITE,
+                null_mut(),
+                OPEN_EXISTING,
+                0,
+                null_mut(),
+            );
+            if handle == INVALID_HANDLE_VALUE {
+                return None;
+            }
+            let result = DeviceIoControl(
+                handle,
+                IOCTL_STORAGE_QUERY_PROPERTY,
+                &mut query as *mut StoragePropertyQuery as *mut c_void,
+                size_of::<StoragePropertyQuery>() as u32,
+                &mut descriptor as *mut DeviceSeekPenaltyDescriptor as *mut c_void,
+                size_of::<DeviceSeekPenaltyDescriptor>() as u32,
+                &mut returned_len,
+                null_mut(),
+            );
+            CloseHandle(handle);
+            if result == 0 || returned_len == 0 {
+                return None;
+            }
+        }
+        Some(descriptor.incurs_seek_penalty != 0)
+    }
+
+    /// Returns the type of the disk.
+    ///
+    /// On Windows, `sysinfo` classifies disks only by their TRIM support,
+    /// so we ask the volume about the seek penalty instead and fall back to
+    /// `sysinfo` if that fails. Removable drives are always reported as removable.
+    #[cfg(windows)]
+    fn disk_type(disk: &Disk) -> DiskType {
+        match (
+            disk.get_type(),
+            Self::incurs_seek_penalty(disk.get_mount_point()),
+        ) {
+            (DiskType::Removable, _) => DiskType::Removable,
+            (_, Some(true)) => DiskType::HDD,
+            (_, Some(false)) => DiskType::SSD,
+            (disk_type, None) => disk_type,
+        }
+    }
+
+    /// Returns the type of the disk.
+    ///
+    /// On macOS, `sysinfo` guesses SSDs only by "SSD" in the model name,
+    /// so we ask `diskutil` about the solid state and removable media properties
+    /// that IOKit reports for the volume, and fall back to `sysinfo` if that fails.
+    #[cfg(target_os = "macos")]
+    fn disk_type(disk: &Disk) -> DiskType {
+        std::process::Command::new("diskutil")
+            .arg("info")
+            .arg(disk.get_mount_point())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| parse_diskutil_info(&String::from_utf8_lossy(&output.stdout)))
+            .unwrap_or_else(|| disk.get_type())
+    }
+
+    /// Returns the type of the disk as reported by `sysinfo`
+    #[cfg(not(any(windows, target_os = "linux", target_os = "macos")))]
+    fn disk_type(disk: &Disk) -> DiskType {
+        disk.get_type()
+    }
+
+    /// Returns the type of the block device with the given name, e.g. `/dev/sda1`,
+    /// by reading its rotational flag from `/sys/block`.
+    /// Partitions, device mapper volumes and `/dev/root` are resolved to their disks.
+    #[cfg(target_os = "linux")]
+    fn block_device_type(name: &OsStr) -> DiskType {
+        let name = name.to_string_lossy();
+        let real_name = fs::canonicalize(name.as_ref())
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_else(|_| name.to_string());
+        let mut block_name = real_name.trim_start_matches("/dev/");
+        if block_name.starts_with("sd") || block_name.starts_with("hd") {
+            block_name = block_name.trim_end_matches(|c: char| c.is_ascii_digit());
+        } else if block_name.starts_with("nvme") || block_name.starts_with("mmcblk") {
+            // e.g. nvme0n1p3 or mmcblk0p1, but not nvme0n1 nor mmcblk0
+            if let Some((disk, partition)) = block_name.rsplit_once('p') {
+                if !partition.is_empty() && partition.chars().all(|c| c.is_ascii_digit()) {
+                    block_name = disk;
+                }
+            }
+        }
+        let rotational = PathBuf::from("/sys/block")
+            .join(block_name)
+            .join("queue/rotational");
+        match fs::read_to_string(rotational).map(|s| s.trim().parse()) {
+            Ok(Ok(1)) => DiskType::HDD,
+            Ok(Ok(0)) => DiskType::SSD,
+            Ok(Ok(x)) => DiskType::Unknown(x),
+            _ => DiskType::Unknown(-1),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn block_device_type(_name: &OsStr) -> DiskType {
+        DiskType::Unknown(-1)
+    }
+
+    /// Returns the structure holding only the default device,
+    /// used for the files not found on any of the known devices.
+    fn with_default_device(pool_sizes: &HashMap<OsString, Parallelism>) -> DiskDevices {
         let mut result = DiskDevices {
             devices: Vec::new(),
             mount_points: Vec::new(),
         };
-
-        // Default device used when we don't find any real device
         result.add_device(
             OsString::from("default"),
             DiskType::Unknown(-1),
             String::from("unknown"),
             pool_sizes,
         );
+        result
+    }
+
+    /// Builds the `DiskDevices` structure from a mount table in the format of `/proc/mounts`.
+    /// Device types are read from `/sys/block` on Linux; on other systems they are unknown.
+    /// Useful for testing with synthetic device tables.
+    pub fn from_mount_table(path: &std::path::Path) -> io::Result<DiskDevices> {
+        Self::read_mount_table(path, &HashMap::new())
+    }
+
+    fn read_mount_table(
+        path: &std::path::Path,
+        pool_sizes: &HashMap<OsString, Parallelism>,
+    ) -> io::Result<DiskDevices> {
+        let content = fs::read_to_string(path)?;
+        let mut result = Self::with_default_device(pool_sizes);
+        for entry in parse_mount_table(&content) {
+            let device_name = Self::physical_device_name(&entry.device);
+            let disk_type = Self::block_device_type(&entry.device);
+            let index = result.add_device(device_name, disk_type, entry.file_system, pool_sizes);
+            result
+                .mount_points
+                .push((Path::from(entry.mount_point), index));
+        }
+        result.sort_mount_points();
+        Ok(result)
+    }
+
+    /// Sorts the mount points from the deepest, so the first mount point being a prefix
+    /// of a path is the mount point of the path
+    fn sort_mount_points(&mut self) {
+        self.mount_points
+            .sort_by_key(|(p, _)| cmp::Reverse(p.component_count()));
+    }
+
+    /// Reads the list of partitions and disks from the system and builds the `DiskDevices`
+    /// structure from that information.
+    ///
+    /// On Linux, the mounted file systems are read from `/proc/mounts`.
+    #[cfg(target_os = "linux")]
+    pub fn new(pool_sizes: &HashMap<OsString, Parallelism>) -> DiskDevices {
+        Self::read_mount_table(std::path::Path::new("/proc/mounts"), pool_sizes)
+            .unwrap_or_else(|_| Self::with_default_device(pool_sizes))
+    }
+
+    /// Reads the list of partitions and disks from the system and builds the `DiskDevices`
+    /// structure from that information.
+    #[cfg(not(target_os = "linux"))]
+    pub fn new(pool_sizes: &HashMap<OsString, Parallelism>) -> DiskDevices {
+        let mut sys = System::new();
+        sys.refresh_disks_list();
+        let mut result = Self::with_default_device(pool_sizes);
         for d in sys.get_disks() {
             let device_name = Self::physical_device_name(d.get_name());
             let index = result.add_device(
                 device_name,
-                d.get_type(),
+                Self::disk_type(d),
                 String::from_utf8_lossy(d.get_file_system()).to_string(),
                 pool_sizes,
             );
@@ -256,11 +612,115 @@ impl DiskDevices {
                 .mount_points
                 .push((Path::from(d.get_mount_point()), index));
         }
+        result.sort_mount_points();
         result
-            .mount_points
-            .sort_by_key(|(p, _)| cmp::Reverse(p.component_count()));
+    }
 
-        result
+    /// Overrides the detected type of the device holding the given path.
+    /// The parallelism of the device is recomputed according to the new type,
+    /// unless the device was given an explicit thread pool size by name.
+    ///
+    /// # Errors
+    /// Returns an error if the path is not located under any of the known mount points.
+    pub fn override_disk_type(
+        &mut self,
+        path: &Path,
+        disk_type: DiskType,
+        pool_sizes: &HashMap<OsString, Parallelism>,
+    ) -> Result<(), String> {
+        let index = self.mounted_index_of(path)?;
+        let device = &mut self.devices[index];
+        device.disk_type = disk_type;
+        device.parallelism = Self::get_parallelism(&device.name, disk_type, pool_sizes);
+        Ok(())
+    }
+
+    /// Overrides the detected properties of the device holding the given path.
+    /// The type is overridden first, so the thread pool sizes not given in the override
+    /// are recomputed according to the new type, like in [`DiskDevices::override_disk_type`].
+    ///
+    /// # Errors
+    /// Returns an error if the path is not located under any of the known mount points.
+    pub fn override_device(
+        &mut self,
+        path: &Path,
+        device_override: &DeviceOverride,
+        pool_sizes: &HashMap<OsString, Parallelism>,
+    ) -> Result<(), String> {
+        if let Some(disk_type) = device_override.disk_type {
+            self.override_disk_type(path, disk_type, pool_sizes)?;
+        }
+        let index = self.mounted_index_of(path)?;
+        let device = &mut self.devices[index];
+        if let Some(n) = device_override.seq_threads {
+            device.parallelism.sequential = n;
+        }
+        if let Some(n) = device_override.rand_threads {
+            device.parallelism.random = n;
+        }
+        if let Some(chunk) = device_override.chunk {
+            let chunk = usize::try_from(chunk.0).unwrap_or(usize::MAX);
+            device.read_buf_len = AdaptiveBufLen::new(chunk, chunk);
+        }
+        Ok(())
+    }
+
+    /// Limits the number of bytes read per second from the device holding the given path.
+    /// If the path is not given, limits each device separately.
+    pub fn set_max_bandwidth(&mut self, path: Option<&Path>, bytes_per_sec: FileLen) {
+        assert!(bytes_per_sec.0 > 0, "Bandwidth must be greater than zero");
+        match path {
+            Some(path) => {
+                let index = self.index_of(path);
+                self.devices[index].throttle = Some(TokenBucket::new(bytes_per_sec.0));
+            }
+            None => {
+                for device in self.devices.iter_mut() {
+                    device.throttle = Some(TokenBucket::new(bytes_per_sec.0));
+                }
+            }
+        }
+    }
+
+    /// Limits the number of reads running at the same time on each device of the given type.
+    pub fn set_max_concurrency(&mut self, disk_type: DiskType, max_concurrency: usize) {
+        assert!(max_concurrency > 0, "Concurrency must be greater than zero");
+        for device in self.devices.iter_mut() {
+            if device.disk_type == disk_type {
+                let semaphore = Semaphore::new(max_concurrency as isize);
+                device.concurrency_limit = Some((max_concurrency, semaphore));
+            }
+        }
+    }
+
+    /// Returns the index of the device holding the given path
+    fn index_of(&self, path: &Path) -> usize {
+        self.mount_points
+            .iter()
+            .find(|(p, _)| p.is_prefix_of(path))
+            .map(|&(_, index)| index)
+            .unwrap_or(0)
+    }
+
+    /// Returns the index of the device holding the given path, or an error if the path
+    /// is not located under any of the known mount points, instead of falling back
+    /// to the default device like [`DiskDevices::index_of`]
+    fn mounted_index_of(&self, path: &Path) -> Result<usize, String> {
+        self.mount_points
+            .iter()
+            .find(|(p, _)| p.is_prefix_of(path))
+            .map(|&(_, index)| index)
+            .ok_or_else(|| format!("No mounted device holds {}", path.display()))
+    }
+
+    /// Sets the bounds the read buffer length of each device may adapt within.
+    /// By default, the read buffer length is fixed.
+    pub fn set_read_buf_len_bounds(&mut self, min_len: FileLen, max_len: FileLen) {
+        let min_len = usize::try_from(min_len.0).unwrap_or(usize::MAX);
+        let max_len = usize::try_from(max_len.0).unwrap_or(usize::MAX);
+        for device in self.devices.iter_mut() {
+            device.read_buf_len = AdaptiveBufLen::new(min_len, max_len);
+        }
     }
 
     /// Returns the mount point holding given path
@@ -281,11 +741,25 @@ impl DiskDevices {
             .unwrap_or(&self.devices[0])
     }
 
+    /// Returns the mount points of the device at given index, from the most nested
+    pub fn mount_points_of(&self, index: usize) -> impl Iterator<Item = &Path> {
+        self.mount_points
+            .iter()
+            .filter(move |(_, i)| *i == index)
+            .map(|(p, _)| p)
+    }
+
     /// Returns the disk device by its device name (not mount point)
     pub fn get_by_name(&self, name: &OsStr) -> Option<&DiskDevice> {
         self.devices.iter().find(|&d| d.name == name)
     }
 
+    /// Returns the name of the device at given index, e.g. `/dev/sda`.
+    /// Returns `None` if the index is out of range or the name is not valid UTF-8.
+    pub fn device_name(&self, index: usize) -> Option<&str> {
+        self.devices.get(index).and_then(|d| d.name.to_str())
+    }
+
     /// Returns the first device on the list
     pub fn get_default(&self) -> &DiskDevice {
         &self.devices[0]
@@ -296,6 +770,11 @@ impl DiskDevices {
         self.devices.len()
     }
 
+    /// Returns true if there are no devices
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
     /// Returns an iterator over devices
     pub fn iter(&self) -> impl Iterator<Item = &DiskDevice> {
         self.devices.iter()
@@ -322,6 +801,29 @@ impl Index<usize> for DiskDevices {
     }
 }
 
+/// Reads the type of the disk from the output of `diskutil info <volume>` on macOS.
+/// Removable media and USB devices are reported as removable, regardless of their medium.
+/// Returns `None` if the output tells neither the medium nor the removability.
+#[cfg(any(test, target_os = "macos"))]
+fn parse_diskutil_info(output: &str) -> Option<DiskType> {
+    let value = |key: &str| {
+        output
+            .lines()
+            .filter_map(|line| line.trim().split_once(':'))
+            .find(|(k, _)| k.trim() == key)
+            .map(|(_, v)| v.trim())
+    };
+    let removable = value("Removable Media") == Some("Removable")
+        || value("Protocol") == Some("USB")
+        || value("Device Location") == Some("External") && value("Ejectable") == Some("Yes");
+    match value("Solid State") {
+        _ if removable => Some(DiskType::Removable),
+        Some("Yes") => Some(DiskType::SSD),
+        Some("No") => Some(DiskType::HDD),
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -350,4 +852,144 @@ mod test {
             OsString::from("/dev/unknown")
         );
     }
+
+    #[test]
+    fn test_override_disk_type() {
+        let mut devices = DiskDevices::single(DiskType::SSD, 16);
+        let pool_sizes = HashMap::new();
+        devices
+            .override_disk_type(&Path::from("/foo"), DiskType::HDD, &pool_sizes)
+            .unwrap();
+        assert_eq!(devices[0].disk_type, DiskType::HDD);
+        assert_eq!(devices[0].parallelism.random, 1);
+        assert_eq!(devices[0].parallelism.sequential, 1);
+    }
+
+    #[test]
+    fn test_override_device() {
+        let mut devices = DiskDevices::single(DiskType::SSD, 16);
+        let pool_sizes = HashMap::new();
+        let device_override = DeviceOverride {
+            disk_type: Some(DiskType::HDD),
+            rand_threads: Some(8),
+            chunk: Some(FileLen(4 * 1024 * 1024)),
+            ..Default::default()
+        };
+        devices
+            .override_device(&Path::from("/foo"), &device_override, &pool_sizes)
+            .unwrap();
+        assert_eq!(devices[0].disk_type, DiskType::HDD);
+        assert_eq!(devices[0].parallelism.random, 8);
+        assert_eq!(devices[0].parallelism.sequential, 1);
+        assert_eq!(devices[0].read_buf_len(), 4 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_override_unmounted_device_fails() {
+        let mut devices = DiskDevices::single(DiskType::SSD, 16);
+        devices.mount_points = vec![(Path::from("/mnt"), 0)];
+        let device_override = DeviceOverride {
+            disk_type: Some(DiskType::HDD),
+            ..Default::default()
+        };
+        let result =
+            devices.override_device(&Path::from("/foo"), &device_override, &HashMap::new());
+        assert!(result.is_err());
+        assert_eq!(devices[0].disk_type, DiskType::SSD);
+    }
+
+    #[test]
+    fn test_parse_diskutil_info() {
+        let info = |lines: &[&str]| lines.join("\n");
+        let internal_ssd = info(&[
+            "   Device Identifier:         disk3s1",
+            "   Protocol:                  Apple Fabric",
+            "   Solid State:               Yes",
+            "   Removable Media:           Fixed",
+        ]);
+        assert_eq!(parse_diskutil_info(&internal_ssd), Some(DiskType::SSD));
+        let hdd = info(&["   Solid State:               No"]);
+        assert_eq!(parse_diskutil_info(&hdd), Some(DiskType::HDD));
+        let usb_ssd = info(&[
+            "   Protocol:                  USB",
+            "   Solid State:               Yes",
+        ]);
+        assert_eq!(parse_diskutil_info(&usb_ssd), Some(DiskType::Removable));
+        let sd_card = info(&["   Removable Media:           Removable"]);
+        assert_eq!(parse_diskutil_info(&sd_card), Some(DiskType::Removable));
+        assert_eq!(parse_diskutil_info("Could not find disk"), None);
+    }
+
+    #[test]
+    fn test_set_max_concurrency() {
+        let mut devices = DiskDevices::single(DiskType::HDD, 16);
+        devices.set_max_concurrency(DiskType::SSD, 8);
+        assert_eq!(devices[0].max_concurrency(), None);
+        assert!(devices[0].read_permit().is_none());
+        devices.set_max_concurrency(DiskType::HDD, 2);
+        assert_eq!(devices[0].max_concurrency(), Some(2));
+        let _permit1 = devices[0].read_permit().unwrap();
+        let _permit2 = devices[0].read_permit().unwrap();
+    }
+
+    #[test]
+    fn test_parse_mount_table() {
+        let table = "\
+            proc /proc proc rw,relatime 0 0\n\
+            /dev/sda1 / ext4 rw,relatime 0 0\n\
+            tmpfs /dev/shm tmpfs rw 0 0\n\
+            /dev/sdb1 /mnt/my\\040disk xfs rw 0 0\n\
+            # comment\n";
+        let entries = parse_mount_table(table);
+        assert_eq!(
+            entries,
+            vec![
+                MountEntry {
+                    device: OsString::from("/dev/sda1"),
+                    mount_point: PathBuf::from("/"),
+                    file_system: String::from("ext4"),
+                },
+                MountEntry {
+                    device: OsString::from("/dev/sdb1"),
+                    mount_point: PathBuf::from("/mnt/my disk"),
+                    file_system: String::from("xfs"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_from_mount_table() {
+        use crate::util::test::with_dir;
+
+        with_dir("device/from_mount_table", |root| {
+            let table = root.join("mounts");
+            fs::write(
+                &table,
+                "/dev/fake1 / ext4 rw 0 0\n\
+                 /dev/fake2 /data xfs rw 0 0\n\
+                 /dev/fake2 /data/backup xfs rw 0 0\n\
+                 sysfs /sys sysfs rw 0 0\n",
+            )
+            .unwrap();
+            let devices = DiskDevices::from_mount_table(&table).unwrap();
+            assert_eq!(devices.len(), 3);
+            assert_eq!(devices.device_name(1), Some("/dev/fake1"));
+            assert_eq!(devices.device_name(2), Some("/dev/fake2"));
+            assert_eq!(devices[2].file_system, "xfs");
+            let data = devices.get_by_path(&Path::from("/data/backup/file"));
+            assert_eq!(data.index, 2);
+            assert_eq!(devices.mount_points_of(2).count(), 2);
+            let home = devices.get_by_path(&Path::from("/home/file"));
+            assert_eq!(home.index, 1);
+            assert!(DiskDevices::from_mount_table(&root.join("missing")).is_err());
+        });
+    }
+
+    #[test]
+    fn test_device_name() {
+        let devices = DiskDevices::single(DiskType::SSD, 1);
+        assert_eq!(devices.device_name(0), Some("/"));
+        assert_eq!(devices.device_name(1), None);
+    }
 }
@@ -0,0 +1,348 @@
+//! Comparing two directory trees by the contents of their files.
+//!
+//! Files of both trees are grouped by content, the same way as by `fclones group`.
+//! Each group is then classified by the trees its files belong to.
+
+use std::cmp::Reverse;
+use std::io;
+use std::io::Write;
+
+use serde::Serialize;
+
+use crate::config::{DiffTreesConfig, GroupConfig};
+use crate::error::Error;
+use crate::file::{FileHash, FileLen};
+use crate::group::group_files;
+use crate::log::Log;
+use crate::path::Path;
+
+/// Files with the same content found in only one of the trees
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct UniqueContent {
+    pub file_len: FileLen,
+    pub files: Vec<Path>,
+}
+
+/// Files with the same content found in both trees
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct CommonContent {
+    pub file_len: FileLen,
+    pub file_hash: FileHash,
+    /// Files in the left tree
+    pub left: Vec<Path>,
+    /// Files in the right tree
+    pub right: Vec<Path>,
+}
+
+/// The result of comparing two directory trees.
+/// Contents are sorted by file size, from the largest.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct TreeDiff {
+    pub left: Path,
+    pub right: Path,
+    pub only_in_left: Vec<UniqueContent>,
+    pub only_in_right: Vec<UniqueContent>,
+    pub in_both: Vec<CommonContent>,
+}
+
+impl TreeDiff {
+    /// Returns true if every content is present in both trees
+    pub fn is_empty(&self) -> bool {
+        self.only_in_left.is_empty() && self.only_in_right.is_empty()
+    }
+
+    /// Writes the result in a human-readable form.
+    ///
+    /// Each section starts with a comment line.
+    /// Files in both trees are prefixed with `<` if they are in the left tree,
+    /// and with `>` if they are in the right tree.
+    pub fn write_as_text(&self, out: &mut impl Write) -> io::Result<()> {
+        for (root, contents) in [
+            (&self.left, &self.only_in_left),
+            (&self.right, &self.only_in_right),
+        ] {
+            let file_count: usize = contents.iter().map(|c| c.files.len()).sum();
+            let total_len: FileLen = contents
+                .iter()
+                .map(|c| c.file_len * c.files.len() as u64)
+                .sum();
+            writeln!(
+                out,
+                "# Only in {}: {} files ({})",
+                root.to_escaped_string(),
+                file_count,
+                total_len
+            )?;
+            for c in contents {
+                writeln!(
+                    out,
+                    "{} B ({}) * {}:",
+                    c.file_len.0,
+                    c.file_len,
+                    c.files.len()
+                )?;
+                for f in &c.files {
+                    writeln!(out, "    {}", f.to_escaped_string())?;
+                }
+            }
+        }
+
+        let file_count: usize = self
+            .in_both
+            .iter()
+            .map(|c| c.left.len() + c.right.len())
+            .sum();
+        writeln!(
+            out,
+            "# In both: {} files with {} distinct contents",
+            file_count,
+            self.in_both.len()
+        )?;
+        for c in &self.in_both {
+            writeln!(out, "{}, {} B ({}):", c.file_hash, c.file_len.0, c.file_len)?;
+            for f in &c.left {
+                writeln!(out, "  < {}", f.to_escaped_string())?;
+            }
+            for f in &c.right {
+                writeln!(out, "  > {}", f.to_escaped_string())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the result in JSON
+    pub fn write_as_json(&self, out: &mut impl Write) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut *out, self)?;
+        writeln!(out)
+    }
+}
+
+/// Compares the contents of the files in the directory trees given by `config.left`
+/// and `config.right`.
+///
+/// # Errors
+/// Returns an error if any of the directories doesn't exist, if one of them is
+/// nested in the other, or if grouping the files fails.
+pub fn diff_trees(config: &DiffTreesConfig, log: &Log) -> Result<TreeDiff, Error> {
+    let left = root_dir(&config.left)?;
+    let right = root_dir(&config.right)?;
+    if left.is_prefix_of(&right) || right.is_prefix_of(&left) {
+        return Err(Error::config(format!(
+            "Directories {} and {} must not contain each other",
+            left.display(),
+            right.display()
+        )));
+    }
+
+    let group_config = GroupConfig {
+        paths: vec![left.clone(), right.clone()],
+        skip_hidden: config.skip_hidden,
+        follow_links: config.follow_links,
+        // Each link is a separate file in its tree; without this, links to the same file
+        // in both trees would be reported as present in only one of them
+        hard_links: true,
+        rf_over: Some(0),
+        min_size: FileLen(0),
+        ..Default::default()
+    };
+    let groups = group_files(&group_config, log)?;
+
+    let mut diff = TreeDiff {
+        left,
+        right,
+        only_in_left: vec![],
+        only_in_right: vec![],
+        in_both: vec![],
+    };
+    for group in groups {
+        let (left_files, right_files): (Vec<_>, Vec<_>) = group
+            .files
+            .into_iter()
+            .partition(|f| diff.left.is_prefix_of(f));
+        let file_len = group.file_len;
+        match (left_files.is_empty(), right_files.is_empty()) {
+            (false, false) => diff.in_both.push(CommonContent {
+                file_len,
+                file_hash: group.file_hash,
+                left: left_files,
+                right: right_files,
+            }),
+            (false, true) => diff.only_in_left.push(UniqueContent {
+                file_len,
+                files: left_files,
+            }),
+            (true, false) => diff.only_in_right.push(UniqueContent {
+                file_len,
+                files: right_files,
+            }),
+            (true, true) => {}
+        }
+    }
+
+    // Make the order of contents of the same size stable:
+    diff.only_in_left
+        .sort_by(|a, b| (Reverse(a.file_len), &a.files).cmp(&(Reverse(b.file_len), &b.files)));
+    diff.only_in_right
+        .sort_by(|a, b| (Reverse(a.file_len), &a.files).cmp(&(Reverse(b.file_len), &b.files)));
+    diff.in_both
+        .sort_by(|a, b| (Reverse(a.file_len), &a.left).cmp(&(Reverse(b.file_len), &b.left)));
+    Ok(diff)
+}
+
+/// Returns the absolute path of the directory.
+/// Fails if the directory doesn't exist.
+fn root_dir(path: &std::path::Path) -> Result<Path, Error> {
+    let dir = dunce::canonicalize(path)
+        .map_err(|e| Error::io("Failed to access directory", Some(&Path::from(path)), e))?;
+    if !dir.is_dir() {
+        return Err(Error::config(format!(
+            "Not a directory: {}",
+            Path::from(path).display()
+        )));
+    }
+    Ok(Path::from(dir))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{create_dir, hard_link};
+
+    use crate::error::ErrorKind;
+    use crate::util::test::{with_dir, write_file};
+
+    use super::*;
+
+    fn diff(left: &std::path::Path, right: &std::path::Path) -> TreeDiff {
+        let config = DiffTreesConfig {
+            left: left.to_path_buf(),
+            right: right.to_path_buf(),
+            ..Default::default()
+        };
+        let mut log = Log::new();
+        log.no_progress = true;
+        diff_trees(&config, &log).unwrap()
+    }
+
+    #[test]
+    fn reports_contents_unique_to_each_tree() {
+        with_dir("diff/unique_to_each_tree", |root| {
+            let left = root.join("left");
+            let right = root.join("right");
+            create_dir(&left).unwrap();
+            create_dir(&right).unwrap();
+            write_file(&left.join("common"), "common");
+            write_file(&right.join("renamed"), "common");
+            write_file(&left.join("old"), "old");
+            write_file(&right.join("new"), "new file");
+
+            let diff = diff(&left, &right);
+            assert!(!diff.is_empty());
+            assert_eq!(
+                diff.only_in_left,
+                vec![UniqueContent {
+                    file_len: FileLen(3),
+                    files: vec![Path::from(left.join("old"))],
+                }]
+            );
+            assert_eq!(
+                diff.only_in_right,
+                vec![UniqueContent {
+                    file_len: FileLen(8),
+                    files: vec![Path::from(right.join("new"))],
+                }]
+            );
+            assert_eq!(diff.in_both.len(), 1);
+            assert_eq!(diff.in_both[0].left, vec![Path::from(left.join("common"))]);
+            assert_eq!(
+                diff.in_both[0].right,
+                vec![Path::from(right.join("renamed"))]
+            );
+        });
+    }
+
+    #[test]
+    fn empty_files_are_compared() {
+        with_dir("diff/empty_files", |root| {
+            let left = root.join("left");
+            let right = root.join("right");
+            create_dir(&left).unwrap();
+            create_dir(&right).unwrap();
+            write_file(&left.join("empty1"), "");
+            write_file(&left.join("empty2"), "");
+
+            let diff1 = diff(&left, &right);
+            assert_eq!(diff1.only_in_left.len(), 1);
+            assert_eq!(diff1.only_in_left[0].files.len(), 2);
+            assert!(diff1.in_both.is_empty());
+
+            write_file(&right.join("empty"), "");
+            let diff2 = diff(&left, &right);
+            assert!(diff2.is_empty());
+            assert_eq!(diff2.in_both.len(), 1);
+            assert_eq!(diff2.in_both[0].file_len, FileLen(0));
+        });
+    }
+
+    #[test]
+    fn hard_links_across_trees_are_in_both() {
+        with_dir("diff/hard_links", |root| {
+            let left = root.join("left");
+            let right = root.join("right");
+            create_dir(&left).unwrap();
+            create_dir(&right).unwrap();
+            write_file(&left.join("file"), "foo");
+            hard_link(left.join("file"), right.join("link")).unwrap();
+            hard_link(left.join("file"), left.join("link")).unwrap();
+
+            let diff = diff(&left, &right);
+            assert!(diff.is_empty());
+            assert_eq!(diff.in_both.len(), 1);
+            assert_eq!(diff.in_both[0].left.len(), 2);
+            assert_eq!(diff.in_both[0].right, vec![Path::from(right.join("link"))]);
+        });
+    }
+
+    #[test]
+    fn nested_trees_are_rejected() {
+        with_dir("diff/nested", |root| {
+            let inner = root.join("inner");
+            create_dir(&inner).unwrap();
+            let config = DiffTreesConfig {
+                left: root.to_path_buf(),
+                right: inner,
+                ..Default::default()
+            };
+            let e = diff_trees(&config, &Log::new()).unwrap_err();
+            assert!(matches!(e.kind, ErrorKind::Config));
+        });
+    }
+
+    #[test]
+    fn text_output_marks_sides() {
+        let diff = TreeDiff {
+            left: Path::from("/a"),
+            right: Path::from("/b"),
+            only_in_left: vec![UniqueContent {
+                file_len: FileLen(10),
+                files: vec![Path::from("/a/x")],
+            }],
+            only_in_right: vec![],
+            in_both: vec![CommonContent {
+                file_len: FileLen(3),
+                file_hash: FileHash(0x1234),
+                left: vec![Path::from("/a/y")],
+                right: vec![Path::from("/b/y")],
+            }],
+        };
+        let mut out = Vec::new();
+        diff.write_as_text(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "# Only in /a: 1 files (10 B)\n\
+             10 B (10 B) * 1:\n    /a/x\n\
+             # Only in /b: 0 files (0 B)\n\
+             # In both: 2 files with 1 distinct contents\n\
+             00000000000000000000000000001234, 3 B (3 B):\n  < /a/y\n  > /b/y\n"
+        );
+    }
+}
@@ -0,0 +1,218 @@
+//! Finding and removing directories left empty after removing redundant files.
+
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::path::Path;
+
+/// Returns the directories below the roots which are empty or would become empty
+/// if the given files were removed, from the deepest.
+///
+/// A directory would become empty if all of its files are going to be removed and all of its
+/// subdirectories would become empty. The roots themselves are never returned.
+/// Symbolic links to directories are treated like files, so they are not followed.
+///
+/// If no roots are given, only the directories directly containing the removed files
+/// are checked, and they would become empty only if all their entries are removed.
+pub fn find_empty_dirs<I>(roots: &[Path], removed_files: I) -> Vec<Path>
+where
+    I: IntoIterator<Item = Path>,
+{
+    let removed: HashSet<PathBuf> = removed_files.into_iter().map(|p| p.to_path_buf()).collect();
+    let mut empty_dirs = Vec::new();
+    if roots.is_empty() {
+        let parents: HashSet<&std::path::Path> =
+            removed.iter().filter_map(|f| f.parent()).collect();
+        empty_dirs.extend(
+            parents
+                .into_iter()
+                .filter(|dir| would_become_empty(dir, &removed))
+                .map(|dir| dir.to_path_buf()),
+        );
+    }
+    for root in roots {
+        let root = root.to_path_buf();
+        if root.is_dir() {
+            collect_empty_dirs(&root, &removed, &mut empty_dirs);
+            empty_dirs.retain(|d| d != &root);
+        }
+    }
+    sort_deepest_first(&mut empty_dirs);
+    empty_dirs.dedup();
+    empty_dirs.into_iter().map(Path::from).collect()
+}
+
+/// Walks the directory recursively, adding the directories which would become empty
+/// to `empty_dirs`. Returns true if `dir` would become empty.
+fn collect_empty_dirs(
+    dir: &std::path::Path,
+    removed: &HashSet<PathBuf>,
+    empty_dirs: &mut Vec<PathBuf>,
+) -> bool {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    let mut empty = true;
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(_) => {
+                empty = false;
+                continue;
+            }
+        };
+        let path = entry.path();
+        let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+        // Subdirectories must be visited even if `dir` is known to stay non-empty,
+        // because they may become empty themselves
+        if is_dir && collect_empty_dirs(&path, removed, empty_dirs) {
+            continue;
+        }
+        if is_dir || !removed.contains(&path) {
+            empty = false;
+        }
+    }
+    if empty {
+        empty_dirs.push(dir.to_path_buf());
+    }
+    empty
+}
+
+/// Returns true if the directory contains only the files to be removed
+fn would_become_empty(dir: &std::path::Path, removed: &HashSet<PathBuf>) -> bool {
+    match fs::read_dir(dir) {
+        Ok(mut entries) => entries.all(|entry| {
+            entry.is_ok_and(|e| {
+                !e.file_type().is_ok_and(|t| t.is_dir()) && removed.contains(&e.path())
+            })
+        }),
+        Err(_) => false,
+    }
+}
+
+/// Returns the directories containing the given files and their ancestors,
+/// up to but excluding the roots. Directories outside of the roots are not returned.
+/// If no roots are given, only the directories directly containing the files are returned.
+pub fn parent_dirs<'a, I>(files: I, roots: &[Path]) -> Vec<Path>
+where
+    I: IntoIterator<Item = &'a Path>,
+{
+    let roots: Vec<PathBuf> = roots.iter().map(|r| r.to_path_buf()).collect();
+    let mut dirs = HashSet::new();
+    for file in files {
+        let file = file.to_path_buf();
+        if roots.is_empty() {
+            dirs.extend(file.parent().map(|p| p.to_path_buf()));
+            continue;
+        }
+        for dir in file.ancestors().skip(1) {
+            if roots.iter().any(|r| r == dir) || !roots.iter().any(|r| dir.starts_with(r)) {
+                break;
+            }
+            if !dirs.insert(dir.to_path_buf()) {
+                break;
+            }
+        }
+    }
+    let mut dirs: Vec<PathBuf> = dirs.into_iter().collect();
+    sort_deepest_first(&mut dirs);
+    dirs.into_iter().map(Path::from).collect()
+}
+
+/// Removes the given directories if they are empty, from the deepest,
+/// so a directory containing only empty directories gets removed as well.
+/// Returns the number of removed directories.
+pub fn prune_empty_dirs(dirs: Vec<Path>) -> usize {
+    let mut dirs: Vec<PathBuf> = dirs.into_iter().map(|d| d.to_path_buf()).collect();
+    sort_deepest_first(&mut dirs);
+    // `remove_dir` fails if the directory is not empty
+    dirs.iter().filter(|d| fs::remove_dir(d).is_ok()).count()
+}
+
+fn sort_deepest_first(dirs: &mut [PathBuf]) {
+    dirs.sort_by(|a, b| {
+        let depth = |p: &PathBuf| p.components().count();
+        Reverse(depth(a))
+            .cmp(&Reverse(depth(b)))
+            .then_with(|| a.cmp(b))
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::create_dir_all;
+
+    use crate::util::test::{create_file, with_dir};
+
+    use super::*;
+
+    #[test]
+    fn finds_dirs_left_empty() {
+        with_dir("empty_dir/find", |root| {
+            create_dir_all(root.join("a/b/c")).unwrap();
+            create_dir_all(root.join("d")).unwrap();
+            create_dir_all(root.join("e")).unwrap();
+            create_file(&root.join("a/b/dup"));
+            create_file(&root.join("d/dup"));
+            create_file(&root.join("d/unique"));
+            create_file(&root.join("e/unique"));
+
+            let removed = vec![
+                Path::from(root.join("a/b/dup")),
+                Path::from(root.join("d/dup")),
+            ];
+            let empty = find_empty_dirs(&[Path::from(root)], removed);
+            let expected: Vec<_> = ["a/b/c", "a/b", "a"]
+                .iter()
+                .map(|d| Path::from(root.join(d)))
+                .collect();
+            assert_eq!(empty, expected);
+        });
+    }
+
+    #[test]
+    fn prunes_empty_parents_up_to_roots() {
+        with_dir("empty_dir/prune", |root| {
+            create_dir_all(root.join("a/b")).unwrap();
+            create_dir_all(root.join("c")).unwrap();
+            create_file(&root.join("c/unique"));
+            let files = [
+                Path::from(root.join("a/b/removed")),
+                Path::from(root.join("c/removed")),
+            ];
+            let roots = [Path::from(root)];
+            let dirs = parent_dirs(files.iter(), &roots);
+            assert_eq!(dirs.len(), 3);
+            assert_eq!(prune_empty_dirs(dirs), 2);
+            assert!(!root.join("a").exists());
+            assert!(root.join("c").exists());
+            assert!(root.exists());
+        });
+    }
+
+    #[test]
+    fn prunes_only_direct_parents_without_roots() {
+        with_dir("empty_dir/prune_without_roots", |root| {
+            create_dir_all(root.join("a/b")).unwrap();
+            create_dir_all(root.join("c/d")).unwrap();
+            create_file(&root.join("c/removed"));
+            let files = [
+                Path::from(root.join("a/b/removed")),
+                Path::from(root.join("c/removed")),
+            ];
+            assert_eq!(
+                find_empty_dirs(&[], files.iter().cloned()),
+                vec![Path::from(root.join("a/b"))]
+            );
+            let dirs = parent_dirs(files.iter(), &[]);
+            assert_eq!(dirs.len(), 2);
+            assert_eq!(prune_empty_dirs(dirs), 1);
+            assert!(!root.join("a/b").exists());
+            assert!(root.join("a").exists());
+            assert!(root.join("c").exists());
+        });
+    }
+}
@@ -1,19 +1,96 @@
 use std::fmt;
 use std::fmt::{Display, Formatter};
+use std::io;
+
+use crate::path::Path;
+
+/// Kind of failure reported by top-level fclones functions
+#[derive(Debug)]
+pub enum ErrorKind {
+    /// Accessing a file failed
+    Io {
+        /// The file that couldn't be accessed, if known
+        path: Option<Path>,
+        source: io::Error,
+    },
+    /// The configuration is invalid or inconsistent
+    Config,
+    /// A report produced by an earlier run could not be parsed
+    ReportParse {
+        /// The line at which parsing failed, if known
+        line: Option<usize>,
+    },
+    /// The operation was interrupted by the user or by a time limit
+    Cancelled,
+    /// The operation is not supported on this platform or file system
+    Unsupported,
+    /// Some files or commands failed the operation, e.g. files failed verification,
+    /// changed since the earlier run, or an `--exec` command exited with an error
+    Failed,
+    /// Any other failure
+    Other,
+}
 
 /// Error reported by top-level fclones functions
 #[derive(Debug)]
 pub struct Error {
+    pub kind: ErrorKind,
     pub message: String,
 }
 
 impl Error {
     pub fn new(msg: String) -> Error {
-        Error { message: msg }
+        Self::with_kind(ErrorKind::Other, msg)
+    }
+
+    pub fn with_kind(kind: ErrorKind, msg: impl Into<String>) -> Error {
+        Error {
+            kind,
+            message: msg.into(),
+        }
+    }
+
+    /// Creates an error of accessing the file at `path`, or of an I/O operation
+    /// not related to a single file if `path` is `None`.
+    /// The message reads `<action> <path>: <source>`.
+    pub fn io(action: &str, path: Option<&Path>, source: io::Error) -> Error {
+        let message = match path {
+            Some(path) => format!("{} {}: {}", action, path.display(), source),
+            None => format!("{}: {}", action, source),
+        };
+        let path = path.cloned();
+        Self::with_kind(ErrorKind::Io { path, source }, message)
+    }
+
+    pub fn config(msg: impl Into<String>) -> Error {
+        Self::with_kind(ErrorKind::Config, msg)
+    }
+
+    pub fn report_parse(line: Option<usize>, msg: impl Into<String>) -> Error {
+        Self::with_kind(ErrorKind::ReportParse { line }, msg)
+    }
+
+    pub fn cancelled(msg: impl Into<String>) -> Error {
+        Self::with_kind(ErrorKind::Cancelled, msg)
+    }
+
+    pub fn unsupported(msg: impl Into<String>) -> Error {
+        Self::with_kind(ErrorKind::Unsupported, msg)
+    }
+
+    pub fn failed(msg: impl Into<String>) -> Error {
+        Self::with_kind(ErrorKind::Failed, msg)
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            ErrorKind::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
@@ -32,3 +109,39 @@ impl From<&str> for Error {
         Error::new(s.to_owned())
     }
 }
+
+/// Converts an I/O error not related to any particular file
+impl From<io::Error> for Error {
+    fn from(source: io::Error) -> Self {
+        let message = source.to_string();
+        Self::with_kind(ErrorKind::Io { path: None, source }, message)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error as _;
+    use std::io;
+
+    use super::{Error, ErrorKind};
+    use crate::path::Path;
+
+    #[test]
+    fn io_error_keeps_path_and_source() {
+        let source = io::Error::new(io::ErrorKind::NotFound, "not found");
+        let error = Error::io("Failed to open", Some(&Path::from("/foo")), source);
+        assert_eq!(error.to_string(), "Failed to open /foo: not found");
+        assert!(
+            matches!(error.kind, ErrorKind::Io { path: Some(ref p), .. } if p == &Path::from("/foo"))
+        );
+        let source = error.source().unwrap().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(source.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn non_io_error_has_no_source() {
+        let error = Error::config("Invalid option");
+        assert!(matches!(error.kind, ErrorKind::Config));
+        assert!(error.source().is_none());
+    }
+}
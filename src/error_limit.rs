@@ -0,0 +1,98 @@
+//! Aborting a run that encounters too many I/O errors.
+//!
+//! On a failing disk, almost every read may fail. Continuing the run would take a long time
+//! and produce mostly invalid results, so it is better to stop early.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::error::Error;
+use crate::log::{LogLevel, LogMessage, LogSink};
+
+/// Forwards messages to another sink and counts the warnings and errors caused by I/O errors.
+pub(crate) struct ErrorLimit<'a> {
+    inner: &'a dyn LogSink,
+    count: AtomicUsize,
+    /// Maximum number of I/O errors allowed; 0 means no limit
+    limit: usize,
+}
+
+impl<'a> ErrorLimit<'a> {
+    pub fn new(inner: &'a dyn LogSink, limit: usize) -> ErrorLimit<'a> {
+        ErrorLimit {
+            inner,
+            count: AtomicUsize::new(0),
+            limit,
+        }
+    }
+
+    /// Returns the number of I/O errors logged so far
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if the number of I/O errors is above the limit
+    pub fn is_exceeded(&self) -> bool {
+        self.limit > 0 && self.count() > self.limit
+    }
+
+    /// Returns an error if the number of I/O errors is above the limit
+    pub fn check(&self) -> Result<(), Error> {
+        if self.is_exceeded() {
+            Err(Error::cancelled(format!(
+                "Aborted after encountering {} I/O errors, more than the limit of {}",
+                self.count(),
+                self.limit
+            )))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl LogSink for ErrorLimit<'_> {
+    fn log(&self, level: LogLevel, msg: LogMessage) {
+        if level >= LogLevel::Warn && msg.error_kind.is_some() {
+            self.count.fetch_add(1, Ordering::Relaxed);
+        }
+        self.inner.log(level, msg)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crate::error::ErrorKind;
+    use crate::log::Log;
+
+    use super::*;
+
+    fn io_warning() -> LogMessage {
+        LogMessage::new("Failed to read").with_error(&io::Error::other("disk failure"))
+    }
+
+    #[test]
+    fn counts_only_io_warnings() {
+        let log = Log::new();
+        let limit = ErrorLimit::new(&log, 1);
+        limit.warn(LogMessage::new("Not an I/O error"));
+        limit.debug(io_warning());
+        assert_eq!(limit.count(), 0);
+        limit.warn(io_warning());
+        assert!(limit.check().is_ok());
+        limit.err(io_warning());
+        assert_eq!(limit.count(), 2);
+        let e = limit.check().unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::Cancelled));
+    }
+
+    #[test]
+    fn zero_means_no_limit() {
+        let log = Log::new();
+        let limit = ErrorLimit::new(&log, 0);
+        for _ in 0..100 {
+            limit.warn(io_warning());
+        }
+        assert!(!limit.is_exceeded());
+    }
+}
@@ -0,0 +1,284 @@
+//! Running a user-supplied command for groups of duplicate files.
+//!
+//! The command is given as a template with placeholders:
+//! - `{keep}` – the path of the file to keep,
+//! - `{dupes...}` – the paths of the redundant files, each passed as a separate argument;
+//!   it must be a whole argument on its own,
+//! - `{hash}` – the hash of the file contents,
+//! - `{size}` – the size of each file in bytes.
+//!
+//! When multiple groups are passed to a single invocation, the arguments from the first one
+//! containing a placeholder to the end of the template are repeated for each group.
+
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::io::Write;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rayon::prelude::*;
+
+use crate::arg;
+use crate::arg::Arg;
+use crate::error::Error;
+use crate::file::{FileHash, FileLen};
+use crate::log::Log;
+use crate::path::Path;
+
+const KEEP: &str = "{keep}";
+const DUPES: &str = "{dupes...}";
+const HASH: &str = "{hash}";
+const SIZE: &str = "{size}";
+
+/// A group of identical files, split into the file to keep and the redundant files
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExecGroup {
+    pub file_len: FileLen,
+    pub file_hash: FileHash,
+    pub keep: Path,
+    pub dupes: Vec<Path>,
+}
+
+/// A command line with placeholders to be replaced by the properties of groups
+#[derive(Clone, Debug)]
+pub struct ExecTemplate {
+    args: Vec<OsString>,
+    /// Index of the first argument with a placeholder
+    group_args_start: usize,
+}
+
+impl ExecTemplate {
+    /// Splits the command into arguments the same way as a Unix shell does
+    /// and finds the placeholders.
+    ///
+    /// # Errors
+    /// Returns an error if the command is empty, can't be split into arguments,
+    /// or if `{dupes...}` is a part of a longer argument.
+    pub fn parse(command: &str) -> Result<ExecTemplate, Error> {
+        let args: Vec<OsString> = arg::split(command)
+            .map_err(|e| Error::config(format!("Failed to parse command {}: {}", command, e)))?
+            .into_iter()
+            .map(|a| a.as_os_str().to_os_string())
+            .collect();
+        if args.is_empty() {
+            return Err(Error::config("Command must not be empty"));
+        }
+        if args.iter().any(|a| a != DUPES && contains(a, DUPES)) {
+            return Err(Error::config(format!(
+                "Placeholder {} must be a separate argument",
+                DUPES
+            )));
+        }
+        let group_args_start = args
+            .iter()
+            .position(|a| [KEEP, DUPES, HASH, SIZE].iter().any(|p| contains(a, p)))
+            .unwrap_or(args.len());
+        Ok(ExecTemplate {
+            args,
+            group_args_start,
+        })
+    }
+
+    /// Returns the arguments of the command for the given groups,
+    /// including the program name as the first item
+    pub fn expand(&self, groups: &[ExecGroup]) -> Vec<OsString> {
+        let (common, per_group) = self.args.split_at(self.group_args_start);
+        let mut result = common.to_vec();
+        for g in groups {
+            for a in per_group {
+                if a == DUPES {
+                    result.extend(g.dupes.iter().map(|p| p.to_path_buf().into_os_string()));
+                } else {
+                    result.push(substitute(a, g));
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Returns true if the argument contains the given placeholder
+fn contains(arg: &OsStr, placeholder: &str) -> bool {
+    arg.to_str().is_some_and(|a| a.contains(placeholder))
+}
+
+/// Replaces the `{keep}`, `{hash}` and `{size}` placeholders in the argument
+fn substitute(arg: &OsStr, group: &ExecGroup) -> OsString {
+    let mut rest = match arg.to_str() {
+        Some(s) => s,
+        None => return arg.to_os_string(),
+    };
+    let mut result = OsString::new();
+    while let Some(start) = rest.find('{') {
+        result.push(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(r) = rest.strip_prefix(KEEP) {
+            result.push(group.keep.to_path_buf());
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix(HASH) {
+            result.push(group.file_hash.to_string());
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix(SIZE) {
+            result.push(group.file_len.0.to_string());
+            rest = r;
+        } else {
+            result.push("{");
+            rest = &rest[1..];
+        }
+    }
+    result.push(rest);
+    result
+}
+
+/// Summary of running the commands
+#[derive(Debug, Default)]
+pub struct ExecResult {
+    /// Number of started commands
+    pub command_count: usize,
+    /// Number of commands that couldn't be started or exited with a non-zero status
+    pub failed_count: usize,
+}
+
+/// Runs the command once for every `batch_size` groups, running up to `concurrency`
+/// commands at the same time.
+///
+/// The standard output and error of the commands are inherited from this process.
+/// Failures are logged as warnings and counted in the result.
+pub fn exec_groups(
+    template: &ExecTemplate,
+    groups: &[ExecGroup],
+    batch_size: usize,
+    concurrency: usize,
+    log: &Log,
+) -> Result<ExecResult, Error> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(concurrency)
+        .build()
+        .map_err(|e| Error::io("Failed to create thread pool", None, io::Error::other(e)))?;
+    let failed_count = AtomicUsize::new(0);
+    let batches: Vec<_> = groups.chunks(batch_size.max(1)).collect();
+    pool.install(|| {
+        batches.par_iter().for_each(|batch| {
+            let args = template.expand(batch);
+            let command_str = arg::join(
+                &args
+                    .iter()
+                    .map(|a| Arg::from(a.as_os_str()))
+                    .collect::<Vec<_>>(),
+            );
+            let status = Command::new(&args[0]).args(&args[1..]).status();
+            let failure = match status {
+                Ok(status) if status.success() => None,
+                Ok(status) => Some(status.to_string()),
+                Err(e) => Some(e.to_string()),
+            };
+            if let Some(failure) = failure {
+                log.warn(format!("Command {} failed: {}", command_str, failure));
+                failed_count.fetch_add(1, Ordering::Relaxed);
+            }
+        })
+    });
+    Ok(ExecResult {
+        command_count: batches.len(),
+        failed_count: failed_count.into_inner(),
+    })
+}
+
+/// Writes the command lines that would be run by [`exec_groups`], one per line
+pub fn log_exec_script(
+    template: &ExecTemplate,
+    groups: &[ExecGroup],
+    batch_size: usize,
+    mut out: impl Write,
+) -> io::Result<usize> {
+    let mut count = 0;
+    for batch in groups.chunks(batch_size.max(1)) {
+        let args: Vec<Arg> = template.expand(batch).into_iter().map(Arg::from).collect();
+        writeln!(out, "{}", arg::join(&args))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::ErrorKind;
+
+    use super::*;
+
+    fn group(hash: u128, keep: &str, dupes: &[&str]) -> ExecGroup {
+        ExecGroup {
+            file_len: FileLen(100),
+            file_hash: FileHash(hash),
+            keep: Path::from(keep),
+            dupes: dupes.iter().map(|&d| Path::from(d)).collect(),
+        }
+    }
+
+    fn expand(template: &str, groups: &[ExecGroup]) -> Vec<OsString> {
+        ExecTemplate::parse(template).unwrap().expand(groups)
+    }
+
+    #[test]
+    fn expand_placeholders() {
+        let g = group(0xab, "/keep", &["/dupe1", "/dupe2"]);
+        assert_eq!(
+            expand(
+                "tool -v {keep} {dupes...} --size={size}",
+                std::slice::from_ref(&g)
+            ),
+            ["tool", "-v", "/keep", "/dupe1", "/dupe2", "--size=100"]
+        );
+        assert_eq!(
+            expand("tool '{hash}' {x}", &[g]),
+            ["tool", "000000000000000000000000000000ab", "{x}"]
+        );
+    }
+
+    #[test]
+    fn expand_batch_repeats_group_args() {
+        let g1 = group(1, "/k1", &["/d1"]);
+        let g2 = group(2, "/k2", &["/d2a", "/d2b"]);
+        assert_eq!(
+            expand("tool -v {keep} {dupes...} --", &[g1, g2]),
+            ["tool", "-v", "/k1", "/d1", "--", "/k2", "/d2a", "/d2b", "--"]
+        );
+    }
+
+    #[test]
+    fn parse_rejects_invalid_templates() {
+        for template in ["", "tool '{dupes...}x'", "tool 'unterminated"] {
+            let e = ExecTemplate::parse(template).unwrap_err();
+            assert!(matches!(e.kind, ErrorKind::Config), "{}", template);
+        }
+    }
+
+    #[test]
+    fn log_exec_script_prints_batches() {
+        let groups = [
+            group(1, "/k1", &["/d1"]),
+            group(2, "/k2", &["/d2"]),
+            group(3, "/k3", &["/d 3"]),
+        ];
+        let template = ExecTemplate::parse("ln -f {keep} {dupes...}").unwrap();
+        let mut out = Vec::new();
+        let count = log_exec_script(&template, &groups, 2, &mut out).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "ln -f /k1 /d1 /k2 /d2\nln -f /k3 '/d 3'\n"
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn exec_groups_counts_failures() {
+        let groups = [group(1, "/k1", &["/d1"]), group(2, "/k2", &["/d2"])];
+        let template =
+            ExecTemplate::parse("sh -c 'test {hash} = $0' 00000000000000000000000000000001")
+                .unwrap();
+        let result = exec_groups(&template, &groups, 1, 2, &Log::new()).unwrap();
+        assert_eq!(result.command_count, 2);
+        assert_eq!(result.failed_count, 1);
+    }
+}
@@ -0,0 +1,261 @@
+//! Mapping the data extents of sparse files and sharing the identical extents
+//! of two files with the native deduplication of the file system.
+
+use std::cmp::{max, min};
+use std::io;
+use std::ops::Range;
+
+use crate::file::{FileLen, FileMetadata};
+use crate::path::Path;
+
+/// Returns true if the file has holes, i.e. it takes less space on disk than its length.
+#[cfg(unix)]
+pub fn is_sparse(metadata: &FileMetadata) -> bool {
+    allocated_len(metadata) < metadata.len()
+}
+
+#[cfg(not(unix))]
+pub fn is_sparse(_metadata: &FileMetadata) -> bool {
+    false
+}
+
+/// Returns the disk space allocated for the data of the file.
+#[cfg(unix)]
+pub fn allocated_len(metadata: &FileMetadata) -> FileLen {
+    use std::os::unix::fs::MetadataExt;
+    // st_blocks is always given in 512-byte units, regardless of the block size
+    FileLen(metadata.blocks() * 512)
+}
+
+#[cfg(not(unix))]
+pub fn allocated_len(metadata: &FileMetadata) -> FileLen {
+    metadata.len()
+}
+
+/// Returns the byte ranges of the file holding data, in the order of their offsets.
+/// Holes are skipped. If the file system doesn't track holes,
+/// the whole file is returned as a single extent.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn data_extents(file: &std::fs::File) -> io::Result<Vec<Range<u64>>> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let seek = |offset: u64, whence: libc::c_int| {
+        let result = unsafe { libc::lseek64(fd, offset as libc::off64_t, whence) };
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(result as u64)
+        }
+    };
+
+    let len = file.metadata()?.len();
+    let mut result = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        let start = match seek(offset, libc::SEEK_DATA) {
+            Ok(start) => start,
+            // No more data until the end of the file
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+            Err(e) => return Err(e),
+        };
+        // There is always an implicit hole at the end of the file
+        let end = seek(start, libc::SEEK_HOLE)?;
+        result.push(start..end);
+        offset = end;
+    }
+    Ok(result)
+}
+
+/// Returns the ranges present in both lists of sorted, non-overlapping ranges.
+pub fn common_ranges(a: &[Range<u64>], b: &[Range<u64>]) -> Vec<Range<u64>> {
+    let mut result = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        let start = max(a[i].start, b[j].start);
+        let end = min(a[i].end, b[j].end);
+        if start < end {
+            result.push(start..end);
+        }
+        if a[i].end < b[j].end {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    result
+}
+
+/// Asks the file system to share the data of `range` of the `dest` file with the data
+/// at the same offsets of the `src` file, with the `FIDEDUPERANGE` ioctl.
+///
+/// The kernel compares the data and shares only the parts that are identical,
+/// so the contents of the files never change. Returns the number of shared bytes.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn dedupe_range(src: &std::fs::File, dest: &std::fs::File, range: Range<u64>) -> io::Result<u64> {
+    use nix::request_code_readwrite;
+    use std::os::unix::io::AsRawFd;
+
+    // From /usr/include/linux/fs.h:
+    // #define FIDEDUPERANGE _IOWR(0x94, 54, struct file_dedupe_range)
+    // #define FILE_DEDUPE_RANGE_SAME   0
+    // #define FILE_DEDUPE_RANGE_DIFFERS 1
+    const FIDEDUPERANGE_TYPE: u8 = 0x94;
+    const FIDEDUPERANGE_NR: u8 = 54;
+    const FIDEDUPERANGE_SIZE: usize = 24; // without the trailing array of destinations
+    const FILE_DEDUPE_RANGE_SAME: i32 = 0;
+    const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+    // Some file systems don't share more than 16 MiB in a single call
+    const MAX_CHUNK_LEN: u64 = 16 * 1024 * 1024;
+
+    #[repr(C)]
+    struct FileDedupeRangeInfo {
+        dest_fd: i64,
+        dest_offset: u64,
+        bytes_deduped: u64,
+        status: i32,
+        reserved: u32,
+    }
+
+    #[repr(C)]
+    struct FileDedupeRange {
+        src_offset: u64,
+        src_length: u64,
+        dest_count: u16,
+        reserved1: u16,
+        reserved2: u32,
+        info: [FileDedupeRangeInfo; 1],
+    }
+
+    let mut deduped = 0;
+    let mut offset = range.start;
+    while offset < range.end {
+        let len = min(range.end - offset, MAX_CHUNK_LEN);
+        let mut arg = FileDedupeRange {
+            src_offset: offset,
+            src_length: len,
+            dest_count: 1,
+            reserved1: 0,
+            reserved2: 0,
+            info: [FileDedupeRangeInfo {
+                dest_fd: dest.as_raw_fd() as i64,
+                dest_offset: offset,
+                bytes_deduped: 0,
+                status: 0,
+                reserved: 0,
+            }],
+        };
+        let ret = unsafe {
+            libc::ioctl(
+                src.as_raw_fd(),
+                request_code_readwrite!(FIDEDUPERANGE_TYPE, FIDEDUPERANGE_NR, FIDEDUPERANGE_SIZE),
+                &mut arg as *mut FileDedupeRange,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let info = &arg.info[0];
+        match info.status {
+            FILE_DEDUPE_RANGE_SAME if info.bytes_deduped > 0 => {
+                deduped += info.bytes_deduped;
+                offset += info.bytes_deduped;
+            }
+            // Partially duplicate files: skip the chunk that differs and try the next one
+            FILE_DEDUPE_RANGE_SAME | FILE_DEDUPE_RANGE_DIFFERS => offset += len,
+            status => return Err(io::Error::from_raw_os_error(-status)),
+        }
+    }
+    Ok(deduped)
+}
+
+/// Shares the data extents of the `link` file that are identical to the extents
+/// at the same offsets of the `target` file. Holes of both files are left unallocated.
+/// Returns the number of bytes shared.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn dedupe_extents(target: &Path, link: &Path) -> io::Result<FileLen> {
+    use std::fs::{File, OpenOptions};
+
+    let _ = crate::lock::FileLock::new(link)?; // don't touch a locked file
+    let result = (|| {
+        let src = File::open(target.to_path_buf())?;
+        // The kernel requires the destination to be open for writing
+        let dest = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(link.to_path_buf())?;
+        let ranges = common_ranges(&data_extents(&src)?, &data_extents(&dest)?);
+        let mut deduped = 0;
+        for range in ranges {
+            deduped += dedupe_range(&src, &dest, range)?;
+        }
+        Ok(FileLen(deduped))
+    })();
+    result.map_err(|e: io::Error| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "Failed to deduplicate extents of {} -> {}: {}",
+                link.display(),
+                target.display(),
+                e
+            ),
+        )
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn dedupe_extents(_target: &Path, link: &Path) -> io::Result<FileLen> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "Failed to deduplicate extents of {}: supported only on Linux",
+            link.display()
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_common_ranges() {
+        let a = [0..10, 20..30, 40..50];
+        let b = [5..25, 28..45];
+        assert_eq!(common_ranges(&a, &b), [5..10, 20..25, 28..30, 40..45]);
+        assert_eq!(common_ranges(&a, &[]), []);
+        assert_eq!(common_ranges(&a, &a), a);
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn test_data_extents_skip_holes() {
+        use std::fs::OpenOptions;
+        use std::io::{Seek, SeekFrom, Write};
+
+        use crate::util::test::with_dir;
+
+        with_dir("extent/data_extents", |root| {
+            const MB: u64 = 1024 * 1024;
+            let path = root.join("sparse");
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&path)
+                .unwrap();
+            file.set_len(4 * MB).unwrap();
+            file.write_all(&[1; 4096]).unwrap();
+            file.seek(SeekFrom::Start(2 * MB)).unwrap();
+            file.write_all(&[2; 4096]).unwrap();
+            file.sync_all().unwrap();
+
+            let extents = data_extents(&file).unwrap();
+            assert_eq!(extents.first().map(|e| e.start), Some(0));
+            assert!(extents.iter().any(|e| e.contains(&(2 * MB))));
+            assert!(extents.iter().all(|e| e.end <= 4 * MB));
+        });
+    }
+}
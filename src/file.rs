@@ -3,7 +3,7 @@
 
 use core::fmt;
 use std::fmt::Display;
-use std::hash::Hash;
+use std::hash::{Hash, Hasher};
 use std::io::{ErrorKind, SeekFrom};
 use std::iter::Sum;
 use std::ops::{Add, AddAssign, BitXor, Deref, Mul, Sub};
@@ -11,12 +11,13 @@ use std::{fs, io};
 
 use byte_unit::Byte;
 use bytesize::ByteSize;
+use data_encoding::{BASE32, BASE64, HEXLOWER};
 use serde::*;
 use smallvec::alloc::fmt::Formatter;
 use smallvec::alloc::str::FromStr;
 
 use crate::device::DiskDevices;
-use crate::log::Log;
+use crate::log::{LogMessage, LogSink};
 use crate::path::Path;
 
 /// Represents data position in the file, counted from the beginning of the file, in bytes.
@@ -29,6 +30,29 @@ impl FilePos {
     pub fn zero() -> FilePos {
         FilePos(0)
     }
+
+    /// Returns the largest multiple of `align` that is less than or equal to this position.
+    ///
+    /// # Panics
+    /// If `align` is zero.
+    pub fn align_down(self, align: u64) -> FilePos {
+        assert!(align > 0, "Alignment must be greater than zero");
+        FilePos(self.0 - self.0 % align)
+    }
+
+    /// Returns the smallest multiple of `align` that is greater than or equal to this position.
+    ///
+    /// # Panics
+    /// If `align` is zero or if the result doesn't fit in `u64`.
+    pub fn align_up(self, align: u64) -> FilePos {
+        assert!(align > 0, "Alignment must be greater than zero");
+        FilePos(
+            self.0
+                .div_ceil(align)
+                .checked_mul(align)
+                .expect("Aligned file position out of range"),
+        )
+    }
 }
 
 impl Display for FilePos {
@@ -93,6 +117,40 @@ impl FileLen {
     pub fn as_pos(self) -> FilePos {
         FilePos(self.0)
     }
+
+    /// Converts the length to a floating point number of bytes,
+    /// e.g. for computing percentages or throughput.
+    ///
+    /// `f64` represents integers exactly only up to 2^53 (8 PiB), so longer lengths
+    /// are rounded to the nearest representable value. This is fine for statistics,
+    /// but the result must not be converted back to `FileLen`.
+    pub fn as_f64(self) -> f64 {
+        self.0 as f64
+    }
+
+    /// Returns the smallest multiple of `align` that is greater than or equal to this length.
+    /// Useful for computing the space taken by a file on a file system with given block size.
+    ///
+    /// # Panics
+    /// If `align` is zero or if the result doesn't fit in `u64`.
+    pub fn round_up_to(self, align: FileLen) -> FileLen {
+        assert!(align.0 > 0, "Alignment must be greater than zero");
+        FileLen(
+            self.to_blocks(align.0)
+                .checked_mul(align.0)
+                .expect("Rounded file length out of range"),
+        )
+    }
+
+    /// Returns the number of blocks of given size needed to store this many bytes,
+    /// counting the last partially filled block as a whole block.
+    ///
+    /// # Panics
+    /// If `block_size` is zero.
+    pub fn to_blocks(self, block_size: u64) -> u64 {
+        assert!(block_size > 0, "Block size must be greater than zero");
+        self.0.div_ceil(block_size)
+    }
 }
 
 impl From<u64> for FileLen {
@@ -167,6 +225,91 @@ impl Display for FileLen {
     }
 }
 
+/// An inclusive range of file lengths used to select the files to process.
+///
+/// Parsed from expressions like `1MiB..100MiB`, `..=4096` or `10GB..`.
+/// As in Rust, the upper bound is exclusive for `..` and inclusive for `..=`.
+/// The lower bound is always inclusive. Each bound accepts the same units as [`FileLen`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct FileLenRange {
+    /// The smallest accepted length
+    pub min: FileLen,
+    /// The largest accepted length
+    pub max: FileLen,
+}
+
+impl FileLenRange {
+    /// Returns a range accepting files of any length
+    pub fn full() -> FileLenRange {
+        FileLenRange {
+            min: FileLen(0),
+            max: FileLen::MAX,
+        }
+    }
+
+    pub fn contains(&self, len: FileLen) -> bool {
+        len >= self.min && len <= self.max
+    }
+
+    /// Returns the range of lengths accepted by both ranges, or `None` if there are none.
+    pub fn intersect(&self, other: &FileLenRange) -> Option<FileLenRange> {
+        let min = self.min.max(other.min);
+        let max = self.max.min(other.max);
+        (min <= max).then_some(FileLenRange { min, max })
+    }
+}
+
+impl FromStr for FileLenRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_bound = |bound: &str| {
+            FileLen::from_str(bound.trim())
+                .map_err(|e| format!("Invalid size range bound '{}': {}", bound.trim(), e))
+        };
+        let (start, end) = s
+            .split_once("..")
+            .ok_or_else(|| format!("Invalid size range '{}': expected <min>..<max>", s))?;
+        let min = match start.trim() {
+            "" => FileLen(0),
+            start => parse_bound(start)?,
+        };
+        let max = match end.strip_prefix('=') {
+            Some(end) => parse_bound(end)?,
+            None if end.trim().is_empty() => FileLen::MAX,
+            None => {
+                let end = parse_bound(end)?;
+                if end <= min {
+                    return Err(format!(
+                        "Invalid size range '{}': the lower bound must be smaller than \
+                         the exclusive upper bound",
+                        s
+                    ));
+                }
+                FileLen(end.0 - 1)
+            }
+        };
+        if min > max {
+            return Err(format!(
+                "Invalid size range '{}': the lower bound must not be larger than \
+                 the upper bound",
+                s
+            ));
+        }
+        Ok(FileLenRange { min, max })
+    }
+}
+
+impl Display for FileLenRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.max == FileLen::MAX {
+            write!(f, "{}..", self.min.0)
+        } else {
+            write!(f, "{}..={}", self.min.0, self.max.0)
+        }
+    }
+}
+
 pub trait AsPath {
     fn path(&self) -> &Path;
 }
@@ -200,14 +343,19 @@ impl FileChunk<'_> {
     }
 }
 
-/// Useful for identifying files in presence of hardlinks
-#[derive(Clone, Copy, PartialEq, Eq, Hash)]
-pub(crate) struct FileId {
+/// Identifies a file on a device, regardless of the path it was reached by.
+/// Useful for identifying files in presence of hardlinks.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct FileId {
+    /// Inode number or the Windows file index
     pub inode: u128,
+    /// Device number or the Windows volume serial number
     pub device: u64,
 }
 
 impl FileId {
+    /// Reads the identifier of the file at given path.
+    /// Follows symbolic links.
     #[cfg(unix)]
     pub fn new(file: &Path) -> io::Result<FileId> {
         use std::os::unix::fs::MetadataExt;
@@ -223,6 +371,8 @@ impl FileId {
         }
     }
 
+    /// Reads the identifier of the file at given path.
+    /// Follows symbolic links.
     #[cfg(windows)]
     pub fn new(file: &Path) -> io::Result<FileId> {
         Self::from_file(&fs::File::open(file.to_path_buf())?).map_err(|_| {
@@ -266,23 +416,31 @@ impl FileId {
     }
 }
 
-pub(crate) fn file_id_or_log_err(file: &Path, log: &Log) -> Option<FileId> {
+pub(crate) fn file_id_or_log_err(file: &Path, log: &dyn LogSink) -> Option<FileId> {
     match FileId::new(file) {
         Ok(id) => Some(id),
         Err(e) if e.kind() == ErrorKind::NotFound => None,
         Err(e) => {
-            log.warn(e);
+            log.warn(
+                LogMessage::new(e.to_string())
+                    .with_path(file)
+                    .with_error(&e),
+            );
             None
         }
     }
 }
 
-/// Convenience wrapper for accessing OS-dependent metadata like inode and device-id
+/// Convenience wrapper for accessing OS-dependent metadata like inode and device-id.
+///
+/// Two `FileMetadata` values are equal if they describe the same file,
+/// i.e. they have the same device and inode identifiers. This makes it possible
+/// to use `FileMetadata` as a key for detecting hard links.
 #[derive(Debug)]
 pub struct FileMetadata {
     metadata: fs::Metadata,
     #[cfg(windows)]
-    file: fs::File,
+    id: FileId,
 }
 
 impl FileMetadata {
@@ -290,10 +448,12 @@ impl FileMetadata {
         let path_buf = path.to_path_buf();
         let metadata = fs::symlink_metadata(&path_buf)?;
 
+        // File identifiers on Windows are available only through an open file handle.
+        // Read them now, so we don't have to keep the file open.
         #[cfg(windows)]
         {
-            let file = fs::File::open(&path_buf)?;
-            Ok(FileMetadata { metadata, file })
+            let id = FileId::from_file(&fs::File::open(&path_buf)?)?;
+            Ok(FileMetadata { metadata, id })
         }
         #[cfg(not(windows))]
         Ok(FileMetadata { metadata })
@@ -311,7 +471,7 @@ impl FileMetadata {
 
     #[cfg(windows)]
     pub fn device_id(&self) -> io::Result<u64> {
-        FileId::from_file(&self.file).map(|f| f.device)
+        Ok(self.id.device)
     }
 
     #[cfg(unix)]
@@ -322,7 +482,48 @@ impl FileMetadata {
 
     #[cfg(windows)]
     pub fn inode_id(&self) -> io::Result<u128> {
-        FileId::from_file(&self.file).map(|f| f.inode)
+        Ok(self.id.inode)
+    }
+
+    /// Returns the number of hard links pointing to the file
+    #[cfg(unix)]
+    pub fn hard_link_count(&self) -> io::Result<u64> {
+        use std::os::unix::fs::MetadataExt;
+        Ok(self.metadata.nlink())
+    }
+
+    #[cfg(not(unix))]
+    pub fn hard_link_count(&self) -> io::Result<u64> {
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "Counting hard links is not supported on this platform",
+        ))
+    }
+
+    /// Returns the device and inode identifiers that uniquely identify the file
+    #[cfg(unix)]
+    fn device_and_inode(&self) -> (u64, u128) {
+        use std::os::unix::fs::MetadataExt;
+        (self.metadata.dev(), self.metadata.ino() as u128)
+    }
+
+    #[cfg(windows)]
+    fn device_and_inode(&self) -> (u64, u128) {
+        (self.id.device, self.id.inode)
+    }
+}
+
+impl PartialEq for FileMetadata {
+    fn eq(&self, other: &Self) -> bool {
+        self.device_and_inode() == other.device_and_inode()
+    }
+}
+
+impl Eq for FileMetadata {}
+
+impl Hash for FileMetadata {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.device_and_inode().hash(state)
     }
 }
 
@@ -334,13 +535,14 @@ impl Deref for FileMetadata {
     }
 }
 
+/// Path and length of a regular file, together with the device it is stored on
 #[derive(Clone, Debug)]
-pub(crate) struct FileInfo {
-    pub path: Path,
-    pub len: FileLen,
+pub struct FileInfo {
+    pub(crate) path: Path,
+    pub(crate) len: FileLen,
     // physical on-disk location of file data for access ordering optimisation
     // the highest 16 bits encode the device id
-    pub location: u64,
+    pub(crate) location: u64,
 }
 
 impl AsPath for FileInfo {
@@ -355,17 +557,100 @@ impl IntoPath for FileInfo {
     }
 }
 
+/// Set after the first `statx` call failed because the kernel doesn't support it,
+/// so we don't waste time on calling it again
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+static STATX_UNSUPPORTED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Reads the length and the inode number of a file with a single `statx` call,
+/// asking the kernel only for the fields we need. Doesn't follow symbolic links.
+///
+/// If `cached` is set, network file systems may serve the fields from their attribute cache
+/// without asking the server (`AT_STATX_DONT_SYNC`), so the length may be stale.
+/// Otherwise the fields are as up-to-date as the ones returned by `stat`.
+///
+/// Returns `Ok(None)` if `statx` is not available, e.g. on kernels older than 4.11
+/// or when blocked by a seccomp filter. The caller should fall back to `FileMetadata` then.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn statx_len_and_inode(path: &Path, cached: bool) -> io::Result<Option<(FileLen, u64)>> {
+    use std::ffi::CString;
+    use std::mem::MaybeUninit;
+    use std::os::unix::ffi::OsStrExt;
+    use std::sync::atomic::Ordering;
+
+    if STATX_UNSUPPORTED.load(Ordering::Relaxed) {
+        return Ok(None);
+    }
+    let c_path = CString::new(path.to_path_buf().as_os_str().as_bytes())?;
+    let sync = if cached {
+        libc::AT_STATX_DONT_SYNC
+    } else {
+        libc::AT_STATX_SYNC_AS_STAT
+    };
+    let mut buf = MaybeUninit::<libc::statx>::zeroed();
+    let result = unsafe {
+        libc::statx(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW | sync,
+            libc::STATX_SIZE | libc::STATX_INO,
+            buf.as_mut_ptr(),
+        )
+    };
+    if result != 0 {
+        let error = io::Error::last_os_error();
+        return match error.raw_os_error() {
+            Some(libc::ENOSYS) | Some(libc::EPERM) => {
+                STATX_UNSUPPORTED.store(true, Ordering::Relaxed);
+                Ok(None)
+            }
+            _ => Err(error),
+        };
+    }
+    let buf = unsafe { buf.assume_init() };
+    // The kernel may not fill in the fields we asked for, e.g. on some network file systems:
+    let required_mask = libc::STATX_SIZE | libc::STATX_INO;
+    if buf.stx_mask & required_mask != required_mask {
+        return Ok(None);
+    }
+    Ok(Some((FileLen(buf.stx_size), buf.stx_ino)))
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+fn statx_len_and_inode(_path: &Path, _cached: bool) -> io::Result<Option<(FileLen, u64)>> {
+    Ok(None)
+}
+
 const OFFSET_MASK: u64 = 0x0000FFFFFFFFFFFF;
 
 #[cfg(target_os = "linux")]
 const DEVICE_MASK: u64 = 0xFFFF000000000000;
 
 impl FileInfo {
-    fn new(path: Path, devices: &DiskDevices) -> io::Result<FileInfo> {
+    /// Reads the metadata of the file at given path.
+    /// The path is expected to point to a regular file. The directory walk already knows
+    /// the file type from the directory entry, so it is not queried again here.
+    pub fn new(path: Path, devices: &DiskDevices) -> io::Result<FileInfo> {
+        Self::read(path, devices, false)
+    }
+
+    /// Like [`FileInfo::new`], but lets network file systems answer from their attribute
+    /// cache without asking the server, which is faster on Linux.
+    /// The length may be stale, so use this only where the length doesn't matter,
+    /// e.g. for ordering reads by the location of the files.
+    pub(crate) fn new_cached(path: Path, devices: &DiskDevices) -> io::Result<FileInfo> {
+        Self::read(path, devices, true)
+    }
+
+    fn read(path: Path, devices: &DiskDevices, cached: bool) -> io::Result<FileInfo> {
         let device_index = devices.get_by_path(&path).index as u64;
-        let metadata = FileMetadata::new(&path)?;
-        let file_len = metadata.len();
-        let inode_id = metadata.inode_id()? as u64;
+        let (file_len, inode_id) = match statx_len_and_inode(&path, cached)? {
+            Some(result) => result,
+            None => {
+                let metadata = FileMetadata::new(&path)?;
+                (metadata.len(), metadata.inode_id()? as u64)
+            }
+        };
         Ok(FileInfo {
             path,
             len: file_len,
@@ -373,13 +658,59 @@ impl FileInfo {
         })
     }
 
+    /// Returns the ordering key of the file on its device, without the device index
+    pub(crate) fn location_on_device(&self) -> u64 {
+        self.location & OFFSET_MASK
+    }
+
+    /// Returns the path of the file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the length of the file at the time it was scanned
+    pub fn len(&self) -> FileLen {
+        self.len
+    }
+
+    /// Returns true if the file was empty at the time it was scanned
+    pub fn is_empty(&self) -> bool {
+        self.len.0 == 0
+    }
+
     /// Returns the device index into the `DiskDevices` instance passed at creation
     pub fn get_device_index(&self) -> usize {
         (self.location >> 48) as usize
     }
 
+    /// Returns all fields of this file as a JSON object, for debugging and diagnostics.
+    ///
+    /// The `location` field holds the ordering key of the file on its device:
+    /// the inode number, or the physical offset of its data divided by 256
+    /// if it was fetched from the file system.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "path": self.path,
+            "len": self.len,
+            "device_index": self.get_device_index(),
+            "location": self.location & OFFSET_MASK,
+        })
+    }
+
+    /// Formats the location of the file as `device:<index> offset:<hex>`, for diagnostics.
+    ///
+    /// Like in [`FileInfo::to_json`], the offset is the ordering key of the file on its device:
+    /// the inode number, or the physical offset of its data divided by 256.
+    pub fn physical_location_str(&self) -> String {
+        format!(
+            "device:{} offset:{:#x}",
+            self.get_device_index(),
+            self.location_on_device()
+        )
+    }
+
     #[cfg(target_os = "linux")]
-    pub fn fetch_physical_location(&mut self) -> io::Result<u64> {
+    pub(crate) fn fetch_physical_location(&mut self) -> io::Result<u64> {
         let new_location = get_physical_file_location(self.path())?;
         if let Some(new_location) = new_location {
             self.location = self.location & DEVICE_MASK | (new_location >> 8) & OFFSET_MASK;
@@ -393,13 +724,18 @@ impl FileInfo {
 pub(crate) fn file_info_or_log_err(
     file: Path,
     devices: &DiskDevices,
-    log: &Log,
+    log: &dyn LogSink,
 ) -> Option<FileInfo> {
+    let path = file.clone();
     match FileInfo::new(file, devices) {
         Ok(info) => Some(info),
         Err(e) if e.kind() == ErrorKind::NotFound => None,
         Err(e) => {
-            log.warn(e);
+            log.warn(
+                LogMessage::new(e.to_string())
+                    .with_path(&path)
+                    .with_error(&e),
+            );
             None
         }
     }
@@ -415,9 +751,140 @@ pub(crate) fn get_physical_file_location(path: &Path) -> io::Result<Option<u64>>
     }
 }
 
+/// Returns the allocation unit of the file system holding the file.
+/// Returns `None` if it cannot be determined.
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)] // c_ulong is not u64 on all platforms
+pub(crate) fn fs_block_size(path: &Path) -> Option<FileLen> {
+    match nix::sys::statvfs::statvfs(&path.to_path_buf()) {
+        Ok(stat) if stat.fragment_size() > 0 => Some(FileLen(stat.fragment_size() as u64)),
+        _ => None,
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn fs_block_size(_path: &Path) -> Option<FileLen> {
+    None
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub struct FileHash(pub u128);
 
+impl FileHash {
+    /// The hash assigned to empty files without reading them.
+    pub const ZERO: FileHash = FileHash(0);
+
+    /// Returns true if all bits of the hash are zero
+    pub fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Keeps only the `bits` most significant bits of the hash and sets the remaining
+    /// lower bits to zero. Useful for checking how grouping behaves with a smaller hash space.
+    ///
+    /// # Panics
+    /// If `bits` is greater than 128.
+    pub fn truncate(self, bits: u8) -> FileHash {
+        assert!(bits <= 128, "Cannot truncate hash to {} bits", bits);
+        match bits {
+            0 => FileHash(0),
+            bits => FileHash(self.0 & (u128::MAX << (128 - bits as u32))),
+        }
+    }
+
+    /// Deterministically derives a hash from the given seeds, for generating test data.
+    ///
+    /// Each seed is mixed into the state with the SplitMix64 finalizer, so the result
+    /// depends on the order of the seeds. A single seed maps to a unique hash,
+    /// because every step of the mixing is a bijection.
+    /// Available only in tests and with the `test-utils` feature.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn mix(seeds: &[u64]) -> FileHash {
+        fn split_mix_64(x: u64) -> u64 {
+            let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        }
+        let state = seeds
+            .iter()
+            .fold(0, |state, &seed| split_mix_64(state ^ seed));
+        let high = split_mix_64(state);
+        let low = split_mix_64(high);
+        FileHash(((high as u128) << 64) | low as u128)
+    }
+
+    /// Formats the hash in the given encoding.
+    /// The hex encoding gives the same result as [`Display`].
+    pub fn encode(&self, encoding: HashEncoding) -> String {
+        let bytes = self.0.to_be_bytes();
+        match encoding {
+            HashEncoding::Hex => HEXLOWER.encode(&bytes),
+            HashEncoding::Base64 => BASE64.encode(&bytes),
+            HashEncoding::Base32 => BASE32.encode(&bytes),
+        }
+    }
+
+    /// Parses a hash written in any of the supported encodings.
+    /// The encoding is recognized by the length of the string and the padding.
+    pub fn decode(s: &str) -> Result<FileHash, String> {
+        let error = |e: &dyn Display| format!("Invalid hash {}: {}", s, e);
+        let bytes = if s.len() == 24 && s.ends_with("==") {
+            BASE64.decode(s.as_bytes()).map_err(|e| error(&e))?
+        } else if s.ends_with('=') {
+            BASE32.decode(s.as_bytes()).map_err(|e| error(&e))?
+        } else {
+            // Hex hashes may have leading zeroes stripped
+            return u128::from_str_radix(s, 16)
+                .map(FileHash)
+                .map_err(|e| error(&e));
+        };
+        let bytes: [u8; 16] = bytes.try_into().map_err(|_| error(&"expected 128 bits"))?;
+        Ok(FileHash(u128::from_be_bytes(bytes)))
+    }
+}
+
+/// Encoding of hashes written to reports.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HashEncoding {
+    /// 32 lowercase hexadecimal digits.
+    #[default]
+    Hex,
+    /// 24 characters of the standard base64 alphabet, with padding.
+    Base64,
+    /// 32 characters of the RFC 4648 base32 alphabet, with padding.
+    Base32,
+}
+
+impl HashEncoding {
+    pub fn variants() -> Vec<&'static str> {
+        vec!["hex", "base64", "base32"]
+    }
+}
+
+impl Display for HashEncoding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            HashEncoding::Hex => f.pad("hex"),
+            HashEncoding::Base64 => f.pad("base64"),
+            HashEncoding::Base32 => f.pad("base32"),
+        }
+    }
+}
+
+impl FromStr for HashEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "hex" => Ok(HashEncoding::Hex),
+            "base64" => Ok(HashEncoding::Base64),
+            "base32" => Ok(HashEncoding::Base32),
+            s => Err(format!("Unrecognized hash encoding: {}", s)),
+        }
+    }
+}
+
 pub trait AsFileHash {
     fn as_file_hash(&self) -> &FileHash;
 }
@@ -463,8 +930,7 @@ impl<'de> Deserialize<'de> for FileHash {
         D: Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        let hash_value = u128::from_str_radix(s.as_str(), 16).map_err(serde::de::Error::custom)?;
-        Ok(FileHash(hash_value))
+        FileHash::decode(&s).map_err(serde::de::Error::custom)
     }
 }
 
@@ -476,12 +942,265 @@ pub(crate) enum FileAccess {
 
 #[cfg(test)]
 mod test {
+    use std::collections::HashSet;
+
+    use proptest::prelude::*;
+
     use super::*;
 
+    #[test]
+    fn test_as_f64() {
+        assert_eq!(FileLen(0).as_f64(), 0.0);
+        assert_eq!(FileLen(1 << 53).as_f64(), 9007199254740992.0);
+        // Not exactly representable, rounded to the nearest even value:
+        assert_eq!(FileLen((1 << 53) + 1).as_f64(), 9007199254740992.0);
+    }
+
+    #[test]
+    fn test_parse_file_len_range() {
+        let range = |s: &str| FileLenRange::from_str(s).unwrap();
+        assert_eq!(range("1KiB..2KiB").min, FileLen(1024));
+        assert_eq!(range("1KiB..2KiB").max, FileLen(2047));
+        assert_eq!(range("1KiB..=2KiB").max, FileLen(2048));
+        assert_eq!(
+            range("..=4096"),
+            FileLenRange {
+                min: FileLen(0),
+                max: FileLen(4096)
+            }
+        );
+        assert_eq!(range("10GB..").min, FileLen(10_000_000_000));
+        assert_eq!(range("10GB..").max, FileLen::MAX);
+        assert_eq!(range(" 5 .. 10 ").max, FileLen(9));
+        assert_eq!(
+            range("5..=5"),
+            FileLenRange {
+                min: FileLen(5),
+                max: FileLen(5)
+            }
+        );
+        assert!(FileLenRange::from_str("100MiB..1MiB").is_err());
+        assert!(FileLenRange::from_str("5..5").is_err());
+        assert!(FileLenRange::from_str("..0").is_err());
+        assert!(FileLenRange::from_str("100MiB").is_err());
+        assert!(FileLenRange::from_str("1foo..").is_err());
+    }
+
+    #[test]
+    fn file_len_range_bounds() {
+        let exclusive = FileLenRange::from_str("..4096").unwrap();
+        let inclusive = FileLenRange::from_str("..=4096").unwrap();
+        assert!(exclusive.contains(FileLen(4095)));
+        assert!(!exclusive.contains(FileLen(4096)));
+        assert!(inclusive.contains(FileLen(4096)));
+        assert!(!inclusive.contains(FileLen(4097)));
+        assert_eq!(exclusive.to_string(), "0..=4095");
+        assert_eq!(FileLenRange::full().to_string(), "0..");
+        assert_eq!(exclusive.intersect(&inclusive), Some(exclusive));
+        assert_eq!(
+            exclusive.intersect(&FileLenRange::from_str("5000..").unwrap()),
+            None
+        );
+    }
+
     #[test]
     fn test_format_bytes() {
         let file_len = FileLen(16000);
         let human_readable = format!("{}", file_len);
         assert_eq!(human_readable, "16.0 KB");
     }
+
+    #[test]
+    fn test_truncate_hash() {
+        let hash = FileHash(0x0123456789abcdef0123456789abcdef);
+        assert_eq!(hash.truncate(128), hash);
+        assert_eq!(hash.truncate(0), FileHash(0));
+        assert_eq!(hash.truncate(4), FileHash(0));
+        assert_eq!(
+            hash.truncate(16),
+            FileHash(0x01230000000000000000000000000000)
+        );
+        assert_eq!(hash.truncate(127), FileHash(hash.0 & !1));
+    }
+
+    #[test]
+    fn test_mix_hash() {
+        assert_eq!(FileHash::mix(&[1, 2]), FileHash::mix(&[1, 2]));
+        assert_ne!(FileHash::mix(&[1, 2]), FileHash::mix(&[2, 1]));
+        assert_ne!(FileHash::mix(&[1]), FileHash::mix(&[1, 0]));
+        assert!(!FileHash::mix(&[]).is_zero());
+        let hashes: HashSet<_> = (0..1000).map(|i| FileHash::mix(&[i])).collect();
+        assert_eq!(hashes.len(), 1000);
+    }
+
+    #[test]
+    fn test_hash_is_zero() {
+        assert!(FileHash::ZERO.is_zero());
+        assert!(FileHash(0x1234).truncate(4).is_zero());
+        assert!(!FileHash(1).is_zero());
+    }
+
+    #[test]
+    fn test_hash_encodings() {
+        let hash = FileHash(0x00112233445566778899aabbccddeeff);
+        assert_eq!(hash.encode(HashEncoding::Hex), hash.to_string());
+        assert_eq!(
+            hash.encode(HashEncoding::Base64),
+            "ABEiM0RVZneImaq7zN3u/w=="
+        );
+        assert_eq!(
+            hash.encode(HashEncoding::Base32),
+            "AAISEM2EKVTHPCEZVK54ZXPO74======"
+        );
+        assert_eq!(FileHash::decode("ff"), Ok(FileHash(0xff)));
+        assert!(FileHash::decode("not a hash").is_err());
+        assert!(FileHash::decode("AAAA====").is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn test_hash_encodings_roundtrip(value: u128) {
+            let hash = FileHash(value);
+            for encoding in [HashEncoding::Hex, HashEncoding::Base64, HashEncoding::Base32] {
+                prop_assert_eq!(FileHash::decode(&hash.encode(encoding)), Ok(hash));
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_truncate_hash_panics_above_128_bits() {
+        FileHash(1).truncate(129);
+    }
+
+    #[test]
+    fn file_info_to_json() {
+        let info = FileInfo {
+            path: Path::from("/foo/bar"),
+            len: FileLen(1024),
+            location: 3 << 48 | 0x1234,
+        };
+        assert_eq!(
+            info.to_json(),
+            serde_json::json!({
+                "path": "/foo/bar",
+                "len": 1024,
+                "device_index": 3,
+                "location": 0x1234,
+            })
+        );
+    }
+
+    #[test]
+    fn file_info_physical_location_str() {
+        let info = FileInfo {
+            path: Path::from("/foo/bar"),
+            len: FileLen(1024),
+            location: 3 << 48 | 0xab12,
+        };
+        assert_eq!(info.physical_location_str(), "device:3 offset:0xab12");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn statx_agrees_with_metadata() {
+        use std::io::Write;
+        use std::os::unix::fs::MetadataExt;
+
+        let root = std::path::PathBuf::from("target/test/statx_agrees_with_metadata");
+        fs::create_dir_all(&root).unwrap();
+        let file = root.join("file");
+        fs::File::create(&file)
+            .unwrap()
+            .write_all(b"some contents")
+            .unwrap();
+        let metadata = fs::metadata(&file).unwrap();
+        for cached in [false, true] {
+            if let Some((len, inode)) = statx_len_and_inode(&Path::from(&file), cached).unwrap() {
+                assert_eq!(len, FileLen(metadata.len()));
+                assert_eq!(inode, metadata.ino());
+            }
+        }
+    }
+
+    #[test]
+    fn metadata_of_hard_links_are_equal() {
+        use std::collections::HashSet;
+
+        let root = std::path::PathBuf::from("target/test/metadata_of_hard_links_are_equal");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let file = root.join("file");
+        let link = root.join("link");
+        let other = root.join("other");
+        fs::File::create(&file).unwrap();
+        fs::File::create(&other).unwrap();
+        fs::hard_link(&file, &link).unwrap();
+
+        let file = FileMetadata::new(&Path::from(&file)).unwrap();
+        let link = FileMetadata::new(&Path::from(&link)).unwrap();
+        let other = FileMetadata::new(&Path::from(&other)).unwrap();
+        assert!(file == link);
+        assert!(file != other);
+
+        let set: HashSet<_> = [file, link, other].into_iter().collect();
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    fn test_round_up_to() {
+        let block = FileLen(4096);
+        assert_eq!(FileLen(0).round_up_to(block), FileLen(0));
+        assert_eq!(FileLen(1).round_up_to(block), FileLen(4096));
+        assert_eq!(FileLen(4096).round_up_to(block), FileLen(4096));
+        assert_eq!(FileLen(4097).round_up_to(block), FileLen(8192));
+        assert_eq!(FileLen(10).round_up_to(FileLen(3)), FileLen(12));
+        assert_eq!(FileLen(7).round_up_to(FileLen(1)), FileLen(7));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_round_up_to_zero_panics() {
+        FileLen(1).round_up_to(FileLen(0));
+    }
+
+    #[test]
+    fn test_to_blocks() {
+        assert_eq!(FileLen(0).to_blocks(4096), 0);
+        assert_eq!(FileLen(1).to_blocks(4096), 1);
+        assert_eq!(FileLen(4096).to_blocks(4096), 1);
+        assert_eq!(FileLen(4097).to_blocks(4096), 2);
+        assert_eq!(FileLen::MAX.to_blocks(1), u64::MAX);
+        assert_eq!(FileLen::MAX.to_blocks(2), u64::MAX / 2 + 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_to_blocks_zero_panics() {
+        FileLen(1).to_blocks(0);
+    }
+
+    #[test]
+    fn test_align_file_pos() {
+        assert_eq!(FilePos(0).align_down(4096), FilePos(0));
+        assert_eq!(FilePos(4095).align_down(4096), FilePos(0));
+        assert_eq!(FilePos(4096).align_down(4096), FilePos(4096));
+        assert_eq!(FilePos(u64::MAX).align_down(2), FilePos(u64::MAX - 1));
+        assert_eq!(FilePos(0).align_up(4096), FilePos(0));
+        assert_eq!(FilePos(1).align_up(4096), FilePos(4096));
+        assert_eq!(FilePos(4096).align_up(4096), FilePos(4096));
+        assert_eq!(FilePos(4097).align_up(4096), FilePos(8192));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_align_down_zero_panics() {
+        FilePos(1).align_down(0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_align_up_zero_panics() {
+        FilePos(1).align_up(0);
+    }
 }
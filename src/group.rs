@@ -2,22 +2,27 @@
 
 use std::cell::RefCell;
 use std::cmp::{max, min, Reverse};
+use std::collections::hash_map::Entry;
 use std::collections::BTreeMap;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env::{args_os, current_dir};
 use std::ffi::{OsStr, OsString};
 use std::fs::File;
 use std::hash::Hash;
 use std::io;
-use std::io::BufWriter;
+use std::io::{BufWriter, Write};
 use std::iter::FromIterator;
 use std::marker::PhantomData;
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::sync_channel;
+use std::sync::{Arc, Mutex};
 
-use chrono::{DateTime, Local};
-use console::Term;
+use chrono::{DateTime, Local, TimeZone, Timelike, Utc};
+use crossbeam_channel::{Receiver, Sender};
 use crossbeam_utils::thread;
+use dashmap::DashMap;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use itertools::Itertools;
 use rayon::prelude::*;
 use serde::*;
@@ -26,17 +31,27 @@ use sysinfo::DiskType;
 use thread_local::ThreadLocal;
 
 use crate::arg::Arg;
+use crate::btrfs::is_in_read_only_subvolume;
 use crate::cache::HashCache;
+use crate::checksum::{compute_checksums, write_checksum_lines};
 use crate::config::*;
+use crate::denied::DeniedAccess;
 use crate::device::{DiskDevice, DiskDevices};
+use crate::empty_dir::find_empty_dirs;
 use crate::error::Error;
+use crate::error_limit::ErrorLimit;
 use crate::file::*;
-use crate::hasher::{FileHasher, HashAlgorithm};
-use crate::log::Log;
+use crate::hasher::{
+    default_max_open_files, FileHandleCache, FileHasher, HashAlgorithm, RetryPolicy,
+    MAX_CACHED_FILE_HANDLES,
+};
+use crate::log::{Log, LogMessage, LogSink};
+use crate::metrics::{Counters, CountingSink, RunMetrics, StageTimer};
 use crate::path::Path;
-use crate::report::{FileStats, ReportHeader, ReportWriter};
-use crate::selector::PathSelector;
+use crate::report::{open_report, FileStats, ReportHeader, ReportWriter};
+use crate::selector::{ExtensionFilter, PathSelector};
 use crate::semaphore::Semaphore;
+use crate::term::{supports_unicode, Term};
 use crate::transform::Transform;
 use crate::walk::Walk;
 
@@ -102,81 +117,238 @@ where
 struct GroupCtx<'a> {
     pub config: &'a GroupConfig,
     pub log: &'a Log,
+    /// Collects the warnings about inaccessible files from the scan and the hashing stages
+    denied: &'a DeniedAccess<'a>,
+    /// Counts the I/O errors from the scan and the hashing stages
+    errors: &'a ErrorLimit<'a>,
     group_filter: FileGroupFilter,
     devices: DiskDevices,
     transform: Option<Transform>,
     path_selector: PathSelector,
+    extension_filter: ExtensionFilter,
     hasher: FileHasher<'a>,
 }
 
 impl<'a> GroupCtx<'a> {
-    pub fn new(config: &'a GroupConfig, log: &'a Log) -> Result<GroupCtx<'a>, Error> {
+    pub fn new(
+        config: &'a GroupConfig,
+        log: &'a Log,
+        denied: &'a DeniedAccess<'a>,
+        errors: &'a ErrorLimit<'a>,
+    ) -> Result<GroupCtx<'a>, Error> {
         let thread_pool_sizes = config.thread_pool_sizes();
-        let devices = DiskDevices::new(&thread_pool_sizes);
+        let mut devices = DiskDevices::new(&thread_pool_sizes);
+        // Must be set before the overrides, so it doesn't reset the chunk sizes:
+        let (min_read_buffer, max_read_buffer) = config.read_buffer_bounds();
+        devices.set_read_buf_len_bounds(min_read_buffer, max_read_buffer);
+        for (path, device_override) in Self::device_overrides(config, &devices)? {
+            devices
+                .override_device(&path, &device_override, &thread_pool_sizes)
+                .map_err(|e| Error::config(format!("Invalid device override: {}", e)))?;
+        }
+        // Global limits must be applied first, so they don't override the per-device limits:
+        let (global_limits, device_limits): (Vec<_>, Vec<_>) = config
+            .max_bandwidth
+            .iter()
+            .partition(|(path, _)| path.is_none());
+        for (path, bandwidth) in global_limits.into_iter().chain(device_limits) {
+            let path = path.as_ref().map(|p| p.canonicalize());
+            devices.set_max_bandwidth(path.as_ref(), *bandwidth);
+        }
+        if let Some(n) = config.ssd_concurrency {
+            devices.set_max_concurrency(DiskType::SSD, n);
+        }
+        if let Some(n) = config.hdd_concurrency {
+            devices.set_max_concurrency(DiskType::HDD, n);
+        }
         let transform = match config.transform() {
             None => None,
             Some(Ok(transform)) => Some(transform),
-            Some(Err(e)) => return Err(Error::new(format!("Invalid transform: {}", e))),
+            Some(Err(e)) => return Err(Error::config(format!("Invalid transform: {}", e))),
         };
         let base_dir = Path::from(current_dir().unwrap_or_default());
         let group_filter = config.group_filter();
         let path_selector = config
             .path_selector(&base_dir)
-            .map_err(|e| format!("Invalid pattern: {}", e))?;
+            .map_err(|e| Error::config(format!("Invalid pattern: {}", e)))?;
 
-        let cache: Option<HashCache> = if config.cache {
+        let cache: Option<HashCache> = if config.xattr_cache {
+            Some(HashCache::open_xattrs()?)
+        } else if config.cache {
             Some(HashCache::open_default()?)
         } else {
             None
         };
+        let max_open_files = config.max_open_files.unwrap_or_else(default_max_open_files);
+        log.debug(format!("Maximum number of open files: {}", max_open_files));
+        // Some descriptors are kept open between the hashing stages to avoid reopening files.
+        // They must be accounted for in the limit of open files:
+        let cached_handles = min(max_open_files / 4, MAX_CACHED_FILE_HANDLES);
+        let max_open_files = max_open_files - cached_handles;
+        let max_buffer_memory = usize::try_from(config.max_buffer_memory().0)
+            .unwrap_or(usize::MAX)
+            .clamp(1, isize::MAX as usize);
+        log.debug(format!(
+            "Maximum size of read buffers: {}",
+            config.max_buffer_memory()
+        ));
         let hasher = FileHasher {
             cache,
+            open_files: Semaphore::new(max_open_files as isize),
+            handles: FileHandleCache::new(cached_handles),
+            buffer_memory: Semaphore::new(max_buffer_memory as isize),
+            max_buffer_memory,
             algorithm: HashAlgorithm::MetroHash128,
-            buf_len: 65536,
-            log,
+            counters: Counters::default(),
+            log: denied,
+            errors,
+            retry: RetryPolicy {
+                retries: config.io_retries,
+                delay: config.io_retry_delay,
+                retry_eio: config.io_retry_eio,
+            },
+            read_errors: Mutex::new(Vec::new()),
         };
 
         Self::check_pool_config(thread_pool_sizes, &devices)?;
+        Self::log_pool_sizes(&devices, log);
+        Self::log_bandwidth_limits(&devices, log);
 
         Ok(GroupCtx {
             config,
             log,
+            denied,
+            errors,
             group_filter,
             devices,
             transform,
             path_selector,
+            extension_filter: config.extension_filter(),
             hasher,
         })
     }
 
-    /// Checks if all thread pool names refer to existing pools or devices
+    /// Checks if all device names used in thread pool names refer to existing devices.
+    /// Other pool names are checked by [`GroupConfig::validate`].
     fn check_pool_config(
         thread_pool_sizes: HashMap<OsString, Parallelism>,
         devices: &DiskDevices,
     ) -> Result<(), Error> {
-        let mut allowed_pool_names = DiskDevices::device_types();
-        allowed_pool_names.push("main");
-        allowed_pool_names.push("default");
         for (name, _) in thread_pool_sizes.iter() {
             let name = name.to_string_lossy();
-            match name.strip_prefix("dev:") {
-                Some(name) if devices.get_by_name(OsStr::new(name)).is_none() => {
-                    return Err(Error::new(format!("Unknown device: {}", name)));
-                }
-                None if !allowed_pool_names.contains(&name.as_ref()) => {
-                    return Err(Error::new(format!(
-                        "Unknown thread pool or device type: {}",
-                        name
-                    )));
+            if let Some(name) = name.strip_prefix("dev:") {
+                if devices.get_by_name(OsStr::new(name)).is_none() {
+                    return Err(Error::config(format!("Unknown device: {}", name)));
                 }
-                _ => {}
             }
         }
         Ok(())
     }
+
+    /// Logs the limits of read bandwidth and concurrency, if any.
+    fn log_bandwidth_limits(devices: &DiskDevices, log: &Log) {
+        for device in devices.iter() {
+            if let Some(bandwidth) = device.max_bandwidth() {
+                log.info(format!(
+                    "Limiting read bandwidth of device {} to {}/s",
+                    device.name.to_string_lossy(),
+                    bandwidth
+                ));
+            }
+            if let Some(n) = device.max_concurrency() {
+                log.debug(format!(
+                    "Limiting concurrent reads of device {} to {}",
+                    device.name.to_string_lossy(),
+                    n
+                ));
+            }
+        }
+    }
+
+    /// Combines the overrides given by `--device-type` and `--device-override`
+    /// into a single override for each device, returned together with a path on the device.
+    /// Fails if the same property of a device is overridden with different values.
+    fn device_overrides(
+        config: &GroupConfig,
+        devices: &DiskDevices,
+    ) -> Result<Vec<(Path, DeviceOverride)>, Error> {
+        let device_types = config.device_type.iter().map(|(path, disk_type)| {
+            let device_override = DeviceOverride {
+                disk_type: Some(*disk_type),
+                ..Default::default()
+            };
+            (path, device_override)
+        });
+        let overrides = device_types.chain(config.device_override.iter().map(|(p, o)| (p, *o)));
+        let mut result: Vec<(Path, DeviceOverride)> = Vec::new();
+        for (path, device_override) in overrides {
+            let path = path.canonicalize();
+            let device = devices.get_by_path(&path);
+            match result
+                .iter_mut()
+                .find(|(p, _)| devices.get_by_path(p).index == device.index)
+            {
+                Some((_, merged)) => merged.merge(&device_override).map_err(|e| {
+                    Error::config(format!(
+                        "Invalid override of device {}: {}",
+                        device.name.to_string_lossy(),
+                        e
+                    ))
+                })?,
+                None => result.push((path, device_override)),
+            }
+        }
+        Ok(result)
+    }
+
+    /// Logs the sizes of thread pools at the verbose level.
+    fn log_pool_sizes(devices: &DiskDevices, log: &Log) {
+        log.debug(format!(
+            "Main thread pool size: {}",
+            rayon::current_num_threads()
+        ));
+        for device in devices.iter() {
+            log.debug(format!(
+                "Thread pool sizes for device {} ({:?}): random access: {}, sequential access: {}",
+                device.name.to_string_lossy(),
+                device.disk_type,
+                device.parallelism.random,
+                device.parallelism.sequential
+            ));
+        }
+    }
 }
 
-/// A group of files that have something in common, e.g. same size or same hash
+/// A group of files that have something in common, e.g. same size or same hash.
+///
+/// The type of the members depends on the stage of grouping: [`group_files`] returns
+/// groups of [`Path`]s, and groups of [`FileInfo`]s are used internally.
+///
+/// # Example
+/// Consuming the groups returned by [`group_files`]:
+/// ```
+/// use fclones::file::{FileHash, FileLen};
+/// use fclones::path::Path;
+/// use fclones::FileGroup;
+///
+/// fn print_groups(groups: &[FileGroup<Path>]) {
+///     for group in groups {
+///         println!("{} files of size {}:", group.file_count(), group.file_len());
+///         for path in group.iter() {
+///             println!("    {}", path.display());
+///         }
+///     }
+/// }
+///
+/// let group = FileGroup {
+///     file_len: FileLen(1024),
+///     file_hash: FileHash(0xcafe),
+///     files: vec![Path::from("/a/foo"), Path::from("/b/foo")],
+/// };
+/// assert_eq!(group.file_hash(), FileHash(0xcafe));
+/// assert_eq!(group.total_size(), FileLen(2048));
+/// print_groups(&[group]);
+/// ```
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct FileGroup<F> {
     /// Length of each file
@@ -187,6 +359,42 @@ pub struct FileGroup<F> {
     pub files: Vec<F>,
 }
 
+/// Changes of the members of a group of identical files between two runs.
+/// Returned by [`FileGroup::diff`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct GroupDiff {
+    /// Members present only in the newer group
+    pub new_members: Vec<Path>,
+    /// Members present only in the older group
+    pub removed_members: Vec<Path>,
+    /// Members present in both groups
+    pub unchanged_members: Vec<Path>,
+}
+
+impl GroupDiff {
+    /// Returns true if no members were added or removed
+    pub fn is_unchanged(&self) -> bool {
+        self.new_members.is_empty() && self.removed_members.is_empty()
+    }
+
+    /// Returns a word describing the change of the group as a whole:
+    /// `new`, `removed`, `grown`, `shrunk`, `changed` or `unchanged`.
+    pub fn kind(&self) -> &'static str {
+        match (
+            self.new_members.is_empty(),
+            self.removed_members.is_empty(),
+            self.unchanged_members.is_empty(),
+        ) {
+            (true, true, _) => "unchanged",
+            (false, true, true) => "new",
+            (true, false, true) => "removed",
+            (false, true, false) => "grown",
+            (true, false, false) => "shrunk",
+            (false, false, _) => "changed",
+        }
+    }
+}
+
 /// Controls the type of search by determining the number of replicas
 /// allowed in a group of identical files.
 pub enum Replication {
@@ -214,21 +422,120 @@ pub struct FileGroupFilter {
     /// If empty - no additional grouping is performed.
     /// See [`GroupConfig::isolate`].
     pub root_paths: Vec<Path>,
+    /// A list of read-only baseline directories.
+    /// Groups consisting only of files under these directories are not reported.
+    /// See [`GroupConfig::baseline`].
+    pub baseline_paths: Vec<Path>,
+    /// The minimum number of distinct `input_roots` the files of a reported group
+    /// must be found under. Values lower than 2 don't restrict the groups.
+    /// See [`GroupConfig::min_roots`].
+    pub min_roots: usize,
+    /// The input paths the files are attributed to when counting roots.
+    /// Empty if `min_roots` doesn't restrict the groups.
+    pub input_roots: Vec<Path>,
 }
 
 impl<F> FileGroup<F> {
+    /// Returns the length of each file in the group
+    pub fn file_len(&self) -> FileLen {
+        self.file_len
+    }
+
+    /// Returns the hash shared by all files in the group
+    pub fn file_hash(&self) -> FileHash {
+        self.file_hash
+    }
+
+    /// Returns an iterator over the members of the group
+    pub fn iter(&self) -> std::slice::Iter<'_, F> {
+        self.files.iter()
+    }
+
     /// Returns the count of all files in the group
     pub fn file_count(&self) -> usize {
         self.files.len()
     }
 
+    /// Returns the number of members of the group.
+    /// Same as [`FileGroup::file_count`].
+    pub fn member_count(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Returns true if the group has fewer than two members,
+    /// so none of its files is a duplicate of another one.
+    pub fn is_single(&self) -> bool {
+        self.member_count() < 2
+    }
+
     /// Returns the total size of all files in the group
     pub fn total_size(&self) -> FileLen {
         self.file_len * self.file_count() as u64
     }
+
+    /// Returns the space taken by all files in the group except one
+    pub fn wasted_bytes(&self) -> FileLen {
+        self.file_len * self.file_count().saturating_sub(1) as u64
+    }
+}
+
+impl FileGroup<FileInfo> {
+    /// Returns the member with the largest length.
+    /// If many members have the same length, the first one is returned.
+    ///
+    /// # Panics
+    /// If the group is empty.
+    pub fn largest_member(&self) -> &FileInfo {
+        debug_assert!(!self.files.is_empty(), "Empty file group");
+        self.files
+            .iter()
+            .rev()
+            .max_by_key(|f| f.len)
+            .expect("Empty file group")
+    }
+
+    /// Returns the member with the smallest length.
+    /// If many members have the same length, the first one is returned.
+    ///
+    /// # Panics
+    /// If the group is empty.
+    pub fn smallest_member(&self) -> &FileInfo {
+        debug_assert!(!self.files.is_empty(), "Empty file group");
+        self.files
+            .iter()
+            .min_by_key(|f| f.len)
+            .expect("Empty file group")
+    }
 }
 
 impl<F: AsPath> FileGroup<F> {
+    /// Compares the members of this group with the members of `other`, an earlier version
+    /// of the same group, e.g. read from the report of a previous run.
+    ///
+    /// Members are compared by paths. The new and unchanged members are returned
+    /// in the order of this group, and the removed members in the order of `other`.
+    pub fn diff<G: AsPath>(&self, other: &FileGroup<G>) -> GroupDiff {
+        let previous: HashSet<&Path> = other.files.iter().map(|f| f.path()).collect();
+        let current: HashSet<&Path> = self.files.iter().map(|f| f.path()).collect();
+        let (unchanged_members, new_members) = self
+            .files
+            .iter()
+            .map(|f| f.path().clone())
+            .partition(|p| previous.contains(p));
+        let removed_members = other
+            .files
+            .iter()
+            .map(|f| f.path())
+            .filter(|p| !current.contains(p))
+            .cloned()
+            .collect();
+        GroupDiff {
+            new_members,
+            removed_members,
+            unchanged_members,
+        }
+    }
+
     /// Returns true if the file group should be forwarded to the next grouping stage,
     /// because the number of duplicate files is higher than the maximum allowed number of replicas.
     ///
@@ -302,6 +609,48 @@ impl<F: AsPath> FileGroup<F> {
         }
     }
 
+    /// Returns the number of redundant files whose removal would reclaim disk space.
+    ///
+    /// Works like [`FileGroup::redundant_count`], but the files located in read-only
+    /// btrfs subvolumes, e.g. snapshots, are left out of the group first. Such files share
+    /// their data with the files they were taken from and can't be removed,
+    /// so they are skipped by dedupe as well.
+    pub fn reclaimable_count(&self, filter: &FileGroupFilter) -> usize {
+        let in_snapshot = |f: &&F| is_in_read_only_subvolume(f.path());
+        if !self.files.iter().any(|f| in_snapshot(&f)) {
+            return self.redundant_count(filter);
+        }
+        FileGroup {
+            file_len: self.file_len,
+            file_hash: self.file_hash,
+            files: self.files.iter().filter(|f| !in_snapshot(f)).collect_vec(),
+        }
+        .redundant_count(filter)
+    }
+
+    /// Returns the files that could be removed from the group.
+    ///
+    /// These are the files counted by [`FileGroup::redundant_count`]:
+    /// the files past the first `r` files, or the files past the first `r` subgroups
+    /// if `filter.roots` are not empty. Files should be sorted with [`FileGroup::sort`] first.
+    pub fn redundant_files(&self, filter: &FileGroupFilter) -> Vec<&F> {
+        match filter.replication {
+            Replication::Underreplicated(_) => vec![],
+            Replication::Overreplicated(rf) => {
+                let rf = max(rf, 1);
+                if filter.root_paths.is_empty() {
+                    self.files.iter().skip(rf).collect()
+                } else {
+                    FileSubGroup::group(&self.files, &filter.root_paths)
+                        .into_iter()
+                        .skip(rf)
+                        .flat_map(|sg| sg.files)
+                        .collect()
+                }
+            }
+        }
+    }
+
     /// Returns either the number of files redundant or missing, depending on the type of search.
     pub fn reported_count(&self, filter: &FileGroupFilter) -> usize {
         match filter.replication {
@@ -310,6 +659,182 @@ impl<F: AsPath> FileGroup<F> {
         }
     }
 
+    /// Returns true if all files in the group have the same file name.
+    pub fn has_same_basename(&self) -> bool {
+        self.files
+            .iter()
+            .map(|f| f.path().file_name_cstr())
+            .all_equal()
+    }
+
+    /// Returns true if all files in the group are located under the given baseline directories.
+    /// Returns false if there are no baseline directories.
+    pub fn is_baseline_only(&self, baseline: &[Path]) -> bool {
+        !baseline.is_empty()
+            && self
+                .files
+                .iter()
+                .all(|f| baseline.iter().any(|b| b.is_prefix_of(f.path())))
+    }
+
+    /// Returns true if at least one file in the group can be replaced by the current user.
+    ///
+    /// On Unix, replacing a file requires write access to its parent directory,
+    /// so this asks the kernel with `access(W_OK)` about the parent directories,
+    /// taking the owner, the groups and the privileges of the user into account.
+    /// On other systems, a file can be replaced if it is not read-only.
+    /// Files whose metadata cannot be read are treated as not writable.
+    pub fn has_writable_member(&self) -> bool {
+        self.files
+            .iter()
+            .any(|f| is_replaceable(&f.path().to_path_buf()))
+    }
+
+    /// Assigns each file the index of the distinct file it is a hard link to.
+    ///
+    /// Files reachable through hard links to the same inode get the same index.
+    /// Indexes are numbered from 0 in the order of the first occurrence.
+    /// Files whose identifier cannot be read are assumed to be distinct from all other files.
+    pub fn inode_groups(&self) -> Vec<usize> {
+        self.inode_groups_with(None)
+    }
+
+    /// Works like [`FileGroup::inode_groups`], but takes the identifiers of the files
+    /// from `file_ids` if given, instead of reading them.
+    /// Files missing from `file_ids` are assumed to be distinct from all other files.
+    pub(crate) fn inode_groups_with(&self, file_ids: Option<&FileIds>) -> Vec<usize> {
+        let mut indexes: HashMap<FileId, usize> = HashMap::new();
+        let mut distinct_count = 0;
+        let mut result = Vec::with_capacity(self.files.len());
+        for f in &self.files {
+            let id = match file_ids {
+                Some(file_ids) => file_ids.get(f.path()).copied(),
+                None => FileId::new(f.path()).ok(),
+            };
+            let index = match id {
+                Some(id) => *indexes.entry(id).or_insert(distinct_count),
+                None => distinct_count,
+            };
+            if index == distinct_count {
+                distinct_count += 1;
+            }
+            result.push(index);
+        }
+        result
+    }
+
+    /// Returns the number of inodes that would be freed by replacing the redundant files
+    /// with hard links to the retained ones.
+    ///
+    /// Hard links to the same inode are counted once. The retained replicas are chosen
+    /// like in [`FileGroup::redundant_count`]: the first `r` distinct inodes, or the inodes
+    /// of the files in the first `r` subgroups if `filter.root_paths` are not empty.
+    pub fn freed_inode_count(&self, filter: &FileGroupFilter) -> usize {
+        self.freed_inode_count_with(&self.inode_groups(), filter)
+    }
+
+    /// Works like [`FileGroup::freed_inode_count`], but takes the result
+    /// of [`FileGroup::inode_groups`] computed earlier.
+    pub(crate) fn freed_inode_count_with(
+        &self,
+        inodes: &[usize],
+        filter: &FileGroupFilter,
+    ) -> usize {
+        let rf = match filter.replication {
+            Replication::Underreplicated(_) => return 0,
+            Replication::Overreplicated(rf) => max(rf, 1),
+        };
+        let distinct_count = inodes.iter().max().map_or(0, |&i| i + 1);
+        if filter.root_paths.is_empty() {
+            return distinct_count.saturating_sub(rf);
+        }
+        let retained: HashSet<&Path> = FileSubGroup::group(&self.files, &filter.root_paths)
+            .into_iter()
+            .take(rf)
+            .flat_map(|sg| sg.files)
+            .map(|f| f.path())
+            .collect();
+        let retained_inodes: HashSet<usize> = self
+            .files
+            .iter()
+            .zip(inodes)
+            .filter(|(f, _)| retained.contains(f.path()))
+            .map(|(_, &i)| i)
+            .collect();
+        distinct_count - retained_inodes.len()
+    }
+
+    /// Returns true if the group has more than one file and all of them are hard links
+    /// to the same inode. Removing such files doesn't reclaim any space.
+    pub fn is_hardlink_only(&self) -> bool {
+        is_hardlink_only(&self.inode_groups())
+    }
+
+    /// Formats a single-line summary of the group for displaying in a terminal
+    /// `terminal_width` characters wide.
+    ///
+    /// The line contains the first 8 hex digits of the hash, the number of files,
+    /// the space wasted by the redundant copies and the path of the first file.
+    /// If the line doesn't fit, the path is shortened from the left and prefixed with `...`.
+    ///
+    /// # Example
+    /// ```
+    /// use fclones::file::{FileHash, FileLen};
+    /// use fclones::path::Path;
+    /// use fclones::FileGroup;
+    ///
+    /// let group = FileGroup {
+    ///     file_len: FileLen(1000),
+    ///     file_hash: FileHash(0xcafebabe << 96),
+    ///     files: vec![Path::from("/photos/2020/img.jpg"), Path::from("/backup/img.jpg")],
+    /// };
+    /// assert_eq!(
+    ///     group.to_report_line(80),
+    ///     "cafebabe 2 files, 1.0 KB wasted: /photos/2020/img.jpg"
+    /// );
+    /// assert_eq!(
+    ///     group.to_report_line(44),
+    ///     "cafebabe 2 files, 1.0 KB wasted: .../img.jpg"
+    /// );
+    /// ```
+    pub fn to_report_line(&self, terminal_width: usize) -> String {
+        let hash = self.file_hash.to_string();
+        let summary = format!(
+            "{} {} files, {} wasted: ",
+            &hash[..8],
+            self.file_count(),
+            self.wasted_bytes()
+        );
+        let path = self
+            .files
+            .first()
+            .map(|f| f.path().to_escaped_string())
+            .unwrap_or_default();
+        let available = terminal_width.saturating_sub(summary.chars().count());
+        summary + &truncate_left(&path, available)
+    }
+
+    /// Returns the number of distinct roots containing at least one file of the group.
+    ///
+    /// Each file is attributed to the first root that is a prefix of its path, as by
+    /// [`FileSubGroup::group`]. Files outside all roots are not counted.
+    /// Hard links count once for every root they are found under.
+    pub fn root_count(&self, roots: &[Path]) -> usize {
+        let mut found = vec![false; roots.len()];
+        for f in self.files.iter() {
+            if let Some(idx) = root_index(roots, f.path()) {
+                found[idx] = true;
+            }
+        }
+        found.into_iter().filter(|&f| f).count()
+    }
+
+    /// Returns true if the files of the group are found under enough distinct roots
+    /// to satisfy `filter.min_roots`.
+    pub fn spans_min_roots(&self, filter: &FileGroupFilter) -> bool {
+        filter.min_roots <= 1 || self.root_count(&filter.input_roots) >= filter.min_roots
+    }
+
     /// The number of subgroups of paths with distinct root prefix.
     fn subgroup_count(&self, filter: &FileGroupFilter) -> usize {
         if filter.root_paths.is_empty() {
@@ -381,6 +906,42 @@ struct HashedFileInfo {
     file_info: FileInfo,
 }
 
+/// Returns the index of the first root that is a prefix of the path.
+pub(crate) fn root_index(roots: &[Path], path: &Path) -> Option<usize> {
+    roots.iter().position(|r| r.is_prefix_of(path))
+}
+
+/// Shortens the string to at most `max_len` characters by replacing its beginning with `...`.
+fn truncate_left(s: &str, max_len: usize) -> String {
+    const ELLIPSIS: &str = "...";
+    let len = s.chars().count();
+    if len <= max_len {
+        return s.to_owned();
+    }
+    if max_len <= ELLIPSIS.len() {
+        return ELLIPSIS[..max_len].to_owned();
+    }
+    let keep = max_len - ELLIPSIS.len();
+    ELLIPSIS.chars().chain(s.chars().skip(len - keep)).collect()
+}
+
+/// Returns true if the current user may remove the file or replace it with a link.
+#[cfg(unix)]
+fn is_replaceable(path: &std::path::Path) -> bool {
+    use nix::unistd::{access, AccessFlags};
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => std::path::Path::new("."),
+    };
+    path.symlink_metadata().is_ok() && access(parent, AccessFlags::W_OK).is_ok()
+}
+
+/// Returns true if the current user may remove the file or replace it with a link.
+#[cfg(not(unix))]
+fn is_replaceable(path: &std::path::Path) -> bool {
+    std::fs::metadata(path).is_ok_and(|m| !m.permissions().readonly())
+}
+
 /// Partitions files into separate vectors, where each vector holds files persisted
 /// on the same disk device. The vectors are returned in the same order as devices.
 fn partition_by_devices(
@@ -403,6 +964,26 @@ fn partition_by_devices(
     result
 }
 
+/// Identifiers of the files of the reported groups, by path
+pub(crate) type FileIds = HashMap<Path, FileId>;
+
+/// Reads the identifiers of the files of all groups, so they can be shared by everything
+/// that checks the hard links between the files, instead of being read again by each check.
+/// Files whose identifier cannot be read are left out.
+pub(crate) fn read_file_ids(groups: &[FileGroup<Path>]) -> FileIds {
+    groups
+        .par_iter()
+        .flat_map(|g| &g.files)
+        .filter_map(|f| FileId::new(f).ok().map(|id| (f.clone(), id)))
+        .collect()
+}
+
+/// Returns true if the result of [`FileGroup::inode_groups`] describes more than one file
+/// and all of them are hard links to the same inode
+pub(crate) fn is_hardlink_only(inode_groups: &[usize]) -> bool {
+    inode_groups.len() > 1 && inode_groups.iter().all(|&i| i == 0)
+}
+
 /// Iterates over grouped files, in parallel
 fn flat_iter(files: &[FileGroup<FileInfo>]) -> impl ParallelIterator<Item = &FileInfo> {
     files.par_iter().flat_map(|g| &g.files)
@@ -418,7 +999,7 @@ fn flat_iter(files: &[FileGroup<FileInfo>]) -> impl ParallelIterator<Item = &Fil
 /// were in the different groups to end up in the same group if they have the same length
 /// and they hash to the same value. If you don't want this, you need to combine the old
 /// hash with the new hash in the provided `hash_fn`.
-fn rehash<'a, F1, F2, H>(
+fn rehash<F1, F2, H>(
     groups: Vec<FileGroup<FileInfo>>,
     group_pre_filter: F1,
     group_post_filter: F2,
@@ -429,13 +1010,9 @@ fn rehash<'a, F1, F2, H>(
 where
     F1: Fn(&FileGroup<FileInfo>) -> bool,
     F2: Fn(&FileGroup<FileInfo>) -> bool,
-    H: Fn((&mut FileInfo, FileHash)) -> Option<FileHash> + Sync + Send + 'a,
+    H: Fn((&mut FileInfo, FileHash)) -> Option<FileHash> + Sync + Send,
 {
-    // Allow sharing the hash function between threads:
-    type HashFn<'a> = dyn Fn((&mut FileInfo, FileHash)) -> Option<FileHash> + Sync + Send + 'a;
-    let hash_fn: &HashFn<'a> = &hash_fn;
-
-    let (tx, rx): (Sender<HashedFileInfo>, Receiver<HashedFileInfo>) = channel();
+    let hash_fn = &hash_fn;
 
     // There is no point in processing groups containing a single file.
     // Normally when searching for duplicates such groups are filtered out automatically after
@@ -445,21 +1022,22 @@ where
 
     // This way we can split processing to separate thread-pools, one per device:
     let files = partition_by_devices(groups_to_fclones, devices);
-    let mut hash_map =
-        GroupMap::new(|f: HashedFileInfo| ((f.file_info.len, f.file_hash), f.file_info));
-    let hash_map_ref = &mut hash_map;
+
+    // Hashed files are grouped directly by the hashing tasks.
+    // The map is split into shards with separate locks, so the tasks running in parallel
+    // rarely contend for the same lock.
+    let hash_map: DashMap<(FileLen, FileHash), SmallVec<[FileInfo; 1]>> = DashMap::new();
+    let hash_map_ref = &hash_map;
 
     // Scope needed so threads can access shared stuff like groups or shared functions.
     // The threads we launch are guaranteed to not live longer than this scope.
-    thread::scope(move |s| {
+    thread::scope(|s| {
         // Process all files in background
         for (mut files, device) in files.into_iter().zip(devices.iter()) {
             if files.is_empty() {
                 continue;
             }
 
-            let tx = tx.clone();
-
             // Launch a separate thread for each device, so we can process
             // files on each device independently
             s.spawn(move |_| {
@@ -485,90 +1063,242 @@ where
                 // when processing 1M of files.
                 let semaphore = Arc::new(Semaphore::new(8 * thread_count));
 
-                // Run hashing on the thread-pool dedicated to the device
-                for mut f in files {
-                    let tx = tx.clone();
-                    let guard = semaphore.clone().access_owned();
-
-                    // Spawning a task into a thread-pool requires a static lifetime,
-                    // because generally the task could outlive caller's stack frame.
-                    // However, this is not the case for rehash function, because
-                    // we don't exit before all tasks are closed.
-                    // In the perfect world we should use scopes for that. Unfortunately
-                    // the current implementation of rayon scopes runs the scope body
-                    // on one of the thread-pool worker threads, so it is not possible
-                    // to safely block inside the scope, because that leads to deadlock
-                    // when the pool has only one thread.
-                    let hash_fn: &HashFn<'static> = unsafe { std::mem::transmute(hash_fn) };
-                    thread_pool.spawn_fifo(move || {
-                        if let Some(hash) = hash_fn((&mut f.file_info, f.file_hash)) {
-                            f.file_hash = hash;
-                            tx.send(f).unwrap();
-                        }
-                        // This forces moving the guard into this task and be released when
-                        // the task is done
-                        drop(guard);
-                    });
-                }
+                // Run hashing on the thread-pool dedicated to the device.
+                // The body of an in-place scope runs on this thread, not on a worker
+                // of the pool, so it can block on the semaphore without a risk of deadlock
+                // when the pool has only one thread. The scope doesn't return, nor unwind,
+                // before all tasks spawned in it complete, so the tasks can borrow
+                // the hash function and the map.
+                thread_pool.in_place_scope_fifo(|pool_scope| {
+                    for mut f in files {
+                        let guard = semaphore.clone().access_owned();
+                        pool_scope.spawn_fifo(move |_| {
+                            if let Some(hash) = hash_fn((&mut f.file_info, f.file_hash)) {
+                                let key = (f.file_info.len, hash);
+                                hash_map_ref.entry(key).or_default().push(f.file_info);
+                            }
+                            // This forces moving the guard into this task and be released when
+                            // the task is done
+                            drop(guard);
+                        });
+                    }
+                });
             });
         }
-        // Drop the original tx, so all tx are closed when the threads finish and
-        // the next while loop will eventually exit
-        drop(tx);
-
-        // Collect the results from all threads and group them.
-        // Note that this will happen as soon as data are available
-        while let Ok(hashed_file) = rx.recv() {
-            hash_map_ref.add(hashed_file);
-        }
     })
     .unwrap();
 
-    // Convert the hashmap into vector, leaving only large-enough groups:
-    hash_map
+    // Convert the hashmap into vector, leaving only large-enough groups.
+    // Shards don't keep the keys ordered, so sort the groups by key to get a stable order:
+    let mut groups: Vec<_> = hash_map
         .into_iter()
         .map(|((len, hash), files)| FileGroup {
             file_len: len,
             file_hash: hash,
-            files: files.to_vec(),
+            files: files.into_vec(),
         })
         .filter(group_post_filter)
-        .chain(groups_to_pass.into_iter())
-        .collect()
+        .collect();
+    groups.par_sort_unstable_by_key(|g| (g.file_len, g.file_hash));
+    groups.extend(groups_to_pass);
+    groups
+}
+
+/// Capacity of the queues of files found by the scan and waiting for background hashing.
+/// Files not fitting in the queue of their device are left to the prefix stage,
+/// so the scan doesn't wait for a slow device and the memory used by the queues is bounded.
+const BACKGROUND_QUEUE_LEN: usize = 4096;
+
+/// Prefix hashes computed while the directory tree is scanned, with `--scan-in-background`
+struct BackgroundHashes {
+    /// Length of the prefix of the files not longer than it; see [`prefix_chunk_len`]
+    prefix_len: FileLen,
+    /// The hashes by path; `None` if the file couldn't be read
+    hashes: DashMap<Path, Option<FileHash>>,
+}
+
+impl BackgroundHashes {
+    fn new(devices: &DiskDevices) -> BackgroundHashes {
+        // The lengths of all files are not known until the scan finishes,
+        // so the prefix must be long enough for any device
+        let prefix_len = devices
+            .iter()
+            .map(|d| d.max_prefix_len())
+            .max()
+            .unwrap_or_else(|| devices.get_default().max_prefix_len());
+        BackgroundHashes {
+            prefix_len,
+            hashes: DashMap::new(),
+        }
+    }
+}
+
+/// Hashes the prefixes of the files received from the directory scan, as soon as
+/// at least two files of the same length are found. Files are hashed by the random access
+/// thread pool of the device they are stored on.
+/// Returns the number of hashed files.
+fn hash_prefixes_in_background(
+    ctx: &GroupCtx<'_>,
+    background: &BackgroundHashes,
+    files: Receiver<FileInfo>,
+) -> usize {
+    let hashed = AtomicUsize::new(0);
+    thread::scope(|s| {
+        // Each device hashes its files in its own random access thread pool,
+        // fed by its own queue created when the first file of the device arrives.
+        let mut queues: Vec<Option<Sender<FileInfo>>> = vec![None; ctx.devices.len()];
+        let mut dispatch = |fi: FileInfo| {
+            let index = fi.get_device_index();
+            let queue = queues[index].get_or_insert_with(|| {
+                let (sender, receiver) = crossbeam_channel::bounded(BACKGROUND_QUEUE_LEN);
+                let device = &ctx.devices[index];
+                let hashed = &hashed;
+                s.spawn(move |_| {
+                    device.rand_thread_pool().in_place_scope(|pool_scope| {
+                        for _ in 0..device.parallelism.random.max(1) {
+                            let receiver: Receiver<FileInfo> = receiver.clone();
+                            pool_scope.spawn(move |_| {
+                                for fi in receiver {
+                                    hash_prefix_in_background(ctx, background, device, fi);
+                                    hashed.fetch_add(1, Ordering::Relaxed);
+                                }
+                            });
+                        }
+                    })
+                });
+                sender
+            });
+            // A full queue means hashing lags behind the scan.
+            // Such files are left to the prefix stage, so the scan doesn't wait for them.
+            let _ = queue.try_send(fi);
+        };
+
+        // The first file of each length waits until another file of the same length is found.
+        // `None` marks the lengths whose files are passed to hashing immediately.
+        let mut first_files: HashMap<FileLen, Option<FileInfo>> = HashMap::new();
+        for fi in files {
+            // Empty files are left to the prefix stage
+            if fi.len == FileLen(0) {
+                continue;
+            }
+            match first_files.entry(fi.len) {
+                Entry::Vacant(e) => {
+                    e.insert(Some(fi));
+                }
+                Entry::Occupied(mut e) => {
+                    if let Some(first) = e.get_mut().take() {
+                        dispatch(first);
+                    }
+                    dispatch(fi);
+                }
+            }
+        }
+        // Closing the queues lets the hashing threads finish
+        drop(queues);
+    })
+    .unwrap();
+    hashed.into_inner()
+}
+
+/// Hashes the prefix of a file found by the scan, unless it changed since it was found
+fn hash_prefix_in_background(
+    ctx: &GroupCtx<'_>,
+    background: &BackgroundHashes,
+    device: &DiskDevice,
+    fi: FileInfo,
+) {
+    // Files that changed are left to the prefix stage
+    if ctx.hasher.current_len(&fi.path).ok() != Some(fi.len) {
+        return;
+    }
+    let len = prefix_chunk_len(ctx, background.prefix_len, &fi);
+    let chunk = FileChunk::new(&fi.path, FilePos(0), len);
+    let hash = ctx.hasher.hash_on_device(&chunk, device, |_| {});
+    background.hashes.insert(fi.path, hash);
 }
 
-/// Walks the directory tree and collects matching files in parallel into a vector
-fn scan_files(ctx: &GroupCtx<'_>) -> Vec<Vec<FileInfo>> {
+/// Walks the directory tree and collects matching files in parallel into a vector.
+/// If `background` is given, the prefixes of the files are hashed while the tree is walked.
+/// The number of files skipped by the extension filter and, if `config.report_total_size`
+/// is set, the total size of the files are recorded in `stats`.
+fn scan_files(
+    ctx: &GroupCtx<'_>,
+    stats: &mut FileStats,
+    metrics: &mut RunMetrics,
+    background: Option<&BackgroundHashes>,
+) -> Vec<Vec<FileInfo>> {
+    let counters = Counters::default();
+    let timer = StageTimer::start("walk", 0, &counters);
+    let log = CountingSink {
+        inner: ctx.denied,
+        counters: &counters,
+    };
     let file_collector = ThreadLocal::new();
     let spinner = ctx.log.spinner("Scanning files");
     let spinner_tick = &|_: &Path| spinner.tick();
+    let errors_exceeded = &|| ctx.errors.is_exceeded();
 
     let config = &ctx.config;
-    let min_size = config.min_size;
-    let max_size = config.max_size.unwrap_or(FileLen::MAX);
+    let size_range = config.size_range().unwrap_or_else(FileLenRange::full);
+    let excluded_by_extension = AtomicUsize::new(0);
 
     let mut walk = Walk::new();
     walk.depth = config.depth.unwrap_or(usize::MAX);
     walk.skip_hidden = config.skip_hidden;
+    walk.skip_ro_snapshots = config.skip_ro_snapshots;
     walk.follow_links = config.follow_links;
     walk.path_selector = ctx.path_selector.clone();
-    walk.log = Some(ctx.log);
+    walk.log = Some(&log);
     walk.on_visit = spinner_tick;
-    walk.run(ctx.config.input_paths(), |path| {
-        file_info_or_log_err(path, &ctx.devices, ctx.log)
-            .into_iter()
-            .filter(|info| {
-                let l = info.len;
-                l >= min_size && l <= max_size
+    walk.should_stop = errors_exceeded;
+    let visit = |path: Path| {
+        // Checked before reading the metadata, because the files are likely many
+        if !ctx.extension_filter.matches(&path) {
+            excluded_by_extension.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        let info = file_info_or_log_err(path, &ctx.devices, &log);
+        info.filter(|info| size_range.contains(info.len))
+    };
+    let collect = |info: FileInfo| {
+        let vec = file_collector.get_or(|| RefCell::new(Vec::new()));
+        vec.borrow_mut().push(info);
+    };
+    match background {
+        None => walk.run(ctx.config.input_paths(), |path| {
+            visit(path).into_iter().for_each(collect)
+        }),
+        Some(background) => {
+            let (sender, receiver) = crossbeam_channel::bounded(BACKGROUND_QUEUE_LEN);
+            let hashed = thread::scope(|s| {
+                let hasher = s.spawn(|_| hash_prefixes_in_background(ctx, background, receiver));
+                walk.run(ctx.config.input_paths(), |path| {
+                    if let Some(info) = visit(path) {
+                        // Fails only if the hashing thread panicked, reported by the scope
+                        let _ = sender.send(info.clone());
+                        collect(info);
+                    }
+                });
+                drop(sender);
+                hasher.join().unwrap()
             })
-            .for_each(|info| {
-                let vec = file_collector.get_or(|| RefCell::new(Vec::new()));
-                vec.borrow_mut().push(info);
-            });
-    });
+            .unwrap();
+            ctx.log
+                .info(format!("Hashed prefixes of {} files during scan", hashed));
+        }
+    }
 
     ctx.log
         .info(format!("Scanned {} file entries", spinner.position()));
+    ctx.denied.set_visited(spinner.position());
+    let excluded_by_extension = excluded_by_extension.into_inner();
+    if !ctx.extension_filter.is_empty() {
+        ctx.log.info(format!(
+            "Skipped {} files not matching the extension filter",
+            excluded_by_extension
+        ));
+        stats.excluded_by_extension = Some(excluded_by_extension);
+    }
 
     let files: Vec<_> = file_collector.into_iter().map(|r| r.into_inner()).collect();
 
@@ -579,9 +1309,29 @@ fn scan_files(ctx: &GroupCtx<'_>) -> Vec<Vec<FileInfo>> {
         file_count,
         FileLen(total_size)
     ));
+    let mut stage = timer.finish(file_count);
+    stage.files_in = spinner.position();
+    metrics.stages.push(stage);
+    if config.report_total_size {
+        stats.scanned_file_size = Some(FileLen(total_size));
+    }
     files
 }
 
+/// Runs a grouping stage and records its metrics
+fn run_stage(
+    ctx: &GroupCtx<'_>,
+    metrics: &mut RunMetrics,
+    name: &'static str,
+    groups: Vec<FileGroup<FileInfo>>,
+    stage: impl FnOnce(Vec<FileGroup<FileInfo>>) -> Vec<FileGroup<FileInfo>>,
+) -> Vec<FileGroup<FileInfo>> {
+    let timer = StageTimer::start(name, file_count(&groups), &ctx.hasher.counters);
+    let groups = stage(groups);
+    metrics.stages.push(timer.finish(file_count(&groups)));
+    groups
+}
+
 /// Returns the sum of number of files in all groups
 fn file_count<'a, T: 'a>(groups: impl IntoIterator<Item = &'a FileGroup<T>>) -> usize {
     groups.into_iter().map(|g| g.file_count()).sum()
@@ -609,23 +1359,23 @@ fn group_by_size(ctx: &GroupCtx<'_>, files: Vec<Vec<FileInfo>>) -> Vec<FileGroup
     let file_count: usize = files.iter().map(|v| v.len()).sum();
     let progress = ctx.log.progress_bar("Grouping by size", file_count as u64);
 
-    let mut groups = GroupMap::new(|info: FileInfo| (info.len, info));
-    for files in files.into_iter() {
-        for file in files.into_iter() {
-            progress.tick();
-            groups.add(file);
-        }
-    }
+    // A map split into shards with separate locks allows adding files from many threads:
+    let groups: DashMap<FileLen, SmallVec<[FileInfo; 1]>> = DashMap::new();
+    files.into_par_iter().flatten().for_each(|file| {
+        progress.tick();
+        groups.entry(file.len).or_default().push(file);
+    });
 
-    let groups: Vec<_> = groups
+    let mut groups: Vec<_> = groups
         .into_iter()
         .map(|(l, files)| FileGroup {
             file_len: l,
-            file_hash: FileHash(0),
+            file_hash: FileHash::ZERO,
             files: files.into_vec(),
         })
         .filter(|g| g.matches(&ctx.group_filter))
         .collect();
+    groups.par_sort_unstable_by_key(|g| g.file_len);
 
     let stats = stage_stats(&groups, &ctx.group_filter);
     ctx.log.info(format!(
@@ -637,6 +1387,8 @@ fn group_by_size(ctx: &GroupCtx<'_>, files: Vec<Vec<FileInfo>>) -> Vec<FileGroup
 
 /// Removes duplicate files matching by full-path or by inode-id.
 /// Deduplication by inode-id is not performed if the flag to preserve hard-links (-H) is set.
+/// If the groups are restricted by the number of roots, one hard link per root is kept,
+/// so the links under different roots still count for each of them.
 fn deduplicate<F>(ctx: &GroupCtx<'_>, files: &mut Vec<FileInfo>, progress: F)
 where
     F: Fn(&Path) + Sync + Send,
@@ -663,11 +1415,15 @@ where
             let mut file_group = file_group;
             file_group.sort_by(|f1, f2| f1.path.cmp(&f2.path));
 
+            let roots = &ctx.group_filter.input_roots;
             files.extend(
                 file_group
                     .into_iter()
                     .inspect(|p| progress(&p.path))
-                    .unique_by(|p| file_id_or_log_err(&p.path, ctx.log)),
+                    .unique_by(|p| {
+                        let id = file_id_or_log_err(&p.path, ctx.log);
+                        (id, root_index(roots, &p.path))
+                    }),
             )
         }
     }
@@ -722,8 +1478,15 @@ fn update_file_locations(ctx: &GroupCtx<'_>, groups: &mut Vec<FileGroup<FileInfo
         .update(|fi| {
             let device: &DiskDevice = &ctx.devices[fi.get_device_index()];
             if device.disk_type != DiskType::SSD {
-                if let Err(e) = fi.fetch_physical_location() {
-                    handle_fetch_physical_location_err(ctx, &err_counters, fi, e)
+                match fi.fetch_physical_location() {
+                    // Checked upfront to not format a message for each file needlessly
+                    Ok(_) if ctx.log.verbosity >= 2 => ctx.log.trace(format!(
+                        "{}: {}",
+                        fi.path.display(),
+                        fi.physical_location_str()
+                    )),
+                    Ok(_) => {}
+                    Err(e) => handle_fetch_physical_location_err(ctx, &err_counters, fi, e),
                 }
             }
         })
@@ -763,19 +1526,29 @@ fn handle_fetch_physical_location_err(
             ));
         }
     } else if counter.load(Ordering::Acquire) < MAX_ERR_COUNT_TO_LOG {
-        ctx.log.warn(format!(
-            "Failed to fetch file extents mapping for file {}: {}. \
-            This is generally harmless, but it might decrease random access performance.",
-            file_info.path.display(),
-            error
-        ));
+        LogSink::warn(
+            ctx.log,
+            LogMessage::new(format!(
+                "Failed to fetch file extents mapping for file {}: {}. \
+                This is generally harmless, but it might decrease random access performance.",
+                file_info.path.display(),
+                error
+            ))
+            .with_path(&file_info.path)
+            .with_error(&error),
+        );
         let err_count = counter.fetch_add(1, Ordering::AcqRel);
         if err_count == MAX_ERR_COUNT_TO_LOG {
-            ctx.log.warn(format!(
-                "Too many errors trying to fetch file extent mappings on device {}. \
-                Subsequent errors for this device will be ignored.",
-                device.name.to_string_lossy()
-            ))
+            LogSink::warn(
+                ctx.log,
+                LogMessage::new(format!(
+                    "Too many errors trying to fetch file extent mappings on device {}. \
+                    Subsequent errors for this device will be ignored.",
+                    device.name.to_string_lossy()
+                ))
+                .with_error(&error)
+                .with_count(err_count as usize + 1),
+            )
         }
     }
 }
@@ -842,17 +1615,36 @@ fn prefix_len<'a>(
     max_device_property(partitions, files, |dd| dd.max_prefix_len())
 }
 
+/// Returns the length of the prefix of the file hashed by the prefix stage.
+/// Files not longer than `prefix_len` are hashed whole.
+fn prefix_chunk_len(ctx: &GroupCtx<'_>, prefix_len: FileLen, fi: &FileInfo) -> FileLen {
+    if fi.len <= prefix_len {
+        prefix_len
+    } else {
+        ctx.devices[fi.get_device_index()].min_prefix_len()
+    }
+}
+
 /// Groups files by a hash of their first few thousand bytes.
+/// The hashes computed during the scan are taken from `background`, if given.
 fn group_by_prefix(
     ctx: &GroupCtx<'_>,
     prefix_len: FileLen,
     groups: Vec<FileGroup<FileInfo>>,
+    background: Option<&BackgroundHashes>,
 ) -> Vec<FileGroup<FileInfo>> {
-    let pre_filter = |g: &FileGroup<FileInfo>| g.files.len() > 1;
+    let pre_filter = |g: &FileGroup<FileInfo>| !g.is_single();
+    let chunk_len = |fi: &FileInfo| prefix_chunk_len(ctx, prefix_len, fi);
     let file_count = file_count(groups.iter().filter(|&g| pre_filter(g)));
-    let progress = ctx
-        .log
-        .progress_bar("Grouping by prefix", file_count as u64);
+    let bytes_to_scan = groups
+        .iter()
+        .filter(|&g| pre_filter(g))
+        .flat_map(|g| g.files.iter())
+        .map(|fi| min(fi.len, chunk_len(fi)))
+        .sum();
+    let progress =
+        ctx.log
+            .stage_progress_bar("Grouping by prefix", file_count as u64, bytes_to_scan);
 
     let groups = rehash(
         groups,
@@ -860,15 +1652,29 @@ fn group_by_prefix(
         |g| g.matches(&ctx.group_filter),
         &ctx.devices,
         FileAccess::Random,
-        |(fi, _)| {
+        |(fi, hash)| {
+            // All empty files have the same content, so there is no need to open them.
+            // They keep the canonical zero hash assigned by the size stage:
+            if fi.len == FileLen(0) && hash.is_zero() {
+                progress.tick();
+                return Some(hash);
+            }
+            if let Some((_, hash)) = background.and_then(|b| b.hashes.remove(&fi.path)) {
+                progress.inc_bytes(min(fi.len, chunk_len(fi)).0 as usize);
+                progress.tick();
+                return hash;
+            }
+            if !check_len(ctx, fi, true) {
+                progress.tick();
+                return None;
+            }
+            let chunk = FileChunk::new(&fi.path, FilePos(0), chunk_len(fi));
+            let device = &ctx.devices[fi.get_device_index()];
+            let hash = ctx
+                .hasher
+                .hash_on_device(&chunk, device, |bytes_read| progress.inc_bytes(bytes_read));
             progress.tick();
-            let prefix_len = if fi.len <= prefix_len {
-                prefix_len
-            } else {
-                ctx.devices[fi.get_device_index()].min_prefix_len()
-            };
-            let chunk = FileChunk::new(&fi.path, FilePos(0), prefix_len);
-            ctx.hasher.hash(&chunk, |_| {})
+            hash
         },
     );
 
@@ -880,6 +1686,44 @@ fn group_by_prefix(
     groups
 }
 
+/// Checks if the file still has the length recorded when the directory tree was scanned,
+/// so the hashed chunks are positioned correctly.
+///
+/// Returns false if the file should be skipped because it disappeared, couldn't be opened
+/// or its length changed. If `--requeue-changed` is set and `requeue` is true, a file with
+/// a changed length is kept and its recorded length is updated instead.
+fn check_len(ctx: &GroupCtx<'_>, fi: &mut FileInfo, requeue: bool) -> bool {
+    match ctx.hasher.current_len(&fi.path) {
+        Ok(len) if len == fi.len => true,
+        Ok(len) if requeue && ctx.config.requeue_changed => {
+            fi.len = len;
+            true
+        }
+        Ok(len) => {
+            let msg = format!(
+                "File {} changed during scan: length was {}, is {}",
+                fi.path.display(),
+                fi.len.0,
+                len.0
+            );
+            ctx.hasher.counters.inc_errors();
+            ctx.hasher
+                .log
+                .warn(LogMessage::new(msg).with_path(&fi.path));
+            false
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+        Err(e) => {
+            let msg = format!("Failed to open {}: {}", fi.path.display(), e);
+            ctx.hasher.record_read_error(&fi.path);
+            ctx.hasher
+                .log
+                .warn(LogMessage::new(msg).with_path(&fi.path).with_error(&e));
+            false
+        }
+    }
+}
+
 /// Returns the desired suffix length for a group of files.
 /// The return value depends on the capabilities of the devices the files are stored on.
 /// Higher values are desired if any of the files resides on an HDD.
@@ -903,11 +1747,16 @@ fn group_by_suffix(
 ) -> Vec<FileGroup<FileInfo>> {
     let suffix_len = suffix_len(&ctx.devices, flat_iter(&groups));
     let suffix_threshold = suffix_threshold(&ctx.devices, flat_iter(&groups));
-    let pre_filter = |g: &FileGroup<FileInfo>| g.file_len >= suffix_threshold && g.files.len() > 1;
+    let pre_filter = |g: &FileGroup<FileInfo>| g.file_len >= suffix_threshold && !g.is_single();
     let file_count = file_count(groups.iter().filter(|&g| pre_filter(g)));
-    let progress = ctx
-        .log
-        .progress_bar("Grouping by suffix", file_count as u64);
+    let bytes_to_scan = groups
+        .iter()
+        .filter(|&g| pre_filter(g))
+        .map(|g| min(g.file_len, suffix_len) * g.files.len() as u64)
+        .sum();
+    let progress =
+        ctx.log
+            .stage_progress_bar("Grouping by suffix", file_count as u64, bytes_to_scan);
 
     let groups = rehash(
         groups,
@@ -916,11 +1765,19 @@ fn group_by_suffix(
         &ctx.devices,
         FileAccess::Random,
         |(fi, old_hash)| {
-            progress.tick();
+            if !check_len(ctx, fi, true) {
+                progress.tick();
+                return None;
+            }
+            let suffix_len = min(suffix_len, fi.len);
             let chunk = FileChunk::new(&fi.path, fi.len.as_pos() - suffix_len, suffix_len);
-            ctx.hasher
-                .hash(&chunk, |_| {})
-                .map(|new_hash| old_hash ^ new_hash)
+            let device = &ctx.devices[fi.get_device_index()];
+            let hash = ctx
+                .hasher
+                .hash_on_device(&chunk, device, |bytes_read| progress.inc_bytes(bytes_read))
+                .map(|new_hash| old_hash ^ new_hash);
+            progress.tick();
+            hash
         },
     );
 
@@ -937,11 +1794,12 @@ fn group_by_contents(
     min_file_len: FileLen,
     groups: Vec<FileGroup<FileInfo>>,
 ) -> Vec<FileGroup<FileInfo>> {
-    let pre_filter = |g: &FileGroup<FileInfo>| g.files.len() > 1 && g.file_len >= min_file_len;
+    let pre_filter = |g: &FileGroup<FileInfo>| !g.is_single() && g.file_len >= min_file_len;
+    let file_count = file_count(groups.iter().filter(|&g| pre_filter(g)));
     let bytes_to_scan = total_size(groups.iter().filter(|&g| pre_filter(g)));
-    let progress = &ctx
-        .log
-        .bytes_progress_bar("Grouping by contents", bytes_to_scan.0);
+    let progress =
+        &ctx.log
+            .stage_progress_bar("Grouping by contents", file_count as u64, bytes_to_scan);
 
     let groups = rehash(
         groups,
@@ -950,9 +1808,17 @@ fn group_by_contents(
         &ctx.devices,
         FileAccess::Sequential,
         |(fi, _)| {
+            if !check_len(ctx, fi, true) {
+                progress.tick();
+                return None;
+            }
             let chunk = FileChunk::new(&fi.path, FilePos(0), fi.len);
-            ctx.hasher
-                .hash(&chunk, |bytes_read| progress.inc(bytes_read))
+            let device = &ctx.devices[fi.get_device_index()];
+            let hash = ctx
+                .hasher
+                .hash_on_device(&chunk, device, |bytes_read| progress.inc_bytes(bytes_read));
+            progress.tick();
+            hash
         },
     );
 
@@ -966,16 +1832,96 @@ fn group_by_contents(
     groups
 }
 
-/// Groups identical files together by 128-bit hash of their contents.
-/// Depending on filtering settings, can find unique, duplicate, over- or under-replicated files.
-///
-/// # Input
-/// The input set of files or paths to scan should be given in the `config.paths` property.
-/// When `config.recursive` is set to true, the search descends into
-/// subdirectories recursively (default is false).
+/// Works like [`group_by_contents`], but passes each group to `emit` as soon as all the files
+/// that could belong to it have been hashed, instead of returning all groups at the end.
 ///
-/// # Output
-/// Returns a vector of groups of absolute paths.
+/// Files can be identical only if they belong to the same input group, so a group is complete
+/// when all files of its input group have been hashed. Input groups not needing hashing are
+/// passed immediately.
+fn stream_by_contents(
+    ctx: &GroupCtx<'_>,
+    min_file_len: FileLen,
+    groups: Vec<FileGroup<FileInfo>>,
+    emit: &(dyn Fn(FileGroup<FileInfo>) + Sync),
+) {
+    let pre_filter = |g: &FileGroup<FileInfo>| !g.is_single() && g.file_len >= min_file_len;
+    let (groups, groups_to_pass): (Vec<_>, Vec<_>) = groups.into_iter().partition(pre_filter);
+    groups_to_pass.into_iter().for_each(emit);
+
+    let file_count = file_count(&groups);
+    let bytes_to_scan = total_size(&groups);
+    let progress =
+        &ctx.log
+            .stage_progress_bar("Grouping by contents", file_count as u64, bytes_to_scan);
+
+    // For each input group, the number of files still to be hashed and the hashed files
+    type PendingGroups = DashMap<(FileLen, FileHash), (usize, Vec<(FileInfo, FileHash)>)>;
+    let pending: PendingGroups = groups
+        .iter()
+        .map(|g| ((g.file_len, g.file_hash), (g.files.len(), Vec::new())))
+        .collect();
+
+    let remaining = rehash(
+        groups,
+        |_| true,
+        |_| true,
+        &ctx.devices,
+        FileAccess::Sequential,
+        |(fi, old_hash)| {
+            // The groups are emitted by input group, so changed files can't be requeued
+            let key = (fi.len, old_hash);
+            let hash = if check_len(ctx, fi, false) {
+                let chunk = FileChunk::new(&fi.path, FilePos(0), fi.len);
+                let device = &ctx.devices[fi.get_device_index()];
+                ctx.hasher
+                    .hash_on_device(&chunk, device, |bytes_read| progress.inc_bytes(bytes_read))
+            } else {
+                None
+            };
+            progress.tick();
+
+            let complete = {
+                let mut entry = pending.get_mut(&key).unwrap();
+                let (remaining, hashed) = entry.value_mut();
+                if let Some(hash) = hash {
+                    hashed.push((fi.clone(), hash));
+                }
+                *remaining -= 1;
+                *remaining == 0
+            };
+            if complete {
+                let (_, (_, hashed)) = pending.remove(&key).unwrap();
+                let mut subgroups: BTreeMap<FileHash, Vec<FileInfo>> = BTreeMap::new();
+                for (fi, hash) in hashed {
+                    subgroups.entry(hash).or_default().push(fi);
+                }
+                subgroups
+                    .into_iter()
+                    .map(|(hash, files)| FileGroup {
+                        file_len: key.0,
+                        file_hash: hash,
+                        files,
+                    })
+                    .filter(|g| g.matches_strictly(&ctx.group_filter))
+                    .for_each(emit);
+            }
+            // The groups are emitted above, so nothing is left for rehash to collect
+            None
+        },
+    );
+    debug_assert!(remaining.is_empty());
+}
+
+/// Groups identical files together by 128-bit hash of their contents.
+/// Depending on filtering settings, can find unique, duplicate, over- or under-replicated files.
+///
+/// # Input
+/// The input set of files or paths to scan should be given in the `config.paths` property.
+/// When `config.recursive` is set to true, the search descends into
+/// subdirectories recursively (default is false).
+///
+/// # Output
+/// Returns a vector of groups of absolute paths.
 /// Each group of files has a common hash and length.
 /// Groups are sorted descending by file size.
 ///
@@ -1032,69 +1978,454 @@ fn group_by_contents(
 /// write_report(&config, &log, &groups).unwrap();
 /// ```
 pub fn group_files(config: &GroupConfig, log: &Log) -> Result<Vec<FileGroup<Path>>, Error> {
+    group_files_with_metrics(config, log).map(|(groups, _, _)| groups)
+}
+
+/// Works like [`group_files`], but additionally returns the statistics of the scan
+/// and the wall time and counters of each grouping stage.
+///
+/// The returned statistics hold only the information that can't be computed
+/// from the groups, e.g. the files that couldn't be read, the inaccessible paths
+/// if `config.report_permission_errors` is set or the total size of the scanned files
+/// if `config.report_total_size` is set.
+/// Pass them to [`write_report_with_metrics`] to include them in the report.
+pub fn group_files_with_metrics(
+    config: &GroupConfig,
+    log: &Log,
+) -> Result<(Vec<FileGroup<Path>>, FileStats, RunMetrics), Error> {
     let spinner = log.spinner("Initializing");
-    let ctx = GroupCtx::new(config, log)?;
+    let errors = ErrorLimit::new(log, config.error_limit);
+    let denied = DeniedAccess::new(&errors);
+    let ctx = GroupCtx::new(config, log, &denied, &errors)?;
+    let mut stats = FileStats::default();
+    let mut metrics = RunMetrics::default();
+    let m = &mut metrics;
 
     drop(spinner);
-    let matching_files = scan_files(&ctx);
-    let size_groups = group_by_size(&ctx, matching_files);
-    let mut size_groups_pruned = remove_same_files(&ctx, size_groups);
-    update_file_locations(&ctx, &mut size_groups_pruned);
-
+    let (candidates, prefix_len) = find_candidates(&ctx, &mut stats, m)?;
     let groups = match &ctx.transform {
-        Some(transform) => group_transformed(&ctx, transform, size_groups_pruned),
+        Some(transform) => run_stage(&ctx, m, "transform", candidates, |groups| {
+            group_transformed(&ctx, transform, groups)
+        }),
         _ => {
-            let prefix_len = prefix_len(&ctx.devices, flat_iter(&size_groups_pruned));
-            let prefix_groups = group_by_prefix(&ctx, prefix_len, size_groups_pruned);
-            let suffix_groups = group_by_suffix(&ctx, prefix_groups);
-            group_by_contents(&ctx, prefix_len, suffix_groups)
+            let groups = run_stage(&ctx, m, "contents hash", candidates, |groups| {
+                group_by_contents(&ctx, prefix_len, groups)
+            });
+            log.debug(format!(
+                "Reused open file handles: {}",
+                ctx.hasher.handles.hits()
+            ));
+            groups
         }
     };
+    ctx.errors.check()?;
     let mut groups: Vec<_> = groups
         .into_par_iter()
-        .map(|g| FileGroup {
-            file_len: g.file_len,
-            file_hash: g.file_hash,
-            files: g.files.into_iter().map(|fi| fi.path).collect(),
-        })
+        .filter_map(|g| finalize_group(&ctx, g))
         .collect();
-    groups.retain(|g| g.files.len() < ctx.config.rf_under());
     groups.par_sort_by_key(|g| Reverse((g.file_len, g.file_hash)));
-    groups
-        .par_iter_mut()
-        .for_each(|g| g.sort(&ctx.group_filter.root_paths));
-    Ok(groups)
+    stats.read_error_files = ctx.hasher.read_error_files();
+    if config.report_permission_errors {
+        stats.permission_errors = Some(denied.paths());
+    }
+    report_denied_access(&ctx)?;
+    Ok((groups, stats, metrics))
+}
+
+/// Maximum number of finished groups waiting for the consumer in [`group_files_streaming`]
+const STREAM_BUFFER_LEN: usize = 256;
+
+/// Works like [`group_files`], but passes each group to `consumer` as soon as it is found,
+/// instead of returning all groups at the end. Returns the statistics of all the groups
+/// passed to the consumer.
+///
+/// # Ordering
+/// The groups are passed in no particular order, and the order may differ between runs.
+/// The files in each group are sorted the same way as by [`group_files`].
+/// If a transform is configured, the files must be transformed before any group is known
+/// to be complete, so the groups are passed only after all files have been transformed.
+///
+/// # Threading
+/// The consumer is called on the caller's thread while the files are processed
+/// in the background. Finished groups are buffered up to a small limit; when the buffer
+/// is full, hashing waits until the consumer takes some groups, so a slow consumer
+/// slows down the search rather than increasing the memory use.
+///
+/// # Example
+/// ```
+/// use fclones::config::GroupConfig;
+/// use fclones::log::Log;
+/// use fclones::path::Path;
+/// use fclones::group_files_streaming;
+///
+/// let log = Log::new();
+/// let mut config = GroupConfig::default();
+/// config.paths = vec![Path::from("/path/to/a/dir")];
+///
+/// let stats = group_files_streaming(&config, &log, |group| {
+///     println!("{} files of size {}", group.file_count(), group.file_len());
+/// })
+/// .unwrap();
+/// println!("Found {} groups", stats.group_count);
+/// ```
+pub fn group_files_streaming<C>(
+    config: &GroupConfig,
+    log: &Log,
+    mut consumer: C,
+) -> Result<FileStats, Error>
+where
+    C: FnMut(FileGroup<Path>),
+{
+    let spinner = log.spinner("Initializing");
+    let errors = ErrorLimit::new(log, config.error_limit);
+    let denied = DeniedAccess::new(&errors);
+    let ctx = GroupCtx::new(config, log, &denied, &errors)?;
+    drop(spinner);
+
+    let ctx = &ctx;
+    let filter = &ctx.group_filter;
+    let mut stats = FileStats {
+        size_histogram: config.size_histogram(),
+        freed_inode_count: config.report_inode_efficiency.then_some(0),
+        ..FileStats::default()
+    };
+    let (sender, receiver) = sync_channel(STREAM_BUFFER_LEN);
+    let scan_stats = thread::scope(|s| {
+        let handle = s.spawn(move |_| {
+            let emit = |g: FileGroup<FileInfo>| {
+                if let Some(g) = finalize_group(ctx, g) {
+                    // Fails only if the consumer panicked; there is nobody to report to then
+                    let _ = sender.send(g);
+                }
+            };
+            let mut scan_stats = FileStats::default();
+            let mut metrics = RunMetrics::default();
+            let (candidates, prefix_len) = find_candidates(ctx, &mut scan_stats, &mut metrics)?;
+            match &ctx.transform {
+                Some(transform) => group_transformed(ctx, transform, candidates)
+                    .into_iter()
+                    .for_each(emit),
+                None => stream_by_contents(ctx, prefix_len, candidates, &emit),
+            }
+            ctx.errors.check()?;
+            Ok::<_, Error>(scan_stats)
+        });
+        for g in receiver {
+            if config.hard_links && g.is_hardlink_only() {
+                stats.add_hardlink_group(&g);
+            } else {
+                stats.add_group(&g, filter);
+            }
+            consumer(g);
+        }
+        handle.join().unwrap()
+    })
+    .unwrap()?;
+    stats.scanned_file_size = scan_stats.scanned_file_size;
+    stats.excluded_by_extension = scan_stats.excluded_by_extension;
+    stats.read_error_files = ctx.hasher.read_error_files();
+    if config.report_permission_errors {
+        stats.permission_errors = Some(ctx.denied.paths());
+    }
+    report_denied_access(ctx)?;
+    Ok(stats)
+}
+
+/// Logs the summary of the files that couldn't be accessed due to insufficient permissions
+/// and writes their list to the file given by `config.list_denied`.
+fn report_denied_access(ctx: &GroupCtx<'_>) -> Result<(), Error> {
+    // Paths read from the standard input can't be read again, so they are not used as roots
+    let roots: Vec<Path> = if ctx.config.stdin {
+        vec![]
+    } else {
+        ctx.config.input_paths().collect()
+    };
+    ctx.denied.log_summary(&roots);
+    if let Some(file) = &ctx.config.list_denied {
+        ctx.denied.write_list(file).map_err(|e| {
+            let file = Path::from(file);
+            Error::io(
+                "Failed to write the list of inaccessible paths to",
+                Some(&file),
+                e,
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// Runs all grouping stages except the last one, which hashes the full contents
+/// or transforms the files. Returns the remaining groups and the length of the prefix
+/// hashed by the prefix stage.
+///
+/// If `config.checksum_file` is set, writes the checksums of all scanned files first.
+/// The checksums cover the whole contents of the files, so the files are then grouped
+/// by their checksums instead of being read again by the hashing stages, and the returned
+/// prefix length is [`FileLen::MAX`].
+/// The statistics of the directory walk are recorded in `stats`.
+fn find_candidates(
+    ctx: &GroupCtx<'_>,
+    stats: &mut FileStats,
+    m: &mut RunMetrics,
+) -> Result<(Vec<FileGroup<FileInfo>>, FileLen), Error> {
+    let background = (ctx.config.scan_in_background
+        && ctx.transform.is_none()
+        && ctx.config.checksum_file.is_none())
+    .then(|| BackgroundHashes::new(&ctx.devices));
+    let matching_files = scan_files(ctx, stats, m, background.as_ref());
+    ctx.errors.check()?;
+    let checksums = match &ctx.config.checksum_file {
+        Some(path) => Some(write_checksum_file(ctx, path, &matching_files)?),
+        None => None,
+    };
+    let file_count_in = matching_files.iter().map(|v| v.len()).sum();
+    let timer = StageTimer::start("size", file_count_in, &ctx.hasher.counters);
+    let size_groups = group_by_size(ctx, matching_files);
+    m.stages.push(timer.finish(file_count(&size_groups)));
+    let size_groups_pruned = run_stage(ctx, m, "same files", size_groups, |groups| {
+        remove_same_files(ctx, groups)
+    });
+    let size_groups_pruned = run_stage(ctx, m, "extents", size_groups_pruned, |mut groups| {
+        update_file_locations(ctx, &mut groups);
+        groups
+    });
+    if ctx.transform.is_some() {
+        return Ok((size_groups_pruned, FileLen(0)));
+    }
+    if let Some(checksums) = checksums {
+        let groups = run_stage(ctx, m, "checksum", size_groups_pruned, |groups| {
+            group_by_checksum(ctx, &checksums, groups)
+        });
+        return Ok((groups, FileLen::MAX));
+    }
+
+    let prefix_len = match &background {
+        Some(background) => background.prefix_len,
+        None => prefix_len(&ctx.devices, flat_iter(&size_groups_pruned)),
+    };
+    let prefix_groups = run_stage(ctx, m, "prefix hash", size_groups_pruned, |groups| {
+        group_by_prefix(ctx, prefix_len, groups, background.as_ref())
+    });
+    ctx.errors.check()?;
+    let suffix_groups = run_stage(ctx, m, "suffix hash", prefix_groups, |groups| {
+        group_by_suffix(ctx, groups)
+    });
+    ctx.errors.check()?;
+    Ok((suffix_groups, prefix_len))
+}
+
+/// Writes the SHA-256 digests of the scanned files to the checksum file at `path`.
+/// Returns the digests truncated to the length of [`FileHash`], by file path.
+fn write_checksum_file(
+    ctx: &GroupCtx<'_>,
+    path: &std::path::Path,
+    files: &[Vec<FileInfo>],
+) -> Result<HashMap<Path, FileHash>, Error> {
+    let files = files.iter().flatten().cloned().collect();
+    let digests = compute_checksums(files, &ctx.devices, ctx.log);
+    write_report_file(path, false, |out| {
+        write_checksum_lines(&digests, ctx.config.checksum_format, out)
+    })
+    .map_err(|e| Error::io("Failed to write checksum file", Some(&Path::from(path)), e))?;
+    ctx.log.info(format!(
+        "Wrote checksums of {} files to {}",
+        digests.len(),
+        Path::from(path).display()
+    ));
+    Ok(digests
+        .into_iter()
+        .map(|(path, digest)| {
+            let hash = u128::from_be_bytes(digest[..16].try_into().unwrap());
+            (path, FileHash(hash))
+        })
+        .collect())
+}
+
+/// Groups the files by the checksums computed by [`write_checksum_file`], without reading them.
+/// Files without a checksum couldn't be read and are left out.
+fn group_by_checksum(
+    ctx: &GroupCtx<'_>,
+    checksums: &HashMap<Path, FileHash>,
+    groups: Vec<FileGroup<FileInfo>>,
+) -> Vec<FileGroup<FileInfo>> {
+    rehash(
+        groups,
+        |g| !g.is_single(),
+        |g| g.matches_strictly(&ctx.group_filter),
+        &ctx.devices,
+        FileAccess::Sequential,
+        |(fi, _)| {
+            let hash = checksums.get(&fi.path).copied();
+            if hash.is_none() {
+                ctx.hasher.record_read_error(&fi.path);
+            }
+            hash
+        },
+    )
+}
+
+/// Applies the filters that can be checked only after the last stage,
+/// and converts the group to the form returned to the caller.
+/// Returns `None` if the group should not be reported.
+fn finalize_group(ctx: &GroupCtx<'_>, group: FileGroup<FileInfo>) -> Option<FileGroup<Path>> {
+    let mut group = FileGroup {
+        file_len: group.file_len,
+        file_hash: group.file_hash,
+        files: group.files.into_iter().map(|fi| fi.path).collect(),
+    };
+    if group.files.len() >= ctx.config.rf_under()
+        || ctx.config.require_same_basename && !group.has_same_basename()
+        || ctx.config.hard_links && ctx.config.exclude_hardlink_groups && group.is_hardlink_only()
+        || group.is_baseline_only(&ctx.group_filter.baseline_paths)
+        || !group.spans_min_roots(&ctx.group_filter)
+    {
+        return None;
+    }
+    group.sort(&ctx.group_filter.root_paths);
+    Some(group)
+}
+
+/// Returns up to `n` groups wasting the most space, from the largest.
+/// Groups wasting the same space are sorted by hash.
+pub fn largest_groups<F>(groups: &[FileGroup<F>], n: usize) -> Vec<&FileGroup<F>> {
+    let mut largest = groups.iter().collect_vec();
+    largest.sort_by(|g1, g2| {
+        g2.wasted_bytes()
+            .cmp(&g1.wasted_bytes())
+            .then_with(|| g1.file_hash.cmp(&g2.file_hash))
+    });
+    largest.truncate(n);
+    largest
+}
+
+/// Computes how many bytes could be saved in each directory by removing redundant files.
+///
+/// The size of each redundant file is added to its parent directory and to all
+/// the ancestors of the parent, so the savings in a directory include the savings
+/// in all its subdirectories. Returns the list of directories sorted by savings,
+/// from the highest. Directories with the same savings are sorted by path.
+pub fn savings_by_directory(
+    groups: &[FileGroup<Path>],
+    filter: &FileGroupFilter,
+) -> Vec<(Path, FileLen)> {
+    let mut savings: HashMap<Path, FileLen> = HashMap::new();
+    for g in groups {
+        for f in g.redundant_files(filter) {
+            let mut dir = f.parent();
+            while let Some(d) = dir {
+                // Ancestors are shared between files, so after the first visit the
+                // lookup doesn't need to clone the path
+                match savings.get_mut(d.as_ref()) {
+                    Some(len) => *len += g.file_len,
+                    None => {
+                        savings.insert(d.as_ref().clone(), g.file_len);
+                    }
+                }
+                dir = d.parent();
+            }
+        }
+    }
+    let mut savings = savings.into_iter().collect_vec();
+    savings.sort_by(|(p1, l1), (p2, l2)| l2.cmp(l1).then_with(|| p1.cmp(p2)));
+    savings
 }
 
 /// Writes the list of groups to a file or the standard output.
 ///
 /// # Parameters
 /// - `config.output`: a path to the output file, `None` for standard output
-/// - `config.format`: selects the format of the output, see [`config::OutputFormat`]
+/// - `config.compress_output`: compresses the output file with gzip and appends `.gz` to its name
+/// - `config.format`: selects the format of the output, see [`config::OutputFormat`];
+///   if not set, the format is guessed from the extension of the output file
 /// - `log`: used for drawing a progress bar to standard error
 /// - `groups`: list of groups of files to print, e.g. obtained from [`group_files`]
 ///
 /// # Errors
 /// Returns [`io::Error`] on I/O write error or if the output file cannot be created.
 pub fn write_report(config: &GroupConfig, log: &Log, groups: &[FileGroup<Path>]) -> io::Result<()> {
+    write_report_with_metrics(config, log, groups, &FileStats::default(), None)
+}
+
+/// Works like [`write_report`], but additionally includes the statistics of the scan
+/// and the stage metrics obtained from [`group_files_with_metrics`] in the report header.
+/// The metrics are written only in the JSON format.
+pub fn write_report_with_metrics(
+    config: &GroupConfig,
+    log: &Log,
+    groups: &[FileGroup<Path>],
+    scan_stats: &FileStats,
+    metrics: Option<&RunMetrics>,
+) -> io::Result<()> {
     let now = Local::now();
 
     let total_count = file_count(groups.iter());
     let total_size = total_size(groups.iter());
 
-    let (redundant_count, redundant_size) = groups.iter().fold((0, FileLen(0)), |res, g| {
-        let count = g.redundant_count(&config.group_filter());
-        (res.0 + count, res.1 + g.file_len * count as u64)
+    // The identifiers are read once for the statistics and the report writer
+    let file_ids = (config.hard_links || config.report_inode_efficiency)
+        .then(|| Arc::new(read_file_ids(groups)));
+    let inode_groups = |g: &FileGroup<Path>| g.inode_groups_with(file_ids.as_deref());
+
+    // Hard links can be found only with `--hard-links`, so don't check them otherwise
+    let group_redundant_count = |g: &FileGroup<Path>| {
+        if config.hard_links && is_hardlink_only(&inode_groups(g)) {
+            0
+        } else {
+            g.reclaimable_count(&config.group_filter())
+        }
+    };
+    let (redundant_count, redundant_size, redundant_allocated_size) =
+        groups.iter().fold((0, FileLen(0), FileLen(0)), |res, g| {
+            let count = group_redundant_count(g);
+            let block_size = match config.block_size {
+                Some(block_size) => Some(block_size),
+                None if count > 0 => g.files.first().and_then(fs_block_size),
+                None => None,
+            };
+            let allocated_len = match block_size {
+                Some(block_size) => g.file_len.round_up_to(block_size),
+                None => g.file_len,
+            };
+            (
+                res.0 + count,
+                res.1 + g.file_len * count as u64,
+                res.2 + allocated_len * count as u64,
+            )
+        });
+    let size_histogram = config.size_histogram().map(|mut histogram| {
+        for g in groups {
+            histogram.add(g.file_len, group_redundant_count(g));
+        }
+        histogram
+    });
+    let freed_inode_count = config.report_inode_efficiency.then(|| {
+        let filter = config.group_filter();
+        groups
+            .iter()
+            .map(|g| g.freed_inode_count_with(&inode_groups(g), &filter))
+            .sum()
     });
     let (missing_count, missing_size) = groups.iter().fold((0, FileLen(0)), |res, g| {
         let count = g.missing_count(&config.group_filter());
         (res.0 + count, res.1 + g.file_len * count as u64)
     });
 
+    let timestamp = if log.strict_iso_8601 {
+        // Sub-second precision makes no sense for a report and could confuse strict parsers
+        let utc = now.naive_utc().with_nanosecond(0).unwrap();
+        Utc.from_utc_datetime(&utc).into()
+    } else {
+        DateTime::from_utc(now.naive_utc(), *now.offset())
+    };
+    let extension_filter = config.extension_filter();
     let header = ReportHeader {
-        timestamp: DateTime::from_utc(now.naive_utc(), *now.offset()),
+        timestamp,
         version: env!("CARGO_PKG_VERSION").to_owned(),
-        command: args_os().map(Arg::from).collect(),
+        command: if config.command_line.is_empty() {
+            args_os().map(Arg::from).collect()
+        } else {
+            config.command_line.iter().cloned().map(Arg::from).collect()
+        },
         base_dir: config.base_dir.clone(),
         stats: Some(FileStats {
             group_count: groups.len(),
@@ -1102,31 +2433,229 @@ pub fn write_report(config: &GroupConfig, log: &Log, groups: &[FileGroup<Path>])
             total_file_size: total_size,
             redundant_file_count: redundant_count,
             redundant_file_size: redundant_size,
+            redundant_allocated_size: Some(redundant_allocated_size),
             missing_file_count: missing_count,
             missing_file_size: missing_size,
+            scanned_file_size: scan_stats.scanned_file_size,
+            size_histogram,
+            freed_inode_count,
+            read_error_files: scan_stats.read_error_files.clone(),
+            excluded_by_extension: scan_stats.excluded_by_extension,
+            permission_errors: scan_stats.permission_errors.clone(),
         }),
+        size_filter: config.size_filter(),
+        extension_filter: Some(extension_filter).filter(|f| !f.is_empty()),
+        baseline: config.baseline_paths().collect(),
+        roots: config.group_filter().input_roots,
+        metrics: metrics.cloned(),
     };
 
-    match &config.output {
+    if config.report_savings_by_directory {
+        let savings = savings_by_directory(groups, &config.group_filter());
+        return match config.output_path() {
+            Some(path) => write_report_file(&path, config.compress_output, |file| {
+                let mut reporter =
+                    ReportWriter::new(file, false).strict_iso_8601(log.strict_iso_8601);
+                reporter.write_savings_by_directory(&header, savings)
+            }),
+            None => {
+                let term = Term::stdout();
+                let color = term.is_term();
+                let mut reporter = ReportWriter::new(BufWriter::new(term), color)
+                    .strict_iso_8601(log.strict_iso_8601);
+                reporter.write_savings_by_directory(&header, savings)
+            }
+        };
+    }
+
+    if config.report_empty_dirs {
+        let roots: Vec<Path> = config.input_paths().collect();
+        let filter = config.group_filter();
+        let removed = groups
+            .iter()
+            .flat_map(|g| g.redundant_files(&filter))
+            .cloned();
+        let dirs = find_empty_dirs(&roots, removed);
+        return match config.output_path() {
+            Some(path) => write_report_file(&path, config.compress_output, |file| {
+                let mut reporter =
+                    ReportWriter::new(file, false).strict_iso_8601(log.strict_iso_8601);
+                reporter.write_empty_dirs(&header, dirs)
+            }),
+            None => {
+                let term = Term::stdout();
+                let color = term.is_term();
+                let mut reporter = ReportWriter::new(BufWriter::new(term), color)
+                    .strict_iso_8601(log.strict_iso_8601);
+                reporter.write_empty_dirs(&header, dirs)
+            }
+        };
+    }
+
+    if let Some(previous) = &config.diff {
+        let previous = read_previous_report(previous)?;
+        let diffs = diff_groups(groups, &previous);
+        return match config.output_path() {
+            Some(path) => write_report_file(&path, config.compress_output, |file| {
+                let mut reporter = ReportWriter::new(file, false)
+                    .strict_iso_8601(log.strict_iso_8601)
+                    .hash_encoding(config.hash_encoding);
+                reporter.write_group_diffs(&header, diffs)
+            }),
+            None => {
+                let term = Term::stdout();
+                let color = term.is_term();
+                let mut reporter = ReportWriter::new(BufWriter::new(term), color)
+                    .strict_iso_8601(log.strict_iso_8601)
+                    .hash_encoding(config.hash_encoding);
+                reporter.write_group_diffs(&header, diffs)
+            }
+        };
+    }
+
+    if let Some(n) = config.report_largest_groups {
+        let largest = largest_groups(groups, n);
+        return match config.output_path() {
+            Some(path) => write_report_file(&path, config.compress_output, |file| {
+                let mut reporter = ReportWriter::new(file, false)
+                    .strict_iso_8601(log.strict_iso_8601)
+                    .hash_encoding(config.hash_encoding);
+                reporter.write_largest_groups(&header, largest)
+            }),
+            None => {
+                let term = Term::stdout();
+                let color = term.is_term();
+                let width = term
+                    .size_checked()
+                    .filter(|_| color)
+                    .map(|(_, w)| w as usize);
+                let mut reporter = ReportWriter::new(BufWriter::new(term), color)
+                    .strict_iso_8601(log.strict_iso_8601)
+                    .hash_encoding(config.hash_encoding)
+                    .terminal_width(width);
+                reporter.write_largest_groups(&header, largest)
+            }
+        };
+    }
+
+    match config.output_path() {
+        #[cfg(feature = "sqlite")]
+        Some(path) if matches!(config.output_format(), OutputFormat::Sqlite) => {
+            let progress = log.progress_bar("Writing report", groups.len() as u64);
+            let iter = groups.iter().inspect(|_g| progress.tick());
+            crate::sqlite::write_sqlite_report(&path, &header, iter)
+        }
         Some(path) => {
             let progress = log.progress_bar("Writing report", groups.len() as u64);
             let iter = groups.iter().inspect(|_g| progress.tick());
-            let file = BufWriter::new(File::create(path)?);
-            let mut reporter = ReportWriter::new(file, false);
-            reporter.write(config.format, &header, iter)
+            write_report_file(&path, config.compress_output, |file| {
+                let mut reporter = ReportWriter::new(file, false)
+                    .strict_iso_8601(log.strict_iso_8601)
+                    .print0(config.print0)
+                    .hard_links(config.hard_links)
+                    .file_ids(file_ids)
+                    .hash_encoding(config.hash_encoding)
+                    .unicode(supports_unicode());
+                reporter.write(config.output_format(), &header, iter)
+            })
         }
         None => {
             let term = Term::stdout();
             let color = term.is_term();
-            let mut reporter = ReportWriter::new(BufWriter::new(term), color);
-            reporter.write(config.format, &header, groups.iter())
+            let mut reporter = ReportWriter::new(BufWriter::new(term), color)
+                .strict_iso_8601(log.strict_iso_8601)
+                .print0(config.print0)
+                .hard_links(config.hard_links)
+                .file_ids(file_ids)
+                .hash_encoding(config.hash_encoding)
+                .unicode(supports_unicode());
+            reporter.write(config.output_format(), &header, groups.iter())
         }
     }
 }
 
+/// Reads all groups from the report of an earlier run
+fn read_previous_report(path: &std::path::Path) -> io::Result<Vec<FileGroup<Path>>> {
+    let read = || -> io::Result<Vec<FileGroup<Path>>> {
+        let mut reader = open_report(File::open(path)?)?;
+        reader.read_header()?;
+        let mut groups = reader.read_groups()?;
+        let mut result = Vec::new();
+        while let Some(group) = groups.next()? {
+            result.push(group);
+        }
+        Ok(result)
+    };
+    read().map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "Failed to read the previous report {}: {}",
+                path.display(),
+                e
+            ),
+        )
+    })
+}
+
+/// Pairs the groups with the groups of the same file size and hash from an earlier report
+/// and compares their members. Groups found only in the earlier report are returned last,
+/// with all members removed.
+fn diff_groups<'a>(
+    groups: &'a [FileGroup<Path>],
+    previous: &'a [FileGroup<Path>],
+) -> Vec<(&'a FileGroup<Path>, GroupDiff)> {
+    let empty = |g: &FileGroup<Path>| FileGroup {
+        file_len: g.file_len,
+        file_hash: g.file_hash,
+        files: Vec::<Path>::new(),
+    };
+    let mut previous_by_key: HashMap<(FileLen, FileHash), &FileGroup<Path>> = previous
+        .iter()
+        .map(|g| ((g.file_len, g.file_hash), g))
+        .collect();
+    let mut result: Vec<_> = groups
+        .iter()
+        .map(
+            |g| match previous_by_key.remove(&(g.file_len, g.file_hash)) {
+                Some(p) => (g, g.diff(p)),
+                None => (g, g.diff(&empty(g))),
+            },
+        )
+        .collect();
+    // Keep the order of the earlier report:
+    result.extend(
+        previous
+            .iter()
+            .filter(|g| previous_by_key.contains_key(&(g.file_len, g.file_hash)))
+            .map(|g| (g, empty(g).diff(g))),
+    );
+    result
+}
+
+/// Creates the report file and passes a buffered writer to `write`.
+/// If `compress` is set, the data are compressed with gzip.
+fn write_report_file<F>(path: &std::path::Path, compress: bool, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut dyn Write) -> io::Result<()>,
+{
+    let file = File::create(path)?;
+    if compress {
+        let mut out = BufWriter::new(GzEncoder::new(file, Compression::default()));
+        write(&mut out)?;
+        out.into_inner().map_err(|e| e.into_error())?.finish()?;
+        Ok(())
+    } else {
+        let mut out = BufWriter::new(file);
+        write(&mut out)?;
+        out.flush()
+    }
+}
+
 #[cfg(test)]
 mod test {
 
+    use std::fs;
     use std::fs::{create_dir, hard_link, File, OpenOptions};
     use std::io::{Read, Write};
     use std::path::PathBuf;
@@ -1235,6 +2764,28 @@ mod test {
         assert_eq!(result[0].files.len(), 2);
     }
 
+    #[test]
+    fn report_line_is_truncated_to_terminal_width() {
+        let group = FileGroup {
+            file_len: FileLen(100),
+            file_hash: FileHash(u128::MAX),
+            files: vec![Path::from("/a/b/c"), Path::from("/d"), Path::from("/e")],
+        };
+        assert_eq!(
+            group.to_report_line(100),
+            "ffffffff 3 files, 200 B wasted: /a/b/c"
+        );
+        assert_eq!(
+            group.to_report_line(36),
+            "ffffffff 3 files, 200 B wasted: ...c"
+        );
+        assert_eq!(
+            group.to_report_line(34),
+            "ffffffff 3 files, 200 B wasted: .."
+        );
+        assert_eq!(group.to_report_line(10), "ffffffff 3 files, 200 B wasted: ");
+    }
+
     #[test]
     fn test_rehash_can_skip_processing_files() {
         let devices = DiskDevices::default();
@@ -1341,6 +2892,65 @@ mod test {
         assert!(distance < (thread_count * count) as i32)
     }
 
+    #[test]
+    fn largest_and_smallest_member() {
+        let file = |name: &str, len: u64| FileInfo {
+            path: Path::from(name),
+            len: FileLen(len),
+            location: 0,
+        };
+        let group = FileGroup {
+            file_len: FileLen(0),
+            file_hash: FileHash(0),
+            files: vec![file("a", 10), file("b", 30), file("c", 5), file("d", 30)],
+        };
+        assert_eq!(group.largest_member().path, Path::from("b"));
+        assert_eq!(group.smallest_member().path, Path::from("c"));
+    }
+
+    #[test]
+    #[should_panic]
+    fn largest_member_of_empty_group() {
+        let group: FileGroup<FileInfo> = FileGroup {
+            file_len: FileLen(0),
+            file_hash: FileHash(0),
+            files: vec![],
+        };
+        group.largest_member();
+    }
+
+    #[test]
+    fn member_count_and_is_single() {
+        let group = |files: &[&str]| FileGroup {
+            file_len: FileLen(0),
+            file_hash: FileHash(0),
+            files: files.iter().map(|&f| Path::from(f)).collect::<Vec<_>>(),
+        };
+        assert_eq!(group(&[]).member_count(), 0);
+        assert!(group(&[]).is_single());
+        assert!(group(&["/a"]).is_single());
+        assert_eq!(group(&["/a", "/b"]).member_count(), 2);
+        assert!(!group(&["/a", "/b"]).is_single());
+    }
+
+    #[test]
+    fn files_outside_snapshots_are_reclaimable() {
+        with_dir("main/files_outside_snapshots_are_reclaimable", |root| {
+            let files = ["file1", "file2", "file3"].map(|f| root.join(f));
+            for f in files.iter() {
+                write_test_file(f, b"aaa", b"", b"");
+            }
+            let group = FileGroup {
+                file_len: FileLen(3),
+                file_hash: FileHash(0),
+                files: files.iter().map(Path::from).collect::<Vec<_>>(),
+            };
+            let filter = GroupConfig::default().group_filter();
+            assert_eq!(group.redundant_count(&filter), 2);
+            assert_eq!(group.reclaimable_count(&filter), 2);
+        });
+    }
+
     #[test]
     fn identical_small_files() {
         with_dir("main/identical_small_files", |root| {
@@ -1359,6 +2969,170 @@ mod test {
         });
     }
 
+    #[test]
+    fn scan_in_background_finds_the_same_groups() {
+        with_dir("main/scan_in_background", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            let file3 = root.join("file3");
+            write_test_file(&file1, b"aaa", b"bbb", b"ccc");
+            write_test_file(&file2, b"aaa", b"bbb", b"ccc");
+            write_test_file(&file3, b"xxx", b"bbb", b"ccc");
+
+            let log = test_log();
+            let config = GroupConfig {
+                paths: vec![root.into()],
+                scan_in_background: true,
+                ..GroupConfig::default()
+            };
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].files.len(), 2);
+        });
+    }
+
+    #[test]
+    fn extension_filter_skips_files() {
+        with_dir("main/extension_filter_skips_files", |root| {
+            for name in ["a.JPG", "b.jpg", "c.tmp", "d"] {
+                write_test_file(&root.join(name), b"aaa", b"", b"");
+            }
+
+            let log = test_log();
+            let config = GroupConfig {
+                paths: vec![root.into()],
+                extensions: vec!["jpg".to_owned(), "-".to_owned()],
+                excluded_extensions: vec!["tmp".to_owned()],
+                ..GroupConfig::default()
+            };
+            let (results, stats, _) = group_files_with_metrics(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].files.len(), 3);
+            assert_eq!(stats.excluded_by_extension, Some(1));
+        });
+    }
+
+    #[test]
+    fn empty_files_get_zero_hash() {
+        with_dir("main/empty_files_get_zero_hash", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            write_test_file(&file1, b"", b"", b"");
+            write_test_file(&file2, b"", b"", b"");
+
+            let log = test_log();
+            let config = GroupConfig {
+                paths: vec![file1.into(), file2.into()],
+                min_size: FileLen(0),
+                ..GroupConfig::default()
+            };
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].file_len, FileLen(0));
+            assert!(results[0].file_hash.is_zero());
+            assert_eq!(results[0].files.len(), 2);
+        });
+    }
+
+    #[test]
+    fn metrics_count_files_and_bytes_of_each_stage() {
+        with_dir("main/metrics_count_files_and_bytes", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            let file3 = root.join("file3");
+            write_test_file(&file1, b"aaa", b"", b"");
+            write_test_file(&file2, b"aaa", b"", b"");
+            write_test_file(&file3, b"a", b"", b"");
+
+            let log = test_log();
+            let config = GroupConfig {
+                paths: vec![file1.into(), file2.into(), file3.into()],
+                ..GroupConfig::default()
+            };
+            let (results, _, metrics) = group_files_with_metrics(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+
+            let names: Vec<_> = metrics.stages.iter().map(|s| s.name.as_str()).collect();
+            assert_eq!(
+                names,
+                [
+                    "walk",
+                    "size",
+                    "same files",
+                    "extents",
+                    "prefix hash",
+                    "suffix hash",
+                    "contents hash"
+                ]
+            );
+            let size = &metrics.stages[1];
+            assert_eq!((size.files_in, size.files_out), (3, 2));
+            let prefix = &metrics.stages[4];
+            assert_eq!(prefix.bytes_read, FileLen(6));
+            assert!(metrics.stages.iter().all(|s| s.errors == 0));
+        });
+    }
+
+    #[test]
+    fn checksums_are_reused_for_grouping() {
+        with_dir("main/checksums_are_reused_for_grouping", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            let file3 = root.join("file3");
+            write_test_file(&file1, b"aaa", b"b", b"ccc");
+            write_test_file(&file2, b"aaa", b"b", b"ccc");
+            write_test_file(&file3, b"aaa", b"x", b"ccc");
+            let checksum_file = root.join("checksums");
+
+            let log = test_log();
+            let config = GroupConfig {
+                paths: vec![file1.clone().into(), file2.into(), file3.into()],
+                checksum_file: Some(checksum_file.clone()),
+                ..GroupConfig::default()
+            };
+            let (results, _, metrics) = group_files_with_metrics(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].files.len(), 2);
+
+            // The hash of the group is the beginning of the checksum of the files
+            let checksums = std::fs::read_to_string(checksum_file).unwrap();
+            let line = checksums.lines().next().unwrap();
+            assert!(line.ends_with(&file1.to_string_lossy().to_string()));
+            assert!(line.starts_with(&results[0].file_hash.to_string()));
+
+            let names: Vec<_> = metrics.stages.iter().map(|s| s.name.as_str()).collect();
+            assert!(names.contains(&"checksum"));
+            assert!(!names.contains(&"prefix hash"));
+            // The files are read only once, when computing the checksums
+            assert!(metrics.stages.iter().all(|s| s.bytes_read == FileLen(0)));
+        });
+    }
+
+    #[test]
+    fn require_same_basename() {
+        with_dir("main/require_same_basename", |root| {
+            create_dir(root.join("dir1")).unwrap();
+            create_dir(root.join("dir2")).unwrap();
+            let file1 = root.join("dir1").join("file");
+            let file2 = root.join("dir2").join("file");
+            let file3 = root.join("dir2").join("other");
+            write_test_file(&file1, b"aaa", b"", b"");
+            write_test_file(&file2, b"aaa", b"", b"");
+            write_test_file(&file3, b"bbb", b"", b"");
+            let file4 = root.join("dir1").join("another");
+            write_test_file(&file4, b"bbb", b"", b"");
+
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![root.join("dir1").into(), root.join("dir2").into()];
+            config.require_same_basename = true;
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].files.len(), 2);
+            assert!(results[0].has_same_basename());
+        });
+    }
+
     #[test]
     fn identical_large_files() {
         with_dir("main/identical_large_files", |root| {
@@ -1377,6 +3151,29 @@ mod test {
         });
     }
 
+    #[test]
+    fn identical_files_with_tight_resource_limits() {
+        with_dir("main/tight_resource_limits", |root| {
+            let paths: Vec<Path> = (0..8)
+                .map(|i| {
+                    let file = root.join(format!("file{}", i));
+                    write_test_file(&file, &[0; MAX_PREFIX_LEN], &[1; 4096], &[2; 4096]);
+                    file.into()
+                })
+                .collect();
+
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = paths;
+            config.max_open_files = Some(1);
+            config.max_buffer_memory = Some(FileLen(1024));
+
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].files.len(), 8);
+        });
+    }
+
     #[test]
     fn files_differing_by_size() {
         with_dir("main/files_differing_by_size", |root| {
@@ -1442,6 +3239,164 @@ mod test {
         });
     }
 
+    #[test]
+    fn streaming_finds_the_same_groups() {
+        with_dir("main/streaming_finds_the_same_groups", |root| {
+            let prefix = [0; MAX_PREFIX_LEN];
+            let suffix = [1; MAX_SUFFIX_LEN];
+            let mut paths = Vec::new();
+            for (name, mid) in [
+                ("a", b"mid1"),
+                ("b", b"mid1"),
+                ("c", b"mid2"),
+                ("d", b"mid2"),
+            ] {
+                let path = root.join(name);
+                write_test_file(&path, &prefix, mid, &suffix);
+                paths.push(path.into());
+            }
+            for name in ["e", "f"] {
+                let path = root.join(name);
+                write_test_file(&path, b"small", b"", b"");
+                paths.push(path.into());
+            }
+
+            let log = test_log();
+            let config = GroupConfig {
+                paths,
+                ..GroupConfig::default()
+            };
+            let expected = group_files(&config, &log).unwrap();
+            let mut streamed = Vec::new();
+            let stats = group_files_streaming(&config, &log, |g| streamed.push(g)).unwrap();
+            streamed.sort_by_key(|g| Reverse((g.file_len, g.file_hash)));
+
+            assert_eq!(expected.len(), 3);
+            assert_eq!(streamed, expected);
+            assert_eq!(stats.group_count, 3);
+            assert_eq!(stats.total_file_count, 6);
+            assert_eq!(stats.redundant_file_count, 3);
+        });
+    }
+
+    /// Scans the files, groups them by size, then appends `tail` to the file at `grown`
+    /// and runs the hashing stages
+    fn group_after_append(config: &GroupConfig, grown: &PathBuf, tail: &[u8]) -> Vec<Vec<Path>> {
+        let log = test_log();
+        let errors = ErrorLimit::new(&log, 0);
+        let denied = DeniedAccess::new(&errors);
+        let ctx = GroupCtx::new(config, &log, &denied, &errors).unwrap();
+        let files = scan_files(
+            &ctx,
+            &mut FileStats::default(),
+            &mut RunMetrics::default(),
+            None,
+        );
+        let groups = group_by_size(&ctx, files);
+        OpenOptions::new()
+            .append(true)
+            .open(grown)
+            .unwrap()
+            .write_all(tail)
+            .unwrap();
+        let prefix_len = prefix_len(&ctx.devices, flat_iter(&groups));
+        let groups = group_by_prefix(&ctx, prefix_len, groups, None);
+        let groups = group_by_suffix(&ctx, groups);
+        let groups = group_by_contents(&ctx, FileLen(0), groups);
+        groups
+            .into_iter()
+            .filter_map(|g| finalize_group(&ctx, g))
+            .map(|g| g.files)
+            .collect()
+    }
+
+    #[test]
+    fn files_changing_size_during_scan_are_skipped() {
+        with_dir("main/files_changing_size_during_scan_are_skipped", |root| {
+            let paths: Vec<_> = ["a", "b", "c", "d"].iter().map(|n| root.join(n)).collect();
+            write_test_file(&paths[0], b"long content", b"", b"");
+            write_test_file(&paths[1], b"long content", b"", b"");
+            write_test_file(&paths[2], b"long", b"", b"");
+            write_test_file(&paths[3], b"long", b"", b"");
+
+            let config = GroupConfig {
+                paths: paths.iter().map(Path::from).collect(),
+                ..GroupConfig::default()
+            };
+            let groups = group_after_append(&config, &paths[3], b" content");
+            assert_eq!(
+                groups,
+                vec![vec![Path::from(&paths[0]), Path::from(&paths[1])]]
+            );
+        });
+    }
+
+    #[test]
+    fn files_changing_size_during_scan_are_requeued() {
+        with_dir(
+            "main/files_changing_size_during_scan_are_requeued",
+            |root| {
+                let paths: Vec<_> = ["a", "b", "c", "d"].iter().map(|n| root.join(n)).collect();
+                write_test_file(&paths[0], b"long content", b"", b"");
+                write_test_file(&paths[1], b"long content", b"", b"");
+                write_test_file(&paths[2], b"long", b"", b"");
+                write_test_file(&paths[3], b"long", b"", b"");
+
+                let config = GroupConfig {
+                    paths: paths.iter().map(Path::from).collect(),
+                    requeue_changed: true,
+                    ..GroupConfig::default()
+                };
+                let groups = group_after_append(&config, &paths[3], b" content");
+                let expected: Vec<_> = [&paths[0], &paths[1], &paths[3]]
+                    .into_iter()
+                    .map(Path::from)
+                    .collect();
+                assert_eq!(groups, vec![expected]);
+            },
+        );
+    }
+
+    #[test]
+    fn grouping_survives_file_growing_concurrently() {
+        with_dir("main/grouping_survives_file_growing_concurrently", |root| {
+            let prefix = [0; MAX_PREFIX_LEN];
+            let suffix = [1; MAX_SUFFIX_LEN];
+            let paths: Vec<_> = ["a", "b", "growing"].iter().map(|n| root.join(n)).collect();
+            for path in &paths {
+                write_test_file(path, &prefix, b"mid", &suffix);
+            }
+
+            let log = test_log();
+            let config = GroupConfig {
+                paths: paths.iter().map(Path::from).collect(),
+                ..GroupConfig::default()
+            };
+            let done = AtomicBool::new(false);
+            let groups = crossbeam_utils::thread::scope(|s| {
+                s.spawn(|_| {
+                    let mut file = OpenOptions::new().append(true).open(&paths[2]).unwrap();
+                    while !done.load(Ordering::Relaxed) {
+                        file.write_all(b"log line\n").unwrap();
+                        std::thread::sleep(std::time::Duration::from_millis(1));
+                    }
+                });
+                let groups = group_files(&config, &log);
+                done.store(true, Ordering::Relaxed);
+                groups
+            })
+            .unwrap()
+            .unwrap();
+
+            // The growing file may or may not be caught in time, but the stable files
+            // must always be found:
+            assert!(groups
+                .iter()
+                .any(|g| g.files.contains(&Path::from(&paths[0]))
+                    && g.files.contains(&Path::from(&paths[1]))));
+        });
+    }
+
     #[test]
     fn files_differing_by_middle() {
         with_dir("main/files_differing_by_middle", |root| {
@@ -1483,6 +3438,92 @@ mod test {
         });
     }
 
+    #[test]
+    fn hardlink_only_groups() {
+        with_dir("main/hardlink_only_groups", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            write_test_file(&file1, b"aaa", b"", b"");
+            hard_link(&file1, &file2).unwrap();
+
+            let log = test_log();
+            let mut config = GroupConfig {
+                paths: vec![file1.into(), file2.into()],
+                hard_links: true,
+                ..GroupConfig::default()
+            };
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].inode_groups(), vec![0, 0]);
+            assert!(results[0].is_hardlink_only());
+            assert_eq!(results[0].freed_inode_count(&config.group_filter()), 0);
+
+            config.exclude_hardlink_groups = true;
+            let results = group_files(&config, &log).unwrap();
+            assert!(results.is_empty());
+        });
+    }
+
+    #[test]
+    fn inode_groups_from_file_ids_read_once() {
+        with_dir("main/inode_groups_from_file_ids_read_once", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            let file3 = root.join("file3");
+            write_test_file(&file1, b"aaa", b"", b"");
+            hard_link(&file1, &file2).unwrap();
+            write_test_file(&file3, b"aaa", b"", b"");
+
+            let group = FileGroup {
+                file_len: FileLen(3),
+                file_hash: FileHash(0),
+                files: vec![Path::from(&file1), Path::from(&file2), Path::from(&file3)],
+            };
+            let file_ids = read_file_ids(std::slice::from_ref(&group));
+            assert_eq!(file_ids.len(), 3);
+            assert_eq!(group.inode_groups_with(Some(&file_ids)), vec![0, 0, 1]);
+            assert_eq!(
+                group.inode_groups_with(Some(&file_ids)),
+                group.inode_groups()
+            );
+
+            // The identifiers are not read again, so removed files keep their inode groups
+            std::fs::remove_file(&file2).unwrap();
+            assert_eq!(group.inode_groups_with(Some(&file_ids)), vec![0, 0, 1]);
+            assert_eq!(group.inode_groups(), vec![0, 1, 2]);
+            assert!(!is_hardlink_only(&group.inode_groups_with(Some(&file_ids))));
+        });
+    }
+
+    #[test]
+    fn freed_inodes_count_hard_links_once() {
+        with_dir("main/freed_inodes_count_hard_links_once", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            let file3 = root.join("file3");
+            let file4 = root.join("file4");
+            write_test_file(&file1, b"aaa", b"", b"");
+            hard_link(&file1, &file2).unwrap();
+            write_test_file(&file3, b"aaa", b"", b"");
+            write_test_file(&file4, b"aaa", b"", b"");
+            let group = FileGroup {
+                file_len: FileLen(3),
+                file_hash: FileHash(0),
+                files: vec![file1, file2, file3, file4]
+                    .into_iter()
+                    .map(Path::from)
+                    .collect_vec(),
+            };
+
+            let mut config = GroupConfig::default();
+            assert_eq!(group.freed_inode_count(&config.group_filter()), 2);
+            config.rf_over = Some(2);
+            assert_eq!(group.freed_inode_count(&config.group_filter()), 1);
+            config.rf_over = Some(3);
+            assert_eq!(group.freed_inode_count(&config.group_filter()), 0);
+        });
+    }
+
     #[test]
     fn duplicate_input_files() {
         with_dir("main/duplicate_input_files", |root| {
@@ -1526,6 +3567,69 @@ mod test {
         });
     }
 
+    #[test]
+    fn min_roots_require_files_under_distinct_roots() {
+        with_dir("main/min_roots", |root| {
+            let roots = ["root1", "root2", "root3"].map(|r| root.join(r));
+            for r in roots.iter() {
+                create_dir(r).unwrap();
+            }
+            // in all roots:
+            write_test_file(&roots[0].join("a"), b"aaa", b"", b"");
+            write_test_file(&roots[1].join("a"), b"aaa", b"", b"");
+            write_test_file(&roots[2].join("a"), b"aaa", b"", b"");
+            // in one root only:
+            write_test_file(&roots[0].join("b1"), b"bbb", b"", b"");
+            write_test_file(&roots[0].join("b2"), b"bbb", b"", b"");
+            // in two roots, as hard links:
+            write_test_file(&roots[0].join("c"), b"ccc", b"", b"");
+            hard_link(roots[0].join("c"), roots[1].join("c")).unwrap();
+
+            let log = test_log();
+            let mut config = GroupConfig {
+                paths: roots.iter().map(Path::from).collect(),
+                min_roots: Some(2),
+                hard_links: true,
+                ..GroupConfig::default()
+            };
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 2);
+            assert!(results.iter().all(|g| g.file_len == FileLen(3)));
+            assert!(results.iter().all(|g| g.root_count(&config.paths) >= 2));
+
+            config.min_roots = None;
+            config.all_roots = true;
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].files.len(), 3);
+        });
+    }
+
+    #[test]
+    fn min_roots_keep_hard_links_under_distinct_roots() {
+        with_dir("main/min_roots_hard_links", |root| {
+            let roots = ["root1", "root2"].map(|r| root.join(r));
+            for r in roots.iter() {
+                create_dir(r).unwrap();
+            }
+            write_test_file(&roots[0].join("a"), b"aaa", b"", b"");
+            hard_link(roots[0].join("a"), roots[0].join("b")).unwrap();
+            hard_link(roots[0].join("a"), roots[1].join("a")).unwrap();
+
+            let log = test_log();
+            let config = GroupConfig {
+                paths: roots.iter().map(Path::from).collect(),
+                min_roots: Some(2),
+                ..GroupConfig::default()
+            };
+            // The links under the same root are still collapsed:
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].files.len(), 2);
+            assert_eq!(results[0].root_count(&config.paths), 2);
+        });
+    }
+
     #[test]
     fn duplicate_files_different_roots() {
         with_dir("main/duplicate_files_different_roots", |root| {
@@ -1555,6 +3659,31 @@ mod test {
         });
     }
 
+    #[test]
+    fn groups_of_baseline_files_are_not_reported() {
+        with_dir("main/groups_of_baseline_files_are_not_reported", |root| {
+            let library = root.join("library");
+            let incoming = root.join("incoming");
+            create_dir(&library).unwrap();
+            create_dir(&incoming).unwrap();
+            write_test_file(&library.join("a1"), b"aaa", b"", b"");
+            write_test_file(&library.join("a2"), b"aaa", b"", b"");
+            write_test_file(&library.join("b1"), b"bbb", b"", b"");
+            write_test_file(&incoming.join("b2"), b"bbb", b"", b"");
+
+            let log = test_log();
+            let config = GroupConfig {
+                paths: vec![incoming.into()],
+                baseline: vec![library.into()],
+                ..GroupConfig::default()
+            };
+
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].files.len(), 2);
+        });
+    }
+
     #[test]
     fn report() {
         with_dir("main/report", |root| {
@@ -1581,6 +3710,149 @@ mod test {
         });
     }
 
+    #[test]
+    fn savings_bubble_up_to_ancestor_dirs() {
+        let groups = [
+            FileGroup {
+                file_len: FileLen(100),
+                file_hash: FileHash(1),
+                files: vec![
+                    Path::from("/photos/a.jpg"),
+                    Path::from("/photos/2020/a.jpg"),
+                    Path::from("/photos/2021/a.jpg"),
+                ],
+            },
+            FileGroup {
+                file_len: FileLen(10),
+                file_hash: FileHash(2),
+                files: vec![Path::from("/docs/b.txt"), Path::from("/photos/2020/b.txt")],
+            },
+        ];
+        let filter = FileGroupFilter {
+            replication: Replication::Overreplicated(1),
+            root_paths: vec![],
+            baseline_paths: vec![],
+            min_roots: 0,
+            input_roots: vec![],
+        };
+        let savings = savings_by_directory(&groups, &filter);
+        assert_eq!(
+            savings,
+            vec![
+                (Path::from("/"), FileLen(210)),
+                (Path::from("/photos"), FileLen(210)),
+                (Path::from("/photos/2020"), FileLen(110)),
+                (Path::from("/photos/2021"), FileLen(100)),
+            ]
+        );
+    }
+
+    #[test]
+    fn largest_groups_are_sorted_by_wasted_bytes() {
+        let group = |len, hash, count| FileGroup {
+            file_len: FileLen(len),
+            file_hash: FileHash(hash),
+            files: (0..count)
+                .map(|i| Path::from(format!("/{}", i)))
+                .collect_vec(),
+        };
+        let groups = [
+            group(1000, 1, 2),
+            group(100, 2, 20),
+            group(500, 3, 5),
+            group(1000, 4, 2),
+        ];
+        let largest = largest_groups(&groups, 3);
+        let hashes = largest.iter().map(|g| g.file_hash.0).collect_vec();
+        assert_eq!(hashes, [3, 2, 1]);
+        assert_eq!(largest[0].wasted_bytes(), FileLen(2000));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn has_writable_member() {
+        with_dir("main/has_writable_member", |root| {
+            let dir1 = root.join("dir1");
+            let dir2 = root.join("dir2");
+            fs::create_dir(&dir1).unwrap();
+            fs::create_dir(&dir2).unwrap();
+            let file1 = dir1.join("file");
+            let file2 = dir2.join("file");
+            write_test_file(&file1, b"", b"", b"");
+            write_test_file(&file2, b"", b"", b"");
+            let set_readonly = |path: &PathBuf, readonly: bool| {
+                let mut permissions = fs::metadata(path).unwrap().permissions();
+                permissions.set_readonly(readonly);
+                fs::set_permissions(path, permissions).unwrap();
+            };
+            let group = FileGroup {
+                file_len: FileLen(0),
+                file_hash: FileHash(0),
+                files: vec![Path::from(&file1), Path::from(&file2)],
+            };
+            // Read-only files in writable directories can be removed or replaced:
+            set_readonly(&file1, true);
+            set_readonly(&file2, true);
+            assert!(group.has_writable_member());
+
+            // Permission checks don't apply to root:
+            if !nix::unistd::geteuid().is_root() {
+                set_readonly(&dir1, true);
+                assert!(group.has_writable_member());
+                set_readonly(&dir2, true);
+                assert!(!group.has_writable_member());
+                set_readonly(&dir1, false);
+                set_readonly(&dir2, false);
+            }
+        });
+    }
+
+    #[test]
+    fn diff_finds_new_and_removed_members() {
+        let group = |files: &[&str]| FileGroup {
+            file_len: FileLen(100),
+            file_hash: FileHash(0xcafe),
+            files: files.iter().map(|f| Path::from(*f)).collect_vec(),
+        };
+        let diff = group(&["/a", "/c", "/d"]).diff(&group(&["/a", "/b", "/c"]));
+        assert_eq!(diff.new_members, [Path::from("/d")]);
+        assert_eq!(diff.removed_members, [Path::from("/b")]);
+        assert_eq!(diff.unchanged_members, [Path::from("/a"), Path::from("/c")]);
+        assert_eq!(diff.kind(), "changed");
+
+        assert_eq!(group(&["/a", "/b"]).diff(&group(&["/a"])).kind(), "grown");
+        assert_eq!(group(&["/a"]).diff(&group(&["/a", "/b"])).kind(), "shrunk");
+        assert_eq!(group(&["/a"]).diff(&group(&[])).kind(), "new");
+        assert_eq!(group(&[]).diff(&group(&["/a"])).kind(), "removed");
+        assert!(group(&["/a", "/b"])
+            .diff(&group(&["/b", "/a"]))
+            .is_unchanged());
+    }
+
+    #[test]
+    fn diff_groups_matches_groups_by_hash_and_len() {
+        let group = |hash: u128, files: &[&str]| FileGroup {
+            file_len: FileLen(100),
+            file_hash: FileHash(hash),
+            files: files.iter().map(|f| Path::from(*f)).collect_vec(),
+        };
+        let current = [group(1, &["/a", "/b", "/c"]), group(2, &["/d", "/e"])];
+        let previous = [group(3, &["/f", "/g"]), group(1, &["/a", "/b"])];
+        let diffs = diff_groups(&current, &previous);
+        let kinds = diffs
+            .iter()
+            .map(|(g, d)| (g.file_hash, d.kind()))
+            .collect_vec();
+        assert_eq!(
+            kinds,
+            [
+                (FileHash(1), "grown"),
+                (FileHash(2), "new"),
+                (FileHash(3), "removed")
+            ]
+        );
+    }
+
     #[test]
     fn split_to_subgroups() {
         let roots = vec![Path::from("/r0"), Path::from("/r1"), Path::from("/r2")];
@@ -1,17 +1,29 @@
 use std::cell::RefCell;
 use std::cmp::{max, min};
+use std::collections::{BTreeMap, HashMap};
 use std::fs::{File, OpenOptions};
 use std::hash::Hasher;
 use std::io;
-use std::io::{ErrorKind, Read, Seek};
+use std::io::{ErrorKind, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use metrohash::MetroHash128;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 use crate::cache::{HashCache, Key};
+use crate::device::DiskDevice;
+use crate::error_limit::ErrorLimit;
 use crate::file::{FileAccess, FileChunk, FileHash, FileLen, FileMetadata, FilePos};
-use crate::log::Log;
+use crate::log::{LogMessage, LogSink};
+use crate::metrics::Counters;
 use crate::path::Path;
+use crate::pause::PAUSE;
+use crate::semaphore::Semaphore;
+use crate::throttle::TokenBucket;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub enum HashAlgorithm {
@@ -21,15 +33,45 @@ pub enum HashAlgorithm {
 /// Hashes file contents
 pub struct FileHasher<'a> {
     pub(crate) algorithm: HashAlgorithm,
-    pub(crate) buf_len: usize,
     pub(crate) cache: Option<HashCache>,
-    pub(crate) log: &'a Log,
+    /// Limits the number of files opened at the same time by all threads
+    pub(crate) open_files: Semaphore,
+    /// Limits the total size of buffers used for reading files by all threads, in bytes
+    pub(crate) buffer_memory: Semaphore,
+    /// Total size of memory available in the `buffer_memory` semaphore, in bytes
+    pub(crate) max_buffer_memory: usize,
+    /// Keeps files open between the hashing stages
+    pub(crate) handles: FileHandleCache,
+    /// Counts bytes read, cache hits and read errors for the stage metrics
+    pub(crate) counters: Counters,
+    pub(crate) log: &'a dyn LogSink,
+    /// Stops hashing when too many I/O errors were encountered
+    pub(crate) errors: &'a ErrorLimit<'a>,
+    /// Decides which read errors are retried
+    pub(crate) retry: RetryPolicy,
+    /// Files that couldn't be read, so they are missing from the groups
+    pub(crate) read_errors: Mutex<Vec<Path>>,
 }
 
 impl FileHasher<'_> {
     /// Computes the file hash or logs an error and returns none if failed.
     /// If file is not found, no error is logged and `None` is returned.
-    pub fn hash(&self, chunk: &FileChunk<'_>, progress: impl Fn(usize)) -> Option<FileHash> {
+    ///
+    /// Reads the file with a buffer adapted to the throughput of the device the file
+    /// is stored on. The time of reading is recorded so the buffer size can be adjusted
+    /// for the next files. If the bandwidth of the device is limited,
+    /// reading is throttled accordingly. If the number of concurrent reads from the device
+    /// is limited, waits until the read can be started.
+    /// Returns `None` without reading the file if the limit of I/O errors was exceeded.
+    pub fn hash_on_device(
+        &self,
+        chunk: &FileChunk<'_>,
+        device: &DiskDevice,
+        progress: impl Fn(usize),
+    ) -> Option<FileHash> {
+        if self.errors.is_exceeded() {
+            return None;
+        }
         let cache = self.cache.as_ref();
         let metadata = cache.and_then(|_| FileMetadata::new(chunk.path).ok());
         let metadata = metadata.as_ref();
@@ -39,27 +81,131 @@ impl FileHasher<'_> {
         let key = key.as_ref();
         let hash = self.load_hash(key, metadata);
         if hash.is_some() {
+            self.counters.inc_cache_hits();
             progress(chunk.len.0 as usize);
             return hash;
         }
 
-        match file_hash(chunk, self.buf_len, progress) {
+        // Don't start new reads while paused; most cached handles are not needed until resumed
+        PAUSE.wait_while_paused(Some(self.log), || {
+            self.handles.shrink_to(PAUSED_CACHED_FILE_HANDLES)
+        });
+
+        // Wait for the device before reserving memory, so waiting reads don't hold any buffers:
+        let read_permit = device.read_permit();
+        let buf_len = device.read_buf_len();
+        // A buffer larger than the budget is allowed only if no other buffers are in use:
+        let reserved_len = min(buf_len, self.max_buffer_memory);
+        let buffer_guard = self.buffer_memory.access_n(reserved_len as isize);
+        let open_file_guard = self.open_files.access();
+        let start = Instant::now();
+        let progress = |bytes_read: usize| {
+            self.counters.add_bytes_read(bytes_read as u64);
+            progress(bytes_read)
+        };
+        let mut result = self.cached_file_hash(chunk, buf_len, device.throttle(), progress);
+        let mut retry = 0;
+        while let Err(e) = &result {
+            if retry >= self.retry.retries || !self.retry.is_transient(e) {
+                break;
+            }
+            let delay = self.retry.delay(retry);
+            self.log.debug(LogMessage::new(format!(
+                "Retrying to read file {} in {:?} after error: {}",
+                chunk.path.to_escaped_string(),
+                delay,
+                e
+            )));
+            thread::sleep(delay);
+            retry += 1;
+            result = self.cached_file_hash(chunk, buf_len, device.throttle(), progress);
+        }
+        let elapsed = start.elapsed();
+        drop(open_file_guard);
+        drop(buffer_guard);
+        drop(read_permit);
+
+        // The time of retried reads includes the delays, so it doesn't tell the throughput
+        if result.is_ok() && retry == 0 {
+            device.record_read(chunk.len, elapsed, self.log);
+        }
+
+        match result {
             Ok(hash) => {
                 self.store_hash(key, metadata, hash);
                 Some(hash)
             }
             Err(e) if e.kind() == ErrorKind::NotFound => None,
             Err(e) => {
-                self.log.warn(format!(
+                self.record_read_error(chunk.path);
+                let msg = format!(
                     "Failed to compute hash of file {}: {}",
                     chunk.path.to_escaped_string(),
                     e
-                ));
+                );
+                self.log
+                    .warn(LogMessage::new(msg).with_path(chunk.path).with_error(&e));
                 None
             }
         }
     }
 
+    /// Counts the error and remembers the file, so it can be reported as missing from the groups.
+    pub fn record_read_error(&self, path: &Path) {
+        self.counters.inc_errors();
+        self.read_errors.lock().unwrap().push(path.clone());
+    }
+
+    /// Returns the files that couldn't be read, sorted and without duplicates
+    pub fn read_error_files(&self) -> Vec<Path> {
+        let mut paths = self.read_errors.lock().unwrap().clone();
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+
+    /// Returns the current length of the file.
+    /// Reads it from the handle left open by the previous hashing stage, if available.
+    /// Otherwise opens the file and keeps the handle for hashing it.
+    pub fn current_len(&self, path: &Path) -> io::Result<FileLen> {
+        let file = self.open_cached(path)?;
+        let len = FileLen(file.metadata()?.len());
+        self.handles.put(path, file);
+        Ok(len)
+    }
+
+    /// Computes the hash of the chunk using the file handle left open by the previous
+    /// hashing stage, if available. Otherwise opens the file.
+    /// After successful hashing, the handle is returned to the handle cache.
+    fn cached_file_hash(
+        &self,
+        chunk: &FileChunk<'_>,
+        buf_len: usize,
+        throttle: Option<&TokenBucket>,
+        progress: impl Fn(usize),
+    ) -> io::Result<FileHash> {
+        let file = self.open_cached(chunk.path)?;
+        let hash = chunk_hash(&file, chunk, buf_len, throttle, progress)?;
+        self.handles.put(chunk.path, file);
+        Ok(hash)
+    }
+
+    /// Takes the handle of the file from the handle cache, or opens the file if it isn't
+    /// cached. Counts the opened files and the reused handles in the stage metrics.
+    fn open_cached(&self, path: &Path) -> io::Result<File> {
+        match self.handles.take(path) {
+            Some(file) => {
+                self.counters.inc_handles_reused();
+                Ok(file)
+            }
+            None => {
+                let file = open_noatime(path)?;
+                self.counters.inc_files_opened();
+                Ok(file)
+            }
+        }
+    }
+
     /// Loads hash from the cache.
     /// If the hash is not present in the cache, returns `None`.
     /// If the operation fails (e.g. corrupted cache), logs a warning and returns `None`.
@@ -71,10 +217,10 @@ impl FileHasher<'_> {
             .and_then(|((cache, key), metadata)| match cache.get(key, metadata) {
                 Ok(hash) => hash,
                 Err(e) => {
-                    self.log.warn(format!(
+                    self.log.warn(LogMessage::new(format!(
                         "Failed to load hash of file id = {} from the cache: {}",
                         key, e
-                    ));
+                    )));
                     None
                 }
             })
@@ -87,15 +233,302 @@ impl FileHasher<'_> {
             self.cache.as_ref().zip(key.as_ref()).zip(metadata.as_ref())
         {
             if let Err(e) = cache.put(key, metadata, hash) {
-                self.log.warn(format!(
+                self.log.warn(LogMessage::new(format!(
                     "Failed to store hash of file {} in the cache: {}",
                     key, e
-                ))
+                )))
             }
         };
     }
 }
 
+/// Decides which read errors are retried and how long to wait before retrying
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct RetryPolicy {
+    /// Maximum number of retries after the first failed read
+    pub retries: usize,
+    /// Time to wait before the first retry, doubled before each next one
+    pub delay: Duration,
+    /// Retries also generic I/O errors (EIO)
+    pub retry_eio: bool,
+}
+
+impl RetryPolicy {
+    /// Returns true if reading again may succeed after the error
+    pub fn is_transient(&self, e: &io::Error) -> bool {
+        match e.kind() {
+            ErrorKind::Interrupted | ErrorKind::TimedOut => true,
+            _ => self.retry_eio && is_eio(e),
+        }
+    }
+
+    /// Returns the time to wait before the retry with given number, counted from 0
+    pub fn delay(&self, retry: usize) -> Duration {
+        self.delay.saturating_mul(1 << min(retry, 16))
+    }
+}
+
+#[cfg(unix)]
+fn is_eio(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::EIO)
+}
+
+#[cfg(windows)]
+fn is_eio(e: &io::Error) -> bool {
+    // ERROR_CRC, ERROR_READ_FAULT, ERROR_IO_DEVICE
+    const IO_ERRORS: [i32; 3] = [23, 30, 1117];
+    e.raw_os_error()
+        .is_some_and(|code| IO_ERRORS.contains(&code))
+}
+
+#[cfg(not(any(unix, windows)))]
+fn is_eio(_e: &io::Error) -> bool {
+    false
+}
+
+/// Default length of the buffer for reading files
+pub(crate) const DEFAULT_BUF_LEN: usize = 64 * 1024;
+
+/// Time we want reading a single buffer to take.
+/// Short enough to keep the progress reporting responsive on slow devices,
+/// but long enough to not make fast devices bound by the number of system calls.
+const TARGET_BUFFER_READ_TIME: Duration = Duration::from_millis(10);
+
+/// Weight of the most recent throughput measurement in the rolling throughput estimate
+const THROUGHPUT_SMOOTHING: f64 = 0.25;
+
+/// Adapts the length of the read buffer to the measured read throughput,
+/// so that reading a single buffer takes approximately [`TARGET_BUFFER_READ_TIME`].
+/// The buffer length affects only the performance, never the computed hashes.
+pub(crate) struct AdaptiveBufLen {
+    min_len: usize,
+    max_len: usize,
+    state: Mutex<AdaptiveBufLenState>,
+}
+
+struct AdaptiveBufLenState {
+    /// Rolling estimate of the throughput in bytes per second
+    throughput: Option<f64>,
+    buf_len: usize,
+}
+
+impl AdaptiveBufLen {
+    /// Creates a buffer length adapting within given bounds, inclusive.
+    pub fn new(min_len: usize, max_len: usize) -> AdaptiveBufLen {
+        assert!(min_len > 0 && min_len <= max_len);
+        AdaptiveBufLen {
+            min_len,
+            max_len,
+            state: Mutex::new(AdaptiveBufLenState {
+                throughput: None,
+                buf_len: DEFAULT_BUF_LEN.clamp(min_len, max_len),
+            }),
+        }
+    }
+
+    /// Returns the current buffer length
+    pub fn get(&self) -> usize {
+        self.state.lock().unwrap().buf_len
+    }
+
+    /// Records that reading `len` bytes took `duration` and adapts the buffer length.
+    /// Reads shorter than the current buffer are ignored, because their time is dominated
+    /// by the latency of opening the file rather than by the throughput.
+    /// If the buffer length changed, returns the new length and the throughput estimate.
+    pub fn record(&self, len: FileLen, duration: Duration) -> Option<(usize, f64)> {
+        let mut state = self.state.lock().unwrap();
+        if len.0 < state.buf_len as u64 {
+            return None;
+        }
+        let sample = len.as_f64() / duration.as_secs_f64().max(1e-6);
+        let throughput = match state.throughput {
+            Some(t) => t + THROUGHPUT_SMOOTHING * (sample - t),
+            None => sample,
+        };
+        state.throughput = Some(throughput);
+
+        // Round down to a power of two, so small fluctuations of throughput
+        // don't cause changing the buffer length all the time:
+        let target_len = (throughput * TARGET_BUFFER_READ_TIME.as_secs_f64()) as usize;
+        let target_len = match target_len.checked_next_power_of_two() {
+            Some(p) if p == target_len => p,
+            Some(p) => p / 2,
+            None => usize::MAX,
+        };
+        let buf_len = target_len.clamp(self.min_len, self.max_len);
+        if buf_len != state.buf_len {
+            state.buf_len = buf_len;
+            Some((buf_len, throughput))
+        } else {
+            None
+        }
+    }
+}
+
+/// Maximum number of file handles kept open between the hashing stages
+pub(crate) const MAX_CACHED_FILE_HANDLES: usize = 1024;
+
+/// Number of the most recently used file handles kept open while the scan is paused,
+/// so the files read last don't need to be reopened after resuming
+const PAUSED_CACHED_FILE_HANDLES: usize = 16;
+
+/// A bounded cache of open file handles, used to avoid reopening the same file
+/// in the subsequent hashing stages (prefix, suffix, full contents).
+/// When the cache is full, the least recently used handle is closed.
+///
+/// A handle is taken out of the cache for the time it is being used,
+/// so it is never shared between threads and its position can be changed freely.
+pub(crate) struct FileHandleCache {
+    capacity: usize,
+    entries: Mutex<HandleCacheEntries>,
+    hits: AtomicU64,
+}
+
+#[derive(Default)]
+struct HandleCacheEntries {
+    /// Maps paths to open files and their last access time
+    files: HashMap<Path, (File, u64)>,
+    /// Maps access times to paths, oldest first; used for finding the eviction candidates
+    lru: BTreeMap<u64, Path>,
+    clock: u64,
+}
+
+impl FileHandleCache {
+    /// Creates a cache holding at most `capacity` open files.
+    /// Zero capacity disables caching.
+    pub fn new(capacity: usize) -> FileHandleCache {
+        FileHandleCache {
+            capacity,
+            entries: Mutex::new(HandleCacheEntries::default()),
+            hits: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns how many times an open handle was reused instead of reopening the file.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Removes the handle of the file at given path from the cache and returns it.
+    /// Returns `None` if the file isn't cached or if the path doesn't point to
+    /// the cached file anymore, e.g. because it was replaced after the handle was cached.
+    pub fn take(&self, path: &Path) -> Option<File> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let file = {
+            let mut entries = self.entries.lock().unwrap();
+            let (file, time) = entries.files.remove(path)?;
+            entries.lru.remove(&time);
+            file
+        };
+        if is_same_file(&file, path) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            Some(file)
+        } else {
+            None
+        }
+    }
+
+    /// Puts an open file into the cache, closing the least recently used handle if
+    /// the cache is full.
+    pub fn put(&self, path: &Path, file: File) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.evict(self.capacity - 1);
+        let time = entries.clock;
+        entries.clock += 1;
+        entries.lru.insert(time, path.clone());
+        if let Some((_, old_time)) = entries.files.insert(path.clone(), (file, time)) {
+            entries.lru.remove(&old_time);
+        }
+    }
+
+    /// Closes the least recently used handles, so at most `len` handles stay open
+    pub fn shrink_to(&self, len: usize) {
+        self.entries.lock().unwrap().evict(len);
+    }
+}
+
+impl HandleCacheEntries {
+    /// Removes the least recently used files until at most `len` files are left
+    fn evict(&mut self, len: usize) {
+        while self.files.len() > len {
+            let oldest = self.lru.keys().next().cloned();
+            match oldest.and_then(|time| self.lru.remove(&time)) {
+                Some(oldest) => self.files.remove(&oldest),
+                None => break,
+            };
+        }
+    }
+}
+
+/// Returns true if the open file is the same file as the one currently found at the path.
+/// Compares the device and inode numbers obtained by `fstat` on the open file with those
+/// obtained by `stat` on the path.
+#[cfg(unix)]
+fn is_same_file(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (file.metadata(), std::fs::metadata(path.to_path_buf())) {
+        (Ok(m1), Ok(m2)) => m1.dev() == m2.dev() && m1.ino() == m2.ino() && m1.len() == m2.len(),
+        _ => false,
+    }
+}
+
+/// Returns true if the open file is the same file as the one currently found at the path.
+/// Getting the file identifier from a path requires opening the file on Windows, which is what
+/// we want to avoid, so we compare only the length and the modification time.
+#[cfg(not(unix))]
+fn is_same_file(file: &File, path: &Path) -> bool {
+    match (file.metadata(), std::fs::metadata(path.to_path_buf())) {
+        (Ok(m1), Ok(m2)) => m1.len() == m2.len() && m1.modified().ok() == m2.modified().ok(),
+        _ => false,
+    }
+}
+
+/// Percentage of the file descriptor limit available for hashing.
+/// The rest is left for other purposes, e.g. directory scanning, the hash cache,
+/// standard streams.
+#[cfg(unix)]
+const HASHING_FILE_DESCRIPTORS_PERCENT: usize = 75;
+
+/// Returns the default maximum number of files opened concurrently for hashing.
+/// It is 75% of the soft limit of open file descriptors of the process (`ulimit -n`),
+/// leaving the remaining descriptors for other purposes.
+#[cfg(unix)]
+pub(crate) fn default_max_open_files() -> usize {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    let result = unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) };
+    let limit = if result != 0 || limit.rlim_cur == libc::RLIM_INFINITY {
+        // unknown or unlimited; fall back to a value large enough to not be a bottleneck
+        65536
+    } else {
+        limit.rlim_cur as usize
+    };
+    max_open_files_for_limit(limit)
+}
+
+#[cfg(unix)]
+fn max_open_files_for_limit(fd_limit: usize) -> usize {
+    max(
+        1,
+        fd_limit.saturating_mul(HASHING_FILE_DESCRIPTORS_PERCENT) / 100,
+    )
+}
+
+/// Returns the default maximum number of files opened concurrently for hashing.
+/// Windows doesn't have a low per-process limit of open handles, so we use a fixed value
+/// that is way above the number of files we can process in parallel efficiently.
+#[cfg(not(unix))]
+pub(crate) fn default_max_open_files() -> usize {
+    4096
+}
+
 #[cfg(target_os = "linux")]
 fn to_off_t(offset: u64) -> libc::off_t {
     min(libc::off_t::MAX as u64, offset) as libc::off_t
@@ -135,7 +568,7 @@ fn configure_readahead(file: &File, offset: FilePos, len: FileLen, access: FileA
 /// Tells the system to remove given file fragment from the page cache.
 /// On non-Unix systems, does nothing.
 #[allow(unused)]
-fn evict_page_cache(file: &File, offset: FilePos, len: FileLen) {
+pub(crate) fn evict_page_cache(file: &File, offset: FilePos, len: FileLen) {
     #[cfg(target_os = "linux")]
     {
         use nix::fcntl::*;
@@ -148,7 +581,7 @@ fn evict_page_cache(file: &File, offset: FilePos, len: FileLen) {
 /// This program is likely to be used only once, so there is little value in keeping its
 /// data cached for further use.
 #[allow(unused)]
-fn evict_page_cache_if_low_mem(file: &mut File, len: FileLen) {
+fn evict_page_cache_if_low_mem(file: &File, len: FileLen) {
     #[cfg(target_os = "linux")]
     {
         use sysinfo::{System, SystemExt};
@@ -171,17 +604,6 @@ fn evict_page_cache_if_low_mem(file: &mut File, len: FileLen) {
     }
 }
 
-/// Opens a file and positions it at the given offset.
-/// Additionally, sends the advice to the operating system about how many bytes will be read.
-fn open(path: &Path, offset: FilePos, len: FileLen, access_type: FileAccess) -> io::Result<File> {
-    let mut file = open_noatime(path)?;
-    configure_readahead(&file, offset, len, access_type);
-    if offset > FilePos::zero() {
-        file.seek(offset.into())?;
-    }
-    Ok(file)
-}
-
 /// Opens a file for read. On unix systems passes O_NOATIME flag to drastically improve
 /// performance of reading small files.
 fn open_noatime(path: &Path) -> io::Result<File> {
@@ -209,7 +631,12 @@ thread_local! {
 }
 
 /// Scans up to `len` bytes in a file and sends data to the given consumer.
+/// Reads at most `buf_len` bytes at once.
 /// Returns the number of bytes successfully read.
+///
+/// The read buffer is kept by the thread for the next scans only up to [`DEFAULT_BUF_LEN`].
+/// Larger buffers are released before returning, so they don't outlive the reservation
+/// of buffer memory made by the caller.
 fn scan<F: FnMut(&[u8])>(
     stream: &mut impl Read,
     len: FileLen,
@@ -218,29 +645,45 @@ fn scan<F: FnMut(&[u8])>(
 ) -> io::Result<u64> {
     BUF.with(|buf| {
         let mut buf = buf.borrow_mut();
+        // Don't allocate more than needed to read a small file at once:
+        let buf_len = max(1, min(buf_len as u64, len.0) as usize);
         let new_len = max(buf.len(), buf_len);
         buf.resize(new_len, 0);
-        let mut read: u64 = 0;
-        let len = len.into();
-        while read < len {
-            let remaining = len - read;
-            let to_read = min(remaining, buf.len() as u64) as usize;
-            let buf = &mut buf[..to_read];
-            match stream.read(buf) {
-                Ok(0) => break,
-                Ok(actual_read) => {
-                    read += actual_read as u64;
-                    (consumer)(&buf[..actual_read]);
-                }
-                Err(e) => {
-                    return Err(e);
-                }
-            }
+        let result = scan_with_buf(stream, len, &mut buf[..buf_len], &mut consumer);
+        if buf.len() > DEFAULT_BUF_LEN {
+            buf.truncate(DEFAULT_BUF_LEN);
+            buf.shrink_to_fit();
         }
-        Ok(read)
+        result
     })
 }
 
+fn scan_with_buf<F: FnMut(&[u8])>(
+    stream: &mut impl Read,
+    len: FileLen,
+    buf: &mut [u8],
+    consumer: &mut F,
+) -> io::Result<u64> {
+    let mut read: u64 = 0;
+    let len = len.into();
+    while read < len {
+        let remaining = len - read;
+        let to_read = min(remaining, buf.len() as u64) as usize;
+        let buf = &mut buf[..to_read];
+        match stream.read(buf) {
+            Ok(0) => break,
+            Ok(actual_read) => {
+                read += actual_read as u64;
+                (consumer)(&buf[..actual_read]);
+            }
+            Err(e) => {
+                return Err(e);
+            }
+        }
+    }
+    Ok(read)
+}
+
 /// Computes the hash value over at most `len` bytes of the stream.
 /// Returns the number of the bytes read and a 128-bit hash value.
 pub(crate) fn stream_hash(
@@ -267,28 +710,287 @@ pub(crate) fn file_hash(
     chunk: &FileChunk<'_>,
     buf_len: usize,
     progress: impl Fn(usize),
+) -> io::Result<FileHash> {
+    let file = open_noatime(chunk.path)?;
+    chunk_hash(&file, chunk, buf_len, None, progress)
+}
+
+/// Computes the SHA-256 digest of the whole file.
+/// Unlike [`file_hash`], the result is cryptographically secure and matches
+/// the output of `sha256sum`.
+pub(crate) fn file_sha256(path: &Path, buf_len: usize) -> io::Result<[u8; 32]> {
+    let mut file = open_noatime(path)?;
+    let len = FileLen(file.metadata()?.len());
+    configure_readahead(&file, FilePos(0), len, FileAccess::Sequential);
+    let mut hasher = Sha256::new();
+    scan(&mut file, FileLen::MAX, buf_len, |buf| hasher.update(buf))?;
+    evict_page_cache_if_low_mem(&file, len);
+    Ok(hasher.finalize().into())
+}
+
+/// Hashes many chunks of files, opening each file only once for all its chunks
+/// that are passed one after another.
+///
+/// The chunks should be sorted by path, so that the chunks of the same file are adjacent.
+/// Unsorted chunks are hashed correctly as well, but a file may be opened
+/// more than once then.
+pub struct BatchHasher {
+    buf_len: usize,
+    files_opened: u64,
+}
+
+impl BatchHasher {
+    /// Creates a hasher reading at most `buf_len` bytes at once.
+    pub fn new(buf_len: usize) -> BatchHasher {
+        BatchHasher {
+            buf_len,
+            files_opened: 0,
+        }
+    }
+
+    /// Returns the number of files opened by this hasher so far
+    pub fn files_opened(&self) -> u64 {
+        self.files_opened
+    }
+
+    /// Computes the hashes of the given chunks.
+    /// Returns the results in the same order as the chunks.
+    /// If a file cannot be opened, all of its adjacent chunks get the error.
+    pub fn hash<'a>(
+        &mut self,
+        chunks: impl IntoIterator<Item = FileChunk<'a>>,
+        progress: impl Fn(usize),
+    ) -> Vec<io::Result<FileHash>> {
+        let mut current: Option<(&Path, io::Result<File>)> = None;
+        let mut results = Vec::new();
+        for chunk in chunks {
+            match &current {
+                Some((path, _)) if *path == chunk.path => {}
+                _ => {
+                    current = Some((chunk.path, open_noatime(chunk.path)));
+                    self.files_opened += 1;
+                }
+            }
+            let result = match &current.as_ref().unwrap().1 {
+                Ok(file) => chunk_hash(file, &chunk, self.buf_len, None, &progress),
+                Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+            };
+            results.push(result);
+        }
+        results
+    }
+}
+
+/// A source of data that can be read at any offset without a cursor,
+/// so it can be shared by many readers at once.
+pub(crate) trait ReadAt {
+    /// Reads up to `buf.len()` bytes starting at `offset`.
+    /// Returns the number of bytes read, 0 at the end of the data.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+}
+
+impl ReadAt for File {
+    #[cfg(unix)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(self, buf, offset)
+    }
+
+    /// On Windows, the cursor of the file is moved, but positioned reads never rely on it.
+    #[cfg(windows)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(self, buf, offset)
+    }
+}
+
+/// Reads the data sequentially from the given position with positioned reads
+/// (`pread` on Unix, `seek_read` on Windows).
+///
+/// Unlike seeking before reading, this takes a single system call per read
+/// and doesn't depend on the position left by previous reads, so a file handle
+/// can be safely shared by threads and reused from the [`FileHandleCache`].
+pub(crate) struct PositionedReader<'a, F: ReadAt + ?Sized> {
+    source: &'a F,
+    pos: u64,
+}
+
+impl<'a, F: ReadAt + ?Sized> PositionedReader<'a, F> {
+    pub fn new(source: &'a F, pos: FilePos) -> PositionedReader<'a, F> {
+        PositionedReader { source, pos: pos.0 }
+    }
+}
+
+impl<F: ReadAt + ?Sized> Read for PositionedReader<'_, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.source.read_at(buf, self.pos)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+/// Computes hash of the chunk of an already open file.
+/// Reads the file with positioned reads, so the file may be at any position
+/// and its position is not relied upon.
+/// Additionally, sends the advice to the operating system about how many bytes will be read.
+/// If `throttle` is given, each byte read takes a token from it.
+fn chunk_hash(
+    file: &File,
+    chunk: &FileChunk<'_>,
+    buf_len: usize,
+    throttle: Option<&TokenBucket>,
+    progress: impl Fn(usize),
 ) -> io::Result<FileHash> {
     let access = if chunk.len.0 < 64 * 1024 {
         FileAccess::Random
     } else {
         FileAccess::Sequential
     };
-    let mut file = open(chunk.path, chunk.pos, chunk.len, access)?;
-    let hash = stream_hash(&mut file, chunk.len, buf_len, progress)?.1;
-    evict_page_cache_if_low_mem(&mut file, chunk.len);
+    configure_readahead(file, chunk.pos, chunk.len, access);
+    let mut reader = PositionedReader::new(file, chunk.pos);
+    let hash = match throttle {
+        Some(throttle) => stream_hash(
+            &mut throttle.reader(&mut reader),
+            chunk.len,
+            buf_len,
+            progress,
+        ),
+        None => stream_hash(&mut reader, chunk.len, buf_len, progress),
+    }?
+    .1;
+    evict_page_cache_if_low_mem(file, chunk.len);
     Ok(hash)
 }
 
 #[cfg(test)]
 mod test {
+    use std::cell::Cell;
+    use std::cmp::min;
     use std::fs::{create_dir_all, File};
-    use std::io::Write;
+    use std::io;
+    use std::io::{ErrorKind, Write};
     use std::path::PathBuf;
 
     use crate::file::{FileChunk, FileLen, FilePos};
-    use crate::hasher::file_hash;
+    use std::time::Duration;
+
+    use crate::hasher::{
+        chunk_hash, default_max_open_files, file_hash, stream_hash, AdaptiveBufLen, BatchHasher,
+        FileHandleCache, PositionedReader, ReadAt, RetryPolicy, BUF, DEFAULT_BUF_LEN,
+    };
     use crate::path::Path;
 
+    /// Serves the data from memory and counts the reads
+    struct CountingSource {
+        data: Vec<u8>,
+        reads: Cell<usize>,
+    }
+
+    impl ReadAt for CountingSource {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            self.reads.set(self.reads.get() + 1);
+            let start = min(offset as usize, self.data.len());
+            let len = min(buf.len(), self.data.len() - start);
+            buf[..len].copy_from_slice(&self.data[start..start + len]);
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn positioned_reader_reads_once_per_buffer() {
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let source = CountingSource {
+            data: data.clone(),
+            reads: Cell::new(0),
+        };
+        let mut reader = PositionedReader::new(&source, FilePos(100));
+        let (len, hash) = stream_hash(&mut reader, FileLen(500), 128, |_| {}).unwrap();
+        let (_, expected) = stream_hash(
+            &mut io::Cursor::new(&data[100..600]),
+            FileLen(500),
+            128,
+            |_| {},
+        )
+        .unwrap();
+        assert_eq!(len, FileLen(500));
+        assert_eq!(hash, expected);
+        // 3 full buffers and a partial one, and no seeks which `CountingSource` doesn't support:
+        assert_eq!(source.reads.get(), 4);
+    }
+
+    #[test]
+    fn chunk_hash_does_not_depend_on_file_position() {
+        use std::io::{Seek, SeekFrom};
+
+        let test_root = PathBuf::from("target/test/hasher/chunk_hash_position");
+        create_dir_all(&test_root).unwrap();
+        let path = test_root.join("file");
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        let path = Path::from(&path);
+        let chunk = FileChunk::new(&path, FilePos(1000), FileLen(50_000));
+
+        let mut file = File::open(path.to_path_buf()).unwrap();
+        let hash1 = chunk_hash(&file, &chunk, 4096, None, |_| {}).unwrap();
+        file.seek(SeekFrom::Start(77)).unwrap();
+        let hash2 = chunk_hash(&file, &chunk, 4096, None, |_| {}).unwrap();
+        let (_, expected) =
+            stream_hash(&mut &data[1000..51000], FileLen(50_000), 4096, |_| {}).unwrap();
+        assert_eq!(hash1, expected);
+        assert_eq!(hash2, expected);
+    }
+
+    #[test]
+    fn large_read_buffers_are_released_after_scan() {
+        let data = vec![7u8; 4 * DEFAULT_BUF_LEN];
+        let (len, _) = stream_hash(
+            &mut data.as_slice(),
+            FileLen::MAX,
+            4 * DEFAULT_BUF_LEN,
+            |_| {},
+        )
+        .unwrap();
+        assert_eq!(len, FileLen(data.len() as u64));
+        assert!(BUF.with(|buf| buf.borrow().capacity()) <= DEFAULT_BUF_LEN);
+    }
+
+    #[test]
+    fn retry_policy_retries_only_transient_errors() {
+        let policy = RetryPolicy {
+            retries: 3,
+            delay: Duration::from_millis(100),
+            retry_eio: false,
+        };
+        assert!(policy.is_transient(&io::Error::from(ErrorKind::Interrupted)));
+        assert!(policy.is_transient(&io::Error::from(ErrorKind::TimedOut)));
+        assert!(!policy.is_transient(&io::Error::from(ErrorKind::PermissionDenied)));
+        assert_eq!(policy.delay(0), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(400));
+
+        #[cfg(unix)]
+        {
+            let eio = io::Error::from_raw_os_error(libc::EIO);
+            assert!(!policy.is_transient(&eio));
+            let policy = RetryPolicy {
+                retry_eio: true,
+                ..policy
+            };
+            assert!(policy.is_transient(&eio));
+        }
+    }
+
+    #[test]
+    fn test_default_max_open_files() {
+        assert!(default_max_open_files() >= 1);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_max_open_files_is_three_quarters_of_limit() {
+        use crate::hasher::max_open_files_for_limit;
+        assert_eq!(max_open_files_for_limit(1024), 768);
+        assert_eq!(max_open_files_for_limit(256), 192);
+        assert_eq!(max_open_files_for_limit(1), 1);
+    }
+
     #[test]
     fn test_file_hash() {
         let test_root = PathBuf::from("target/test/file_hash/");
@@ -319,4 +1021,177 @@ mod test {
         assert_ne!(hash1, hash2);
         assert_ne!(hash2, hash3);
     }
+
+    #[test]
+    fn handle_cache_returns_cached_file() {
+        let test_root = PathBuf::from("target/test/handle_cache_returns_cached_file/");
+        create_dir_all(&test_root).unwrap();
+        let file = test_root.join("file");
+        File::create(&file).unwrap().write_all(b"contents").unwrap();
+        let file = Path::from(&file);
+
+        let cache = FileHandleCache::new(1);
+        assert!(cache.take(&file).is_none());
+        cache.put(&file, File::open(file.to_path_buf()).unwrap());
+        assert!(cache.take(&file).is_some());
+        assert!(cache.take(&file).is_none());
+        assert_eq!(cache.hits(), 1);
+    }
+
+    #[test]
+    fn handle_cache_evicts_least_recently_used_file() {
+        let test_root = PathBuf::from("target/test/handle_cache_evicts_lru/");
+        create_dir_all(&test_root).unwrap();
+        let file1 = test_root.join("file1");
+        let file2 = test_root.join("file2");
+        let file3 = test_root.join("file3");
+        File::create(&file1).unwrap().write_all(b"1").unwrap();
+        File::create(&file2).unwrap().write_all(b"2").unwrap();
+        File::create(&file3).unwrap().write_all(b"3").unwrap();
+        let file1 = Path::from(&file1);
+        let file2 = Path::from(&file2);
+        let file3 = Path::from(&file3);
+
+        let cache = FileHandleCache::new(2);
+        cache.put(&file1, File::open(file1.to_path_buf()).unwrap());
+        cache.put(&file2, File::open(file2.to_path_buf()).unwrap());
+        cache.put(&file3, File::open(file3.to_path_buf()).unwrap());
+        assert!(cache.take(&file1).is_none());
+        assert!(cache.take(&file2).is_some());
+        assert!(cache.take(&file3).is_some());
+    }
+
+    #[test]
+    fn handle_cache_shrinks_to_most_recently_used_files() {
+        let test_root = PathBuf::from("target/test/handle_cache_shrinks/");
+        create_dir_all(&test_root).unwrap();
+        let files: Vec<_> = (1..=3)
+            .map(|i| {
+                let file = test_root.join(format!("file{}", i));
+                File::create(&file).unwrap().write_all(b"1").unwrap();
+                Path::from(&file)
+            })
+            .collect();
+
+        let cache = FileHandleCache::new(3);
+        for f in files.iter() {
+            cache.put(f, File::open(f.to_path_buf()).unwrap());
+        }
+        cache.shrink_to(1);
+        assert!(cache.take(&files[0]).is_none());
+        assert!(cache.take(&files[1]).is_none());
+        assert!(cache.take(&files[2]).is_some());
+    }
+
+    #[test]
+    fn handle_cache_detects_replaced_file() {
+        let test_root = PathBuf::from("target/test/handle_cache_detects_replaced_file/");
+        create_dir_all(&test_root).unwrap();
+        let file = test_root.join("file");
+        let new_file = test_root.join("new_file");
+        File::create(&file).unwrap().write_all(b"original").unwrap();
+        File::create(&new_file)
+            .unwrap()
+            .write_all(b"replaced contents")
+            .unwrap();
+        let file_path = Path::from(&file);
+
+        let cache = FileHandleCache::new(1);
+        cache.put(&file_path, File::open(&file).unwrap());
+        std::fs::rename(&new_file, &file).unwrap();
+        assert!(cache.take(&file_path).is_none());
+        assert_eq!(cache.hits(), 0);
+    }
+
+    #[test]
+    fn adaptive_buf_len_follows_throughput() {
+        let buf_len = AdaptiveBufLen::new(64 * 1024, 16 * 1024 * 1024);
+        assert_eq!(buf_len.get(), 64 * 1024);
+
+        // 1 GB/s -> 10 MB in 10 ms, rounded down to 8 MiB
+        let result = buf_len.record(FileLen(1 << 30), Duration::from_secs(1));
+        assert_eq!(result.map(|r| r.0), Some(8 * 1024 * 1024));
+        assert_eq!(buf_len.get(), 8 * 1024 * 1024);
+
+        // no change, no report
+        assert!(buf_len
+            .record(FileLen(1 << 30), Duration::from_secs(1))
+            .is_none());
+    }
+
+    #[test]
+    fn adaptive_buf_len_stays_within_bounds() {
+        let buf_len = AdaptiveBufLen::new(64 * 1024, 1024 * 1024);
+        buf_len.record(FileLen(1 << 40), Duration::from_secs(1));
+        assert_eq!(buf_len.get(), 1024 * 1024);
+        for _ in 0..100 {
+            buf_len.record(FileLen(1 << 20), Duration::from_secs(100));
+        }
+        assert_eq!(buf_len.get(), 64 * 1024);
+    }
+
+    #[test]
+    fn adaptive_buf_len_ignores_short_reads() {
+        let buf_len = AdaptiveBufLen::new(64 * 1024, 16 * 1024 * 1024);
+        assert!(buf_len
+            .record(FileLen(1024), Duration::from_secs(1))
+            .is_none());
+        assert_eq!(buf_len.get(), 64 * 1024);
+    }
+
+    #[test]
+    fn file_hash_does_not_depend_on_buf_len() {
+        let test_root = PathBuf::from("target/test/file_hash_does_not_depend_on_buf_len/");
+        create_dir_all(&test_root).unwrap();
+        let file = test_root.join("file");
+        let contents: Vec<u8> = (0..100000).map(|i| (i % 251) as u8).collect();
+        File::create(&file).unwrap().write_all(&contents).unwrap();
+        let file = Path::from(&file);
+        let chunk = FileChunk::new(&file, FilePos(0), FileLen::MAX);
+        let hash1 = file_hash(&chunk, 4096, |_| {}).unwrap();
+        let hash2 = file_hash(&chunk, 65536, |_| {}).unwrap();
+        let hash3 = file_hash(&chunk, 1024 * 1024, |_| {}).unwrap();
+        assert_eq!(hash1, hash2);
+        assert_eq!(hash2, hash3);
+    }
+
+    #[test]
+    fn batch_hasher_opens_each_file_once() {
+        let test_root = PathBuf::from("target/test/batch_hasher_opens_each_file_once/");
+        create_dir_all(&test_root).unwrap();
+        let file1 = test_root.join("file1");
+        let file2 = test_root.join("file2");
+        let contents: Vec<u8> = (0..100000).map(|i| (i % 251) as u8).collect();
+        File::create(&file1).unwrap().write_all(&contents).unwrap();
+        File::create(&file2)
+            .unwrap()
+            .write_all(&contents[..1000])
+            .unwrap();
+        let file1 = Path::from(&file1);
+        let file2 = Path::from(&file2);
+        let missing = Path::from(test_root.join("missing"));
+        let chunks = || {
+            [
+                FileChunk::new(&file1, FilePos(0), FileLen(4096)),
+                FileChunk::new(&file1, FilePos(4096), FileLen(4096)),
+                FileChunk::new(&file1, FilePos(90000), FileLen::MAX),
+                FileChunk::new(&file2, FilePos(0), FileLen::MAX),
+                FileChunk::new(&missing, FilePos(0), FileLen(10)),
+                FileChunk::new(&missing, FilePos(10), FileLen(10)),
+            ]
+        };
+
+        let mut hasher = BatchHasher::new(4096);
+        let hashes = hasher.hash(chunks(), |_| {});
+        assert_eq!(hasher.files_opened(), 3);
+        assert_eq!(hashes.len(), 6);
+        for (chunk, hash) in chunks().iter().zip(hashes.iter()).take(4) {
+            assert_eq!(
+                hash.as_ref().unwrap(),
+                &file_hash(chunk, 4096, |_| {}).unwrap()
+            );
+        }
+        assert!(hashes[4].is_err());
+        assert!(hashes[5].is_err());
+    }
 }
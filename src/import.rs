@@ -0,0 +1,462 @@
+//! Importing lists of duplicate files found by other programs.
+//!
+//! The lists produced by `fdupes`, `jdupes` and `rmlint` can be passed to the deduplication
+//! commands instead of a report produced by `fclones group`. These lists carry no hashes
+//! we could trust, so the sizes and, unless disabled, the contents of the files are verified
+//! again before the groups are deduplicated.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::BufRead;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use lazy_static::lazy_static;
+use rayon::prelude::*;
+use regex::Regex;
+use serde_json::Value;
+
+use crate::error::Error;
+use crate::file::{FileChunk, FileHash, FileLen, FilePos};
+use crate::group::FileGroup;
+use crate::hasher::{file_hash, DEFAULT_BUF_LEN};
+use crate::log::Log;
+use crate::path::Path;
+
+/// Format of a list of duplicate files written by another program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat {
+    /// Paths one per line, groups separated by blank lines,
+    /// optionally preceded by a `N bytes each:` line (`fdupes -S`)
+    Fdupes,
+    /// The same as `Fdupes`, as printed by `jdupes` and `jdupes -S`
+    Jdupes,
+    /// JSON written by `rmlint -o json`
+    Rmlint,
+}
+
+impl ImportFormat {
+    pub fn variants() -> Vec<&'static str> {
+        vec!["fdupes", "jdupes", "rmlint"]
+    }
+}
+
+impl Display for ImportFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportFormat::Fdupes => f.pad("fdupes"),
+            ImportFormat::Jdupes => f.pad("jdupes"),
+            ImportFormat::Rmlint => f.pad("rmlint"),
+        }
+    }
+}
+
+impl FromStr for ImportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fdupes" => Ok(ImportFormat::Fdupes),
+            "jdupes" => Ok(ImportFormat::Jdupes),
+            "rmlint" => Ok(ImportFormat::Rmlint),
+            s => Err(format!("Unrecognized import format: {}", s)),
+        }
+    }
+}
+
+/// A group of files claimed to be identical by another program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportedGroup {
+    /// Size of each file, if given in the imported list
+    pub file_len: Option<FileLen>,
+    pub files: Vec<Path>,
+}
+
+/// Parses the list of duplicate files in the given format.
+/// Relative paths are resolved against `base_dir`.
+/// Groups with fewer than two files are skipped.
+pub fn parse_import(
+    format: ImportFormat,
+    reader: impl BufRead,
+    base_dir: &Arc<Path>,
+) -> Result<Vec<ImportedGroup>, Error> {
+    let groups = match format {
+        ImportFormat::Fdupes | ImportFormat::Jdupes => parse_fdupes(reader, base_dir)?,
+        ImportFormat::Rmlint => parse_rmlint(reader, base_dir)?,
+    };
+    Ok(groups.into_iter().filter(|g| g.files.len() > 1).collect())
+}
+
+lazy_static! {
+    static ref SIZE_LINE: Regex = Regex::new(r"^(\d+) bytes? each:$").unwrap();
+    static ref SUMMARY_LINE: Regex = Regex::new(r"^\d+ duplicate files? \(in \d+ sets?\)").unwrap();
+}
+
+fn parse_fdupes(reader: impl BufRead, base_dir: &Arc<Path>) -> Result<Vec<ImportedGroup>, Error> {
+    let mut groups = Vec::new();
+    let mut group = ImportedGroup {
+        file_len: None,
+        files: vec![],
+    };
+    for (index, line) in reader.lines().enumerate() {
+        let line_no = index + 1;
+        let line = line.map_err(|e| Error::io("Failed to read the imported list", None, e))?;
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            if !group.files.is_empty() || group.file_len.is_some() {
+                groups.push(std::mem::replace(
+                    &mut group,
+                    ImportedGroup {
+                        file_len: None,
+                        files: vec![],
+                    },
+                ));
+            }
+        } else if let Some(captures) = SIZE_LINE.captures(line) {
+            if !group.files.is_empty() || group.file_len.is_some() {
+                return Err(Error::report_parse(
+                    Some(line_no),
+                    format!("Unexpected file size in line {}: {}", line_no, line),
+                ));
+            }
+            let len = captures[1].parse().map_err(|_| {
+                Error::report_parse(
+                    Some(line_no),
+                    format!("Invalid file size in line {}: {}", line_no, line),
+                )
+            })?;
+            group.file_len = Some(FileLen(len));
+        } else if SUMMARY_LINE.is_match(line) || line.ends_with(" each:") {
+            return Err(Error::report_parse(
+                Some(line_no),
+                format!("Unrecognized line {}: {}", line_no, line),
+            ));
+        } else {
+            group.files.push(base_dir.resolve(Path::from(line)));
+        }
+    }
+    if !group.files.is_empty() {
+        groups.push(group);
+    }
+    Ok(groups)
+}
+
+/// Types of rmlint entries that don't describe duplicate files and are skipped
+const RMLINT_SKIPPED_TYPES: &[&str] = &[
+    "unique_file",
+    "emptyfile",
+    "emptydir",
+    "duplicate_dir",
+    "part_of_directory",
+    "nonstripped",
+    "badlink",
+    "baduid",
+    "badgid",
+    "badugid",
+];
+
+fn parse_rmlint(reader: impl BufRead, base_dir: &Arc<Path>) -> Result<Vec<ImportedGroup>, Error> {
+    let entries: Vec<Value> = serde_json::from_reader(reader).map_err(|e| {
+        Error::report_parse(
+            Some(e.line()),
+            format!("Malformed rmlint JSON in line {}: {}", e.line(), e),
+        )
+    })?;
+
+    let mut groups: Vec<ImportedGroup> = Vec::new();
+    let mut group_index: HashMap<String, usize> = HashMap::new();
+    for (index, entry) in entries.iter().enumerate() {
+        let entry_no = index + 1;
+        let malformed = |msg: &str| {
+            Error::report_parse(
+                None,
+                format!("Malformed rmlint entry {}: {}", entry_no, msg),
+            )
+        };
+        let entry = entry
+            .as_object()
+            .ok_or_else(|| malformed("not an object"))?;
+        let entry_type = match entry.get("type") {
+            Some(t) => t.as_str().ok_or_else(|| malformed("invalid type"))?,
+            // The first entry describes the run, the last one holds the statistics
+            None if entry.contains_key("description") || entry.contains_key("total_files") => {
+                continue
+            }
+            None => return Err(malformed("missing type")),
+        };
+        if RMLINT_SKIPPED_TYPES.contains(&entry_type) {
+            continue;
+        }
+        if entry_type != "duplicate_file" {
+            return Err(malformed(&format!("unknown type {}", entry_type)));
+        }
+        let path = entry
+            .get("path")
+            .and_then(|p| p.as_str())
+            .ok_or_else(|| malformed("missing path"))?;
+        let size = entry
+            .get("size")
+            .and_then(|s| s.as_u64())
+            .ok_or_else(|| malformed("missing size"))?;
+        let checksum = entry
+            .get("checksum")
+            .and_then(|c| c.as_str())
+            .ok_or_else(|| malformed("missing checksum"))?;
+
+        // Files of different sizes with the same checksum are not duplicates:
+        let key = format!("{}:{}", size, checksum);
+        let index = *group_index.entry(key).or_insert_with(|| {
+            groups.push(ImportedGroup {
+                file_len: Some(FileLen(size)),
+                files: vec![],
+            });
+            groups.len() - 1
+        });
+        groups[index].files.push(base_dir.resolve(Path::from(path)));
+    }
+    Ok(groups)
+}
+
+/// Checks that the imported groups really contain identical files.
+///
+/// Files that can't be read, are not regular files or differ in size from the size given
+/// in the imported list, or from the first file of the group if the size is not given,
+/// are logged and left out. If `verify_contents` is set, the files are also hashed
+/// and the groups are split by the hashes of their contents. Files not identical to any
+/// other file of their group are logged and left out, too.
+///
+/// If `verify_contents` is not set, the returned groups have a zero hash.
+pub fn verify_imported(
+    groups: Vec<ImportedGroup>,
+    verify_contents: bool,
+    log: &Log,
+) -> Vec<FileGroup<Path>> {
+    groups
+        .into_par_iter()
+        .flat_map_iter(|g| verify_group(g, verify_contents, log))
+        .collect()
+}
+
+fn verify_group(group: ImportedGroup, verify_contents: bool, log: &Log) -> Vec<FileGroup<Path>> {
+    let mut file_len = group.file_len;
+    let mut files = Vec::with_capacity(group.files.len());
+    for path in group.files {
+        let len = match fs::metadata(path.to_path_buf()) {
+            Ok(m) if m.is_file() => FileLen(m.len()),
+            Ok(_) => {
+                log.warn(format!(
+                    "Skipping file {}: Not a regular file",
+                    path.display()
+                ));
+                continue;
+            }
+            Err(e) => {
+                log.warn(format!("Skipping file {}: {}", path.display(), e));
+                continue;
+            }
+        };
+        let expected_len = *file_len.get_or_insert(len);
+        if len != expected_len {
+            log.warn(format!(
+                "Skipping file {} with length {} different than the group length {}",
+                path.display(),
+                len.0,
+                expected_len.0
+            ));
+            continue;
+        }
+        files.push(path);
+    }
+
+    let file_len = file_len.unwrap_or_default();
+    if !verify_contents {
+        return if files.len() > 1 {
+            vec![FileGroup {
+                file_len,
+                file_hash: FileHash(0),
+                files,
+            }]
+        } else {
+            vec![]
+        };
+    }
+
+    let mut sub_groups: Vec<FileGroup<Path>> = Vec::new();
+    for path in files {
+        let chunk = FileChunk::new(&path, FilePos(0), file_len);
+        let hash = match file_hash(&chunk, DEFAULT_BUF_LEN, |_| {}) {
+            Ok(hash) => hash,
+            Err(e) => {
+                log.warn(format!("Skipping file {}: {}", path.display(), e));
+                continue;
+            }
+        };
+        match sub_groups.iter_mut().find(|g| g.file_hash == hash) {
+            Some(g) => g.files.push(path),
+            None => sub_groups.push(FileGroup {
+                file_len,
+                file_hash: hash,
+                files: vec![path],
+            }),
+        }
+    }
+    sub_groups.retain(|g| {
+        if g.member_count() == 1 {
+            log.warn(format!(
+                "Skipping file {}: Contents differ from the other files in the group",
+                g.files[0].display()
+            ));
+        }
+        !g.is_single()
+    });
+    sub_groups
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use crate::util::test::{with_dir, write_file};
+
+    use super::*;
+
+    #[test]
+    fn parse_fdupes_groups() {
+        let input = "1024 bytes each:\n/a/1\n/a/2\n\n/b/1\n/b/2\n/b/3\n\n/c/1\n";
+        let groups = parse_import(
+            ImportFormat::Fdupes,
+            Cursor::new(input),
+            &Arc::new(Path::from("/")),
+        )
+        .unwrap();
+        assert_eq!(
+            groups,
+            vec![
+                ImportedGroup {
+                    file_len: Some(FileLen(1024)),
+                    files: vec![Path::from("/a/1"), Path::from("/a/2")],
+                },
+                ImportedGroup {
+                    file_len: None,
+                    files: vec![Path::from("/b/1"), Path::from("/b/2"), Path::from("/b/3")],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_fdupes_resolves_relative_paths() {
+        let input = "a\nsub/b\n";
+        let groups = parse_import(
+            ImportFormat::Jdupes,
+            Cursor::new(input),
+            &Arc::new(Path::from("/base")),
+        )
+        .unwrap();
+        assert_eq!(
+            groups[0].files,
+            vec![Path::from("/base/a"), Path::from("/base/sub/b")]
+        );
+    }
+
+    #[test]
+    fn parse_fdupes_reports_unknown_line_number() {
+        let input = "/a/1\n/a/2\n10 bytes each:\n";
+        let e = parse_import(
+            ImportFormat::Fdupes,
+            Cursor::new(input),
+            &Arc::new(Path::from("/")),
+        )
+        .unwrap_err();
+        assert!(e.message.contains("line 3"), "{}", e.message);
+
+        let input = "3 duplicate files (in 1 sets), occupying 20 bytes\n";
+        let e = parse_import(
+            ImportFormat::Jdupes,
+            Cursor::new(input),
+            &Arc::new(Path::from("/")),
+        )
+        .unwrap_err();
+        assert!(e.message.contains("line 1"), "{}", e.message);
+    }
+
+    #[test]
+    fn parse_rmlint_groups() {
+        let input = r#"[
+            {"description": "rmlint json-dump of lint files", "cwd": "/"},
+            {"type": "duplicate_file", "path": "/a/1", "size": 10, "checksum": "aa"},
+            {"type": "emptyfile", "path": "/e", "size": 0},
+            {"type": "duplicate_file", "path": "/b/1", "size": 20, "checksum": "bb"},
+            {"type": "duplicate_file", "path": "/a/2", "size": 10, "checksum": "aa"},
+            {"type": "duplicate_file", "path": "/b/2", "size": 20, "checksum": "bb"},
+            {"aborted": false, "total_files": 5}
+        ]"#;
+        let groups = parse_import(
+            ImportFormat::Rmlint,
+            Cursor::new(input),
+            &Arc::new(Path::from("/")),
+        )
+        .unwrap();
+        assert_eq!(
+            groups,
+            vec![
+                ImportedGroup {
+                    file_len: Some(FileLen(10)),
+                    files: vec![Path::from("/a/1"), Path::from("/a/2")],
+                },
+                ImportedGroup {
+                    file_len: Some(FileLen(20)),
+                    files: vec![Path::from("/b/1"), Path::from("/b/2")],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_rmlint_reports_malformed_entry() {
+        let input = r#"[{"type": "duplicate_file", "size": 10, "checksum": "aa"}]"#;
+        let e = parse_import(
+            ImportFormat::Rmlint,
+            Cursor::new(input),
+            &Arc::new(Path::from("/")),
+        )
+        .unwrap_err();
+        assert!(e.message.contains("missing path"), "{}", e.message);
+    }
+
+    #[test]
+    fn verify_drops_files_with_different_size_or_contents() {
+        with_dir("import/verify", |root| {
+            let a = root.join("a");
+            let b = root.join("b");
+            let c = root.join("c");
+            let d = root.join("d");
+            write_file(&a, "foo");
+            write_file(&b, "foo");
+            write_file(&c, "bar");
+            write_file(&d, "longer");
+            let group = ImportedGroup {
+                file_len: None,
+                files: vec![
+                    Path::from(&a),
+                    Path::from(&b),
+                    Path::from(&c),
+                    Path::from(&d),
+                    Path::from(root.join("missing")),
+                ],
+            };
+            let log = Log::new();
+
+            let groups = verify_imported(vec![group.clone()], false, &log);
+            assert_eq!(groups.len(), 1);
+            assert_eq!(groups[0].file_len, FileLen(3));
+            assert_eq!(groups[0].files.len(), 3);
+
+            let groups = verify_imported(vec![group], true, &log);
+            assert_eq!(groups.len(), 1);
+            assert_eq!(groups[0].files, vec![Path::from(&a), Path::from(&b)]);
+        });
+    }
+}
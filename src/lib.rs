@@ -1,29 +1,96 @@
+use std::fmt::Display;
+
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+
 pub mod config;
+#[cfg(feature = "cli")]
+pub mod config_file;
 pub mod file;
 pub mod log;
+#[cfg(feature = "cli")]
+pub mod options;
 pub mod path;
+pub mod pause;
 pub mod progress;
 pub mod report;
+pub mod tag;
 
 mod arg;
+mod bench;
+mod btrfs;
 mod cache;
+mod checksum;
 mod dedupe;
+mod denied;
 mod device;
+mod diff;
+mod empty_dir;
 mod error;
+mod error_limit;
+mod exec;
+mod extent;
 mod group;
 mod hasher;
+mod import;
 mod lock;
+mod metrics;
 mod pattern;
+#[cfg(feature = "reflink")]
 mod reflink;
 mod regex;
 mod selector;
 mod semaphore;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod term;
+mod throttle;
 mod transform;
+#[cfg(feature = "trash")]
+mod trash;
 mod util;
 mod walk;
+#[cfg(feature = "watch")]
+mod watch;
 
-pub use dedupe::{dedupe, log_script, run_script, DedupeOp, DedupeResult};
-pub use error::Error;
-pub use group::{group_files, write_report};
+pub use bench::{bench, BenchReport, Measurement, Recommendation};
+pub use checksum::{
+    parse_checksums, verify_checksums, write_checksums, ChecksumEntry, ChecksumFormat,
+    VerifyResult, VerifyStatus,
+};
+pub use dedupe::{
+    check_hard_links, dedupe, dedupe_with_selector, log_script, run_script, select_for_exec,
+    DedupeOp, DedupeResult, PathAndMetadata, PrioritySelector, ReplicaSelector, Selection,
+};
+pub use device::{DiskDevice, DiskDevices};
+pub use diff::{diff_trees, CommonContent, TreeDiff, UniqueContent};
+pub use empty_dir::{find_empty_dirs, parent_dirs, prune_empty_dirs};
+pub use error::{Error, ErrorKind};
+pub use exec::{exec_groups, log_exec_script, ExecGroup, ExecResult, ExecTemplate};
+pub use group::{
+    group_files, group_files_streaming, group_files_with_metrics, write_report,
+    write_report_with_metrics, FileGroup,
+};
+pub use hasher::BatchHasher;
+pub use import::{parse_import, verify_imported, ImportFormat, ImportedGroup};
+pub use metrics::{RunMetrics, StageMetrics};
+#[cfg(feature = "watch")]
+pub use watch::watch_files;
 
 const TIMESTAMP_FMT: &str = "%Y-%m-%d %H:%M:%S.%3f %z";
+
+/// Formats a timestamp shown to the user or written to a report.
+/// In the strict mode, the timestamp is converted to UTC and formatted as specified
+/// by RFC 3339, e.g. `2024-01-15T10:30:00Z`.
+fn format_timestamp<Tz>(timestamp: &DateTime<Tz>, strict_iso_8601: bool) -> String
+where
+    Tz: TimeZone,
+    Tz::Offset: Display,
+{
+    if strict_iso_8601 {
+        timestamp
+            .with_timezone(&Utc)
+            .to_rfc3339_opts(SecondsFormat::AutoSi, true)
+    } else {
+        timestamp.format(TIMESTAMP_FMT).to_string()
+    }
+}
@@ -1,19 +1,317 @@
 //! Logging and progress reporting.
 
+use std::fmt;
+use std::io;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex, Weak};
 
-use console::style;
-use indicatif::ProgressDrawTarget;
 use nom::lib::std::fmt::Display;
 
-use crate::progress::FastProgressBar;
-use chrono::Local;
+use crate::file::FileLen;
+use crate::path::Path;
+use crate::progress::{FastProgressBar, ProgressListener, ProgressStats};
+use crate::term::{style, ProgressDrawTarget, StyledObject, Term};
+use chrono::{Local, SecondsFormat, Utc};
+use serde::Serialize;
+
+/// Severity of a log message, from the least to the most severe
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    /// Returns the lowercase name of the level, e.g. `warn`
+    pub fn name(&self) -> &'static str {
+        match self {
+            LogLevel::Trace => "trace",
+            LogLevel::Debug => "debug",
+            LogLevel::Info => "info",
+            LogLevel::Warn => "warn",
+            LogLevel::Error => "error",
+        }
+    }
+}
+
+/// Format of the messages written to the standard error
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines, with progress bars on terminals
+    #[default]
+    Text,
+    /// One JSON object per line, without progress reporting; see [`JsonSink`]
+    Json,
+}
+
+impl LogFormat {
+    pub fn variants() -> Vec<&'static str> {
+        vec!["text", "json"]
+    }
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Text => f.pad("text"),
+            LogFormat::Json => f.pad("json"),
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            s => Err(format!("Unrecognized log format: {}", s)),
+        }
+    }
+}
+
+/// A message passed to a [`LogSink`].
+///
+/// The text is complete and meant for the user; the path of the file the message
+/// is about and the kind of the I/O error are provided separately, if known,
+/// so the sink can act on them without parsing the text.
+/// Summaries of many similar problems, e.g. of skipped warnings, carry the number
+/// of the problems in `count`.
+#[derive(Clone, Debug)]
+pub struct LogMessage {
+    pub text: String,
+    pub path: Option<Path>,
+    pub error_kind: Option<io::ErrorKind>,
+    pub count: Option<usize>,
+}
+
+impl LogMessage {
+    pub fn new(text: impl Into<String>) -> LogMessage {
+        LogMessage {
+            text: text.into(),
+            path: None,
+            error_kind: None,
+            count: None,
+        }
+    }
+
+    pub fn with_path(mut self, path: &Path) -> LogMessage {
+        self.path = Some(path.clone());
+        self
+    }
+
+    pub fn with_error(mut self, error: &io::Error) -> LogMessage {
+        self.error_kind = Some(error.kind());
+        self
+    }
+
+    pub fn with_error_kind(mut self, kind: io::ErrorKind) -> LogMessage {
+        self.error_kind = Some(kind);
+        self
+    }
+
+    pub fn with_count(mut self, count: usize) -> LogMessage {
+        self.count = Some(count);
+        self
+    }
+}
+
+impl Display for LogMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl From<String> for LogMessage {
+    fn from(text: String) -> Self {
+        LogMessage::new(text)
+    }
+}
+
+impl From<&str> for LogMessage {
+    fn from(text: &str) -> Self {
+        LogMessage::new(text)
+    }
+}
+
+/// Receives the messages logged by fclones.
+///
+/// [`Log`] is the sink used by the command line program and writes to the standard error.
+/// Library users can set [`Log::sink`] to route the messages elsewhere, e.g. to a GUI.
+pub trait LogSink: Send + Sync {
+    fn log(&self, level: LogLevel, msg: LogMessage);
+
+    fn trace(&self, msg: LogMessage) {
+        self.log(LogLevel::Trace, msg)
+    }
+    fn debug(&self, msg: LogMessage) {
+        self.log(LogLevel::Debug, msg)
+    }
+    fn info(&self, msg: LogMessage) {
+        self.log(LogLevel::Info, msg)
+    }
+    fn warn(&self, msg: LogMessage) {
+        self.log(LogLevel::Warn, msg)
+    }
+    fn err(&self, msg: LogMessage) {
+        self.log(LogLevel::Error, msg)
+    }
+}
+
+/// Writes messages to the standard error, in the same format as [`Log`],
+/// but without coordinating with progress bars.
+pub struct StderrSink {
+    program_name: String,
+    /// Formats timestamps as specified by RFC 3339, in UTC
+    pub strict_iso_8601: bool,
+}
+
+impl StderrSink {
+    pub fn new(program_name: &str) -> StderrSink {
+        StderrSink {
+            program_name: program_name.to_owned(),
+            strict_iso_8601: false,
+        }
+    }
+}
+
+impl LogSink for StderrSink {
+    fn log(&self, level: LogLevel, msg: LogMessage) {
+        let line = format_line(&self.program_name, self.strict_iso_8601, level, msg);
+        eprintln!("{}", line)
+    }
+}
+
+/// Writes each message to the standard error as a single-line JSON object, e.g.:
+///
+/// ```json
+/// {"timestamp":"2024-01-15T10:30:00.000Z","level":"warn","message":"Failed to read /foo: Permission denied (os error 13)","path":"/foo","error_kind":"PermissionDenied"}
+/// ```
+///
+/// The `path`, `error_kind` and `count` fields are present only if they are set in
+/// the [`LogMessage`]. The timestamps are always in UTC, as specified by RFC 3339.
+/// Meant for log collectors like journald or Kubernetes.
+pub struct JsonSink;
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    timestamp: String,
+    level: &'static str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_kind: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    count: Option<usize>,
+}
+
+impl JsonSink {
+    /// Formats the message as a JSON object, without the trailing newline
+    pub fn format(level: LogLevel, msg: &LogMessage) -> String {
+        let record = JsonRecord {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            level: level.name(),
+            message: &msg.text,
+            path: msg.path.as_ref().map(|p| p.to_string_lossy()),
+            error_kind: msg.error_kind.map(|k| format!("{:?}", k)),
+            count: msg.count,
+        };
+        serde_json::to_string(&record).expect("log records are always serializable")
+    }
+}
+
+impl LogSink for JsonSink {
+    fn log(&self, level: LogLevel, msg: LogMessage) {
+        eprintln!("{}", JsonSink::format(level, &msg))
+    }
+}
+
+/// Collects messages in memory. Useful for testing.
+#[derive(Default)]
+pub struct VecSink {
+    messages: Mutex<Vec<(LogLevel, LogMessage)>>,
+}
+
+impl VecSink {
+    pub fn new() -> VecSink {
+        VecSink::default()
+    }
+
+    /// Returns the messages received so far, in the order of arrival
+    pub fn messages(&self) -> Vec<(LogLevel, LogMessage)> {
+        self.messages.lock().unwrap().clone()
+    }
+}
+
+impl LogSink for VecSink {
+    fn log(&self, level: LogLevel, msg: LogMessage) {
+        self.messages.lock().unwrap().push((level, msg))
+    }
+}
+
+/// Discards all messages
+pub struct NoopSink;
+
+impl LogSink for NoopSink {
+    fn log(&self, _level: LogLevel, _msg: LogMessage) {}
+}
+
+const TIMESTAMP_FMT: &str = "[%Y-%m-%d %H:%M:%S.%3f]";
+
+fn level_label(level: LogLevel) -> StyledObject<&'static str> {
+    match level {
+        LogLevel::Trace => style("trace:").for_stderr().magenta(),
+        LogLevel::Debug => style("debug:").for_stderr().blue(),
+        LogLevel::Info => style(" info:").for_stderr().green(),
+        LogLevel::Warn => style(" warn:").for_stderr().yellow(),
+        LogLevel::Error => style("error:").for_stderr().red(),
+    }
+}
+
+/// Formats a line of the log with the timestamp, program name and level
+fn format_line<I: Display>(
+    program_name: &str,
+    strict_iso_8601: bool,
+    level: LogLevel,
+    msg: I,
+) -> String {
+    let timestamp = Local::now();
+    let timestamp = if strict_iso_8601 {
+        let timestamp = timestamp.with_timezone(&Utc);
+        format!(
+            "[{}]",
+            timestamp.to_rfc3339_opts(SecondsFormat::Millis, true)
+        )
+    } else {
+        timestamp.format(TIMESTAMP_FMT).to_string()
+    };
+    format!(
+        "{} {}: {} {}",
+        style(timestamp).for_stderr().dim().white(),
+        style(program_name).for_stderr().yellow(),
+        level_label(level),
+        msg
+    )
+}
 
 pub struct Log {
     program_name: String,
     progress_bar: Mutex<Weak<FastProgressBar>>,
     pub log_stderr_to_stdout: bool,
     pub no_progress: bool,
+    /// Level of diagnostic messages: 0 - none, 1 - debug, 2 - debug and trace
+    pub verbosity: u8,
+    /// Receives the progress of the stages created with [`Log::stage_progress_bar`]
+    pub progress_listener: Option<ProgressListener>,
+    /// If set, receives the logged messages instead of the standard error
+    pub sink: Option<Arc<dyn LogSink>>,
+    /// Formats timestamps of log messages and reports as specified by RFC 3339, in UTC
+    pub strict_iso_8601: bool,
 }
 
 impl Log {
@@ -28,6 +326,10 @@ impl Log {
                 .to_string(),
             log_stderr_to_stdout: false,
             no_progress: false,
+            verbosity: 0,
+            progress_listener: None,
+            sink: None,
+            strict_iso_8601: false,
         }
     }
 
@@ -57,6 +359,60 @@ impl Log {
         result
     }
 
+    /// Clears any previous progress bar or spinner and installs a new progress bar of a stage
+    /// processing the given number of items and bytes. The progress bar displays
+    /// the throughput and the estimated time to complete the stage.
+    ///
+    /// If progress reporting is disabled or the standard error is not a terminal,
+    /// the progress is periodically logged instead, unless the messages go to a `sink`.
+    /// In all cases the progress is also passed to the `progress_listener`.
+    pub fn stage_progress_bar(
+        &self,
+        msg: &str,
+        items_total: u64,
+        bytes_total: FileLen,
+    ) -> Arc<FastProgressBar> {
+        let listener = self.progress_listener.clone();
+        if self.no_progress || !Term::stderr().is_term() {
+            let program_name = self.program_name.clone();
+            let strict_iso_8601 = self.strict_iso_8601;
+            let to_stdout = self.log_stderr_to_stdout;
+            let logger: Option<ProgressListener> = match self.sink {
+                Some(_) => None,
+                None => Some(Arc::new(move |stats: &ProgressStats| {
+                    let msg = format!("{}: {}", stats.stage, stats);
+                    let msg = format_line(&program_name, strict_iso_8601, LogLevel::Info, msg);
+                    if to_stdout {
+                        println!("{}", msg)
+                    } else {
+                        eprintln!("{}", msg)
+                    }
+                })),
+            };
+            return Arc::new(FastProgressBar::new_hidden_stage_progress_bar(
+                msg,
+                items_total,
+                bytes_total,
+                listener,
+                logger,
+            ));
+        }
+        self.progress_bar
+            .lock()
+            .unwrap()
+            .upgrade()
+            .iter()
+            .for_each(|pb| pb.finish_and_clear());
+        let result = Arc::new(FastProgressBar::new_stage_progress_bar(
+            msg,
+            items_total,
+            bytes_total,
+            listener,
+        ));
+        *self.progress_bar.lock().unwrap() = Arc::downgrade(&result);
+        result
+    }
+
     /// Creates a no-op progressbar that doesn't display itself.
     pub fn hidden(&self) -> Arc<FastProgressBar> {
         Arc::new(FastProgressBar::new_hidden())
@@ -101,51 +457,66 @@ impl Log {
         }
     }
 
-    const TIMESTAMP_FMT: &'static str = "[%Y-%m-%d %H:%M:%S.%3f]";
-
     pub fn info<I: Display>(&self, msg: I) {
-        let timestamp = Local::now();
-        let msg = format!(
-            "{} {}: {} {}",
-            style(timestamp.format(Self::TIMESTAMP_FMT))
-                .for_stderr()
-                .dim()
-                .white(),
-            style(&self.program_name).for_stderr().yellow(),
-            style(" info:").for_stderr().green(),
-            msg
-        );
-        self.eprintln(msg);
+        self.log_display(LogLevel::Info, msg)
+    }
+
+    /// Prints a diagnostic message, only if `verbosity` is at least 1.
+    pub fn debug<I: Display>(&self, msg: I) {
+        if self.verbosity < 1 {
+            return;
+        }
+        self.log_display(LogLevel::Debug, msg)
+    }
+
+    /// Prints a detailed diagnostic message, only if `verbosity` is at least 2.
+    pub fn trace<I: Display>(&self, msg: I) {
+        if self.verbosity < 2 {
+            return;
+        }
+        self.log_display(LogLevel::Trace, msg)
     }
 
     pub fn warn<I: Display>(&self, msg: I) {
-        let timestamp = Local::now();
-        let msg = format!(
-            "{} {}: {} {}",
-            style(timestamp.format(Self::TIMESTAMP_FMT))
-                .for_stderr()
-                .dim()
-                .white(),
-            style(&self.program_name).for_stderr().yellow(),
-            style(" warn:").for_stderr().yellow(),
-            msg
-        );
-        self.eprintln(msg);
+        self.log_display(LogLevel::Warn, msg)
     }
 
     pub fn err<I: Display>(&self, msg: I) {
-        let timestamp = Local::now();
-        let msg = format!(
-            "{} {}: {} {}",
-            style(timestamp.format(Self::TIMESTAMP_FMT))
-                .for_stderr()
-                .dim()
-                .white(),
-            style(&self.program_name).for_stderr().yellow(),
-            style("error:").for_stderr().red(),
-            msg
-        );
-        self.eprintln(msg);
+        self.log_display(LogLevel::Error, msg)
+    }
+
+    fn format_line<I: Display>(&self, level: LogLevel, msg: I) -> String {
+        format_line(&self.program_name, self.strict_iso_8601, level, msg)
+    }
+
+    fn log_display<I: Display>(&self, level: LogLevel, msg: I) {
+        match &self.sink {
+            Some(sink) => sink.log(level, LogMessage::new(msg.to_string())),
+            None => self.eprintln(self.format_line(level, msg)),
+        }
+    }
+
+    /// Passes a structured message to the sink, or prints its text to stderr
+    /// if the sink is not set. Messages below the `verbosity` are dropped.
+    fn log_message(&self, level: LogLevel, msg: LogMessage) {
+        let min_level = match self.verbosity {
+            0 => LogLevel::Info,
+            1 => LogLevel::Debug,
+            _ => LogLevel::Trace,
+        };
+        if level < min_level {
+            return;
+        }
+        match &self.sink {
+            Some(sink) => sink.log(level, msg),
+            None => self.eprintln(self.format_line(level, msg)),
+        }
+    }
+}
+
+impl LogSink for Log {
+    fn log(&self, level: LogLevel, msg: LogMessage) {
+        self.log_message(level, msg)
     }
 }
 
@@ -154,3 +525,61 @@ impl Default for Log {
         Log::new()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn sink_receives_messages_above_verbosity() {
+        let sink = Arc::new(VecSink::new());
+        let mut log = Log::new();
+        log.sink = Some(sink.clone());
+        let error = io::Error::from(io::ErrorKind::PermissionDenied);
+        let path = Path::from("/foo");
+        LogSink::warn(
+            &log,
+            LogMessage::new("denied")
+                .with_path(&path)
+                .with_error(&error),
+        );
+        LogSink::debug(&log, LogMessage::new("hidden"));
+        log.info("plain");
+
+        let messages = sink.messages();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].0, LogLevel::Warn);
+        assert_eq!(messages[0].1.path, Some(path));
+        assert_eq!(
+            messages[0].1.error_kind,
+            Some(io::ErrorKind::PermissionDenied)
+        );
+        assert_eq!(messages[1].0, LogLevel::Info);
+        assert_eq!(messages[1].1.text, "plain");
+    }
+
+    #[test]
+    fn json_sink_writes_structured_fields() {
+        let error = io::Error::from(io::ErrorKind::PermissionDenied);
+        let msg = LogMessage::new("Permission denied to 3 entries in /foo")
+            .with_path(&Path::from("/foo"))
+            .with_error(&error)
+            .with_count(3);
+        let line = JsonSink::format(LogLevel::Warn, &msg);
+        assert!(!line.contains('\n'));
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["level"], "warn");
+        assert_eq!(json["message"], "Permission denied to 3 entries in /foo");
+        assert_eq!(json["path"], "/foo");
+        assert_eq!(json["error_kind"], "PermissionDenied");
+        assert_eq!(json["count"], 3);
+        assert!(json["timestamp"].as_str().unwrap().ends_with('Z'));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&JsonSink::format(LogLevel::Info, &"plain".into())).unwrap();
+        assert!(json.get("path").is_none());
+        assert!(json.get("count").is_none());
+    }
+}
@@ -1,23 +1,44 @@
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
-use std::fs::File;
-use std::io::{stdin, Write};
+use std::fs::{File, OpenOptions};
+use std::io::{stdin, BufReader, Read, Write};
 use std::process::exit;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 use std::{fs, io};
 
+use chrono::{DateTime, FixedOffset, Local};
 use console::style;
 use fallible_iterator::FallibleIterator;
+use flate2::read::MultiGzDecoder;
 use itertools::Itertools;
-use rayon::iter::ParallelBridge;
+use rayon::iter::{ParallelBridge, ParallelIterator};
 use regex::Regex;
 use structopt::StructOpt;
 
-use fclones::config::{Command, Config, DedupeConfig, GroupConfig, Parallelism};
-use fclones::log::Log;
-use fclones::report::{open_report, ReportHeader};
-use fclones::{dedupe, log_script, run_script, DedupeOp};
-use fclones::{group_files, write_report, Error};
+use fclones::config::{
+    BenchConfig, Command, Config, ConfigCommand, DedupeConfig, DiffTreesConfig, ExecConfig,
+    GroupConfig, GroupConfigBuilder, Parallelism, TagConfig, VerifyConfig, WatchConfig,
+};
+use fclones::config_file::Settings;
+use fclones::log::{JsonSink, Log, LogFormat};
+use fclones::options::describe_options;
+use fclones::pause;
+use fclones::report::{open_report, report_format_version, FileStats, GroupIterator, ReportHeader};
+use fclones::tag::TagOp;
+#[cfg(feature = "xattr")]
+use fclones::tag::{is_unsupported, tag_command, tag_file};
+use fclones::{
+    bench, diff_trees, group_files_with_metrics, parse_checksums, parse_import, verify_checksums,
+    verify_imported, write_report_with_metrics, Error, ImportFormat,
+};
+use fclones::{check_hard_links, dedupe, log_script, run_script, DedupeOp, DedupeResult};
+use fclones::{exec_groups, log_exec_script, select_for_exec, ExecGroup, ExecTemplate};
+use fclones::{find_empty_dirs, parent_dirs, prune_empty_dirs};
+use fclones::{DiskDevices, VerifyStatus};
 
 /// Strips a red "error:" prefix and usage information added by clap.
 /// Removes ansi formatting.
@@ -74,20 +95,50 @@ fn check_input_paths_exist(config: &GroupConfig, log: &mut Log) -> Result<(), Er
         return Err(Error::from("Some input paths could not be accessed."));
     }
     if input_paths.is_empty() {
-        return Err(Error::from("No input files."));
+        return Err(Error::config("No input files."));
+    }
+    Ok(())
+}
+
+/// Adds the paths listed in the file given by `--paths-file` to the input paths.
+/// Warns if the file lists no paths.
+fn read_paths_file(config: &mut GroupConfig, log: &Log) -> Result<(), Error> {
+    if let Some(file) = config.paths_file.clone() {
+        let count = config.read_paths_file().map_err(|e| {
+            let file = fclones::path::Path::from(&file);
+            Error::io("Failed to read input paths from", Some(&file), e)
+        })?;
+        if count == 0 {
+            log.warn(format!("No input paths found in {}", file.display()));
+        }
     }
     Ok(())
 }
 
 /// Attempts to create the output file and returns an error if it fails.
+/// An existing file is not truncated, so its previous contents are kept if the search fails.
+/// This matters for the SQLite database, which is replaced in a single transaction.
 fn check_can_create_output_file(config: &GroupConfig) -> Result<(), Error> {
-    if let Some(output) = &config.output {
-        if let Err(e) = File::create(output) {
-            return Err(Error::new(format!(
-                "Cannot create output file {}: {}",
-                output.display(),
-                e
-            )));
+    if let Some(output) = config.output_path() {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&output);
+        if let Err(e) = file {
+            let output = fclones::path::Path::from(output);
+            return Err(Error::io("Cannot create output file", Some(&output), e));
+        }
+    }
+    Ok(())
+}
+
+/// Fails early if the report to compare with can't be opened, so the search isn't wasted
+fn check_can_read_previous_report(config: &GroupConfig) -> Result<(), Error> {
+    if let Some(previous) = &config.diff {
+        if let Err(e) = File::open(previous) {
+            let previous = fclones::path::Path::from(previous);
+            return Err(Error::io("Cannot open previous report", Some(&previous), e));
         }
     }
     Ok(())
@@ -111,15 +162,158 @@ fn configure_main_thread_pool(pool_sizes: &HashMap<OsString, Parallelism>) {
 }
 
 fn run_group(mut config: GroupConfig, log: &mut Log) -> Result<(), Error> {
-    config.resolve_base_dir().map_err(|e| e.to_string())?;
+    config.resolve_base_dir()?;
     check_input_paths_exist(&config, log)?;
     check_can_create_output_file(&config)?;
+    check_can_read_previous_report(&config)?;
     configure_main_thread_pool(&config.thread_pool_sizes());
+    if let Err(e) = pause::install() {
+        log.warn(format!("Pausing the scan will not be possible: {}", e));
+    }
     log.info("Started grouping");
-    let results = group_files(&config, log).map_err(|e| Error::new(e.message))?;
+    let (results, stats, metrics) = group_files_with_metrics(&config, log)?;
+    let metrics = if config.timings || log.verbosity >= 2 {
+        log.info(format!("Stage timings: {}", metrics));
+        Some(metrics)
+    } else {
+        None
+    };
+
+    write_report_with_metrics(&config, log, &results, &stats, metrics.as_ref())
+        .map_err(|e| Error::io("Failed to write report", None, e))
+}
 
-    write_report(&config, log, &results)
-        .map_err(|e| Error::new(format!("Failed to write report: {}", e)))
+#[cfg(feature = "watch")]
+fn run_watch(mut config: WatchConfig, log: &mut Log) -> Result<(), Error> {
+    config.group.resolve_base_dir()?;
+    check_input_paths_exist(&config.group, log)?;
+    check_can_create_output_file(&config.group)?;
+    configure_main_thread_pool(&config.group.thread_pool_sizes());
+    log.info("Started watching");
+    fclones::watch_files(config, log, &AtomicBool::new(false))
+}
+
+#[cfg(not(feature = "watch"))]
+fn run_watch(_config: WatchConfig, _log: &mut Log) -> Result<(), Error> {
+    Err(Error::unsupported(
+        "Command \"watch\" requires fclones built with the watch feature",
+    ))
+}
+
+fn run_bench(config: BenchConfig, log: &Log) -> Result<(), Error> {
+    log.info(format!("Benchmarking {}", config.path.display()));
+    let report = bench(&config, log)?;
+    let mut out = io::stdout();
+    let result = if config.json {
+        report.write_as_json(&mut out)
+    } else {
+        report.write_as_text(&mut out)
+    };
+    result.map_err(|e| Error::io("Failed to write report", None, e))
+}
+
+fn run_devices() -> Result<(), Error> {
+    let devices = DiskDevices::default();
+    let header = [
+        "NAME",
+        "TYPE",
+        "FS",
+        "RAND THREADS",
+        "SEQ THREADS",
+        "MOUNT POINTS",
+    ];
+    let rows: Vec<[String; 6]> = devices
+        .iter()
+        .map(|d| {
+            [
+                d.name.to_string_lossy().to_string(),
+                d.disk_type_name().to_string(),
+                d.file_system.clone(),
+                d.parallelism.random.to_string(),
+                d.parallelism.sequential.to_string(),
+                devices
+                    .mount_points_of(d.index)
+                    .map(|p| p.display())
+                    .join(", "),
+            ]
+        })
+        .collect();
+    let mut widths = header.map(|h| h.len());
+    for row in rows.iter() {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.chars().count());
+        }
+    }
+    let header = header.map(String::from);
+    let mut out = io::stdout();
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, w)| format!("{:w$}", cell, w = w))
+            .join("  ");
+        writeln!(out, "{}", line.trim_end())
+            .map_err(|e| Error::io("Failed to write device list", None, e))?;
+    }
+    Ok(())
+}
+
+fn run_verify(config: VerifyConfig, log: &Log) -> Result<(), Error> {
+    let checksum_file = &config.checksum_file;
+    let entries = if checksum_file.as_os_str() == "-" {
+        parse_checksums(stdin().lock())?
+    } else {
+        let file = File::open(checksum_file).map_err(|e| {
+            let path = fclones::path::Path::from(checksum_file);
+            Error::io("Failed to open checksum file", Some(&path), e)
+        })?;
+        let is_gz = checksum_file
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("gz"));
+        if is_gz {
+            parse_checksums(BufReader::new(MultiGzDecoder::new(file)))?
+        } else {
+            parse_checksums(BufReader::new(file))?
+        }
+    };
+    let base_dir = fclones::path::Path::from(std::env::current_dir()?);
+    let devices = DiskDevices::new(&HashMap::new());
+    let results = verify_checksums(&entries, &base_dir, &devices, log);
+
+    let mut out = io::stdout();
+    for result in results.iter() {
+        writeln!(out, "{}: {}", result.name, result.status)
+            .map_err(|e| Error::io("Failed to write report", None, e))?;
+    }
+    let failed_count = results
+        .iter()
+        .filter(|r| r.status != VerifyStatus::Ok)
+        .count();
+    if failed_count > 0 {
+        return Err(Error::failed(format!(
+            "{} of {} files failed verification",
+            failed_count,
+            results.len()
+        )));
+    }
+    log.info(format!("Verified {} files", results.len()));
+    Ok(())
+}
+
+fn run_diff_trees(config: DiffTreesConfig, log: &Log) -> Result<(), Error> {
+    log.info(format!(
+        "Comparing {} with {}",
+        config.left.display(),
+        config.right.display()
+    ));
+    let diff = diff_trees(&config, log)?;
+    let mut out = io::stdout();
+    let result = if config.json {
+        diff.write_as_json(&mut out)
+    } else {
+        diff.write_as_text(&mut out)
+    };
+    result.map_err(|e| Error::io("Failed to write report", None, e))
 }
 
 /// Depending on the `output` configuration field, returns either a reference to the standard
@@ -128,8 +322,10 @@ fn run_group(mut config: GroupConfig, log: &mut Log) -> Result<(), Error> {
 fn get_output_writer(config: &DedupeConfig) -> Result<Box<dyn Write + Send>, Error> {
     match &config.output {
         Some(path) => {
-            let f = File::create(path)
-                .map_err(|e| format!("Failed to create output file {}: {}", path.display(), e))?;
+            let f = File::create(path).map_err(|e| {
+                let path = fclones::path::Path::from(path);
+                Error::io("Failed to create output file", Some(&path), e)
+            })?;
             Ok(Box::new(f))
         }
         None => Ok(Box::new(io::stdout())),
@@ -141,29 +337,171 @@ fn get_output_writer(config: &DedupeConfig) -> Result<Box<dyn Write + Send>, Err
 fn get_command_config(header: &ReportHeader) -> Result<Config, Error> {
     let mut command: Config = Config::from_iter_safe(&header.command).map_err(|e| {
         let message: String = extract_error_cause(&e.message);
-        format!("Unrecognized earlier fclones configuration: {}", message)
+        Error::report_parse(
+            None,
+            format!("Unrecognized earlier fclones configuration: {}", message),
+        )
     })?;
 
     // Configure the same base directory as set when running the previous command.
     // This is important to get the correct input paths.
     if let Command::Group(ref mut group_config) = command.command {
         group_config.base_dir = header.base_dir.clone();
+        // Paths listed on the standard input can't be read again, as it holds the report now
+        if group_config.paths_file.iter().any(|f| f.as_os_str() != "-") {
+            // A relative path of the file is resolved against the current working directory,
+            // like the paths given to the current command
+            let cwd = std::env::current_dir().map_err(|e| {
+                Error::io("Cannot determine the current working directory", None, e)
+            })?;
+            group_config.paths_file = group_config.paths_file.take().map(|f| cwd.join(f));
+            group_config.read_paths_file().map_err(|e| {
+                Error::io(
+                    "Failed to read input paths of the earlier fclones run",
+                    None,
+                    e,
+                )
+            })?;
+        }
     }
     Ok(command)
 }
 
-pub fn run_dedupe(op: DedupeOp, config: DedupeConfig, log: &mut Log) -> Result<(), Error> {
-    let input_error = |e: io::Error| format!("Input error: {}", e);
+/// Starts a background timer that sets the returned flag after given time.
+/// If the time is not given, the flag is never set.
+fn start_timer(timeout: Option<Duration>) -> Arc<AtomicBool> {
+    let flag = Arc::new(AtomicBool::new(false));
+    if let Some(timeout) = timeout {
+        let flag = flag.clone();
+        thread::spawn(move || {
+            thread::sleep(timeout);
+            flag.store(true, Ordering::Relaxed);
+        });
+    }
+    flag
+}
+
+/// Reports the redundant files that were left intact because of their protection attributes.
+fn log_protected_count(result: &DedupeResult, log: &Log) {
+    if result.protected_count > 0 {
+        log.warn(format!(
+            "Skipped {} protected (immutable, append-only, read-only or system) files. \
+            Use --include-protected to process them anyway.",
+            result.protected_count
+        ));
+    }
+}
+
+/// Reads the list of duplicates written by another program from the standard input
+/// and verifies the listed files. Returns a header describing the import and the groups
+/// of files that passed the verification.
+fn import_groups(
+    format: ImportFormat,
+    input: impl Read,
+    cwd: &fclones::path::Path,
+    config: &DedupeConfig,
+    log: &Log,
+) -> Result<(ReportHeader, io::Result<Box<GroupIterator>>), Error> {
+    let base_dir = Arc::new(cwd.clone());
+    // Files modified after the verification started must not be deduplicated:
+    let timestamp = DateTime::<FixedOffset>::from(Local::now());
+    let groups = parse_import(format, BufReader::new(input), &base_dir)?;
+
+    let spinner = if config.no_verify {
+        log.spinner("Verifying sizes of imported files")
+    } else {
+        log.spinner("Verifying contents of imported files")
+    };
+    let groups = verify_imported(groups, !config.no_verify, log);
+    drop(spinner);
+    log.info(format!(
+        "Imported {} groups of {} files from {} output",
+        groups.len(),
+        groups.iter().map(|g| g.files.len()).sum::<usize>(),
+        format
+    ));
+
+    let header = ReportHeader {
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        timestamp,
+        command: vec![],
+        base_dir: fclones::path::Path::clone(&base_dir),
+        stats: Some(FileStats {
+            group_count: groups.len(),
+            ..FileStats::default()
+        }),
+        size_filter: None,
+        extension_filter: None,
+        baseline: vec![],
+        roots: vec![],
+        metrics: None,
+    };
+    let groups: Box<GroupIterator> =
+        Box::new(fallible_iterator::convert(groups.into_iter().map(Ok)));
+    Ok((header, Ok(groups)))
+}
+
+/// Deduplicates the groups of files read from the standard input.
+/// If `exec` contains a command, runs the command for the groups instead of applying `op`.
+pub fn run_dedupe(
+    op: DedupeOp,
+    exec: Option<ExecConfig>,
+    config: DedupeConfig,
+    log: &mut Log,
+) -> Result<(), Error> {
+    let cwd = std::env::current_dir()
+        .map_err(|e| Error::io("Cannot determine current working directory", None, e))?;
+    let cwd = fclones::path::Path::from(cwd);
+    dedupe_input(op, exec, config, stdin(), &cwd, log)
+}
+
+/// Deduplicates the groups of files read from `input`.
+/// Relative paths given on the command line are resolved against `cwd`.
+fn dedupe_input(
+    op: DedupeOp,
+    exec: Option<ExecConfig>,
+    config: DedupeConfig,
+    input: impl Read + Send + 'static,
+    cwd: &fclones::path::Path,
+    log: &mut Log,
+) -> Result<(), Error> {
+    let input_error = |e: io::Error| report_error("Input error", e);
     let mut dedupe_config = config;
-    let mut reader = open_report(stdin()).map_err(input_error)?;
-    let header = reader.read_header().map_err(input_error)?;
-    let prev_command_config = get_command_config(&header)?;
+    let removes_files = matches!(op, DedupeOp::Remove | DedupeOp::Trash | DedupeOp::Move(_));
+    if dedupe_config.prune_empty_dirs && (!removes_files || exec.is_some()) {
+        return Err(Error::config(
+            "The --prune-empty-dirs option can be used only with the remove, trash and move commands",
+        ));
+    }
+    // Report a mistake in the command before spending time on reading the groups
+    let exec = match exec {
+        Some(exec) => {
+            let template = ExecTemplate::parse(exec.exec.as_deref().unwrap_or_default())?;
+            Some((exec, template))
+        }
+        None => None,
+    };
+    let (header, groups, prev_command) = match dedupe_config.import_format {
+        Some(format) => {
+            let (header, groups) = import_groups(format, input, cwd, &dedupe_config, log)?;
+            (header, groups, None)
+        }
+        None => {
+            let mut reader = open_report(input).map_err(input_error)?;
+            let header = reader.read_header().map_err(input_error)?;
+            let prev_command = get_command_config(&header)?.command;
+            (header, reader.read_groups(), Some(prev_command))
+        }
+    };
 
     if dedupe_config.rf_over.is_none() {
-        match &prev_command_config.command {
-            Command::Group(c) => dedupe_config.rf_over = Some(c.rf_over()),
-            _ => {
-                return Err(Error::from(
+        match &prev_command {
+            Some(Command::Group(c)) => dedupe_config.rf_over = Some(c.rf_over()),
+            // Imported lists contain all copies of each file, so keep at least one
+            None => dedupe_config.rf_over = Some(1),
+            Some(_) => {
+                return Err(Error::report_parse(
+                    None,
                     "Could not extract --rf-over setting from the earlier fclones configuration.",
                 ))
             }
@@ -171,13 +509,61 @@ pub fn run_dedupe(op: DedupeOp, config: DedupeConfig, log: &mut Log) -> Result<(
     };
 
     if dedupe_config.isolated_roots.is_empty() {
-        if let Command::Group(c) = &prev_command_config.command {
+        if let Some(Command::Group(c)) = &prev_command {
             if c.isolate {
                 dedupe_config.isolated_roots = c.input_paths().collect();
             }
         }
     }
 
+    if !dedupe_config.roots.is_empty() {
+        let cwd = std::env::current_dir()
+            .map_err(|e| Error::io("Cannot determine current working directory", None, e))?;
+        let cwd = Arc::new(fclones::path::Path::from(cwd));
+        for root in dedupe_config.roots.iter_mut() {
+            *root = cwd.resolve(std::mem::take(root));
+        }
+    }
+    if let Some(Command::Group(c)) = &prev_command {
+        if dedupe_config.min_roots.is_none() && c.min_roots() > 1 {
+            dedupe_config.min_roots = Some(c.min_roots());
+        }
+    }
+    if dedupe_config.roots.is_empty() {
+        dedupe_config.roots = header.roots.clone();
+    }
+    let min_roots = dedupe_config.min_roots.unwrap_or(0);
+    if min_roots > dedupe_config.roots.len() {
+        return Err(Error::config(format!(
+            "The --min-roots value ({}) is larger than the number of roots ({}). \
+             Use --root to specify the roots.",
+            min_roots,
+            dedupe_config.roots.len()
+        )));
+    }
+
+    let cwd = Arc::new(cwd.clone());
+    if dedupe_config.baseline_paths.is_empty() {
+        if let Some(Command::Group(c)) = &prev_command {
+            dedupe_config.baseline_paths = c.baseline_paths().collect();
+        }
+    } else {
+        for dir in dedupe_config.baseline_paths.iter_mut() {
+            *dir = cwd.resolve(std::mem::take(dir));
+        }
+    }
+
+    // Empty directories are pruned only below the directories given to `fclones group`.
+    // If those are unknown, only the directories containing the removed files are pruned.
+    let prune_roots: Vec<fclones::path::Path> = match &prev_command {
+        Some(Command::Group(c)) if !c.stdin => c.input_paths().collect(),
+        _ => Vec::new(),
+    };
+
+    for prefix in dedupe_config.preferred_path_prefixes.iter_mut() {
+        *prefix = cwd.resolve(std::mem::take(prefix));
+    }
+
     if dedupe_config.modified_before.is_none() {
         dedupe_config.modified_before = Some(header.timestamp);
     }
@@ -196,7 +582,8 @@ pub fn run_dedupe(op: DedupeOp, config: DedupeConfig, log: &mut Log) -> Result<(
         None => log.spinner("Deduplicating"),
     };
 
-    let groups = reader.read_groups();
+    let timed_out = start_timer(dedupe_config.dedup_timeout);
+    let interrupted = AtomicBool::new(false);
 
     let groups = groups
         .map_err(input_error)?
@@ -210,10 +597,16 @@ pub fn run_dedupe(op: DedupeOp, config: DedupeConfig, log: &mut Log) -> Result<(
         })
         .take_while(|g| g.is_some())
         .map(|g| g.unwrap())
+        .take_while(|_| {
+            let timed_out = timed_out.load(Ordering::Relaxed);
+            interrupted.store(timed_out, Ordering::Relaxed);
+            !timed_out
+        })
         .inspect(|_| progress.tick())
+        .filter(|g| min_roots <= 1 || g.root_count(&dedupe_config.roots) >= min_roots)
         .par_bridge();
 
-    let upto = if op == DedupeOp::RefLink {
+    let upto = if op == DedupeOp::RefLink || op == DedupeOp::SparseRefLink {
         // Can't be sure because any previous deduplications are not
         // visible without calling fs-specific tooling.
         "up to "
@@ -221,35 +614,318 @@ pub fn run_dedupe(op: DedupeOp, config: DedupeConfig, log: &mut Log) -> Result<(
         ""
     };
 
-    let script = dedupe(groups, op, &dedupe_config, log);
-    if dedupe_config.dry_run {
-        let out = get_output_writer(&dedupe_config)?;
-        let result = log_script(script, out).map_err(|e| format!("Output error: {}", e))?;
-        log.info(format!(
-            "Would process {} files and reclaim {}{} space",
-            result.processed_count, upto, result.reclaimed_space
+    // Trashed files still take space until the trash is emptied
+    let trash = op == DedupeOp::Trash;
+    let check_links = matches!(
+        op,
+        DedupeOp::HardLink {
+            check_semantics: true,
+            ..
+        }
+    );
+
+    let exec_result = match exec {
+        Some((exec, template)) => {
+            let groups = select_for_exec(groups, &dedupe_config, log).collect();
+            drop(progress);
+            run_exec(&exec, &template, groups, &dedupe_config, log)
+        }
+        None => {
+            let removed_files = Mutex::new(Vec::new());
+            let script = dedupe(groups, op, &dedupe_config, log).inspect(|cmd| {
+                if let Some(file) = cmd
+                    .removed_file()
+                    .filter(|_| dedupe_config.prune_empty_dirs)
+                {
+                    removed_files.lock().unwrap().push(file.clone());
+                }
+            });
+            if dedupe_config.dry_run {
+                let mut out = get_output_writer(&dedupe_config)?;
+                let result =
+                    log_script(script, &mut out).map_err(|e| Error::io("Output error", None, e))?;
+                if trash {
+                    log.info(format!(
+                        "Would move {} files taking {} to trash",
+                        result.processed_count, result.reclaimed_space
+                    ));
+                } else {
+                    log.info(format!(
+                        "Would process {} files and reclaim {}{} space",
+                        result.processed_count, upto, result.reclaimed_space
+                    ));
+                }
+                log_protected_count(&result, log);
+                if dedupe_config.prune_empty_dirs {
+                    let removed_files = removed_files.into_inner().unwrap();
+                    log_prune_script(removed_files, &prune_roots, out, log)
+                        .map_err(|e| Error::io("Output error", None, e))?;
+                }
+            } else {
+                let result = if check_links {
+                    // All links must be known before running the check,
+                    // so that no file gets linked on a file system failing it
+                    let script: Vec<_> = script.collect();
+                    check_hard_links(&script)?;
+                    run_script(script, log)
+                } else {
+                    run_script(script, log)
+                };
+                if trash {
+                    log.info(format!(
+                        "Moved {} files taking {} to trash",
+                        result.processed_count, result.reclaimed_space
+                    ));
+                } else {
+                    log.info(format!(
+                        "Processed {} files and reclaimed {}{} space",
+                        result.processed_count, upto, result.reclaimed_space
+                    ));
+                }
+                log_protected_count(&result, log);
+                if dedupe_config.prune_empty_dirs {
+                    let removed_files = removed_files.into_inner().unwrap();
+                    let dirs = parent_dirs(removed_files.iter(), &prune_roots);
+                    let count = prune_empty_dirs(dirs);
+                    log.info(format!("Removed {} empty directories", count));
+                }
+            };
+            Ok(())
+        }
+    };
+    if interrupted.load(Ordering::Relaxed) {
+        log.warn(format!(
+            "Time limit of {}s exceeded. Remaining groups were not processed.",
+            dedupe_config.dedup_timeout.unwrap_or_default().as_secs()
         ));
-    } else {
-        let result = run_script(script, log);
+    }
+    result.map_err(|e| report_error("Failed to read file list", e))?;
+    exec_result
+}
+
+/// Prints the commands removing the directories which would be left empty
+/// after removing the files.
+fn log_prune_script(
+    removed_files: Vec<fclones::path::Path>,
+    roots: &[fclones::path::Path],
+    mut out: impl Write,
+    log: &Log,
+) -> io::Result<()> {
+    let dirs = parent_dirs(removed_files.iter(), roots);
+    let empty_dirs: HashSet<_> = find_empty_dirs(roots, removed_files).into_iter().collect();
+    let mut count = 0;
+    for dir in dirs.iter().filter(|d| empty_dirs.contains(*d)) {
+        writeln!(out, "rmdir {}", dir.quote())?;
+        count += 1;
+    }
+    log.info(format!("Would remove {} empty directories", count));
+    Ok(())
+}
+
+/// Runs the `--exec` command for the groups, or prints the command lines in the dry-run mode.
+/// Returns an error if any command failed.
+fn run_exec(
+    exec: &ExecConfig,
+    template: &ExecTemplate,
+    mut groups: Vec<ExecGroup>,
+    config: &DedupeConfig,
+    log: &Log,
+) -> Result<(), Error> {
+    // Groups come in random order from parallel processing; make batches reproducible:
+    groups.sort_by(|a, b| {
+        (Reverse(a.file_len), a.file_hash).cmp(&(Reverse(b.file_len), b.file_hash))
+    });
+    if config.dry_run {
+        let out = get_output_writer(config)?;
+        let count = log_exec_script(template, &groups, exec.exec_batch, out)
+            .map_err(|e| Error::io("Output error", None, e))?;
         log.info(format!(
-            "Processed {} files and reclaimed {}{} space",
-            result.processed_count, upto, result.reclaimed_space
+            "Would run {} commands for {} groups",
+            count,
+            groups.len()
         ));
+        return Ok(());
+    }
+    let result = exec_groups(
+        template,
+        &groups,
+        exec.exec_batch,
+        exec.exec_concurrency,
+        log,
+    )?;
+    log.info(format!(
+        "Ran {} commands for {} groups, {} failed",
+        result.command_count,
+        groups.len(),
+        result.failed_count
+    ));
+    if result.failed_count > 0 {
+        return Err(Error::failed(format!(
+            "{} of {} commands failed",
+            result.failed_count, result.command_count
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "xattr")]
+fn run_tag(op: TagOp, config: TagConfig, log: &Log) -> Result<(), Error> {
+    let input_error = |e: io::Error| report_error("Input error", e);
+    let mut reader = open_report(stdin()).map_err(input_error)?;
+    let header = reader.read_header().map_err(input_error)?;
+    let progress = match header.stats {
+        _ if config.dry_run => log.hidden(),
+        Some(stats) => log.progress_bar("Tagging", stats.group_count as u64),
+        None => log.spinner("Tagging"),
     };
-    result.map_err(|e| Error::new(format!("Failed to read file list: {}", e)))
+
+    let mut out = io::stdout();
+    let mut file_count = 0;
+    let mut groups = reader.read_groups().map_err(input_error)?;
+    while let Some(group) = groups.next().map_err(input_error)? {
+        for path in group.files.iter() {
+            if config.dry_run {
+                writeln!(out, "{}", tag_command(path, group.file_hash, op))
+                    .map_err(|e| Error::io("Output error", None, e))?;
+                file_count += 1;
+                continue;
+            }
+            match tag_file(path, group.file_hash, op) {
+                Ok(()) => file_count += 1,
+                Err(e) if is_unsupported(&e) => {
+                    return Err(Error::unsupported(format!(
+                        "Cannot set extended attributes of {}: \
+                        the file system doesn't support them",
+                        path.display()
+                    )))
+                }
+                Err(e) => log.warn(format!(
+                    "Failed to update extended attributes of {}: {}",
+                    path.display(),
+                    e
+                )),
+            }
+        }
+        progress.tick();
+    }
+    drop(progress);
+
+    let action = match (op, config.dry_run) {
+        (TagOp::Tag, false) => "Tagged",
+        (TagOp::Tag, true) => "Would tag",
+        (TagOp::Untag, false) => "Untagged",
+        (TagOp::Untag, true) => "Would untag",
+    };
+    log.info(format!("{} {} files", action, file_count));
+    Ok(())
 }
 
-fn main() {
-    let config: Config = Config::from_args();
-    if let Err(e) = config.command.validate() {
-        eprintln!("{} {}", style("error:").for_stderr().bold().red(), e);
-        exit(1);
+#[cfg(not(feature = "xattr"))]
+fn run_tag(_op: TagOp, _config: TagConfig, _log: &Log) -> Result<(), Error> {
+    Err(Error::unsupported(
+        "Commands \"tag\" and \"untag\" require fclones built with the xattr feature",
+    ))
+}
+
+/// Converts an error of reading a report to the crate error.
+/// Malformed reports are distinguished from failures of reading them.
+fn report_error(action: &str, e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => {
+            Error::report_parse(None, format!("{}: {}", action, e))
+        }
+        _ => Error::io(action, None, e),
+    }
+}
+
+/// Parses the command line and sets the options not given on it to the values
+/// from the environment variables and the configuration file.
+/// The command line recorded in the report of the `group` command includes these options,
+/// so the commands reading the report rebuild the same configuration.
+/// Exits with the usage message if the command line can't be parsed.
+fn load_config() -> Result<(Config, Settings), Error> {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let matches = Config::clap().get_matches_from(&args);
+    let settings = Settings::load(&matches)?;
+    let mut config = settings.apply(Config::from_clap(&matches), &matches)?;
+    if let Command::Group(c) = &mut config.command {
+        c.command_line = settings.command_line(args, &matches);
     }
+    Ok((config, settings))
+}
+
+fn run_config_dump(settings: &Settings, command: Option<&str>) -> Result<(), Error> {
+    print!("{}", settings.dump(command)?);
+    Ok(())
+}
+
+/// Prints the completion script of the given shell to stdout
+fn run_complete(shell: &str) -> Result<(), Error> {
+    let shell = shell.parse::<clap::Shell>().map_err(Error::config)?;
+    Config::clap().gen_completions_to("fclones", shell, &mut io::stdout());
+    Ok(())
+}
+
+fn run_options(json: bool) -> Result<(), Error> {
+    let options = describe_options();
+    let text = if json {
+        options.to_json() + "\n"
+    } else {
+        options.to_text()
+    };
+    io::stdout()
+        .write_all(text.as_bytes())
+        .map_err(|e| Error::io("Output error", None, e))
+}
 
+fn main() {
+    let (mut config, settings) = match load_config() {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{} {}", style("error:").for_stderr().bold().red(), e);
+            exit(1);
+        }
+    };
     let mut log = Log::new();
     if config.quiet {
         log.no_progress = true;
     }
+    log.verbosity = config.verbose;
+    log.strict_iso_8601 = config.strict_iso_8601;
+    if config.log_format == LogFormat::Json {
+        log.no_progress = true;
+        log.sink = Some(Arc::new(JsonSink));
+    }
+    for warning in settings.warnings() {
+        log.warn(warning);
+    }
+
+    if matches!(&config.command, Command::Group(c) if c.report_format_version) {
+        println!("{}", report_format_version());
+        return;
+    }
+
+    let validated = match &mut config.command {
+        Command::Group(c) => read_paths_file(c, &log).and_then(|_| {
+            GroupConfigBuilder::from(std::mem::take(c))
+                .build()
+                .map(|built| *c = built)
+        }),
+        Command::Watch(c) => read_paths_file(&mut c.group, &log).and_then(|_| {
+            GroupConfigBuilder::from(std::mem::take(&mut c.group))
+                .build()
+                .map(|built| c.group = built)?;
+            c.validate().map_err(Error::from)
+        }),
+        command => command.validate().map_err(Error::from),
+    };
+    if let Err(e) = validated {
+        match config.log_format {
+            LogFormat::Json => log.err(e),
+            LogFormat::Text => eprintln!("{} {}", style("error:").for_stderr().bold().red(), e),
+        }
+        exit(1);
+    }
 
     let cwd = match std::env::current_dir() {
         Ok(cwd) => cwd,
@@ -261,24 +937,71 @@ fn main() {
 
     let result = match config.command {
         Command::Group(config) => run_group(config, &mut log),
-        Command::Remove(config) => run_dedupe(DedupeOp::Remove, config, &mut log),
-        Command::Link { config, soft: true } => run_dedupe(DedupeOp::SoftLink, config, &mut log),
+        Command::Remove(config) => run_dedupe(DedupeOp::Remove, None, config, &mut log),
+        Command::Trash(config) => run_dedupe(DedupeOp::Trash, None, config, &mut log),
+        Command::Link {
+            config, soft: true, ..
+        } => run_dedupe(DedupeOp::SoftLink, None, config, &mut log),
         Command::Link {
             config,
             soft: false,
-        } => run_dedupe(DedupeOp::HardLink, config, &mut log),
+            link_then_verify,
+            hardlink_posix_semantics_check,
+        } => run_dedupe(
+            DedupeOp::HardLink {
+                verify: link_then_verify,
+                check_semantics: hardlink_posix_semantics_check,
+            },
+            None,
+            config,
+            &mut log,
+        ),
+        Command::Dedupe { config, exec, .. } if exec.exec.is_some() => {
+            run_dedupe(DedupeOp::RefLink, Some(exec), config, &mut log)
+        }
+        Command::Dedupe {
+            config,
+            sparse: true,
+            ..
+        } => {
+            if cfg!(any(target_os = "linux", target_os = "android")) {
+                run_dedupe(DedupeOp::SparseRefLink, None, config, &mut log)
+            } else {
+                Err(Error::unsupported(
+                    "Command \"dedupe --sparse\" is supported only on Linux",
+                ))
+            }
+        }
         Command::Dedupe { config, .. } => {
             if cfg!(windows) {
-                log.err("Command \"dedupe\" is unsupported on Windows");
-                exit(1);
+                Err(Error::unsupported(
+                    "Command \"dedupe\" is unsupported on Windows",
+                ))
+            } else if !cfg!(feature = "reflink") {
+                Err(Error::unsupported(
+                    "Command \"dedupe\" requires fclones built with the reflink feature",
+                ))
+            } else {
+                run_dedupe(DedupeOp::RefLink, None, config, &mut log)
             }
-            run_dedupe(DedupeOp::RefLink, config, &mut log)
         }
         Command::Move { config, target } => {
             let target = fclones::path::Path::from(target);
             let target = Arc::new(fclones::path::Path::from(cwd)).resolve(target);
-            run_dedupe(DedupeOp::Move(Arc::new(target)), config, &mut log)
+            run_dedupe(DedupeOp::Move(Arc::new(target)), None, config, &mut log)
+        }
+        Command::Watch(config) => run_watch(config, &mut log),
+        Command::Tag(config) => run_tag(TagOp::Tag, config, &log),
+        Command::Untag(config) => run_tag(TagOp::Untag, config, &log),
+        Command::Bench(config) => run_bench(config, &log),
+        Command::Verify(config) => run_verify(config, &log),
+        Command::DiffTrees(config) => run_diff_trees(config, &log),
+        Command::Devices => run_devices(),
+        Command::Config(ConfigCommand::Dump { command }) => {
+            run_config_dump(&settings, command.as_deref())
         }
+        Command::Complete { shell } => run_complete(&shell),
+        Command::Options { json } => run_options(json),
     };
 
     if let Err(e) = result {
@@ -291,6 +1014,162 @@ fn main() {
 
 #[cfg(test)]
 mod test {
+    use std::io;
+
+    use chrono::Local;
+    use fclones::config::{Command, DedupeConfig, ExecConfig, GroupConfig, Priority};
+    use fclones::file::{FileHash, FileLen};
+    use fclones::log::Log;
+    use fclones::path::Path;
+    use fclones::report::{FileStats, ReportHeader, ReportWriter};
+    use fclones::{DedupeOp, FileGroup};
+    use filetime::FileTime;
+
+    #[test]
+    fn test_get_command_config_reads_paths_file_relative_to_cwd() {
+        // Tests run in the crate directory
+        let paths_dir = "target/test/main/paths_file";
+        std::fs::create_dir_all(paths_dir).unwrap();
+        std::fs::write(format!("{}/paths.txt", paths_dir), "a\n").unwrap();
+        // The earlier run was started elsewhere
+        let base_dir = tempfile::tempdir().unwrap();
+        let header = ReportHeader {
+            version: "0.0.0".to_owned(),
+            timestamp: Local::now().into(),
+            command: [
+                "fclones",
+                "group",
+                "--paths-file",
+                &format!("{}/paths.txt", paths_dir),
+            ]
+            .iter()
+            .map(|&a| a.into())
+            .collect(),
+            base_dir: Path::from(base_dir.path()),
+            stats: None,
+            size_filter: None,
+            extension_filter: None,
+            baseline: vec![],
+            roots: vec![],
+            metrics: None,
+        };
+        let config = super::get_command_config(&header).unwrap();
+        match config.command {
+            Command::Group(c) => assert_eq!(c.paths, vec![Path::from("a")]),
+            _ => panic!("Expected the group command"),
+        }
+    }
+
+    #[test]
+    fn test_dedupe_resolves_relative_baseline_against_cwd() {
+        let dir = tempfile::tempdir().unwrap();
+        let library = dir.path().join("library");
+        let incoming = dir.path().join("incoming");
+        std::fs::create_dir(&library).unwrap();
+        std::fs::create_dir(&incoming).unwrap();
+        std::fs::write(library.join("file"), "content").unwrap();
+        std::fs::write(incoming.join("file"), "content").unwrap();
+        // The file in the library is older, so it would be removed without the baseline:
+        let hour_ago = FileTime::from_unix_time(Local::now().timestamp() - 3600, 0);
+        let two_hours_ago = FileTime::from_unix_time(Local::now().timestamp() - 7200, 0);
+        filetime::set_file_mtime(library.join("file"), two_hours_ago).unwrap();
+        filetime::set_file_mtime(incoming.join("file"), hour_ago).unwrap();
+
+        let header = ReportHeader {
+            version: "0.0.0".to_owned(),
+            timestamp: Local::now().into(),
+            command: ["fclones", "group", "library", "incoming"]
+                .iter()
+                .map(|&a| a.into())
+                .collect(),
+            base_dir: Path::from(dir.path()),
+            stats: Some(FileStats::default()),
+            size_filter: None,
+            extension_filter: None,
+            baseline: vec![],
+            roots: vec![],
+            metrics: None,
+        };
+        let group = FileGroup {
+            file_len: FileLen(7),
+            file_hash: FileHash(1),
+            files: vec![
+                Path::from(library.join("file")),
+                Path::from(incoming.join("file")),
+            ],
+        };
+
+        let script = |baseline: Vec<Path>| {
+            let mut report = Vec::new();
+            ReportWriter::new(&mut report, false)
+                .write_as_text(&header, [&group])
+                .unwrap();
+            let output = dir.path().join("script.sh");
+            let config = DedupeConfig {
+                dry_run: true,
+                output: Some(output.clone()),
+                priority: vec![Priority::LeastRecentlyModified],
+                baseline_paths: baseline,
+                ..DedupeConfig::default()
+            };
+            let cwd = Path::from(dir.path());
+            let input = io::Cursor::new(report);
+            let mut log = Log::new();
+            log.no_progress = true;
+            super::dedupe_input(DedupeOp::Remove, None, config, input, &cwd, &mut log).unwrap();
+            std::fs::read_to_string(output).unwrap()
+        };
+
+        assert!(script(vec![]).contains("library"));
+        let script = script(vec![Path::from("library")]);
+        assert!(!script.contains("library"));
+        assert!(script.contains("incoming"));
+    }
+
+    #[test]
+    fn test_prune_empty_dirs_rejected_for_links() {
+        let config = DedupeConfig {
+            prune_empty_dirs: true,
+            ..DedupeConfig::default()
+        };
+        let cwd = Path::from(std::env::current_dir().unwrap());
+        let input = io::Cursor::new(Vec::new());
+        let mut log = Log::new();
+        let op = DedupeOp::SoftLink;
+        let err = super::dedupe_input(op, None, config, input, &cwd, &mut log).unwrap_err();
+        assert!(matches!(err.kind, fclones::ErrorKind::Config));
+    }
+
+    #[test]
+    fn test_invalid_exec_command_rejected_before_reading_input() {
+        let exec = ExecConfig {
+            exec: Some("echo {dupes...}x".to_owned()),
+            exec_batch: 1,
+            exec_concurrency: 1,
+        };
+        let cwd = Path::from(std::env::current_dir().unwrap());
+        // Not a valid report, so reading it would fail with a different error
+        let input = io::Cursor::new(b"garbage".to_vec());
+        let mut log = Log::new();
+        let op = DedupeOp::Remove;
+        let config = DedupeConfig::default();
+        let err = super::dedupe_input(op, Some(exec), config, input, &cwd, &mut log).unwrap_err();
+        assert!(matches!(err.kind, fclones::ErrorKind::Config));
+        assert!(err.message.contains("must be a separate argument"));
+    }
+
+    #[test]
+    fn test_check_can_create_output_file_keeps_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("report.db");
+        std::fs::write(&output, "previous report").unwrap();
+        let config = GroupConfig {
+            output: Some(output.clone()),
+            ..GroupConfig::default()
+        };
+        super::check_can_create_output_file(&config).unwrap();
+        assert_eq!(std::fs::read_to_string(output).unwrap(), "previous report");
+    }
 
     #[test]
     fn test_extract_error_cause_strips_error_prefix() {
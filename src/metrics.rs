@@ -0,0 +1,323 @@
+//! Timing and counters of the grouping stages.
+
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::file::FileLen;
+use crate::log::{LogLevel, LogMessage, LogSink};
+use crate::pause::PAUSE;
+
+/// Counters updated by the worker threads of a stage.
+///
+/// The counters are cumulative; a stage records their values at the start and
+/// reports the increase. Relaxed ordering is enough, because the values are read
+/// only after all workers of the stage have finished.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    bytes_read: AtomicU64,
+    cache_hits: AtomicU64,
+    errors: AtomicU64,
+    warnings: AtomicU64,
+    files_opened: AtomicU64,
+    handles_reused: AtomicU64,
+}
+
+impl Counters {
+    pub fn add_bytes_read(&self, len: u64) {
+        self.bytes_read.fetch_add(len, Ordering::Relaxed);
+    }
+
+    pub fn inc_cache_hits(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_errors(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_warnings(&self) {
+        self.warnings.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_files_opened(&self) {
+        self.files_opened.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_handles_reused(&self) {
+        self.handles_reused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn values(&self) -> [u64; 6] {
+        [
+            self.bytes_read.load(Ordering::Relaxed),
+            self.cache_hits.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed),
+            self.warnings.load(Ordering::Relaxed),
+            self.files_opened.load(Ordering::Relaxed),
+            self.handles_reused.load(Ordering::Relaxed),
+        ]
+    }
+}
+
+/// Forwards messages to another sink and counts warnings and errors separately
+pub(crate) struct CountingSink<'a> {
+    pub inner: &'a dyn LogSink,
+    pub counters: &'a Counters,
+}
+
+impl LogSink for CountingSink<'_> {
+    fn log(&self, level: LogLevel, msg: LogMessage) {
+        match level {
+            LogLevel::Error => self.counters.inc_errors(),
+            LogLevel::Warn => self.counters.inc_warnings(),
+            _ => {}
+        }
+        self.inner.log(level, msg)
+    }
+}
+
+/// Measures a stage from its creation until [`StageTimer::finish`] is called.
+/// Time spent paused is not counted.
+pub(crate) struct StageTimer<'a> {
+    name: &'static str,
+    files_in: usize,
+    counters: &'a Counters,
+    counters_at_start: [u64; 6],
+    paused_time_at_start: Duration,
+    start: Instant,
+}
+
+impl<'a> StageTimer<'a> {
+    pub fn start(name: &'static str, files_in: usize, counters: &'a Counters) -> StageTimer<'a> {
+        StageTimer {
+            name,
+            files_in,
+            counters,
+            counters_at_start: counters.values(),
+            paused_time_at_start: PAUSE.paused_time(),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn finish(self, files_out: usize) -> StageMetrics {
+        let paused_time = PAUSE.paused_time() - self.paused_time_at_start;
+        let [bytes_read, cache_hits, errors, warnings, files_opened, handles_reused] =
+            self.counters.values();
+        let [bytes_read_0, cache_hits_0, errors_0, warnings_0, files_opened_0, handles_reused_0] =
+            self.counters_at_start;
+        StageMetrics {
+            name: self.name.to_owned(),
+            wall_time: self.start.elapsed().saturating_sub(paused_time),
+            files_in: self.files_in,
+            files_out,
+            bytes_read: FileLen(bytes_read - bytes_read_0),
+            cache_hits: cache_hits - cache_hits_0,
+            errors: errors - errors_0,
+            warnings: warnings - warnings_0,
+            files_opened: files_opened - files_opened_0,
+            handles_reused: handles_reused - handles_reused_0,
+        }
+    }
+}
+
+/// Describes the work done by a single stage of grouping files
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct StageMetrics {
+    pub name: String,
+    /// Time spent in the stage, excluding the time the scan was paused
+    #[serde(rename = "wall_time_secs", with = "duration_secs")]
+    pub wall_time: Duration,
+    /// Number of files passed to the stage; for the directory walk, the number of visited entries
+    pub files_in: usize,
+    /// Number of files remaining after the stage
+    pub files_out: usize,
+    /// Number of bytes read from files, not counting the hashes loaded from the cache
+    pub bytes_read: FileLen,
+    /// Number of hashes loaded from the hash cache instead of being computed
+    pub cache_hits: u64,
+    /// Number of files that couldn't be accessed
+    pub errors: u64,
+    /// Number of warnings logged by the stage, e.g. about skipped directories
+    #[serde(default)]
+    pub warnings: u64,
+    /// Number of files opened for hashing
+    #[serde(default)]
+    pub files_opened: u64,
+    /// Number of files hashed through a handle left open by the previous stage,
+    /// each saving an `open` and a `close` call
+    #[serde(default)]
+    pub handles_reused: u64,
+}
+
+impl Display for StageMetrics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:.1}s, {} files",
+            self.name,
+            self.wall_time.as_secs_f64(),
+            format_count(self.files_in as u64)
+        )?;
+        if self.bytes_read > FileLen(0) {
+            write!(f, ", {} read", self.bytes_read)?;
+        }
+        if self.cache_hits > 0 {
+            write!(f, ", {} cache hits", format_count(self.cache_hits))?;
+        }
+        if self.errors > 0 {
+            write!(f, ", {} errors", format_count(self.errors))?;
+        }
+        if self.warnings > 0 {
+            write!(f, ", {} warnings", format_count(self.warnings))?;
+        }
+        if self.handles_reused > 0 {
+            write!(f, ", {} handles reused", format_count(self.handles_reused))?;
+        }
+        Ok(())
+    }
+}
+
+/// Timing and counters of all stages of a grouping run, in the order of execution
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RunMetrics {
+    pub stages: Vec<StageMetrics>,
+}
+
+impl RunMetrics {
+    /// Returns the sum of the wall times of all stages
+    pub fn total_time(&self) -> Duration {
+        self.stages.iter().map(|s| s.wall_time).sum()
+    }
+}
+
+/// Formats the stages in a single line, separated by semicolons,
+/// e.g. `walk 12.3s, 1.2M files; prefix hash 48.0s, 300k files, 18.0 GB read`
+impl Display for RunMetrics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (i, stage) in self.stages.iter().enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+            write!(f, "{}", stage)?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats a count with a `k` or `M` suffix if it is large
+fn format_count(count: u64) -> String {
+    match count {
+        0..=999 => count.to_string(),
+        1_000..=9_999 => format!("{:.1}k", count as f64 / 1e3),
+        10_000..=999_999 => format!("{}k", count / 1_000),
+        1_000_000..=9_999_999 => format!("{:.1}M", count as f64 / 1e6),
+        _ => format!("{}M", count / 1_000_000),
+    }
+}
+
+/// Serializes durations as fractional seconds
+mod duration_secs {
+    use super::*;
+    use serde::de::Error;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(duration.as_secs_f64())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let secs = f64::deserialize(deserializer)?;
+        Duration::try_from_secs_f64(secs).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::log::NoopSink;
+
+    #[test]
+    fn stage_timer_reports_counter_increments() {
+        let counters = Counters::default();
+        counters.add_bytes_read(100);
+        let timer = StageTimer::start("prefix hash", 3, &counters);
+        counters.add_bytes_read(4096);
+        counters.inc_cache_hits();
+        counters.inc_files_opened();
+        counters.inc_handles_reused();
+        let metrics = timer.finish(2);
+        assert_eq!(metrics.files_in, 3);
+        assert_eq!(metrics.files_out, 2);
+        assert_eq!(metrics.bytes_read, FileLen(4096));
+        assert_eq!(metrics.cache_hits, 1);
+        assert_eq!(metrics.errors, 0);
+        assert_eq!(metrics.files_opened, 1);
+        assert_eq!(metrics.handles_reused, 1);
+    }
+
+    #[test]
+    fn counting_sink_counts_warnings_and_errors_separately() {
+        let counters = Counters::default();
+        let sink = CountingSink {
+            inner: &NoopSink,
+            counters: &counters,
+        };
+        let timer = StageTimer::start("walk", 0, &counters);
+        sink.log(LogLevel::Info, LogMessage::new("info"));
+        sink.log(LogLevel::Warn, LogMessage::new("warning"));
+        sink.log(LogLevel::Warn, LogMessage::new("warning"));
+        sink.log(LogLevel::Error, LogMessage::new("error"));
+        let metrics = timer.finish(0);
+        assert_eq!(metrics.warnings, 2);
+        assert_eq!(metrics.errors, 1);
+    }
+
+    #[test]
+    fn format_run_metrics() {
+        let stage = |name: &str, secs, files_in, bytes_read| StageMetrics {
+            name: name.to_owned(),
+            wall_time: Duration::from_secs_f64(secs),
+            files_in,
+            files_out: 0,
+            bytes_read: FileLen(bytes_read),
+            cache_hits: 0,
+            errors: 0,
+            warnings: 0,
+            files_opened: 0,
+            handles_reused: 0,
+        };
+        let metrics = RunMetrics {
+            stages: vec![
+                stage("walk", 12.3, 1_234_567, 0),
+                stage("prefix hash", 48.0, 300_000, 18_000_000_000),
+            ],
+        };
+        assert_eq!(
+            metrics.to_string(),
+            "walk 12.3s, 1.2M files; prefix hash 48.0s, 300k files, 18.0 GB read"
+        );
+    }
+
+    #[test]
+    fn serialize_wall_time_as_seconds() {
+        let metrics = StageMetrics {
+            name: "walk".to_owned(),
+            wall_time: Duration::from_millis(1500),
+            files_in: 10,
+            files_out: 8,
+            bytes_read: FileLen(0),
+            cache_hits: 0,
+            errors: 1,
+            warnings: 2,
+            files_opened: 8,
+            handles_reused: 2,
+        };
+        let json = serde_json::to_value(&metrics).unwrap();
+        assert_eq!(json["wall_time_secs"], 1.5);
+        let metrics2: StageMetrics = serde_json::from_value(json).unwrap();
+        assert_eq!(metrics2, metrics);
+    }
+}
@@ -0,0 +1,273 @@
+//! Describing the command line options in a machine-readable form,
+//! e.g. for graphical front-ends building forms of the commands.
+//!
+//! The descriptions are read from the same clap definitions that parse the command line,
+//! so they can't get out of sync with the options accepted by fclones.
+
+use std::fmt::Write;
+
+use clap::{App, ArgSettings};
+use serde::Serialize;
+use structopt::StructOpt;
+
+use crate::config::Config;
+
+/// Kind of the value taken by an option
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OptionType {
+    /// A flag without a value
+    Bool,
+    /// A flag that can be repeated, e.g. `-vv`
+    Count,
+    /// One of the values listed in `possible_values`
+    Enum,
+    /// A non-negative integer
+    Integer,
+    /// A size in bytes, optionally with a unit suffix, e.g. `10MB`
+    Size,
+    /// Any other value
+    String,
+}
+
+impl OptionType {
+    /// Returns the type of an option taking a value.
+    /// Clap doesn't know the types of the values, so they are inferred from the names of
+    /// the values used consistently by the definitions of the options.
+    fn of_value(value_name: Option<&str>, possible_values: &[String]) -> OptionType {
+        if !possible_values.is_empty() {
+            return OptionType::Enum;
+        }
+        match value_name {
+            Some("count" | "n") => OptionType::Integer,
+            Some("bytes") => OptionType::Size,
+            _ => OptionType::String,
+        }
+    }
+}
+
+/// Description of a single command line argument
+#[derive(Clone, Debug, Serialize)]
+pub struct OptionDescription {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub long: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub short: Option<char>,
+    /// True for the arguments given by position rather than by a flag
+    pub positional: bool,
+    #[serde(rename = "type")]
+    pub value_type: OptionType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_name: Option<String>,
+    /// True if the option can be given more than once or takes many values
+    pub multiple: bool,
+    pub required: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub possible_values: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub help: Option<String>,
+}
+
+/// Description of a command and its options
+#[derive(Clone, Debug, Serialize)]
+pub struct CommandDescription {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub about: Option<String>,
+    pub options: Vec<OptionDescription>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub subcommands: Vec<CommandDescription>,
+}
+
+/// Returns the description of the whole command line of fclones.
+/// The options of the top-level command are the global options.
+pub fn describe_options() -> CommandDescription {
+    describe_command(&Config::clap())
+}
+
+/// Clap 2 doesn't expose the definitions of arguments other than through the fields of
+/// its parser, which are public but not a part of its stable API. That's why clap is pinned
+/// to an exact version in `Cargo.toml`; check this function when upgrading it.
+fn describe_command(app: &App<'static, 'static>) -> CommandDescription {
+    let text = |s: Option<&str>| s.map(str::to_owned);
+    let strings = |v: Option<&Vec<&str>>| -> Vec<String> {
+        v.map(|v| v.iter().map(|s| s.to_string()).collect())
+            .unwrap_or_default()
+    };
+
+    let flags = app.p.flags.iter().map(|f| {
+        let multiple = f.b.is_set(ArgSettings::Multiple);
+        OptionDescription {
+            name: f.b.name.to_owned(),
+            long: text(f.s.long),
+            short: f.s.short,
+            positional: false,
+            value_type: if multiple {
+                OptionType::Count
+            } else {
+                OptionType::Bool
+            },
+            value_name: None,
+            multiple,
+            required: false,
+            default: None,
+            possible_values: Vec::new(),
+            help: text(f.b.help),
+        }
+    });
+    let opts = app.p.opts.iter().map(|o| {
+        let possible_values = strings(o.v.possible_vals.as_ref());
+        let value_name =
+            o.v.val_names
+                .as_ref()
+                .and_then(|n| n.values().next().copied());
+        OptionDescription {
+            name: o.b.name.to_owned(),
+            long: text(o.s.long),
+            short: o.s.short,
+            positional: false,
+            value_type: OptionType::of_value(value_name, &possible_values),
+            value_name: text(value_name),
+            multiple: o.b.is_set(ArgSettings::Multiple),
+            required: o.b.is_set(ArgSettings::Required),
+            default: o.v.default_val.map(|v| v.to_string_lossy().to_string()),
+            possible_values,
+            help: text(o.b.help),
+        }
+    });
+    let positionals = app.p.positionals.values().map(|p| {
+        let possible_values = strings(p.v.possible_vals.as_ref());
+        let value_name =
+            p.v.val_names
+                .as_ref()
+                .and_then(|n| n.values().next().copied());
+        OptionDescription {
+            name: p.b.name.to_owned(),
+            long: None,
+            short: None,
+            positional: true,
+            value_type: OptionType::of_value(value_name, &possible_values),
+            value_name: text(value_name),
+            multiple: p.b.is_set(ArgSettings::Multiple),
+            required: p.b.is_set(ArgSettings::Required),
+            default: p.v.default_val.map(|v| v.to_string_lossy().to_string()),
+            possible_values,
+            help: text(p.b.help),
+        }
+    });
+    let options = flags
+        .chain(opts)
+        .chain(positionals)
+        .filter(|o| !["help", "version"].contains(&o.name.as_str()))
+        .collect();
+    let subcommands = app
+        .p
+        .subcommands
+        .iter()
+        .filter(|c| c.get_name() != "help")
+        .map(describe_command)
+        .collect();
+
+    CommandDescription {
+        name: app.get_name().to_owned(),
+        about: text(app.p.meta.about),
+        options,
+        subcommands,
+    }
+}
+
+impl CommandDescription {
+    /// Returns the description serialized as pretty-printed JSON
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("descriptions are always serializable")
+    }
+
+    /// Returns a human-readable listing of the options, one option per line,
+    /// prefixed by the name of the command they belong to.
+    pub fn to_text(&self) -> String {
+        let mut result = String::new();
+        self.write_text(None, &mut result);
+        result
+    }
+
+    fn write_text(&self, prefix: Option<&str>, out: &mut String) {
+        let command = match prefix {
+            Some(prefix) => format!("{} {}", prefix, self.name),
+            None => self.name.clone(),
+        };
+        for o in &self.options {
+            let name = match (&o.long, &o.value_name) {
+                (Some(long), Some(value)) => format!("--{} <{}>", long, value),
+                (Some(long), None) => format!("--{}", long),
+                (None, Some(value)) => format!("<{}>", value),
+                (None, None) => format!("<{}>", o.name),
+            };
+            let _ = write!(out, "{} {}", command, name);
+            if o.multiple {
+                out.push_str("...");
+            }
+            if !o.possible_values.is_empty() {
+                let _ = write!(out, " [{}]", o.possible_values.join(", "));
+            }
+            if let Some(default) = &o.default {
+                let _ = write!(out, " (default: {})", default);
+            }
+            out.push('\n');
+        }
+        for c in &self.subcommands {
+            c.write_text(Some(&command), out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn find<'a>(command: &'a CommandDescription, name: &str) -> &'a CommandDescription {
+        command.subcommands.iter().find(|c| c.name == name).unwrap()
+    }
+
+    fn option<'a>(command: &'a CommandDescription, long: &str) -> &'a OptionDescription {
+        command
+            .options
+            .iter()
+            .find(|o| o.long.as_deref() == Some(long))
+            .unwrap()
+    }
+
+    #[test]
+    fn describes_options_of_commands() {
+        let fclones = describe_options();
+        assert_eq!(option(&fclones, "quiet").value_type, OptionType::Bool);
+        assert_eq!(option(&fclones, "verbose").value_type, OptionType::Count);
+
+        let group = find(&fclones, "group");
+        let format = option(group, "format");
+        assert_eq!(format.value_type, OptionType::Enum);
+        assert!(format.possible_values.contains(&"json".to_owned()));
+        let encoding = option(group, "hash-encoding");
+        assert_eq!(encoding.default.as_deref(), Some("hex"));
+        assert_eq!(option(group, "rf-over").value_type, OptionType::Integer);
+        assert_eq!(
+            option(group, "max-buffer-memory").value_type,
+            OptionType::Size
+        );
+        assert_eq!(option(group, "name").value_type, OptionType::String);
+        assert!(group.options.iter().any(|o| o.positional && o.multiple));
+
+        let dump = find(find(&fclones, "config"), "dump");
+        assert_eq!(dump.options.len(), 1);
+    }
+
+    #[test]
+    fn json_contains_all_commands() {
+        let json: serde_json::Value = serde_json::from_str(&describe_options().to_json()).unwrap();
+        let commands = json["subcommands"].as_array().unwrap();
+        assert!(commands.iter().any(|c| c["name"] == "link"));
+        assert!(commands.iter().any(|c| c["name"] == "options"));
+    }
+}
@@ -6,6 +6,7 @@ use std::hash::Hash;
 use std::path::{Component, PathBuf};
 use std::sync::Arc;
 
+use dashmap::DashSet;
 use metrohash::MetroHash128;
 use nom::lib::std::fmt::Formatter;
 use serde::de::{Error, Visitor};
@@ -29,6 +30,22 @@ pub const PATH_ESCAPE_CHAR: &str = "^";
 /// would keep the parent path text duplicated in memory, wasting a lot of memory.
 /// This structure here shares the common parent between many paths by reference-counted
 /// references.
+///
+/// A `Path` can be created from anything convertible to a standard path, e.g. a `&str`
+/// or a `PathBuf`, and converted back with [`Path::to_path_buf`] or `PathBuf::from`.
+/// Because the path is stored as a list of components, it can't be borrowed
+/// as a [`std::path::Path`].
+///
+/// Paths are serialized as strings, in the same format as in the reports, so they can be
+/// embedded in other serializable structures without losing non-UTF-8 characters:
+/// ```
+/// use fclones::path::Path;
+///
+/// let path = Path::from("/home/user/photo.jpg");
+/// let json = serde_json::to_string(&path).unwrap();
+/// assert_eq!(json, r#""/home/user/photo.jpg""#);
+/// assert_eq!(serde_json::from_str::<Path>(&json).unwrap(), path);
+/// ```
 #[derive(Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Path {
     parent: Option<Arc<Path>>,
@@ -202,6 +219,45 @@ impl Path {
         arg::quote(self.to_path_buf().into_os_string())
     }
 
+    /// Returns a `file://` URI of the path, with the components percent-encoded
+    /// as specified by RFC 3986, e.g. `file:///home/user/my%20photo.jpg`.
+    /// Bytes that are not valid UTF-8 are percent-encoded as they are.
+    /// Relative paths are returned as percent-encoded relative references, without the scheme.
+    #[cfg(unix)]
+    pub fn to_uri(&self) -> String {
+        use std::os::unix::ffi::OsStrExt;
+        let path = self.to_path_buf();
+        let encoded = percent_encode(path.as_os_str().as_bytes());
+        if self.is_absolute() {
+            format!("file://{}", encoded)
+        } else {
+            encoded
+        }
+    }
+
+    /// Returns a `file://` URI of the path, with the components percent-encoded
+    /// as specified by RFC 3986, e.g. `file:///C:/Users/user/my%20photo.jpg`.
+    /// The server of a UNC path becomes the host of the URI,
+    /// e.g. `\\server\share\file` is converted to `file://server/share/file`.
+    /// Relative paths are returned as percent-encoded relative references, without the scheme.
+    #[cfg(windows)]
+    pub fn to_uri(&self) -> String {
+        let path = self.to_path_buf().to_string_lossy().replace('\\', "/");
+        // Verbatim paths, e.g. `\\?\C:\file` or `\\?\UNC\server\share\file`:
+        let path = match path.strip_prefix("//?/") {
+            Some(p) => match p.strip_prefix("UNC/") {
+                Some(unc) => format!("//{}", unc),
+                None => p.to_owned(),
+            },
+            None => path,
+        };
+        match path.strip_prefix("//") {
+            Some(unc) => format!("file://{}", percent_encode(unc.as_bytes())),
+            None if self.is_absolute() => format!("file:///{}", percent_encode(path.as_bytes())),
+            None => percent_encode(path.as_bytes()),
+        }
+    }
+
     /// Returns a representation suitable for display in the console.
     /// Control characters like newline or linefeed are escaped.
     pub fn display(&self) -> String {
@@ -307,6 +363,48 @@ impl Default for Path {
     }
 }
 
+/// Makes paths created independently of each other share their common parent directories.
+///
+/// Paths obtained by walking the directory tree share parents automatically, because
+/// they are created by joining names to the shared parent. However, paths parsed from strings,
+/// e.g. read from the standard input, get their own copies of all the parent components.
+/// Passing such paths through the interner brings their memory use down to the level of
+/// the paths obtained by walking.
+///
+/// Interning doesn't change the value of a path, so it doesn't affect its ordering,
+/// hashing or serialization. The interner can be shared by many threads.
+#[derive(Default)]
+pub struct PathInterner {
+    dirs: DashSet<Arc<Path>>,
+}
+
+impl PathInterner {
+    /// Returns a path equal to the given one, sharing parents with the paths interned earlier.
+    pub fn intern(&self, path: Path) -> Path {
+        Path {
+            parent: path.parent.map(|p| self.intern_dir(&p)),
+            component: path.component,
+        }
+    }
+
+    fn intern_dir(&self, dir: &Arc<Path>) -> Arc<Path> {
+        if let Some(interned) = self.dirs.get(dir.as_ref()) {
+            return interned.clone();
+        }
+        let interned = Arc::new(Path {
+            parent: dir.parent.as_ref().map(|p| self.intern_dir(p)),
+            component: dir.component.clone(),
+        });
+        // Another thread could have interned the same directory in the meantime
+        if !self.dirs.insert(interned.clone()) {
+            if let Some(existing) = self.dirs.get(dir.as_ref()) {
+                return existing.clone();
+            }
+        }
+        interned
+    }
+}
+
 /// Converts std path Component to a new CString
 fn component_to_c_string(c: &Component<'_>) -> CString {
     os_to_c_str(c.as_os_str())
@@ -329,6 +427,21 @@ where
     }
 }
 
+impl From<Path> for PathBuf {
+    fn from(p: Path) -> Self {
+        p.to_path_buf()
+    }
+}
+
+impl From<&Path> for PathBuf {
+    fn from(p: &Path) -> Self {
+        p.to_path_buf()
+    }
+}
+
+/// Serializes the path as a string in the same format as in the reports.
+/// Characters that can't be represented in UTF-8 and control characters are escaped,
+/// see [`Path::to_escaped_string`].
 impl Serialize for Path {
     fn serialize<S>(&self, serializer: S) -> Result<<S as Serializer>::Ok, <S as Serializer>::Error>
     where
@@ -355,6 +468,7 @@ impl Visitor<'_> for PathVisitor {
     }
 }
 
+/// Deserializes the path from the string written by the [`Serialize`] implementation.
 impl<'de> Deserialize<'de> for Path {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -364,6 +478,29 @@ impl<'de> Deserialize<'de> for Path {
     }
 }
 
+/// Percent-encodes all bytes except the unreserved characters of RFC 3986,
+/// the path separator `/` and the drive separator `:`.
+fn percent_encode(bytes: &[u8]) -> String {
+    let mut result = String::with_capacity(bytes.len());
+    for &b in bytes {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' | b':' => {
+                result.push(b as char)
+            }
+            _ => result.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    result
+}
+
+/// Formats the path the same way as in the text report,
+/// i.e. like [`Path::to_escaped_string`].
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.pad(&self.to_escaped_string())
+    }
+}
+
 impl fmt::Debug for Path {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(f, "{:?}", self.to_path_buf())
@@ -401,6 +538,93 @@ mod test {
     use super::*;
     use serde_test::{assert_ser_tokens, Token};
 
+    #[test]
+    fn interned_paths_share_parents() {
+        let interner = PathInterner::default();
+        let path1 = interner.intern(Path::from("/foo/bar/file1"));
+        let path2 = interner.intern(Path::from("/foo/bar/file2"));
+        let path3 = interner.intern(Path::from("/foo/baz/file3"));
+        assert_eq!(path1, Path::from("/foo/bar/file1"));
+        assert_eq!(path2, Path::from("/foo/bar/file2"));
+        assert_eq!(path3, Path::from("/foo/baz/file3"));
+        assert!(Arc::ptr_eq(
+            path1.parent().unwrap(),
+            path2.parent().unwrap()
+        ));
+        assert!(!Arc::ptr_eq(
+            path1.parent().unwrap(),
+            path3.parent().unwrap()
+        ));
+        assert!(Arc::ptr_eq(
+            path1.parent().unwrap().parent().unwrap(),
+            path3.parent().unwrap().parent().unwrap()
+        ));
+    }
+
+    #[test]
+    fn interning_preserves_path_value() {
+        let interner = PathInterner::default();
+        let path = Path::from("foo/bar");
+        let interned = interner.intern(path.clone());
+        assert_eq!(interned.to_path_buf(), path.to_path_buf());
+        assert_eq!(interned.hash128(), path.hash128());
+        assert_eq!(interned.to_escaped_string(), path.to_escaped_string());
+        let root = interner.intern(Path::from("/"));
+        assert_eq!(root, Path::from("/"));
+    }
+
+    #[test]
+    fn walked_paths_share_parents() {
+        let parent = Path::from("/foo/bar").share();
+        let path1 = parent.join(Path::from("file1"));
+        let path2 = parent.join(Path::from("file2"));
+        assert!(Arc::ptr_eq(
+            path1.parent().unwrap(),
+            path2.parent().unwrap()
+        ));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn to_uri() {
+        use std::os::unix::ffi::OsStrExt;
+        assert_eq!(Path::from("/").to_uri(), "file:///");
+        assert_eq!(
+            Path::from("/home/user/my photo.jpg").to_uri(),
+            "file:///home/user/my%20photo.jpg"
+        );
+        assert_eq!(
+            Path::from("/home/zażółć/ą.txt").to_uri(),
+            "file:///home/za%C5%BC%C3%B3%C5%82%C4%87/%C4%85.txt"
+        );
+        assert_eq!(
+            Path::from("/tmp/a#b?c%d&e'f\"g<h>").to_uri(),
+            "file:///tmp/a%23b%3Fc%25d%26e%27f%22g%3Ch%3E"
+        );
+        assert_eq!(
+            Path::from(std::ffi::OsStr::from_bytes(b"/tmp/\xff")).to_uri(),
+            "file:///tmp/%FF"
+        );
+        assert_eq!(Path::from("foo/bar baz").to_uri(), "foo/bar%20baz");
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn to_uri() {
+        assert_eq!(
+            Path::from("C:\\Users\\user\\my photo.jpg").to_uri(),
+            "file:///C:/Users/user/my%20photo.jpg"
+        );
+        assert_eq!(
+            Path::from("C:\\zażółć\\a#b%c").to_uri(),
+            "file:///C:/za%C5%BC%C3%B3%C5%82%C4%87/a%23b%25c"
+        );
+        assert_eq!(
+            Path::from("\\\\server\\share\\dir\\file").to_uri(),
+            "file://server/share/dir/file"
+        );
+    }
+
     fn test_convert(s: &str) {
         assert_eq!(PathBuf::from(s), Path::from(s).to_path_buf());
     }
@@ -511,4 +735,36 @@ mod test {
     fn serialize() {
         assert_ser_tokens(&Path::from("a \n b"), &[Token::String("a \\n b")])
     }
+
+    #[test]
+    fn display_matches_escaped_string() {
+        let path = Path::from("a/b \t c");
+        assert_eq!(path.to_string(), path.to_escaped_string());
+        assert_eq!(path.to_string(), "a/b \\t c");
+    }
+
+    #[test]
+    fn convert_to_path_buf() {
+        let path = Path::from("/foo/bar");
+        assert_eq!(PathBuf::from(&path), PathBuf::from("/foo/bar"));
+        assert_eq!(PathBuf::from(path), PathBuf::from("/foo/bar"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn serde_roundtrip_of_non_utf8_path() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let name = OsString::from_vec(b"/foo/b\xffar\xc3".to_vec());
+        let path = Path::from(PathBuf::from(name));
+        let json = serde_json::to_string(&path).unwrap();
+        assert_eq!(json, r#""/foo/b\\xFFar\\xC3""#);
+        let path2: Path = serde_json::from_str(&json).unwrap();
+        assert_eq!(path2, path);
+        assert_eq!(path2.to_path_buf(), path.to_path_buf());
+
+        let bytes = bincode::serialize(&path).unwrap();
+        let path3: Path = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(path3, path);
+    }
 }
@@ -0,0 +1,293 @@
+//! Pausing and resuming a running scan on user request.
+//!
+//! On Unix, `SIGUSR1` pauses the scan and `SIGUSR2` or another `SIGUSR1` resumes it.
+//! On Windows, the commands `pause` and `resume` can be written to the named pipe
+//! `\\.\pipe\fclones-<pid>`.
+//!
+//! Pausing stops starting new reads; reads already in progress are completed
+//! and their results are kept.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+
+use crate::log::{LogMessage, LogSink};
+
+/// How often the paused threads check if they can continue
+const POLL_PERIOD: Duration = Duration::from_millis(100);
+
+lazy_static! {
+    /// Pause state of this process, controlled by signals
+    pub static ref PAUSE: PauseControl = PauseControl::new();
+}
+
+/// Tracks whether the work should be paused and for how long it was paused.
+///
+/// Requesting a pause or resume is lock-free, so it can be done from a signal handler.
+/// Workers call [`PauseControl::wait_while_paused`] before starting a new piece of work.
+pub struct PauseControl {
+    requested: AtomicBool,
+    state: Mutex<PauseState>,
+}
+
+#[derive(Default)]
+struct PauseState {
+    /// Set when a worker noticed the pause request, cleared after resuming
+    paused_at: Option<Instant>,
+    /// Total time of all finished pauses
+    paused_time: Duration,
+}
+
+impl PauseControl {
+    pub fn new() -> PauseControl {
+        PauseControl {
+            requested: AtomicBool::new(false),
+            state: Mutex::new(PauseState::default()),
+        }
+    }
+
+    /// Requests pausing the work
+    pub fn pause(&self) {
+        self.requested.store(true, Ordering::SeqCst)
+    }
+
+    /// Requests resuming the work
+    pub fn resume(&self) {
+        self.requested.store(false, Ordering::SeqCst)
+    }
+
+    /// Pauses the work if it is running, resumes it if it is paused
+    pub fn toggle(&self) {
+        self.requested.fetch_xor(true, Ordering::SeqCst);
+    }
+
+    /// Returns true if the work should be paused
+    pub fn is_paused(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Returns the total time the work was paused, including the current pause.
+    /// Subtract it from the wall-clock time to get the time spent on actual work.
+    pub fn paused_time(&self) -> Duration {
+        let state = self.state.lock().unwrap();
+        state.paused_time + state.paused_at.map_or(Duration::ZERO, |t| t.elapsed())
+    }
+
+    /// Blocks the calling thread until the work is resumed.
+    /// Returns immediately if the work is not paused.
+    ///
+    /// The first thread noticing a pause logs a notice and calls `on_pause`,
+    /// which can be used to release resources not needed while paused.
+    /// The first thread noticing a resume logs a notice as well.
+    pub fn wait_while_paused(&self, log: Option<&dyn LogSink>, on_pause: impl FnOnce()) {
+        if !self.is_paused() {
+            return;
+        }
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.paused_at.is_none() {
+                state.paused_at = Some(Instant::now());
+                drop(state);
+                if let Some(log) = log {
+                    log.info(LogMessage::new(Self::paused_notice()));
+                }
+                on_pause();
+            }
+        }
+        while self.is_paused() {
+            thread::sleep(POLL_PERIOD);
+        }
+        let mut state = self.state.lock().unwrap();
+        if let Some(paused_at) = state.paused_at.take() {
+            let elapsed = paused_at.elapsed();
+            state.paused_time += elapsed;
+            drop(state);
+            if let Some(log) = log {
+                let msg = format!("Resumed after {:.1} s", elapsed.as_secs_f64());
+                log.info(LogMessage::new(msg));
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    fn paused_notice() -> String {
+        format!(
+            "Paused. Send SIGUSR1 or SIGUSR2 to process {} to resume.",
+            std::process::id()
+        )
+    }
+
+    #[cfg(windows)]
+    fn paused_notice() -> String {
+        format!(
+            "Paused. Write 'resume' to {} to resume.",
+            windows::pipe_name()
+        )
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn paused_notice() -> String {
+        "Paused.".to_owned()
+    }
+}
+
+impl Default for PauseControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Makes the [`PAUSE`] state controllable by the user: by signals on Unix,
+/// or by a named pipe on Windows.
+#[cfg(unix)]
+pub fn install() -> io::Result<()> {
+    use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+    extern "C" fn handle_signal(signal: libc::c_int) {
+        if signal == libc::SIGUSR1 {
+            PAUSE.toggle()
+        } else {
+            PAUSE.resume()
+        }
+    }
+
+    // Make sure the handler doesn't need to initialize the lazy static:
+    lazy_static::initialize(&PAUSE);
+    let action = SigAction::new(
+        SigHandler::Handler(handle_signal),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    for signal in [Signal::SIGUSR1, Signal::SIGUSR2] {
+        unsafe { sigaction(signal, &action) }.map_err(|_| io::Error::last_os_error())?;
+    }
+    Ok(())
+}
+
+/// Makes the [`PAUSE`] state controllable by the user: by signals on Unix,
+/// or by a named pipe on Windows.
+#[cfg(windows)]
+pub fn install() -> io::Result<()> {
+    thread::Builder::new()
+        .name("pause-control".to_owned())
+        .spawn(windows::serve_pipe)?;
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn install() -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::ffi::OsStr;
+    use std::fs::File;
+    use std::io::Read;
+    use std::iter::once;
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::io::{FromRawHandle, RawHandle};
+    use std::ptr::null_mut;
+
+    use winapi::shared::winerror::ERROR_PIPE_CONNECTED;
+    use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+    use winapi::um::namedpipeapi::{ConnectNamedPipe, CreateNamedPipeW};
+    use winapi::um::winbase::{PIPE_ACCESS_INBOUND, PIPE_READMODE_BYTE, PIPE_TYPE_BYTE, PIPE_WAIT};
+
+    use super::PAUSE;
+
+    pub fn pipe_name() -> String {
+        format!(r"\\.\pipe\fclones-{}", std::process::id())
+    }
+
+    /// Accepts connections to the control pipe, one at a time, and executes
+    /// the commands written to it. Returns if the pipe cannot be created.
+    pub fn serve_pipe() {
+        let name: Vec<u16> = OsStr::new(&pipe_name())
+            .encode_wide()
+            .chain(once(0))
+            .collect();
+        loop {
+            let handle = unsafe {
+                CreateNamedPipeW(
+                    name.as_ptr(),
+                    PIPE_ACCESS_INBOUND,
+                    PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT,
+                    1,
+                    0,
+                    512,
+                    0,
+                    null_mut(),
+                )
+            };
+            if handle == INVALID_HANDLE_VALUE {
+                return;
+            }
+            let connected = unsafe { ConnectNamedPipe(handle, null_mut()) } != 0
+                || std::io::Error::last_os_error().raw_os_error()
+                    == Some(ERROR_PIPE_CONNECTED as i32);
+            if !connected {
+                unsafe { CloseHandle(handle) };
+                continue;
+            }
+            // The file takes ownership of the handle and closes it when dropped
+            let mut pipe = unsafe { File::from_raw_handle(handle as RawHandle) };
+            let mut command = String::new();
+            if pipe.read_to_string(&mut command).is_ok() {
+                match command.trim() {
+                    "pause" => PAUSE.pause(),
+                    "resume" => PAUSE.resume(),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use crossbeam_utils::thread;
+
+    use super::PauseControl;
+
+    #[test]
+    fn wait_returns_immediately_if_not_paused() {
+        let control = PauseControl::new();
+        let called = AtomicBool::new(false);
+        control.wait_while_paused(None, || called.store(true, Ordering::SeqCst));
+        assert!(!called.load(Ordering::SeqCst));
+        assert_eq!(control.paused_time(), Duration::ZERO);
+    }
+
+    #[test]
+    fn toggle_pauses_and_resumes() {
+        let control = PauseControl::new();
+        control.toggle();
+        assert!(control.is_paused());
+        control.toggle();
+        assert!(!control.is_paused());
+    }
+
+    #[test]
+    fn wait_blocks_until_resumed() {
+        let control = PauseControl::new();
+        let released = AtomicBool::new(false);
+        control.pause();
+        thread::scope(|s| {
+            s.spawn(|_| control.wait_while_paused(None, || released.store(true, Ordering::SeqCst)));
+            sleep(Duration::from_millis(200));
+            assert!(released.load(Ordering::SeqCst));
+            control.resume();
+        })
+        .unwrap();
+        assert!(control.paused_time() >= Duration::from_millis(100));
+    }
+}
@@ -1,11 +1,160 @@
 //! Fast, concurrent, lockless progress bars.
 
-use atomic_counter::{AtomicCounter, RelaxedCounter};
-use console::style;
-use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fmt::{Display, Formatter};
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+use atomic_counter::{AtomicCounter, RelaxedCounter};
+
+use crate::file::FileLen;
+use crate::pause::PAUSE;
+use crate::term::{style, HumanDuration, ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+/// Progress of a stage processing a known number of items and bytes,
+/// e.g. hashing files.
+#[derive(Clone, Debug)]
+pub struct ProgressStats {
+    /// Name of the stage
+    pub stage: String,
+    pub items_done: u64,
+    pub items_total: u64,
+    pub bytes_done: FileLen,
+    pub bytes_total: FileLen,
+    /// Time since the start of the stage, excluding the time the scan was paused
+    pub elapsed: Duration,
+    /// Bytes per second processed recently
+    pub throughput: f64,
+    /// Bytes per second processed since the start of the stage
+    pub avg_throughput: f64,
+    /// Estimated time to complete the stage, if it can be estimated
+    pub eta: Option<Duration>,
+}
+
+impl Display for ProgressStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} files, {}/{}, {}/s (avg {}/s), ETA {}",
+            self.items_done,
+            self.items_total,
+            self.bytes_done,
+            self.bytes_total,
+            FileLen(self.throughput as u64),
+            FileLen(self.avg_throughput as u64),
+            self.eta
+                .map_or("unknown".to_owned(), |eta| HumanDuration(eta).to_string())
+        )
+    }
+}
+
+/// Receives the progress of a stage periodically
+pub type ProgressListener = Arc<dyn Fn(&ProgressStats) + Send + Sync>;
+
+/// Computes [`ProgressStats`] from the subsequent counter readings of a stage.
+struct StatsTracker {
+    stage: String,
+    items_total: u64,
+    bytes_total: u64,
+    start: Instant,
+    paused_time_at_start: Duration,
+    /// Recent readings of the byte counter, used to compute instantaneous throughput
+    samples: VecDeque<(Duration, u64)>,
+    /// Called at every update
+    listener: Option<ProgressListener>,
+    /// Called at most once per `LOG_PERIOD`
+    logger: Option<ProgressListener>,
+    last_log: Instant,
+}
+
+impl StatsTracker {
+    /// Time window for computing the instantaneous throughput
+    const THROUGHPUT_WINDOW: Duration = Duration::from_secs(2);
+    /// How often to log the progress if the progress bar is not visible
+    const LOG_PERIOD: Duration = Duration::from_secs(10);
+
+    fn new(stage: &str, items_total: u64, bytes_total: u64) -> StatsTracker {
+        let now = Instant::now();
+        StatsTracker {
+            stage: stage.to_owned(),
+            items_total,
+            bytes_total,
+            start: now,
+            paused_time_at_start: PAUSE.paused_time(),
+            samples: VecDeque::new(),
+            listener: None,
+            logger: None,
+            last_log: now,
+        }
+    }
+
+    /// Returns the time since start, excluding the time spent in pause
+    fn elapsed(&self) -> Duration {
+        let paused = PAUSE
+            .paused_time()
+            .saturating_sub(self.paused_time_at_start);
+        self.start.elapsed().saturating_sub(paused)
+    }
+
+    fn update(&mut self, items_done: u64, bytes_done: u64) -> ProgressStats {
+        let elapsed = self.elapsed();
+        self.samples.push_back((elapsed, bytes_done));
+        while let Some(&(t, _)) = self.samples.front() {
+            if elapsed.saturating_sub(t) <= Self::THROUGHPUT_WINDOW {
+                break;
+            }
+            self.samples.pop_front();
+        }
+        let (window_start, window_start_bytes) = self.samples.front().cloned().unwrap();
+        let recent_throughput = throughput(
+            bytes_done - window_start_bytes,
+            elapsed.saturating_sub(window_start),
+        )
+        .unwrap_or(0.0);
+        let avg_throughput = throughput(bytes_done, elapsed);
+        let eta = avg_throughput
+            .filter(|&t| t > 0.0)
+            .map(|t| (self.bytes_total.saturating_sub(bytes_done)) as f64 / t)
+            .map(Duration::from_secs_f64);
+
+        ProgressStats {
+            stage: self.stage.clone(),
+            items_done,
+            items_total: self.items_total,
+            bytes_done: FileLen(bytes_done),
+            bytes_total: FileLen(self.bytes_total),
+            elapsed,
+            throughput: recent_throughput,
+            avg_throughput: avg_throughput.unwrap_or(0.0),
+            eta,
+        }
+    }
+
+    /// Passes the stats to the listener and, if it is time, to the logger
+    fn notify(&mut self, stats: &ProgressStats) {
+        if let Some(listener) = &self.listener {
+            listener(stats);
+        }
+        if let Some(logger) = &self.logger {
+            if self.last_log.elapsed() >= Self::LOG_PERIOD {
+                self.last_log = Instant::now();
+                logger(stats);
+            }
+        }
+    }
+}
+
+/// Returns the number of bytes per second, or `None` if the time is zero
+fn throughput(bytes: u64, time: Duration) -> Option<f64> {
+    let secs = time.as_secs_f64();
+    if secs > 0.0 {
+        Some(bytes as f64 / secs)
+    } else {
+        None
+    }
+}
 
 /// A wrapper over `indicatif::ProgressBar` that makes updating its progress lockless.
 /// Unfortunately `indicatif::ProgressBar` wraps state in a `Mutex`, so updates are slow
@@ -15,6 +164,11 @@ use std::time::Duration;
 /// `ProgressBar` at a low rate.
 pub struct FastProgressBar {
     counter: Arc<RelaxedCounter>,
+    /// Number of bytes processed, tracked only by the stage progress bars
+    bytes: Arc<RelaxedCounter>,
+    /// True if the position of the underlying `ProgressBar` is given in bytes
+    /// and the number of items is displayed in the message
+    tracks_stats: bool,
     progress_bar: Arc<ProgressBar>,
 }
 
@@ -32,22 +186,61 @@ impl FastProgressBar {
     /// The thread periodically copies the `FastProgressBar` position into the wrapped
     /// `ProgressBar` instance.
     pub fn wrap(progress_bar: ProgressBar) -> FastProgressBar {
+        Self::wrap_with_stats(progress_bar, None)
+    }
+
+    /// Like [`FastProgressBar::wrap`], but if `tracker` is given, the background thread
+    /// additionally computes the stats of the stage, displays them in the message
+    /// of the progress bar and passes them to the listeners of the tracker.
+    fn wrap_with_stats(
+        progress_bar: ProgressBar,
+        tracker: Option<StatsTracker>,
+    ) -> FastProgressBar {
         let pb = Arc::new(progress_bar);
         let pb2 = pb.clone();
         let counter = Arc::new(RelaxedCounter::new(0));
         let counter2 = counter.clone();
+        let bytes = Arc::new(RelaxedCounter::new(0));
+        let bytes2 = bytes.clone();
+        let tracks_stats = tracker.is_some();
+        let mut tracker = tracker;
         thread::spawn(move || {
             while Arc::strong_count(&counter2) > 1 && !pb2.is_finished() {
-                pb2.set_position(counter2.get() as u64);
+                match &mut tracker {
+                    Some(tracker) => {
+                        let stats = tracker.update(counter2.get() as u64, bytes2.get() as u64);
+                        pb2.set_position(stats.bytes_done.0);
+                        pb2.set_message(&Self::stats_message(&stats));
+                        tracker.notify(&stats);
+                    }
+                    None => pb2.set_position(counter2.get() as u64),
+                }
                 thread::sleep(Duration::from_millis(Self::REFRESH_PERIOD_MS));
             }
         });
         FastProgressBar {
             counter,
+            bytes,
+            tracks_stats,
             progress_bar: pb,
         }
     }
 
+    /// Formats the second line of the stage progress bar
+    fn stats_message(stats: &ProgressStats) -> String {
+        format!(
+            "{:28} {:>10}/{} files, {}/s (avg {}/s), ETA {}",
+            "",
+            stats.items_done,
+            stats.items_total,
+            FileLen(stats.throughput as u64),
+            FileLen(stats.avg_throughput as u64),
+            stats
+                .eta
+                .map_or("unknown".to_owned(), |eta| HumanDuration(eta).to_string())
+        )
+    }
+
     /// Generate spinner animation strings.
     /// The spinner moves to the next string from the returned vector with every tick.
     /// The spinner is rendered as a SPACESHIP that bounces right and left from the
@@ -115,6 +308,50 @@ impl FastProgressBar {
         FastProgressBar::wrap(inner)
     }
 
+    /// Creates a new preconfigured progress bar of a stage processing the given number
+    /// of items and bytes. Displays progress in bytes, and in the second line displays
+    /// the number of processed items, the throughput and the estimated time to complete.
+    /// The `listener` gets the same information periodically.
+    pub fn new_stage_progress_bar(
+        msg: &str,
+        items_total: u64,
+        bytes_total: FileLen,
+        listener: Option<ProgressListener>,
+    ) -> FastProgressBar {
+        let inner = ProgressBar::new(bytes_total.0);
+        let template = style("{prefix:28}").cyan().bold().for_stderr().to_string()
+            + &"[{bar:WIDTH}] {bytes:>10}/{total_bytes}\n{msg}"
+                .replace("WIDTH", Self::WIDTH.to_string().as_str());
+
+        inner.set_style(
+            ProgressStyle::default_bar()
+                .template(template.as_str())
+                .progress_chars(Self::PROGRESS_CHARS),
+        );
+        inner.set_prefix(msg);
+        let mut tracker = StatsTracker::new(msg, items_total, bytes_total.0);
+        tracker.listener = listener;
+        Self::wrap_with_stats(inner, Some(tracker))
+    }
+
+    /// Creates a new invisible progress bar of a stage processing the given number
+    /// of items and bytes. The `listener` gets the stats of the stage at every update,
+    /// and the `logger` gets them at most every few seconds.
+    pub fn new_hidden_stage_progress_bar(
+        msg: &str,
+        items_total: u64,
+        bytes_total: FileLen,
+        listener: Option<ProgressListener>,
+        logger: Option<ProgressListener>,
+    ) -> FastProgressBar {
+        let inner = ProgressBar::new(bytes_total.0);
+        inner.set_draw_target(ProgressDrawTarget::hidden());
+        let mut tracker = StatsTracker::new(msg, items_total, bytes_total.0);
+        tracker.listener = listener;
+        tracker.logger = logger;
+        Self::wrap_with_stats(inner, Some(tracker))
+    }
+
     /// Creates a new invisible progress bar.
     /// This is useful when you need to disable progress bar, but you need to pass an instance
     /// of a `ProgressBar` to something that expects it.
@@ -125,8 +362,12 @@ impl FastProgressBar {
     }
 
     fn update_progress(&self) {
-        let value = self.counter.get() as u64;
-        self.progress_bar.set_position(value);
+        let value = if self.tracks_stats {
+            self.bytes.get()
+        } else {
+            self.counter.get()
+        };
+        self.progress_bar.set_position(value as u64);
     }
 
     pub fn set_draw_target(&self, target: ProgressDrawTarget) {
@@ -149,6 +390,12 @@ impl FastProgressBar {
         self.counter.add(delta);
     }
 
+    /// Adds the number of processed bytes.
+    /// Displayed only by the stage progress bars.
+    pub fn inc_bytes(&self, delta: usize) {
+        self.bytes.add(delta);
+    }
+
     pub fn position(&self) -> usize {
         self.counter.get()
     }
@@ -210,4 +457,20 @@ mod test {
         assert_eq!(pb.position(), 100000);
         assert_eq!(pb.last_displayed_position(), 100000);
     }
+
+    #[test]
+    fn stats_tracker_estimates_remaining_time() {
+        let mut tracker = StatsTracker::new("test", 10, 1000);
+        let stats = tracker.update(0, 0);
+        assert_eq!(stats.eta, None);
+        thread::sleep(Duration::from_millis(100));
+        let stats = tracker.update(5, 500);
+        assert_eq!(stats.items_done, 5);
+        assert_eq!(stats.bytes_done, FileLen(500));
+        assert!(stats.avg_throughput > 0.0);
+        assert!(stats.throughput > 0.0);
+        // Half of the work took at least 100 ms, so the other half should take about the same
+        let eta = stats.eta.unwrap();
+        assert!(eta >= Duration::from_millis(50) && eta <= stats.elapsed * 2);
+    }
 }
@@ -5,35 +5,228 @@ use std::cell::Cell;
 use std::cmp::min;
 use std::io;
 use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::sync::Arc;
 
-use chrono::{DateTime, FixedOffset};
-use console::style;
+use chrono::{DateTime, FixedOffset, Local};
 use fallible_iterator::FallibleIterator;
+use flate2::read::MultiGzDecoder;
 use itertools::Itertools;
 use lazy_static::lazy_static;
 use regex::Regex;
-use serde::{Deserialize, Serialize};
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize, Serializer};
 
 use crate::arg;
 use crate::arg::Arg;
 use crate::config::OutputFormat;
-use crate::file::{FileHash, FileLen};
-use crate::group::FileGroup;
+use crate::file::{AsPath, FileHash, FileLen, FileLenRange, FileMetadata, HashEncoding};
+use crate::group::{is_hardlink_only, root_index, FileGroup, FileGroupFilter, FileIds, GroupDiff};
+use crate::metrics::RunMetrics;
 use crate::path::Path;
+use crate::selector::ExtensionFilter;
+use crate::term::style;
 use crate::util::IteratorWrapper;
-use crate::TIMESTAMP_FMT;
+use crate::{format_timestamp, TIMESTAMP_FMT};
+
+/// Version of the schema of the JSON report.
+/// Must be incremented whenever the schema changes in a backward-incompatible way.
+pub const REPORT_FORMAT_VERSION: u32 = 3;
+
+/// Returns the version of the schema of the JSON report together with the version of fclones,
+/// as a JSON object, e.g. `{"format_version":3,"fclones_version":"0.20.1"}`.
+pub fn report_format_version() -> serde_json::Value {
+    serde_json::json!({
+        "format_version": REPORT_FORMAT_VERSION,
+        "fclones_version": env!("CARGO_PKG_VERSION"),
+    })
+}
 
 /// Describes how many redundant files were found, in how many groups,
 /// how much space can be reclaimed, etc.
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
 pub struct FileStats {
     pub group_count: usize,
     pub total_file_count: usize,
     pub total_file_size: FileLen,
     pub redundant_file_count: usize,
     pub redundant_file_size: FileLen,
+    /// Disk space allocated for the redundant files, in whole file system blocks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redundant_allocated_size: Option<FileLen>,
     pub missing_file_count: usize,
     pub missing_file_size: FileLen,
+    /// Total size of all scanned files, including the unique ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scanned_file_size: Option<FileLen>,
+    /// Numbers and sizes of redundant files in ranges of file sizes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size_histogram: Option<SizeHistogram>,
+    /// Number of inodes that would be freed by replacing the duplicates with hard links
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub freed_inode_count: Option<usize>,
+    /// Files that couldn't be read, so they are missing from the groups
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub read_error_files: Vec<Path>,
+    /// Number of files skipped because of their extensions, if extensions were filtered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub excluded_by_extension: Option<usize>,
+    /// Paths skipped because of insufficient permissions, if requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permission_errors: Option<Vec<Path>>,
+}
+
+impl FileStats {
+    /// Adds the files of the group to the totals.
+    /// Files in read-only btrfs snapshots are not counted as redundant,
+    /// see [`FileGroup::reclaimable_count`].
+    /// The allocated size of redundant files is not updated.
+    pub fn add_group<F: AsPath>(&mut self, group: &FileGroup<F>, filter: &FileGroupFilter) {
+        let redundant_count = group.reclaimable_count(filter);
+        let missing_count = group.missing_count(filter);
+        self.group_count += 1;
+        self.total_file_count += group.file_count();
+        self.total_file_size += group.total_size();
+        self.redundant_file_count += redundant_count;
+        self.redundant_file_size += group.file_len * redundant_count as u64;
+        self.missing_file_count += missing_count;
+        self.missing_file_size += group.file_len * missing_count as u64;
+        if let Some(histogram) = &mut self.size_histogram {
+            histogram.add(group.file_len, redundant_count);
+        }
+        if let Some(freed_inodes) = &mut self.freed_inode_count {
+            *freed_inodes += group.freed_inode_count(filter);
+        }
+    }
+
+    /// Adds the files of a group consisting only of hard links to the same file.
+    /// Such files are not redundant, because removing them doesn't reclaim any space.
+    pub fn add_hardlink_group<F>(&mut self, group: &FileGroup<F>) {
+        self.group_count += 1;
+        self.total_file_count += group.file_count();
+        self.total_file_size += group.total_size();
+    }
+
+    /// Formats the disk space allocated for redundant files and its difference from their
+    /// logical size, to be appended to the redundant files summary.
+    /// Returns an empty string if the allocated size is unknown.
+    fn allocated_size_summary(&self) -> String {
+        match self.redundant_allocated_size {
+            Some(allocated) => {
+                let overhead = FileLen(allocated.0.saturating_sub(self.redundant_file_size.0));
+                format!(
+                    "; allocated: {} B ({}), +{} B ({})",
+                    allocated.0, allocated, overhead.0, overhead
+                )
+            }
+            None => String::new(),
+        }
+    }
+
+    /// Returns the summaries of the total scanned size and of the part of it taken by
+    /// redundant files, or `None` if the scanned size is unknown.
+    fn scanned_size_summary(&self) -> Option<(String, String)> {
+        let scanned = self.scanned_file_size?;
+        let percent = if scanned.0 == 0 {
+            0.0
+        } else {
+            self.redundant_file_size.as_f64() * 100.0 / scanned.as_f64()
+        };
+        Some((
+            format!("{} B ({})", scanned.0, scanned),
+            format!(
+                "{} B ({}), {:.1}% of total",
+                self.redundant_file_size.0, self.redundant_file_size, percent
+            ),
+        ))
+    }
+}
+
+/// Redundant files with sizes in a range
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SizeHistogramBucket {
+    /// Inclusive upper bound of the sizes of files in this bucket;
+    /// `None` in the last bucket, which has no upper bound
+    pub max_size: Option<FileLen>,
+    pub redundant_file_count: usize,
+    pub redundant_file_size: FileLen,
+}
+
+/// Distribution of the sizes of redundant files.
+/// Tells whether the reclaimable space is taken by many small files or a few large ones.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SizeHistogram {
+    pub buckets: Vec<SizeHistogramBucket>,
+}
+
+impl SizeHistogram {
+    /// Default upper bounds of the buckets: 4 KiB, 64 KiB, 1 MiB, 16 MiB and 256 MiB
+    pub const DEFAULT_BOUNDS: [FileLen; 5] = [
+        FileLen(4 << 10),
+        FileLen(64 << 10),
+        FileLen(1 << 20),
+        FileLen(16 << 20),
+        FileLen(256 << 20),
+    ];
+
+    /// Creates an empty histogram with buckets bounded by the given sizes.
+    /// An additional bucket for files larger than all of the bounds is added at the end.
+    pub fn new(bounds: &[FileLen]) -> SizeHistogram {
+        let mut bounds = bounds.to_vec();
+        bounds.sort();
+        bounds.dedup();
+        let buckets = bounds
+            .into_iter()
+            .map(Some)
+            .chain(std::iter::once(None))
+            .map(|max_size| SizeHistogramBucket {
+                max_size,
+                ..Default::default()
+            })
+            .collect();
+        SizeHistogram { buckets }
+    }
+
+    /// Records `count` redundant files of length `file_len`
+    pub fn add(&mut self, file_len: FileLen, count: usize) {
+        if count == 0 {
+            return;
+        }
+        let bucket = self
+            .buckets
+            .iter_mut()
+            .find(|b| b.max_size.is_none_or(|max| file_len <= max));
+        if let Some(bucket) = bucket {
+            bucket.redundant_file_count += count;
+            bucket.redundant_file_size += file_len * count as u64;
+        }
+    }
+
+    /// Formats the histogram as a table with aligned columns, one line per bucket
+    fn table(&self) -> Vec<String> {
+        let last_bound = self.buckets.iter().rev().find_map(|b| b.max_size);
+        let rows: Vec<_> = self
+            .buckets
+            .iter()
+            .map(|b| {
+                let range = match (b.max_size, last_bound) {
+                    (Some(max), _) => format!("<= {}", max),
+                    (None, Some(last)) => format!("> {}", last),
+                    (None, None) => "any size".to_owned(),
+                };
+                (
+                    range,
+                    b.redundant_file_count.to_string(),
+                    b.redundant_file_size.to_string(),
+                )
+            })
+            .collect();
+        let w0 = rows.iter().map(|r| r.0.len()).max().unwrap_or(0);
+        let w1 = rows.iter().map(|r| r.1.len()).max().unwrap_or(0);
+        let w2 = rows.iter().map(|r| r.2.len()).max().unwrap_or(0);
+        rows.into_iter()
+            .map(|(range, count, size)| format!("  {range:<w0$}  {count:>w1$} files  {size:>w2$}"))
+            .collect()
+    }
 }
 
 /// Data in the header of the whole report.
@@ -50,6 +243,25 @@ pub struct ReportHeader {
     /// Information on the number of duplicate files reported.
     /// This is optional to allow streaming the report out before finding all files in the future.
     pub stats: Option<FileStats>,
+    /// The range of sizes of the files searched, if restricted by the user
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size_filter: Option<FileLenRange>,
+    /// The extensions of the files searched, if restricted by the user
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extension_filter: Option<ExtensionFilter>,
+    /// Read-only baseline directories; the files under them are never removed or replaced.
+    /// The JSON formats flag the baseline files of each group.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub baseline: Vec<Path>,
+    /// The directory arguments counted by `--min-roots` or `--all-roots`, in order.
+    /// The JSON formats record the index of the root of each file.
+    /// Empty if the groups were not restricted by the number of roots.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roots: Vec<Path>,
+    /// Timing and counters of the grouping stages, if requested.
+    /// Written only in the JSON format.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<RunMetrics>,
 }
 
 /// A helper struct that allows to serialize the report with serde.
@@ -76,11 +288,79 @@ struct DeserializedReport {
 pub struct ReportWriter<W: Write> {
     out: W,
     color: bool,
+    print0: bool,
+    strict_iso_8601: bool,
+    hard_links: bool,
+    file_ids: Option<Arc<FileIds>>,
+    hash_encoding: HashEncoding,
+    unicode: bool,
+    terminal_width: Option<usize>,
 }
 
 impl<W: Write> ReportWriter<W> {
     pub fn new(out: W, color: bool) -> ReportWriter<W> {
-        ReportWriter { out, color }
+        ReportWriter {
+            out,
+            color,
+            print0: false,
+            strict_iso_8601: false,
+            hard_links: false,
+            file_ids: None,
+            hash_encoding: HashEncoding::Hex,
+            unicode: false,
+            terminal_width: None,
+        }
+    }
+
+    /// Makes the `machine` format terminate lines with NUL characters instead of newlines
+    /// and print paths without escaping.
+    pub fn print0(mut self, print0: bool) -> ReportWriter<W> {
+        self.print0 = print0;
+        self
+    }
+
+    /// Makes the text and html formats write the timestamp in UTC, as specified by RFC 3339.
+    /// The JSON format always uses RFC 3339.
+    pub fn strict_iso_8601(mut self, strict_iso_8601: bool) -> ReportWriter<W> {
+        self.strict_iso_8601 = strict_iso_8601;
+        self
+    }
+
+    /// Makes the text and JSON formats mark the groups consisting only of hard links
+    /// to the same file. The JSON format additionally lists the index of the distinct
+    /// file each member is linked to, see [`FileGroup::inode_groups`].
+    /// Requires reading the identifiers of all reported files.
+    pub fn hard_links(mut self, hard_links: bool) -> ReportWriter<W> {
+        self.hard_links = hard_links;
+        self
+    }
+
+    /// Sets the file identifiers read earlier, so the hard links are checked
+    /// without reading the identifiers again.
+    pub(crate) fn file_ids(mut self, file_ids: Option<Arc<FileIds>>) -> ReportWriter<W> {
+        self.file_ids = file_ids;
+        self
+    }
+
+    /// Sets the encoding of the hashes of the groups. Hex is used by default.
+    pub fn hash_encoding(mut self, hash_encoding: HashEncoding) -> ReportWriter<W> {
+        self.hash_encoding = hash_encoding;
+        self
+    }
+
+    /// Makes the `report` format draw the group frames with unicode box-drawing characters
+    /// instead of ASCII characters.
+    pub fn unicode(mut self, unicode: bool) -> ReportWriter<W> {
+        self.unicode = unicode;
+        self
+    }
+
+    /// Sets the width of the terminal the report is written to.
+    /// If set, the summaries of the largest groups are shortened to fit in a single line,
+    /// see [`FileGroup::to_report_line`].
+    pub fn terminal_width(mut self, width: Option<usize>) -> ReportWriter<W> {
+        self.terminal_width = width;
+        self
     }
 
     fn write_header_line(&mut self, line: &str) -> io::Result<()> {
@@ -106,7 +386,7 @@ impl<W: Write> ReportWriter<W> {
     /// # Command: fclones group .
     /// # Base dir: /home/pkolaczk/Projekty/fclones
     /// # Total: 13589 B (13.6 KB) in 31 files in 14 groups
-    /// # Redundant: 6819 B (6.8 KB) in 17 files
+    /// # Redundant: 6819 B (6.8 KB) in 17 files; allocated: 73728 B (73.7 KB), +66909 B (66.9 KB)
     /// # Missing: 0 B (0 B) in 0 files
     /// 49165422e775f631cca3b09124f8ee89, 6274 B (6.3 KB) * 2:
     ///     /home/pkolaczk/Projekty/fclones/src/semaphore.rs
@@ -124,11 +404,203 @@ impl<W: Write> ReportWriter<W> {
         I: IntoIterator<Item = G>,
         G: Borrow<FileGroup<Path>>,
     {
+        self.write_text_header(header)?;
+        for g in groups {
+            let g = g.borrow();
+            let group_header = format!(
+                "{}, {} B ({}) * {}:{}",
+                g.file_hash.encode(self.hash_encoding),
+                g.file_len.0,
+                g.file_len,
+                g.files.len(),
+                if self.hard_links
+                    && is_hardlink_only(&g.inode_groups_with(self.file_ids.as_deref()))
+                {
+                    " hard links only"
+                } else {
+                    ""
+                }
+            );
+            let group_header = style(group_header).yellow();
+            writeln!(self.out, "{}", group_header.force_styling(self.color),)?;
+            for f in g.files.iter() {
+                writeln!(self.out, "    {}", f.to_escaped_string())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the number of bytes taken by redundant files in each directory,
+    /// in the text format. The header is the same as in the [`ReportWriter::write_as_text`]
+    /// format. Each following line holds the savings in bytes, the savings in human-readable
+    /// units and the directory, in the order given by `savings`.
+    ///
+    /// # Example
+    /// The savings of two redundant files, `/photos/2020/a.jpg` and `/photos/2021/b.jpg`,
+    /// are counted in their directories and in all ancestors of these directories:
+    /// ```text
+    /// # Report by fclones 0.20.1
+    /// ...
+    /// # Missing: 0 B (0 B) in 0 files
+    /// 7340032 B (7.3 MB): /
+    /// 7340032 B (7.3 MB): /photos
+    /// 5242880 B (5.2 MB): /photos/2020
+    /// 2097152 B (2.1 MB): /photos/2021
+    /// ```
+    pub fn write_savings_by_directory<I>(
+        &mut self,
+        header: &ReportHeader,
+        savings: I,
+    ) -> io::Result<()>
+    where
+        I: IntoIterator<Item = (Path, FileLen)>,
+    {
+        self.write_text_header(header)?;
+        for (dir, len) in savings {
+            let size = style(format!("{} B ({}):", len.0, len)).yellow();
+            writeln!(
+                self.out,
+                "{} {}",
+                size.force_styling(self.color),
+                dir.to_escaped_string()
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes the changes of the groups since an earlier run.
+    /// The header is the same as in the [`ReportWriter::write_as_text`] format.
+    ///
+    /// Each changed group starts with a line with the hash, the file size, the number
+    /// of files in the newer group and the kind of the change returned by [`GroupDiff::kind`].
+    /// Added members are prefixed with `+`, removed members with `-`.
+    /// Groups without changes are skipped.
+    ///
+    /// # Example output
+    /// ```text
+    /// # Report by fclones 0.20.1
+    /// ...
+    /// # Missing: 0 B (0 B) in 0 files
+    /// 49165422e775f631cca3b09124f8ee89, 6274 B (6.3 KB) * 3, grown:
+    ///       /home/user/src/semaphore.rs
+    ///       /home/user/backup/semaphore.rs
+    ///     + /home/user/backup2/semaphore.rs
+    /// d0521f268e17c28b10c48e5f5de48f21, 41 B (41 B) * 0, removed:
+    ///     - /home/user/a.txt
+    ///     - /home/user/b.txt
+    /// ```
+    pub fn write_group_diffs<I, G>(&mut self, header: &ReportHeader, diffs: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = (G, GroupDiff)>,
+        G: Borrow<FileGroup<Path>>,
+    {
+        self.write_text_header(header)?;
+        for (g, diff) in diffs {
+            if diff.is_unchanged() {
+                continue;
+            }
+            let g = g.borrow();
+            let group_header = format!(
+                "{}, {} B ({}) * {}, {}:",
+                g.file_hash.encode(self.hash_encoding),
+                g.file_len.0,
+                g.file_len,
+                diff.unchanged_members.len() + diff.new_members.len(),
+                diff.kind()
+            );
+            let group_header = style(group_header).yellow();
+            writeln!(self.out, "{}", group_header.force_styling(self.color))?;
+            for f in diff.unchanged_members.iter() {
+                writeln!(self.out, "      {}", f.to_escaped_string())?;
+            }
+            for f in diff.new_members.iter() {
+                let line = style(format!("    + {}", f.to_escaped_string())).green();
+                writeln!(self.out, "{}", line.force_styling(self.color))?;
+            }
+            for f in diff.removed_members.iter() {
+                let line = style(format!("    - {}", f.to_escaped_string())).red();
+                writeln!(self.out, "{}", line.force_styling(self.color))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the given directories, one per line.
+    ///
+    /// # Example output
+    /// ```text
+    /// # Report by fclones 0.20.1
+    /// ...
+    /// # Missing: 0 B (0 B) in 0 files
+    /// /photos/2020/backup/raw
+    /// /photos/2020/backup
+    /// ```
+    pub fn write_empty_dirs<I>(&mut self, header: &ReportHeader, dirs: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = Path>,
+    {
+        self.write_text_header(header)?;
+        for dir in dirs {
+            writeln!(self.out, "{}", dir.to_escaped_string())?;
+        }
+        Ok(())
+    }
+
+    /// Writes the hash, the file size, the number of files and the space wasted
+    /// by the redundant files of each group, in the order given.
+    /// If the terminal width is set, each group is summarized by
+    /// [`FileGroup::to_report_line`] instead.
+    ///
+    /// # Example output
+    /// ```text
+    /// # Report by fclones 0.20.1
+    /// ...
+    /// # Missing: 0 B (0 B) in 0 files
+    /// d0521f268e17c28b10c48e5f5de48f21, 5242880 B (5.2 MB) * 3, 10485760 B (10.5 MB) wasted
+    /// 49165422e775f631cca3b09124f8ee89, 6274 B (6.3 KB) * 2, 6274 B (6.3 KB) wasted
+    /// ```
+    pub fn write_largest_groups<I, G>(&mut self, header: &ReportHeader, groups: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = G>,
+        G: Borrow<FileGroup<Path>>,
+    {
+        self.write_text_header(header)?;
+        for g in groups {
+            let g = g.borrow();
+            let line = match self.terminal_width {
+                Some(width) => g.to_report_line(width),
+                None => format!(
+                    "{}, {} B ({}) * {}, {} B ({}) wasted",
+                    g.file_hash.encode(self.hash_encoding),
+                    g.file_len.0,
+                    g.file_len,
+                    g.files.len(),
+                    g.wasted_bytes().0,
+                    g.wasted_bytes()
+                ),
+            };
+            writeln!(
+                self.out,
+                "{}",
+                style(line).yellow().force_styling(self.color)
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes the header lines of the text format
+    fn write_text_header(&mut self, header: &ReportHeader) -> io::Result<()> {
+        self.write_titled_text_header("Report", header)
+    }
+
+    /// Writes the header like [`ReportWriter::write_text_header`], but with a different title
+    /// in the first line, so the report is not mistaken for one that can be read back
+    fn write_titled_text_header(&mut self, title: &str, header: &ReportHeader) -> io::Result<()> {
         let command = arg::join(&header.command);
-        self.write_header_line(&format!("Report by fclones {}", header.version))?;
+        self.write_header_line(&format!("{} by fclones {}", title, header.version))?;
         self.write_header_line(&format!(
             "Timestamp: {}",
-            header.timestamp.format(TIMESTAMP_FMT)
+            format_timestamp(&header.timestamp, self.strict_iso_8601)
         ))?;
         self.write_header_line(&format!("Command: {}", command))?;
         self.write_header_line(&format!(
@@ -144,29 +616,60 @@ impl<W: Write> ReportWriter<W> {
                 stats.group_count
             ))?;
             self.write_header_line(&format!(
-                "Redundant: {} B ({}) in {} files",
-                stats.redundant_file_size.0, stats.redundant_file_size, stats.redundant_file_count
+                "Redundant: {} B ({}) in {} files{}",
+                stats.redundant_file_size.0,
+                stats.redundant_file_size,
+                stats.redundant_file_count,
+                stats.allocated_size_summary()
             ))?;
             self.write_header_line(&format!(
                 "Missing: {} B ({}) in {} files",
                 stats.missing_file_size.0, stats.missing_file_size, stats.missing_file_count
             ))?;
-        }
-
-        for g in groups {
-            let g = g.borrow();
-            let group_header = format!(
-                "{}, {} B ({}) * {}:",
-                g.file_hash,
-                g.file_len.0,
-                g.file_len,
-                g.files.len()
-            );
-            let group_header = style(group_header).yellow();
-            writeln!(self.out, "{}", group_header.force_styling(self.color),)?;
-            for f in g.files.iter() {
-                writeln!(self.out, "    {}", f.to_escaped_string())?;
+            if let Some((scanned, wasted)) = stats.scanned_size_summary() {
+                self.write_header_line(&format!("Total scanned: {}", scanned))?;
+                self.write_header_line(&format!("Duplicate wasted: {}", wasted))?;
             }
+            if let Some(freed_inodes) = stats.freed_inode_count {
+                self.write_header_line(&format!("Inodes freed: {}", freed_inodes))?;
+            }
+            if let Some(excluded) = stats.excluded_by_extension {
+                self.write_header_line(&format!("Excluded by extension: {} files", excluded))?;
+            }
+            if let Some(skipped) = &stats.permission_errors {
+                self.write_header_line(&format!(
+                    "Skipped (no read permission): {} entries",
+                    skipped.len()
+                ))?;
+            }
+            if let Some(histogram) = &stats.size_histogram {
+                self.write_header_line("Redundant by size:")?;
+                for line in histogram.table() {
+                    self.write_header_line(&line)?;
+                }
+            }
+            if !stats.read_error_files.is_empty() {
+                self.write_header_line(&format!(
+                    "Read errors: {} files could not be read and are missing from the groups:",
+                    stats.read_error_files.len()
+                ))?;
+                for path in stats.read_error_files.iter() {
+                    self.write_header_line(&format!("  {}", path.to_escaped_string()))?;
+                }
+            }
+        }
+        if let Some(size_filter) = &header.size_filter {
+            self.write_header_line(&format!("Size filter: {}", size_filter))?;
+        }
+        if let Some(extension_filter) = &header.extension_filter {
+            self.write_header_line(&format!("Extension filter: {}", extension_filter))?;
+        }
+        if !header.baseline.is_empty() {
+            let mut dirs = header.baseline.iter().map(|p| p.to_escaped_string());
+            self.write_header_line(&format!("Baseline (read-only): {}", dirs.join(", ")))?;
+        }
+        if !header.roots.is_empty() {
+            self.write_header_line(&format!("Roots: {}", join_paths(&header.roots)))?;
         }
         Ok(())
     }
@@ -214,7 +717,7 @@ impl<W: Write> ReportWriter<W> {
             let g = g.borrow();
             let mut record = csv::StringRecord::new();
             record.push_field(g.file_len.0.to_string().as_str());
-            record.push_field(g.file_hash.to_string().as_str());
+            record.push_field(g.file_hash.encode(self.hash_encoding).as_str());
             record.push_field(g.files.len().to_string().as_str());
             for f in g.files.iter() {
                 record.push_field(f.to_escaped_string().as_ref());
@@ -224,6 +727,39 @@ impl<W: Write> ReportWriter<W> {
         wtr.flush()
     }
 
+    /// Writes results as tab-separated values.
+    ///
+    /// The first line is a header with the column names.
+    /// Each file of each group is written in a separate line with the following columns:
+    /// - file size in bytes
+    /// - file hash
+    /// - number of files in the group
+    /// - file path
+    ///
+    /// Tabs, newlines and backslashes in paths are escaped as `\t`, `\n` and `\\`,
+    /// so each line has exactly four columns.
+    pub fn write_as_tsv<I, G>(&mut self, _header: &ReportHeader, groups: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = G>,
+        G: Borrow<FileGroup<Path>>,
+    {
+        writeln!(self.out, "size\thash\tcount\tpath")?;
+        for g in groups {
+            let g = g.borrow();
+            for f in g.files.iter() {
+                writeln!(
+                    self.out,
+                    "{}\t{}\t{}\t{}",
+                    g.file_len.0,
+                    g.file_hash.encode(self.hash_encoding),
+                    g.files.len(),
+                    f.to_escaped_string()
+                )?;
+            }
+        }
+        Ok(())
+    }
+
     /// Writes results as JSON.
     /// # Example output
     /// ```json
@@ -245,6 +781,7 @@ impl<W: Write> ReportWriter<W> {
     ///       "total_file_size": 13589,
     ///       "redundant_file_count": 17,
     ///       "redundant_file_size": 6819,
+    ///       "redundant_allocated_size": 73728,
     ///       "missing_file_count": 0,
     ///       "missing_file_size": 0
     ///     }
@@ -281,6 +818,312 @@ impl<W: Write> ReportWriter<W> {
         Ok(())
     }
 
+    /// Writes the report in JSON Lines format.
+    ///
+    /// The first line contains a JSON object with the report header under the `header` key.
+    /// Each of the following lines contains a JSON object describing a single group of files.
+    /// Unlike with [`ReportWriter::write_as_json`], the output can be processed
+    /// line by line, before the whole report is written.
+    pub fn write_as_json_lines<I, P>(&mut self, header: &ReportHeader, groups: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = P>,
+        P: Serialize,
+    {
+        #[derive(Serialize)]
+        struct HeaderLine<'a> {
+            header: &'a ReportHeader,
+        }
+
+        serde_json::to_writer(&mut self.out, &HeaderLine { header })?;
+        writeln!(self.out)?;
+        for g in groups {
+            serde_json::to_writer(&mut self.out, &g)?;
+            writeln!(self.out)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the report as a self-contained HTML page.
+    ///
+    /// The page contains the report header and a table with one row per group.
+    /// The table can be sorted by clicking the column headers.
+    /// The paths of each group are hidden in an expandable `<details>` element.
+    /// All styles and scripts are inlined, so the page can be shared as a single file.
+    pub fn write_as_html<I, G>(&mut self, header: &ReportHeader, groups: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = G>,
+        G: Borrow<FileGroup<Path>>,
+    {
+        const TEMPLATE: &str = include_str!("report.html");
+        let (page_start, page_end) = TEMPLATE.split_once("{{groups}}").unwrap();
+
+        let mut header_rows = vec![
+            (
+                "Timestamp",
+                format_timestamp(&header.timestamp, self.strict_iso_8601),
+            ),
+            ("Command", arg::join(&header.command)),
+            ("Base dir", header.base_dir.to_escaped_string()),
+        ];
+        if let Some(stats) = &header.stats {
+            header_rows.push((
+                "Total",
+                format!(
+                    "{} B ({}) in {} files in {} groups",
+                    stats.total_file_size.0,
+                    stats.total_file_size,
+                    stats.total_file_count,
+                    stats.group_count
+                ),
+            ));
+            header_rows.push((
+                "Redundant",
+                format!(
+                    "{} B ({}) in {} files{}",
+                    stats.redundant_file_size.0,
+                    stats.redundant_file_size,
+                    stats.redundant_file_count,
+                    stats.allocated_size_summary()
+                ),
+            ));
+            header_rows.push((
+                "Missing",
+                format!(
+                    "{} B ({}) in {} files",
+                    stats.missing_file_size.0, stats.missing_file_size, stats.missing_file_count
+                ),
+            ));
+            if let Some((scanned, wasted)) = stats.scanned_size_summary() {
+                header_rows.push(("Total scanned", scanned));
+                header_rows.push(("Duplicate wasted", wasted));
+            }
+            if let Some(freed_inodes) = stats.freed_inode_count {
+                header_rows.push(("Inodes freed", freed_inodes.to_string()));
+            }
+            if let Some(excluded) = stats.excluded_by_extension {
+                header_rows.push(("Excluded by extension", format!("{} files", excluded)));
+            }
+            if let Some(skipped) = &stats.permission_errors {
+                header_rows.push((
+                    "Skipped (no read permission)",
+                    format!("{} entries", skipped.len()),
+                ));
+            }
+            if !stats.read_error_files.is_empty() {
+                let mut paths = stats.read_error_files.iter().map(|p| p.to_escaped_string());
+                header_rows.push(("Read errors", paths.join(", ")));
+            }
+        }
+        if let Some(size_filter) = &header.size_filter {
+            header_rows.push(("Size filter", size_filter.to_string()));
+        }
+        if let Some(extension_filter) = &header.extension_filter {
+            header_rows.push(("Extension filter", extension_filter.to_string()));
+        }
+        if !header.baseline.is_empty() {
+            let mut dirs = header.baseline.iter().map(|p| p.to_escaped_string());
+            header_rows.push(("Baseline (read-only)", dirs.join(", ")));
+        }
+        if !header.roots.is_empty() {
+            header_rows.push(("Roots", join_paths(&header.roots)));
+        }
+        let header_rows = header_rows
+            .into_iter()
+            .map(|(name, value)| {
+                format!("<tr><td>{}</td><td>{}</td></tr>", name, escape_html(&value))
+            })
+            .join("\n");
+
+        let page_start = page_start
+            .replace("{{version}}", &escape_html(&header.version))
+            .replace("{{header}}", &header_rows);
+        write!(self.out, "{}", page_start)?;
+
+        for g in groups {
+            let g = g.borrow();
+            let wasted = g.file_len * (g.files.len().saturating_sub(1) as u64);
+            writeln!(self.out, "<tr>")?;
+            writeln!(
+                self.out,
+                "  <td class=\"hash\" data-value=\"{0}\">{0}</td>",
+                g.file_hash.encode(self.hash_encoding)
+            )?;
+            writeln!(
+                self.out,
+                "  <td class=\"num\" data-value=\"{}\">{}</td>",
+                g.file_len.0, g.file_len
+            )?;
+            writeln!(
+                self.out,
+                "  <td class=\"num\" data-value=\"{0}\">{0}</td>",
+                g.files.len()
+            )?;
+            writeln!(
+                self.out,
+                "  <td class=\"num\" data-value=\"{}\">{}</td>",
+                wasted.0, wasted
+            )?;
+            let first_path = g.files.first().map(|f| f.to_escaped_string());
+            writeln!(
+                self.out,
+                "  <td data-value=\"{}\"><details><summary>{} files</summary><ul class=\"files\">",
+                escape_html(first_path.as_deref().unwrap_or_default()),
+                g.files.len()
+            )?;
+            for f in g.files.iter() {
+                writeln!(
+                    self.out,
+                    "    <li><a href=\"{}\">{}</a></li>",
+                    escape_html(&f.to_uri()),
+                    escape_html(&f.to_escaped_string())
+                )?;
+            }
+            writeln!(self.out, "  </ul></details></td>")?;
+            writeln!(self.out, "</tr>")?;
+        }
+
+        write!(self.out, "{}", page_end)?;
+        Ok(())
+    }
+
+    /// Writes the report in a simple line-oriented format for shell scripts.
+    ///
+    /// Each file of each group is written in a separate line with the following
+    /// space-separated `key=value` fields, always in this order:
+    /// - `group` – hash of the group, in hexadecimal
+    /// - `size` – length of each file in the group, in bytes
+    /// - `path` – path to the file; it is always the last field and takes
+    ///   the remainder of the line, so it may contain spaces
+    ///
+    /// Paths are escaped the same way as in the default text format.
+    /// If `print0` is set, lines are terminated with NUL characters instead of newlines and
+    /// paths are printed as is. There is no header.
+    ///
+    /// # Example
+    /// ```text
+    /// group=49165422e775f631cca3b09124f8ee89 size=6274 path=/home/user/src/semaphore.rs
+    /// group=49165422e775f631cca3b09124f8ee89 size=6274 path=/home/user/backup/semaphore.rs
+    /// ```
+    pub fn write_as_machine<I, G>(&mut self, _header: &ReportHeader, groups: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = G>,
+        G: Borrow<FileGroup<Path>>,
+    {
+        for g in groups {
+            let g = g.borrow();
+            for f in g.files.iter() {
+                write!(
+                    self.out,
+                    "group={} size={} path=",
+                    g.file_hash.encode(self.hash_encoding),
+                    g.file_len.0
+                )?;
+                if self.print0 {
+                    self.out.write_all(&path_bytes(f))?;
+                    self.out.write_all(b"\0")?;
+                } else {
+                    writeln!(self.out, "{}", f.to_escaped_string())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the report in a verbose, human-readable format.
+    ///
+    /// The header is the same as in the [`ReportWriter::write_as_text`] format, except for
+    /// the first line starting with `# Detailed report by fclones`. The report can't be read
+    /// back by the other commands, because the file metadata were read when writing it
+    /// and would be stale by then.
+    /// Each group is a block starting with a line with the hash, the file size,
+    /// the number of files and the space wasted by the redundant files.
+    /// It is followed by a table with the modification time, the inode and the device
+    /// of each file. Metadata that can't be read are printed as `-`.
+    ///
+    /// # Example
+    /// ```text
+    /// # Detailed report by fclones 0.20.1
+    /// ...
+    /// # Missing: 0 B (0 B) in 0 files
+    /// ┌─ 49165422e775f631cca3b09124f8ee89, 6274 B (6.3 KB) * 2, 6274 B (6.3 KB) wasted
+    /// │  Modified                       Inode    Device  Path
+    /// ├─ 2022-03-18 08:22:00.844 +0100  2764412  66306   /home/pkolaczk/src/semaphore.rs
+    /// └─ 2022-03-10 11:02:47.103 +0100  3302177  66306   /home/pkolaczk/backup/semaphore.rs
+    /// ```
+    pub fn write_as_report<I, G>(&mut self, header: &ReportHeader, groups: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = G>,
+        G: Borrow<FileGroup<Path>>,
+    {
+        let (top, side, middle, bottom) = if self.unicode {
+            ("┌─", "│ ", "├─", "└─")
+        } else {
+            ("+-", "| ", "+-", "`-")
+        };
+        self.write_titled_text_header("Detailed report", header)?;
+        for g in groups {
+            let g = g.borrow();
+            let group_header = format!(
+                "{}, {} B ({}) * {}, {} B ({}) wasted",
+                g.file_hash.encode(self.hash_encoding),
+                g.file_len.0,
+                g.file_len,
+                g.files.len(),
+                g.wasted_bytes().0,
+                g.wasted_bytes(),
+            );
+            let group_header = style(group_header).yellow().force_styling(self.color);
+            writeln!(self.out, "{} {}", top, group_header)?;
+
+            let mut rows = vec![[
+                "Modified".to_owned(),
+                "Inode".to_owned(),
+                "Device".to_owned(),
+                "Path".to_owned(),
+            ]];
+            for f in g.files.iter() {
+                let metadata = FileMetadata::new(f).ok();
+                let modified = metadata.as_ref().and_then(|m| m.modified().ok());
+                let modified = modified.map(|t| {
+                    let t: DateTime<Local> = t.into();
+                    format_timestamp(&t, self.strict_iso_8601)
+                });
+                let inode = metadata.as_ref().and_then(|m| m.inode_id().ok());
+                let device = metadata.as_ref().and_then(|m| m.device_id().ok());
+                rows.push([
+                    modified.unwrap_or_else(|| "-".to_owned()),
+                    inode.map_or_else(|| "-".to_owned(), |i| i.to_string()),
+                    device.map_or_else(|| "-".to_owned(), |d| d.to_string()),
+                    f.to_escaped_string(),
+                ]);
+            }
+            let widths: Vec<usize> = (0..3)
+                .map(|i| rows.iter().map(|r| r[i].chars().count()).max().unwrap_or(0))
+                .collect();
+            let last = rows.len() - 1;
+            for (i, [modified, inode, device, path]) in rows.iter().enumerate() {
+                let prefix = match i {
+                    0 => side,
+                    i if i == last => bottom,
+                    _ => middle,
+                };
+                writeln!(
+                    self.out,
+                    "{} {:w0$}  {:w1$}  {:w2$}  {}",
+                    prefix,
+                    modified,
+                    inode,
+                    device,
+                    path,
+                    w0 = widths[0],
+                    w1 = widths[1],
+                    w2 = widths[2],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
     /// Writes the report in the format given by `format` parameter.
     pub fn write<I, G>(
         &mut self,
@@ -296,9 +1139,169 @@ impl<W: Write> ReportWriter<W> {
             OutputFormat::Default => self.write_as_text(header, groups),
             OutputFormat::Fdupes => self.write_as_fdupes(header, groups),
             OutputFormat::Csv => self.write_as_csv(header, groups),
-            OutputFormat::Json => self.write_as_json(header, groups),
+            OutputFormat::Tsv => self.write_as_tsv(header, groups),
+            OutputFormat::Json if self.hard_links => {
+                let file_ids = self.file_ids.clone();
+                let groups = EncodedGroup::wrap(groups, self.hash_encoding, header);
+                let groups = groups.map(|g| LinkedGroup::new(g, file_ids.as_deref()));
+                self.write_as_json(header, groups)
+            }
+            OutputFormat::JsonLines if self.hard_links => {
+                let file_ids = self.file_ids.clone();
+                let groups = EncodedGroup::wrap(groups, self.hash_encoding, header);
+                let groups = groups.map(|g| LinkedGroup::new(g, file_ids.as_deref()));
+                self.write_as_json_lines(header, groups)
+            }
+            OutputFormat::Json => {
+                let groups = EncodedGroup::wrap(groups, self.hash_encoding, header);
+                self.write_as_json(header, groups)
+            }
+            OutputFormat::JsonLines => {
+                let groups = EncodedGroup::wrap(groups, self.hash_encoding, header);
+                self.write_as_json_lines(header, groups)
+            }
+            OutputFormat::Html => self.write_as_html(header, groups),
+            OutputFormat::Machine => self.write_as_machine(header, groups),
+            OutputFormat::Report => self.write_as_report(header, groups),
+            OutputFormat::Sqlite => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "The sqlite format can be written only to a file",
+            )),
+        }
+    }
+}
+
+/// A group serialized with its hash in the given encoding,
+/// with the flags telling which of its files are in the baseline directories
+/// and with the indexes of the roots of its files
+struct EncodedGroup<G> {
+    group: G,
+    encoding: HashEncoding,
+    /// For each file, true if it is located in a baseline directory.
+    /// Empty if no baseline directories were given.
+    baseline: Vec<bool>,
+    /// For each file, the index of its root in [`ReportHeader::roots`].
+    /// Empty if no roots were given.
+    roots: Vec<Option<usize>>,
+}
+
+impl<G: Borrow<FileGroup<Path>>> EncodedGroup<G> {
+    fn wrap<I>(
+        groups: I,
+        encoding: HashEncoding,
+        header: &ReportHeader,
+    ) -> impl Iterator<Item = EncodedGroup<G>>
+    where
+        I: IntoIterator<Item = G>,
+    {
+        let baseline = header.baseline.clone();
+        let roots = header.roots.clone();
+        groups.into_iter().map(move |group| {
+            let files = &group.borrow().files;
+            let baseline_flags = if baseline.is_empty() {
+                vec![]
+            } else {
+                files
+                    .iter()
+                    .map(|f| baseline.iter().any(|dir| dir.is_prefix_of(f)))
+                    .collect()
+            };
+            let root_indexes = if roots.is_empty() {
+                vec![]
+            } else {
+                files.iter().map(|f| root_index(&roots, f)).collect()
+            };
+            EncodedGroup {
+                group,
+                encoding,
+                baseline: baseline_flags,
+                roots: root_indexes,
+            }
+        })
+    }
+}
+
+impl<G: Borrow<FileGroup<Path>>> Borrow<FileGroup<Path>> for EncodedGroup<G> {
+    fn borrow(&self) -> &FileGroup<Path> {
+        self.group.borrow()
+    }
+}
+
+impl<G: Borrow<FileGroup<Path>>> Serialize for EncodedGroup<G> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let group = self.group.borrow();
+        let field_count = 3 + !self.baseline.is_empty() as usize + !self.roots.is_empty() as usize;
+        let mut s = serializer.serialize_struct("FileGroup", field_count)?;
+        s.serialize_field("file_len", &group.file_len)?;
+        s.serialize_field("file_hash", &group.file_hash.encode(self.encoding))?;
+        s.serialize_field("files", &group.files)?;
+        if !self.baseline.is_empty() {
+            s.serialize_field("baseline", &self.baseline)?;
+        }
+        if !self.roots.is_empty() {
+            s.serialize_field("roots", &self.roots)?;
+        }
+        s.end()
+    }
+}
+
+/// A group serialized together with the information on hard links between its files
+#[derive(Serialize)]
+struct LinkedGroup<G: Serialize> {
+    #[serde(flatten)]
+    group: G,
+    hardlink_only: bool,
+    /// Index of the distinct file each member is linked to
+    inode_groups: Vec<usize>,
+}
+
+impl<G: Borrow<FileGroup<Path>> + Serialize> LinkedGroup<G> {
+    fn new(group: G, file_ids: Option<&FileIds>) -> LinkedGroup<G> {
+        let inode_groups = group.borrow().inode_groups_with(file_ids);
+        LinkedGroup {
+            hardlink_only: is_hardlink_only(&inode_groups),
+            inode_groups,
+            group,
+        }
+    }
+}
+
+/// Returns the raw bytes of the path
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.to_path_buf().as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_bytes()
+}
+
+/// Joins the paths into a single line, quoting them like command line arguments.
+/// The line can be split back with [`arg::split`].
+fn join_paths(paths: &[Path]) -> String {
+    let args: Vec<Arg> = paths
+        .iter()
+        .map(|p| Arg::from(p.to_path_buf().into_os_string()))
+        .collect();
+    arg::join(&args)
+}
+
+/// Escapes characters that have a special meaning in HTML text and attribute values.
+fn escape_html(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            c => result.push(c),
         }
     }
+    result
 }
 
 /// Iterator over groups of files, read form the report
@@ -366,7 +1369,7 @@ where
 
         lazy_static! {
             static ref GROUP_HEADER_RE: Regex =
-                Regex::new(r"^([a-f0-9]{32}), ([0-9]+) B [^*]* \* ([0-9]+):").unwrap();
+                Regex::new(r"^([a-zA-Z0-9+/=]+), ([0-9]+) B [^*]* \* ([0-9]+):").unwrap();
         }
 
         let captures = GROUP_HEADER_RE.captures(header_str).ok_or_else(|| {
@@ -376,10 +1379,10 @@ where
             )
         })?;
 
+        let file_hash = FileHash::decode(captures.get(1).unwrap().as_str())
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
         Ok(Some(GroupHeader {
-            file_hash: FileHash(
-                u128::from_str_radix(captures.get(1).unwrap().as_str(), 16).unwrap(),
-            ),
+            file_hash,
             file_len: FileLen(captures.get(2).unwrap().as_str().parse::<u64>().unwrap()),
             count: captures.get(3).unwrap().as_str().parse::<usize>().unwrap(),
         }))
@@ -474,20 +1477,24 @@ impl<R: BufRead> TextReportReader<R> {
             })?
             .iter()
             .skip(1)
-            .map(|c| c.unwrap().as_str().to_owned())
+            .map(|c| c.map(|c| c.as_str().to_owned()).unwrap_or_default())
             .collect())
     }
 
+    /// Parses a timestamp in the default format or, if written with `--strict-iso-8601`,
+    /// in the RFC 3339 format.
     fn parse_timestamp(value: &str, name: &str) -> io::Result<DateTime<FixedOffset>> {
-        DateTime::parse_from_str(value, TIMESTAMP_FMT).map_err(|e| {
-            Error::new(
-                ErrorKind::InvalidData,
-                format!(
-                    "Malformed header: Failed to parse {}: {}. Expected timestamp format: {}",
-                    name, e, TIMESTAMP_FMT
-                ),
-            )
-        })
+        DateTime::parse_from_str(value, TIMESTAMP_FMT)
+            .or_else(|e| DateTime::parse_from_rfc3339(value).map_err(|_| e))
+            .map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Malformed header: Failed to parse {}: {}. Expected timestamp format: {}",
+                        name, e, TIMESTAMP_FMT
+                    ),
+                )
+            })
     }
 
     fn parse_u64(value: Option<&String>, name: &str) -> io::Result<u64> {
@@ -529,10 +1536,30 @@ impl<R: BufRead + Send + 'static> ReportReader for TextReportReader<R> {
             static ref TOTAL_RE: Regex =
                 Regex::new(r"^# Total: ([0-9]+) B \([^)]+\) in ([0-9]+) files in ([0-9]+) groups")
                     .unwrap();
-            static ref REDUNDANT_RE: Regex =
-                Regex::new(r"^# Redundant: ([0-9]+) B \([^)]+\) in ([0-9]+) files").unwrap();
+            static ref REDUNDANT_RE: Regex = Regex::new(
+                r"^# Redundant: ([0-9]+) B \([^)]+\) in ([0-9]+) files(?:; allocated: ([0-9]+) B)?"
+            )
+            .unwrap();
             static ref MISSING_RE: Regex =
                 Regex::new(r"^# Missing: ([0-9]+) B \([^)]+\) in ([0-9]+) files").unwrap();
+            static ref ROOTS_RE: Regex = Regex::new(r"^# Roots: (.*)").unwrap();
+        }
+
+        if self
+            .stream
+            .fill_buf()?
+            .starts_with(b"# Detailed report by fclones")
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Reports written in the {} format can't be read back. \
+                     Use the {} or {} format instead",
+                    OutputFormat::Report,
+                    OutputFormat::Default,
+                    OutputFormat::Json
+                ),
+            ));
         }
 
         let version = self
@@ -560,11 +1587,36 @@ impl<R: BufRead + Send + 'static> ReportReader for TextReportReader<R> {
         let stats = self.read_extract(&REDUNDANT_RE, "redundant file statistics")?;
         let redundant_file_size = Self::parse_file_len(stats.get(0), "redundant file size")?;
         let redundant_file_count = Self::parse_usize(stats.get(1), "redundant file count")?;
+        let redundant_allocated_size = match stats.get(2) {
+            Some(s) if !s.is_empty() => {
+                Some(Self::parse_file_len(Some(s), "redundant allocated size")?)
+            }
+            _ => None,
+        };
 
         let stats = self.read_extract(&MISSING_RE, "missing file statistics")?;
         let missing_file_size = Self::parse_file_len(stats.get(0), "missing file size")?;
         let missing_file_count = Self::parse_usize(stats.get(1), "missing file count")?;
 
+        // The remaining header lines are optional and only the roots are needed to read
+        // the groups back
+        let mut roots = vec![];
+        while self.stream.fill_buf()?.first() == Some(&b'#') {
+            let line = self.read_line()?;
+            if let Some(captures) = ROOTS_RE.captures(line.trim()) {
+                roots = arg::split(&captures[1])
+                    .map_err(|e| {
+                        Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Malformed header: Failed to parse roots: {}", e),
+                        )
+                    })?
+                    .iter()
+                    .map(|a| Path::from(a.as_os_str()))
+                    .collect();
+            }
+        }
+
         Ok(ReportHeader {
             version,
             timestamp,
@@ -576,9 +1628,21 @@ impl<R: BufRead + Send + 'static> ReportReader for TextReportReader<R> {
                 total_file_size,
                 redundant_file_count,
                 redundant_file_size,
+                redundant_allocated_size,
                 missing_file_count,
                 missing_file_size,
+                scanned_file_size: None,
+                size_histogram: None,
+                freed_inode_count: None,
+                read_error_files: vec![],
+                excluded_by_extension: None,
+                permission_errors: None,
             }),
+            size_filter: None,
+            extension_filter: None,
+            baseline: vec![],
+            roots,
+            metrics: None,
         })
     }
 
@@ -637,10 +1701,44 @@ impl ReportReader for JsonReportReader {
     }
 }
 
+/// The first two bytes of a gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// The first bytes of every SQLite database file
+const SQLITE_MAGIC: &[u8] = b"SQLite format 3\0";
+
 /// Returns a `ReportReader` that can read and decode the report from the given stream.
-/// Automatically detects the type of the report.
+/// Automatically detects the type of the report and decompresses gzip-compressed reports.
+/// SQLite databases are recognized if fclones was built with the `sqlite` feature.
 pub fn open_report(r: impl Read + Send + 'static) -> io::Result<Box<dyn ReportReader>> {
     let mut buf_reader = BufReader::with_capacity(16 * 1024, r);
+    let preview = buf_reader.fill_buf()?;
+    if preview.starts_with(&GZIP_MAGIC) {
+        open_uncompressed_report(BufReader::new(MultiGzDecoder::new(buf_reader)))
+    } else if preview.starts_with(SQLITE_MAGIC) {
+        open_sqlite_report(buf_reader)
+    } else {
+        open_uncompressed_report(buf_reader)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn open_sqlite_report(r: impl Read) -> io::Result<Box<dyn ReportReader>> {
+    Ok(Box::new(crate::sqlite::SqliteReportReader::from_stream(r)?))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_sqlite_report(_r: impl Read) -> io::Result<Box<dyn ReportReader>> {
+    Err(io::Error::new(
+        ErrorKind::InvalidData,
+        "Cannot read an SQLite report, because fclones was built without the sqlite feature",
+    ))
+}
+
+fn open_uncompressed_report<R>(mut buf_reader: BufReader<R>) -> io::Result<Box<dyn ReportReader>>
+where
+    R: Read + Send + 'static,
+{
     let preview = buf_reader.fill_buf()?;
     let preview = String::from_utf8_lossy(preview);
     if preview.starts_with('{') {
@@ -666,8 +1764,10 @@ mod test {
 
     use tempfile::NamedTempFile;
 
+    use crate::config::GroupConfig;
     use crate::file::{FileHash, FileLen};
     use crate::path::Path;
+    use crate::util::test::{with_dir, write_file};
 
     use super::*;
 
@@ -683,10 +1783,22 @@ mod test {
                 total_file_count: 1000,
                 total_file_size: FileLen(2500),
                 redundant_file_count: 234,
+                redundant_allocated_size: Some(FileLen(4096)),
                 redundant_file_size: FileLen(1000),
                 missing_file_count: 93,
                 missing_file_size: FileLen(300),
+                scanned_file_size: None,
+                size_histogram: None,
+                freed_inode_count: None,
+                read_error_files: vec![],
+                excluded_by_extension: None,
+                permission_errors: None,
             }),
+            size_filter: None,
+            extension_filter: None,
+            baseline: vec![],
+            roots: vec![],
+            metrics: None,
         }
     }
 
@@ -709,6 +1821,248 @@ mod test {
         assert_eq!(header2.stats, header1.stats);
     }
 
+    #[test]
+    fn test_reports_record_roots() {
+        let mut header = dummy_report_header();
+        header.roots = vec![Path::from("/root/a b"), Path::from("/root/c")];
+        let group = FileGroup {
+            file_len: FileLen(100),
+            file_hash: FileHash(1),
+            files: vec![
+                Path::from("/root/c/file"),
+                Path::from("/root/a b/file"),
+                Path::from("/other/file"),
+            ],
+        };
+
+        let mut output = Vec::new();
+        let mut writer = ReportWriter::new(&mut output, false);
+        writer.write_as_text(&header, [&group]).unwrap();
+        let input = io::Cursor::new(output);
+        let mut reader = TextReportReader::new(BufReader::new(input));
+        assert_eq!(reader.read_header().unwrap().roots, header.roots);
+        let groups: Vec<_> = Box::new(reader).read_groups().unwrap().collect().unwrap();
+        assert_eq!(groups, vec![group.clone()]);
+
+        let mut output = Vec::new();
+        let mut writer = ReportWriter::new(&mut output, false);
+        writer.write(OutputFormat::Json, &header, [&group]).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(json["groups"][0]["roots"], serde_json::json!([1, 0, null]));
+    }
+
+    #[test]
+    fn test_text_report_reader_reads_strict_iso_8601_timestamp() {
+        let header1 = dummy_report_header();
+        let groups: Vec<FileGroup<Path>> = vec![];
+
+        let mut output = Vec::new();
+        let mut writer = ReportWriter::new(&mut output, false).strict_iso_8601(true);
+        writer.write_as_text(&header1, groups.iter()).unwrap();
+        let text = String::from_utf8(output).unwrap();
+        assert!(text.contains("# Timestamp: 2021-08-27T12:11:23.456Z\n"));
+
+        let input = io::Cursor::new(text.into_bytes());
+        let mut reader = TextReportReader::new(BufReader::new(input));
+        let header2 = reader.read_header().unwrap();
+        assert_eq!(header2.timestamp, header1.timestamp);
+    }
+
+    #[test]
+    fn test_largest_groups_fit_terminal_width() {
+        let header = dummy_report_header();
+        let group = FileGroup {
+            file_len: FileLen(100),
+            file_hash: FileHash(u128::MAX),
+            files: vec![Path::from("/a/b/c"), Path::from("/d")],
+        };
+
+        let mut output = Vec::new();
+        let mut writer = ReportWriter::new(&mut output, false);
+        writer.write_largest_groups(&header, [&group]).unwrap();
+        let report = String::from_utf8(output).unwrap();
+        assert!(report.ends_with(
+            "\nffffffffffffffffffffffffffffffff, 100 B (100 B) * 2, 100 B (100 B) wasted\n"
+        ));
+
+        let mut output = Vec::new();
+        let mut writer = ReportWriter::new(&mut output, false).terminal_width(Some(36));
+        writer.write_largest_groups(&header, [&group]).unwrap();
+        let report = String::from_utf8(output).unwrap();
+        assert!(report.ends_with("\nffffffff 2 files, 100 B wasted: ...c\n"));
+    }
+
+    #[test]
+    fn test_text_report_reader_reads_header_without_allocated_size() {
+        let mut header1 = dummy_report_header();
+        header1.stats.as_mut().unwrap().redundant_allocated_size = None;
+        let groups: Vec<FileGroup<Path>> = vec![];
+
+        let output = NamedTempFile::new().unwrap();
+        let input = output.reopen().unwrap();
+
+        let mut writer = ReportWriter::new(output, false);
+        writer.write_as_text(&header1, groups.iter()).unwrap();
+
+        let mut reader = TextReportReader::new(BufReader::new(input));
+        let header2 = reader.read_header().unwrap();
+        assert_eq!(header2.stats, header1.stats);
+    }
+
+    #[test]
+    fn test_text_report_contains_total_scanned_size() {
+        let mut header = dummy_report_header();
+        header.stats.as_mut().unwrap().scanned_file_size = Some(FileLen(4000));
+        let groups = vec![FileGroup {
+            file_len: FileLen(100),
+            file_hash: FileHash(0x00112233445566778899aabbccddeeff),
+            files: vec![Path::from("/file1"), Path::from("/file2")],
+        }];
+
+        let mut output = Vec::new();
+        let mut writer = ReportWriter::new(&mut output, false);
+        writer.write_as_text(&header, groups.iter()).unwrap();
+        let report = String::from_utf8(output.clone()).unwrap();
+        assert!(report.contains("# Total scanned: 4000 B (4.0 KB)\n"));
+        assert!(report.contains("# Duplicate wasted: 1000 B (1.0 KB), 25.0% of total\n"));
+
+        // The additional header lines must not break reading the report:
+        let mut reader = TextReportReader::new(BufReader::new(io::Cursor::new(output)));
+        reader.read_header().unwrap();
+        let groups2: Vec<_> = Box::new(reader).read_groups().unwrap().collect().unwrap();
+        assert_eq!(groups2, groups);
+    }
+
+    #[test]
+    fn test_size_histogram_buckets() {
+        let mut histogram = SizeHistogram::new(&[FileLen(1000), FileLen(100)]);
+        histogram.add(FileLen(100), 2);
+        histogram.add(FileLen(101), 1);
+        histogram.add(FileLen(5000), 3);
+        histogram.add(FileLen(10), 0);
+        let counts: Vec<_> = histogram
+            .buckets
+            .iter()
+            .map(|b| (b.max_size, b.redundant_file_count, b.redundant_file_size))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                (Some(FileLen(100)), 2, FileLen(200)),
+                (Some(FileLen(1000)), 1, FileLen(101)),
+                (None, 3, FileLen(15000)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_report_format_version() {
+        let version = report_format_version();
+        assert_eq!(version["format_version"], REPORT_FORMAT_VERSION);
+        assert_eq!(version["fclones_version"], env!("CARGO_PKG_VERSION"));
+    }
+
+    #[test]
+    fn test_stats_count_freed_inodes() {
+        let mut stats = FileStats {
+            freed_inode_count: Some(0),
+            ..FileStats::default()
+        };
+        let group = FileGroup {
+            file_len: FileLen(100),
+            file_hash: FileHash(0),
+            files: vec![Path::from("/a"), Path::from("/b"), Path::from("/c")],
+        };
+        stats.add_group(&group, &GroupConfig::default().group_filter());
+        stats.add_hardlink_group(&group);
+        assert_eq!(stats.freed_inode_count, Some(2));
+
+        let mut header = dummy_report_header();
+        header.stats = Some(stats);
+        let mut output = Vec::new();
+        let mut writer = ReportWriter::new(&mut output, false);
+        writer.write_as_text(&header, [group].iter()).unwrap();
+        let report = String::from_utf8(output).unwrap();
+        assert!(report.contains("# Inodes freed: 2\n"));
+    }
+
+    #[test]
+    fn test_text_report_lists_read_errors() {
+        let mut header = dummy_report_header();
+        header.stats.as_mut().unwrap().read_error_files =
+            vec![Path::from("/flaky/a"), Path::from("/flaky/b")];
+        let groups = vec![FileGroup {
+            file_len: FileLen(100),
+            file_hash: FileHash(0x00112233445566778899aabbccddeeff),
+            files: vec![Path::from("/file1"), Path::from("/file2")],
+        }];
+
+        let mut output = Vec::new();
+        let mut writer = ReportWriter::new(&mut output, false);
+        writer.write_as_text(&header, groups.iter()).unwrap();
+        let report = String::from_utf8(output.clone()).unwrap();
+        assert!(report.contains(
+            "# Read errors: 2 files could not be read and are missing from the groups:\n\
+             #   /flaky/a\n\
+             #   /flaky/b\n"
+        ));
+
+        let mut reader = TextReportReader::new(BufReader::new(io::Cursor::new(output)));
+        reader.read_header().unwrap();
+        let groups2: Vec<_> = Box::new(reader).read_groups().unwrap().collect().unwrap();
+        assert_eq!(groups2, groups);
+    }
+
+    #[test]
+    fn test_reports_list_permission_errors() {
+        let mut header = dummy_report_header();
+        header.stats.as_mut().unwrap().permission_errors =
+            Some(vec![Path::from("/root/a"), Path::from("/root/b")]);
+        let groups: Vec<FileGroup<Path>> = vec![];
+
+        let mut output = Vec::new();
+        let mut writer = ReportWriter::new(&mut output, false);
+        writer.write_as_text(&header, groups.iter()).unwrap();
+        let report = String::from_utf8(output).unwrap();
+        assert!(report.contains("# Skipped (no read permission): 2 entries\n"));
+
+        let mut output = Vec::new();
+        let mut writer = ReportWriter::new(&mut output, false);
+        writer.write_as_json(&header, groups.iter()).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(
+            json["header"]["stats"]["permission_errors"],
+            serde_json::json!(["/root/a", "/root/b"])
+        );
+    }
+
+    #[test]
+    fn test_text_report_contains_size_histogram() {
+        let mut header = dummy_report_header();
+        let mut histogram = SizeHistogram::new(&[FileLen(1000)]);
+        histogram.add(FileLen(100), 1);
+        histogram.add(FileLen(2000), 10);
+        header.stats.as_mut().unwrap().size_histogram = Some(histogram);
+        let groups = vec![FileGroup {
+            file_len: FileLen(100),
+            file_hash: FileHash(0x00112233445566778899aabbccddeeff),
+            files: vec![Path::from("/file1"), Path::from("/file2")],
+        }];
+
+        let mut output = Vec::new();
+        let mut writer = ReportWriter::new(&mut output, false);
+        writer.write_as_text(&header, groups.iter()).unwrap();
+        let report = String::from_utf8(output.clone()).unwrap();
+        assert!(report.contains("# Redundant by size:\n"));
+        assert!(report.contains("#   <= 1.0 KB   1 files    100 B\n"));
+        assert!(report.contains("#   > 1.0 KB   10 files  20.0 KB\n"));
+
+        let mut reader = TextReportReader::new(BufReader::new(io::Cursor::new(output)));
+        reader.read_header().unwrap();
+        let groups2: Vec<_> = Box::new(reader).read_groups().unwrap().collect().unwrap();
+        assert_eq!(groups2, groups);
+    }
+
     fn roundtrip_groups_text(header: &ReportHeader, groups: Vec<FileGroup<Path>>) {
         let output = NamedTempFile::new().unwrap();
         let input = output.reopen().unwrap();
@@ -842,6 +2196,41 @@ mod test {
         assert_eq!(header2.stats, header1.stats);
     }
 
+    #[test]
+    fn test_reports_roundtrip_with_any_hash_encoding() {
+        let header = dummy_report_header();
+        let groups = vec![FileGroup {
+            file_len: FileLen(100),
+            file_hash: FileHash(0x00112233445566778899aabbccddeeff),
+            files: vec![Path::from("a"), Path::from("b")],
+        }];
+        for encoding in [HashEncoding::Base64, HashEncoding::Base32] {
+            let mut output = Vec::new();
+            let mut writer = ReportWriter::new(&mut output, false).hash_encoding(encoding);
+            writer.write_as_text(&header, groups.iter()).unwrap();
+            let report = String::from_utf8(output.clone()).unwrap();
+            assert!(report.contains(&groups[0].file_hash.encode(encoding)));
+            let mut reader = Box::new(TextReportReader::new(BufReader::new(io::Cursor::new(
+                output,
+            ))));
+            reader.read_header().unwrap();
+            let groups2: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+            assert_eq!(groups2, groups);
+
+            let mut output = Vec::new();
+            let mut writer = ReportWriter::new(&mut output, false).hash_encoding(encoding);
+            writer
+                .write(OutputFormat::Json, &header, groups.iter())
+                .unwrap();
+            let report = String::from_utf8(output.clone()).unwrap();
+            assert!(report.contains(&groups[0].file_hash.encode(encoding)));
+            let mut reader = Box::new(JsonReportReader::new(io::Cursor::new(output)).unwrap());
+            reader.read_header().unwrap();
+            let groups2: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+            assert_eq!(groups2, groups);
+        }
+    }
+
     fn roundtrip_groups_json(header: &ReportHeader, groups: Vec<FileGroup<Path>>) {
         let output = NamedTempFile::new().unwrap();
         let input = output.reopen().unwrap();
@@ -909,6 +2298,211 @@ mod test {
         roundtrip_groups_json(&header, groups);
     }
 
+    #[test]
+    fn test_json_report_lists_inode_groups() {
+        with_dir("report/json_inode_groups", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            let file3 = root.join("file3");
+            write_file(&file1, "foo");
+            std::fs::hard_link(&file1, &file2).unwrap();
+            write_file(&file3, "foo");
+
+            let header = dummy_report_header();
+            let groups = [FileGroup {
+                file_len: FileLen(3),
+                file_hash: FileHash(1),
+                files: vec![Path::from(&file1), Path::from(&file2), Path::from(&file3)],
+            }];
+            let mut output = Vec::new();
+            let mut writer = ReportWriter::new(&mut output, false).hard_links(true);
+            writer
+                .write(OutputFormat::Json, &header, groups.iter())
+                .unwrap();
+
+            let report: serde_json::Value = serde_json::from_slice(&output).unwrap();
+            let group = &report["groups"][0];
+            assert_eq!(group["inode_groups"], serde_json::json!([0, 0, 1]));
+            assert_eq!(group["hardlink_only"], false);
+            assert_eq!(group["files"].as_array().unwrap().len(), 3);
+        });
+    }
+
+    #[test]
+    fn test_json_lines_report_writes_one_group_per_line() {
+        let header = dummy_report_header();
+        let groups = [
+            FileGroup {
+                file_len: FileLen(100),
+                file_hash: FileHash(0x00112233445566778899aabbccddeeff),
+                files: vec![Path::from("a"), Path::from("b")],
+            },
+            FileGroup {
+                file_len: FileLen(40),
+                file_hash: FileHash(0x0000000000000000000000000000beef),
+                files: vec![Path::from("c"), Path::from("d")],
+            },
+        ];
+
+        let mut output = Vec::new();
+        let mut writer = ReportWriter::new(&mut output, false);
+        writer.write_as_json_lines(&header, groups.iter()).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.len(), 3);
+
+        let header_line: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(header_line["header"]["version"], header.version.as_str());
+        let group: FileGroup<String> = serde_json::from_str(lines[2]).unwrap();
+        assert_eq!(group.file_len, FileLen(40));
+        assert_eq!(group.files, vec!["c", "d"]);
+    }
+
+    #[test]
+    fn test_html_report_lists_escaped_paths() {
+        let header = dummy_report_header();
+        let groups = [FileGroup {
+            file_len: FileLen(100),
+            file_hash: FileHash(0x00112233445566778899aabbccddeeff),
+            files: vec![Path::from("/a<b>"), Path::from("/c&d")],
+        }];
+
+        let mut output = Vec::new();
+        let mut writer = ReportWriter::new(&mut output, false);
+        writer.write_as_html(&header, groups.iter()).unwrap();
+        let html = String::from_utf8(output).unwrap();
+
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("00112233445566778899aabbccddeeff"));
+        assert!(html.contains("<li><a href=\"file:///a%3Cb%3E\">/a&lt;b&gt;</a></li>"));
+        assert!(html.contains("<li><a href=\"file:///c%26d\">/c&amp;d</a></li>"));
+        assert!(!html.contains("{{"));
+    }
+
+    #[test]
+    fn test_machine_report() {
+        let header = dummy_report_header();
+        let groups = [FileGroup {
+            file_len: FileLen(100),
+            file_hash: FileHash(0x00112233445566778899aabbccddeeff),
+            files: vec![Path::from("/a b"), Path::from("/c\nd")],
+        }];
+
+        let mut output = Vec::new();
+        let mut writer = ReportWriter::new(&mut output, false);
+        writer.write_as_machine(&header, groups.iter()).unwrap();
+        let text = String::from_utf8(output).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "group=00112233445566778899aabbccddeeff size=100 path=/a b",
+                "group=00112233445566778899aabbccddeeff size=100 path=/c\\nd",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verbose_report() {
+        with_dir("report/verbose_report", |root| {
+            let file = root.join("file");
+            write_file(&file, "aaa");
+            let metadata = FileMetadata::new(&Path::from(&file)).unwrap();
+            let header = dummy_report_header();
+            let groups = [FileGroup {
+                file_len: FileLen(3),
+                file_hash: FileHash(0x00112233445566778899aabbccddeeff),
+                files: vec![Path::from(&file), Path::from(root.join("missing"))],
+            }];
+
+            for (unicode, frame) in [(false, ["+-", "| ", "`-"]), (true, ["┌─", "│ ", "└─"])]
+            {
+                let mut output = Vec::new();
+                let mut writer = ReportWriter::new(&mut output, false).unicode(unicode);
+                writer.write_as_report(&header, groups.iter()).unwrap();
+                let text = String::from_utf8(output).unwrap();
+                let lines: Vec<_> = text.lines().skip_while(|l| l.starts_with('#')).collect();
+                assert_eq!(lines.len(), 4);
+                assert_eq!(
+                    lines[0],
+                    format!(
+                        "{} 00112233445566778899aabbccddeeff, 3 B (3 B) * 2, 3 B (3 B) wasted",
+                        frame[0]
+                    )
+                );
+                assert!(lines[1].starts_with(frame[1]));
+                assert!(lines[1].contains("Modified"));
+                assert!(lines[2].contains(&metadata.inode_id().unwrap().to_string()));
+                assert!(lines[2].ends_with(&Path::from(&file).to_escaped_string()));
+                assert!(lines[3].starts_with(frame[2]));
+                assert!(lines[3].contains(" -  "));
+            }
+        });
+    }
+
+    #[test]
+    fn test_verbose_report_is_not_read_back() {
+        let header = dummy_report_header();
+        let mut output = Vec::new();
+        let mut writer = ReportWriter::new(&mut output, false);
+        writer
+            .write_as_report(&header, Vec::<FileGroup<Path>>::new())
+            .unwrap();
+        assert!(output.starts_with(b"# Detailed report by fclones"));
+
+        let mut reader = open_report(io::Cursor::new(output)).unwrap();
+        let err = reader.read_header().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("can't be read back"));
+    }
+
+    #[test]
+    fn test_tsv_report_escapes_paths() {
+        let header = dummy_report_header();
+        let groups = [FileGroup {
+            file_len: FileLen(100),
+            file_hash: FileHash(0x00112233445566778899aabbccddeeff),
+            files: vec![Path::from("/a\tb"), Path::from("/c\\d")],
+        }];
+
+        let mut output = Vec::new();
+        let mut writer = ReportWriter::new(&mut output, false);
+        writer.write_as_tsv(&header, groups.iter()).unwrap();
+        let text = String::from_utf8(output).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "size\thash\tcount\tpath",
+                "100\t00112233445566778899aabbccddeeff\t2\t/a\\tb",
+                "100\t00112233445566778899aabbccddeeff\t2\t/c\\\\d",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_machine_report_print0() {
+        let header = dummy_report_header();
+        let groups = [FileGroup {
+            file_len: FileLen(100),
+            file_hash: FileHash(0x00112233445566778899aabbccddeeff),
+            files: vec![Path::from("/a b"), Path::from("/c\nd")],
+        }];
+
+        let mut output = Vec::new();
+        let mut writer = ReportWriter::new(&mut output, false).print0(true);
+        writer.write_as_machine(&header, groups.iter()).unwrap();
+        let text = String::from_utf8(output).unwrap();
+        let lines: Vec<_> = text.split_terminator('\0').collect();
+        assert_eq!(
+            lines,
+            [
+                "group=00112233445566778899aabbccddeeff size=100 path=/a b",
+                "group=00112233445566778899aabbccddeeff size=100 path=/c\nd",
+            ]
+        );
+    }
+
     fn roundtrip_header(header: &ReportHeader, format: OutputFormat) -> ReportHeader {
         let groups: Vec<FileGroup<Path>> = vec![];
         let output = NamedTempFile::new().unwrap();
@@ -927,4 +2521,26 @@ mod test {
         assert_eq!(header, reread_header_1);
         assert_eq!(header, reread_header_2);
     }
+
+    #[test]
+    fn test_open_compressed_report() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+
+        let header = dummy_report_header();
+        let groups = vec![FileGroup {
+            file_len: FileLen(100),
+            file_hash: FileHash(0x00112233445566778899aabbccddeeff),
+            files: vec![Path::from("/a"), Path::from("/b")],
+        }];
+        let mut writer =
+            ReportWriter::new(GzEncoder::new(Vec::new(), Compression::default()), false);
+        writer.write_as_json(&header, groups.iter()).unwrap();
+        let compressed = writer.out.finish().unwrap();
+
+        let mut reader = open_report(io::Cursor::new(compressed)).unwrap();
+        assert_eq!(reader.read_header().unwrap(), header);
+        let groups2: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+        assert_eq!(groups2, groups);
+    }
 }
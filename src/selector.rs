@@ -1,6 +1,10 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
 use std::path::MAIN_SEPARATOR;
 use std::sync::Arc;
 
+use serde::{Deserialize, Serialize};
+
 use crate::path::Path;
 use crate::pattern::Pattern;
 
@@ -129,6 +133,80 @@ impl PathSelector {
     }
 }
 
+/// Selects files by the extensions of their names.
+///
+/// A file is selected if its extension is on the allow-list, or the allow-list is empty,
+/// and its extension is not on the deny-list. Extensions are compared case-insensitively.
+/// Files without an extension are matched by the special [`ExtensionFilter::NO_EXTENSION`]
+/// token. As usual, only the part after the last dot is the extension, and dot-files
+/// like `.bashrc` have no extension.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtensionFilter {
+    /// Allowed extensions, lowercase, without the leading dot
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub included: Vec<String>,
+    /// Denied extensions, lowercase, without the leading dot
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub excluded: Vec<String>,
+}
+
+impl ExtensionFilter {
+    /// Matches files without an extension
+    pub const NO_EXTENSION: &'static str = "-";
+
+    pub fn new<S: AsRef<str>>(included: &[S], excluded: &[S]) -> ExtensionFilter {
+        let normalize = |extensions: &[S]| {
+            extensions
+                .iter()
+                .map(|e| e.as_ref().trim().trim_start_matches('.').to_lowercase())
+                .filter(|e| !e.is_empty())
+                .collect()
+        };
+        ExtensionFilter {
+            included: normalize(included),
+            excluded: normalize(excluded),
+        }
+    }
+
+    /// Returns true if the filter selects all files
+    pub fn is_empty(&self) -> bool {
+        self.included.is_empty() && self.excluded.is_empty()
+    }
+
+    /// Returns true if the extension of the last component of the path is selected
+    pub fn matches(&self, path: &Path) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        let name = path
+            .file_name_cstr()
+            .map(|s| s.to_string_lossy().to_lowercase());
+        let extension = name
+            .as_deref()
+            .and_then(|n| std::path::Path::new(n).extension())
+            .map(|e| e.to_string_lossy().to_string());
+        let extension = extension.as_deref().unwrap_or(Self::NO_EXTENSION);
+        (self.included.is_empty() || self.included.iter().any(|e| e == extension))
+            && self.excluded.iter().all(|e| e != extension)
+    }
+}
+
+/// Formats the filter as in the report header, e.g. `jpg, png; excluded: tmp`
+impl Display for ExtensionFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if !self.included.is_empty() {
+            write!(f, "{}", self.included.join(", "))?;
+        }
+        if !self.excluded.is_empty() {
+            if !self.included.is_empty() {
+                write!(f, "; ")?;
+            }
+            write!(f, "excluded: {}", self.excluded.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -229,6 +307,24 @@ mod test {
         assert!(!selector.matches_full_path(&Path::from("foo/foo/private-3.jpg")));
     }
 
+    #[test]
+    fn extension_filter() {
+        let filter = ExtensionFilter::new(&["jpg", ".PNG", "-"], &[]);
+        assert!(filter.matches(&Path::from("/photos/a.jpg")));
+        assert!(filter.matches(&Path::from("/photos/a.JPG")));
+        assert!(filter.matches(&Path::from("/photos/b.tar.png")));
+        assert!(filter.matches(&Path::from("/photos/README")));
+        assert!(filter.matches(&Path::from("/photos/.hidden")));
+        assert!(!filter.matches(&Path::from("/photos/a.jpeg")));
+        assert!(!filter.matches(&Path::from("/photos.jpg/a.txt")));
+
+        let filter = ExtensionFilter::new(&[], &["tmp", "part"]);
+        assert!(filter.matches(&Path::from("/photos/a.jpg")));
+        assert!(filter.matches(&Path::from("/photos/README")));
+        assert!(!filter.matches(&Path::from("/photos/a.jpg.PART")));
+        assert_eq!(filter.to_string(), "excluded: tmp, part");
+    }
+
     #[test]
     fn matches_dir() {
         let selector = PathSelector::new(Path::from("/"))
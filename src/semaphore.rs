@@ -29,6 +29,7 @@ pub struct Semaphore {
 /// dropped.
 pub struct SemaphoreGuard<'a> {
     sem: &'a Semaphore,
+    count: isize,
 }
 
 /// An RAII guard which will release a resource acquired from a semaphore when
@@ -58,11 +59,20 @@ impl Semaphore {
     /// This method will block until the internal count of the semaphore is at
     /// least 1.
     pub fn acquire(&self) {
+        self.acquire_n(1)
+    }
+
+    /// Acquires `n` resources of this semaphore, blocking the current thread until
+    /// it can do so.
+    ///
+    /// This method will block until the internal count of the semaphore is at
+    /// least `n`.
+    pub fn acquire_n(&self, n: isize) {
         let mut count = self.lock.lock().unwrap();
-        while *count <= 0 {
+        while *count < n {
             count = self.cvar.wait(count).unwrap();
         }
-        *count -= 1;
+        *count -= n;
     }
 
     /// Release a resource from this semaphore.
@@ -74,6 +84,15 @@ impl Semaphore {
         self.cvar.notify_one();
     }
 
+    /// Release `n` resources to this semaphore.
+    ///
+    /// All pending waiters are notified, because they might be waiting
+    /// for different numbers of resources.
+    pub fn release_n(&self, n: isize) {
+        *self.lock.lock().unwrap() += n;
+        self.cvar.notify_all();
+    }
+
     /// Acquires a resource of this semaphore, returning an RAII guard to
     /// release the semaphore when dropped.
     ///
@@ -81,7 +100,23 @@ impl Semaphore {
     /// `release` when the guard returned is dropped.
     pub fn access(&self) -> SemaphoreGuard<'_> {
         self.acquire();
-        SemaphoreGuard { sem: self }
+        SemaphoreGuard {
+            sem: self,
+            count: 1,
+        }
+    }
+
+    /// Acquires `n` resources of this semaphore, returning an RAII guard to
+    /// release them when dropped.
+    ///
+    /// This function is semantically equivalent to an `acquire_n` followed by a
+    /// `release_n` when the guard returned is dropped.
+    pub fn access_n(&self, n: isize) -> SemaphoreGuard<'_> {
+        self.acquire_n(n);
+        SemaphoreGuard {
+            sem: self,
+            count: n,
+        }
     }
 
     /// Acquires a resource of this semaphore, returning an RAII guard to
@@ -98,7 +133,7 @@ impl Semaphore {
 
 impl<'a> Drop for SemaphoreGuard<'a> {
     fn drop(&mut self) {
-        self.sem.release();
+        self.sem.release_n(self.count);
     }
 }
 
@@ -194,6 +229,31 @@ mod tests {
         rx1.recv().unwrap();
     }
 
+    #[test]
+    fn test_sem_acquire_release_many() {
+        let s = Semaphore::new(10);
+        s.acquire_n(7);
+        s.acquire_n(3);
+        s.release_n(10);
+        let _g = s.access_n(10);
+    }
+
+    #[test]
+    fn test_sem_multi_resource_guard() {
+        let s = Arc::new(Semaphore::new(4));
+        let s2 = s.clone();
+        let (tx, rx) = channel();
+        let g = s.access_n(3);
+        let t = thread::spawn(move || {
+            let _g = s2.access_n(2);
+            tx.send(()).unwrap();
+        });
+        assert!(rx.try_recv().is_err());
+        drop(g);
+        rx.recv().unwrap();
+        t.join().unwrap();
+    }
+
     #[test]
     fn test_sem_runtime_friendly_blocking() {
         let s = Arc::new(Semaphore::new(1));
@@ -0,0 +1,295 @@
+//! Writing and reading reports stored in an SQLite database.
+//!
+//! The database contains the following tables:
+//! - `header(json)` with a single row holding the report header serialized to JSON,
+//! - `groups(id, hash, file_len, count)` with one row per group of identical files,
+//! - `files(path, size, mtime, inode, device, group_id)` with one row per file.
+//!
+//! The database is written in a single transaction, so an interrupted write leaves
+//! the previous contents of the database intact.
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{ErrorKind, Read};
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+use rusqlite::{params, Connection, OpenFlags};
+
+use crate::file::{FileHash, FileId, FileLen};
+use crate::group::FileGroup;
+use crate::path::Path;
+use crate::report::{GroupIterator, ReportHeader, ReportReader};
+
+const SCHEMA: &str = "
+    DROP TABLE IF EXISTS header;
+    DROP TABLE IF EXISTS files;
+    DROP TABLE IF EXISTS groups;
+    CREATE TABLE header(json TEXT NOT NULL);
+    CREATE TABLE groups(
+        id INTEGER PRIMARY KEY,
+        hash TEXT NOT NULL,
+        file_len INTEGER NOT NULL,
+        count INTEGER NOT NULL
+    );
+    CREATE TABLE files(
+        path TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        mtime INTEGER,
+        inode INTEGER,
+        device INTEGER,
+        group_id INTEGER NOT NULL REFERENCES groups(id)
+    );
+    CREATE INDEX groups_hash ON groups(hash);
+    CREATE INDEX files_path ON files(path);
+    CREATE INDEX files_group_id ON files(group_id);
+";
+
+fn to_io_error(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Writes the report to the SQLite database at given path, replacing the tables of the
+/// previous report, if the database exists.
+///
+/// The modification time, inode and device number of each file are read from the file system.
+/// They are set to `NULL` if the metadata of the file can't be read.
+pub fn write_sqlite_report<'a, I>(
+    path: &std::path::Path,
+    header: &ReportHeader,
+    groups: I,
+) -> io::Result<()>
+where
+    I: IntoIterator<Item = &'a FileGroup<Path>>,
+{
+    let mut conn = Connection::open(path).map_err(to_io_error)?;
+    let tx = conn.transaction().map_err(to_io_error)?;
+    tx.execute_batch(SCHEMA).map_err(to_io_error)?;
+    tx.execute(
+        "INSERT INTO header(json) VALUES (?1)",
+        [serde_json::to_string(header)?],
+    )
+    .map_err(to_io_error)?;
+    {
+        let mut insert_group = tx
+            .prepare("INSERT INTO groups(id, hash, file_len, count) VALUES (?1, ?2, ?3, ?4)")
+            .map_err(to_io_error)?;
+        let mut insert_file = tx
+            .prepare(
+                "INSERT INTO files(path, size, mtime, inode, device, group_id) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )
+            .map_err(to_io_error)?;
+
+        for (id, group) in groups.into_iter().enumerate() {
+            let id = id as i64;
+            insert_group
+                .execute(params![
+                    id,
+                    group.file_hash.to_string(),
+                    group.file_len.0 as i64,
+                    group.files.len() as i64
+                ])
+                .map_err(to_io_error)?;
+            for file in &group.files {
+                let mtime = fs::metadata(file.to_path_buf())
+                    .and_then(|m| m.modified())
+                    .ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs() as i64);
+                let file_id = FileId::new(file).ok();
+                insert_file
+                    .execute(params![
+                        file.to_escaped_string(),
+                        group.file_len.0 as i64,
+                        mtime,
+                        file_id.map(|id| id.inode as i64),
+                        file_id.map(|id| id.device as i64),
+                        id
+                    ])
+                    .map_err(to_io_error)?;
+            }
+        }
+    }
+    tx.commit().map_err(to_io_error)
+}
+
+/// Reads a report from an SQLite database.
+///
+/// The whole report is loaded into memory when the reader is created.
+pub struct SqliteReportReader {
+    header: ReportHeader,
+    groups: Vec<FileGroup<Path>>,
+}
+
+impl SqliteReportReader {
+    /// Reads the report from the database at given path
+    pub fn open(path: &std::path::Path) -> io::Result<SqliteReportReader> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(to_io_error)?;
+        let invalid_data = |e: rusqlite::Error| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Failed to read SQLite report: {}", e),
+            )
+        };
+
+        let header: String = conn
+            .query_row("SELECT json FROM header", [], |row| row.get(0))
+            .map_err(invalid_data)?;
+        let header: ReportHeader = serde_json::from_str(&header)?;
+
+        let mut groups: Vec<FileGroup<Path>> = Vec::new();
+        let mut group_ids: Vec<i64> = Vec::new();
+        let mut stmt = conn
+            .prepare("SELECT id, hash, file_len FROM groups ORDER BY id")
+            .map_err(invalid_data)?;
+        let mut rows = stmt.query([]).map_err(invalid_data)?;
+        while let Some(row) = rows.next().map_err(invalid_data)? {
+            let hash: String = row.get(1).map_err(invalid_data)?;
+            let hash =
+                FileHash::decode(&hash).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            let file_len: i64 = row.get(2).map_err(invalid_data)?;
+            group_ids.push(row.get(0).map_err(invalid_data)?);
+            groups.push(FileGroup {
+                file_len: FileLen(file_len as u64),
+                file_hash: hash,
+                files: vec![],
+            });
+        }
+
+        let mut stmt = conn
+            .prepare("SELECT group_id, path FROM files ORDER BY group_id, rowid")
+            .map_err(invalid_data)?;
+        let mut rows = stmt.query([]).map_err(invalid_data)?;
+        while let Some(row) = rows.next().map_err(invalid_data)? {
+            let group_id: i64 = row.get(0).map_err(invalid_data)?;
+            let path: String = row.get(1).map_err(invalid_data)?;
+            let path = Path::from_escaped_string(&path).map_err(|e| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid path {}: {}", path, e),
+                )
+            })?;
+            let index = group_ids.binary_search(&group_id).map_err(|_| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "File {} belongs to unknown group {}",
+                        path.display(),
+                        group_id
+                    ),
+                )
+            })?;
+            groups[index].files.push(path);
+        }
+        Ok(SqliteReportReader { header, groups })
+    }
+
+    /// Reads the database from a stream.
+    /// The database is copied to a temporary file first, because SQLite can't read streams.
+    pub fn from_stream(mut stream: impl Read) -> io::Result<SqliteReportReader> {
+        let temp = TempFile::new();
+        io::copy(&mut stream, &mut File::create(&temp.0)?)?;
+        Self::open(&temp.0)
+    }
+}
+
+impl ReportReader for SqliteReportReader {
+    fn read_header(&mut self) -> io::Result<ReportHeader> {
+        Ok(self.header.clone())
+    }
+
+    fn read_groups(self: Box<Self>) -> io::Result<Box<GroupIterator>> {
+        let iter = fallible_iterator::convert(self.groups.into_iter().map(Ok));
+        Ok(Box::new(iter))
+    }
+}
+
+/// A file in the temporary directory, removed when dropped
+struct TempFile(PathBuf);
+
+impl TempFile {
+    fn new() -> TempFile {
+        let name = format!("fclones-{}.db", uuid::Uuid::new_v4());
+        TempFile(std::env::temp_dir().join(name))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::DateTime;
+    use fallible_iterator::FallibleIterator;
+
+    use crate::arg::Arg;
+    use crate::report::open_report;
+    use crate::util::test::{with_dir, write_file};
+    use crate::TIMESTAMP_FMT;
+
+    use super::*;
+
+    fn header(base_dir: &std::path::Path) -> ReportHeader {
+        ReportHeader {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            timestamp: DateTime::parse_from_str("2021-08-27 12:11:23.456 +0000", TIMESTAMP_FMT)
+                .unwrap(),
+            command: vec![Arg::from("fclones"), Arg::from("group"), Arg::from(".")],
+            base_dir: Path::from(base_dir),
+            stats: None,
+            size_filter: None,
+            extension_filter: None,
+            baseline: vec![],
+            roots: vec![],
+            metrics: None,
+        }
+    }
+
+    #[test]
+    fn write_and_read_sqlite_report() {
+        with_dir("sqlite/write_and_read_sqlite_report", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            write_file(&file1, "foo");
+            write_file(&file2, "foo");
+            let groups = vec![
+                FileGroup {
+                    file_len: FileLen(3),
+                    file_hash: FileHash(0xabcd),
+                    files: vec![Path::from(&file1), Path::from(&file2)],
+                },
+                FileGroup {
+                    file_len: FileLen(100),
+                    file_hash: FileHash(0x1234),
+                    files: vec![Path::from("/missing/a"), Path::from("/missing/b")],
+                },
+            ];
+            let header = header(root);
+            let db = root.join("report.db");
+            write_sqlite_report(&db, &header, groups.iter()).unwrap();
+            // Writing again must replace the previous report:
+            write_sqlite_report(&db, &header, groups.iter()).unwrap();
+
+            let conn = Connection::open(&db).unwrap();
+            let count: i64 = conn
+                .query_row(
+                    "SELECT count(*) FROM files WHERE mtime IS NOT NULL",
+                    [],
+                    |r| r.get(0),
+                )
+                .unwrap();
+            assert_eq!(count, 2);
+
+            let mut reader = open_report(File::open(&db).unwrap()).unwrap();
+            assert_eq!(reader.read_header().unwrap(), header);
+            let read_groups: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+            assert_eq!(read_groups, groups);
+        });
+    }
+}
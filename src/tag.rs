@@ -0,0 +1,119 @@
+//! Marking duplicate files with an extended attribute holding the hash of their group.
+//!
+//! Tagging doesn't modify the contents or the location of files, so it is a safe way of
+//! making the results of `fclones group` visible to other tools, e.g. file managers.
+
+use std::io;
+
+use crate::file::FileHash;
+use crate::path::Path;
+
+/// Name of the extended attribute storing the hash of the group the file belongs to
+pub const GROUP_HASH_ATTR: &str = "user.fclones.group_hash";
+
+/// Selects whether the group hash attribute is set or removed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TagOp {
+    Tag,
+    Untag,
+}
+
+/// Sets the group hash attribute of the file to `hash`, or removes the attribute.
+/// Removing the attribute from a file that doesn't have it is not an error.
+#[cfg(all(unix, feature = "xattr"))]
+pub fn tag_file(path: &Path, hash: FileHash, op: TagOp) -> io::Result<()> {
+    let path = path.to_path_buf();
+    match op {
+        TagOp::Tag => xattr::set(&path, GROUP_HASH_ATTR, hash.to_string().as_bytes()),
+        TagOp::Untag => match xattr::get(&path, GROUP_HASH_ATTR)? {
+            Some(_) => xattr::remove(&path, GROUP_HASH_ATTR),
+            None => Ok(()),
+        },
+    }
+}
+
+#[cfg(not(unix))]
+pub fn tag_file(_path: &Path, _hash: FileHash, _op: TagOp) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Extended attributes are not supported on this platform",
+    ))
+}
+
+#[cfg(all(unix, not(feature = "xattr")))]
+pub fn tag_file(_path: &Path, _hash: FileHash, _op: TagOp) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Setting extended attributes requires fclones built with the xattr feature",
+    ))
+}
+
+/// Returns the shell command performing the same change as [`tag_file`]
+pub fn tag_command(path: &Path, hash: FileHash, op: TagOp) -> String {
+    match op {
+        TagOp::Tag => format!(
+            "setfattr -n {} -v {} {}",
+            GROUP_HASH_ATTR,
+            hash,
+            path.quote()
+        ),
+        TagOp::Untag => format!("setfattr -x {} {}", GROUP_HASH_ATTR, path.quote()),
+    }
+}
+
+/// Returns true if the error means the file system doesn't support extended attributes
+pub fn is_unsupported(e: &io::Error) -> bool {
+    // ENOTSUP and EOPNOTSUPP are the same on Linux, but differ on some other systems
+    #[cfg(unix)]
+    if let Some(code) = e.raw_os_error() {
+        if code == libc::ENOTSUP || code == libc::EOPNOTSUPP {
+            return true;
+        }
+    }
+    e.kind() == io::ErrorKind::Unsupported
+}
+
+#[cfg(test)]
+#[cfg(unix)]
+mod test {
+    #[cfg(feature = "xattr")]
+    use crate::util::test::{with_dir, write_file};
+
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "xattr")]
+    fn tag_and_untag_file() {
+        with_dir("tag/tag_and_untag_file", |root| {
+            let file = root.join("file");
+            write_file(&file, "foo");
+            let path = Path::from(&file);
+            let hash = FileHash(0x00112233445566778899aabbccddeeff);
+            match tag_file(&path, hash, TagOp::Tag) {
+                Err(e) if is_unsupported(&e) => return,
+                result => result.unwrap(),
+            }
+            let value = xattr::get(&file, GROUP_HASH_ATTR).unwrap();
+            assert_eq!(value, Some(b"00112233445566778899aabbccddeeff".to_vec()));
+
+            tag_file(&path, hash, TagOp::Untag).unwrap();
+            assert_eq!(xattr::get(&file, GROUP_HASH_ATTR).unwrap(), None);
+            // Untagging an untagged file is a no-op:
+            tag_file(&path, hash, TagOp::Untag).unwrap();
+        });
+    }
+
+    #[test]
+    fn tag_command_quotes_paths() {
+        let path = Path::from("/a b");
+        let hash = FileHash(0xff);
+        assert_eq!(
+            tag_command(&path, hash, TagOp::Tag),
+            "setfattr -n user.fclones.group_hash -v 000000000000000000000000000000ff '/a b'"
+        );
+        assert_eq!(
+            tag_command(&path, hash, TagOp::Untag),
+            "setfattr -x user.fclones.group_hash '/a b'"
+        );
+    }
+}
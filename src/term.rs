@@ -0,0 +1,253 @@
+//! Terminal styling and progress bars.
+//!
+//! With the `cli` feature, these are the types from `console` and `indicatif`.
+//! Without it, they are replaced by minimal stand-ins that print text without styling
+//! and never draw progress bars, so the library can be used without these dependencies.
+
+#[cfg(feature = "cli")]
+pub use console::{style, StyledObject, Term};
+#[cfg(feature = "cli")]
+pub use indicatif::{HumanDuration, ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+#[cfg(not(feature = "cli"))]
+pub use plain::*;
+
+/// Returns true if the locale selected by the environment uses the UTF-8 encoding,
+/// so unicode characters like box-drawing characters can be printed.
+/// Windows terminals are assumed to support unicode.
+pub fn supports_unicode() -> bool {
+    if cfg!(windows) {
+        return true;
+    }
+    // The first non-empty variable takes precedence, as in the C library
+    ["LC_ALL", "LC_CTYPE", "LANG"]
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|value| !value.is_empty())
+        .is_some_and(|value| {
+            let value = value.to_lowercase();
+            value.contains("utf-8") || value.contains("utf8")
+        })
+}
+
+#[cfg(not(feature = "cli"))]
+mod plain {
+    use std::fmt;
+    use std::fmt::{Display, Formatter};
+    use std::io;
+    use std::io::{IsTerminal, Write};
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::time::Duration;
+
+    /// Wraps a value to be displayed as is
+    pub fn style<D>(val: D) -> StyledObject<D> {
+        StyledObject(val)
+    }
+
+    /// A value with styling ignored
+    pub struct StyledObject<D>(D);
+
+    impl<D> StyledObject<D> {
+        pub fn for_stderr(self) -> Self {
+            self
+        }
+        pub fn force_styling(self, _value: bool) -> Self {
+            self
+        }
+        pub fn bold(self) -> Self {
+            self
+        }
+        pub fn dim(self) -> Self {
+            self
+        }
+        pub fn red(self) -> Self {
+            self
+        }
+        pub fn green(self) -> Self {
+            self
+        }
+        pub fn yellow(self) -> Self {
+            self
+        }
+        pub fn blue(self) -> Self {
+            self
+        }
+        pub fn magenta(self) -> Self {
+            self
+        }
+        pub fn cyan(self) -> Self {
+            self
+        }
+        pub fn white(self) -> Self {
+            self
+        }
+    }
+
+    impl<D: Display> Display for StyledObject<D> {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+
+    enum Stream {
+        Stdout,
+        Stderr,
+    }
+
+    /// Standard output or standard error stream
+    pub struct Term(Stream);
+
+    impl Term {
+        pub fn stdout() -> Term {
+            Term(Stream::Stdout)
+        }
+
+        pub fn stderr() -> Term {
+            Term(Stream::Stderr)
+        }
+
+        pub fn is_term(&self) -> bool {
+            match self.0 {
+                Stream::Stdout => io::stdout().is_terminal(),
+                Stream::Stderr => io::stderr().is_terminal(),
+            }
+        }
+
+        /// Returns the number of rows and columns of the terminal.
+        /// The size can't be determined without `console`, so this always returns `None`.
+        pub fn size_checked(&self) -> Option<(u16, u16)> {
+            None
+        }
+    }
+
+    impl Write for Term {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            match self.0 {
+                Stream::Stdout => io::stdout().write(buf),
+                Stream::Stderr => io::stderr().write(buf),
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            match self.0 {
+                Stream::Stdout => io::stdout().flush(),
+                Stream::Stderr => io::stderr().flush(),
+            }
+        }
+    }
+
+    /// Formats a duration in a human readable way, e.g. `3 minutes`
+    pub struct HumanDuration(pub Duration);
+
+    impl Display for HumanDuration {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            const UNITS: [(u64, &str); 4] = [
+                (86400, "day"),
+                (3600, "hour"),
+                (60, "minute"),
+                (1, "second"),
+            ];
+            let secs = self.0.as_secs();
+            let (unit_secs, name) = UNITS
+                .iter()
+                .find(|(unit_secs, _)| secs >= *unit_secs)
+                .unwrap_or(&UNITS[3]);
+            let count = secs / unit_secs;
+            if count == 1 {
+                write!(f, "1 {}", name)
+            } else {
+                write!(f, "{} {}s", count, name)
+            }
+        }
+    }
+
+    /// Where a progress bar is drawn; progress bars are never drawn without the `cli` feature
+    pub struct ProgressDrawTarget;
+
+    impl ProgressDrawTarget {
+        pub fn hidden() -> ProgressDrawTarget {
+            ProgressDrawTarget
+        }
+
+        pub fn stderr() -> ProgressDrawTarget {
+            ProgressDrawTarget
+        }
+    }
+
+    pub struct ProgressStyle;
+
+    impl ProgressStyle {
+        pub fn default_bar() -> ProgressStyle {
+            ProgressStyle
+        }
+        pub fn default_spinner() -> ProgressStyle {
+            ProgressStyle
+        }
+        pub fn template(self, _template: &str) -> Self {
+            self
+        }
+        pub fn tick_strings(self, _tick_strings: &[&str]) -> Self {
+            self
+        }
+        pub fn progress_chars(self, _chars: &str) -> Self {
+            self
+        }
+    }
+
+    /// Keeps the position of a progress bar without displaying it
+    pub struct ProgressBar {
+        position: AtomicU64,
+        finished: AtomicBool,
+    }
+
+    impl ProgressBar {
+        pub fn new(_len: u64) -> ProgressBar {
+            ProgressBar {
+                position: AtomicU64::new(0),
+                finished: AtomicBool::new(false),
+            }
+        }
+
+        pub fn new_spinner() -> ProgressBar {
+            Self::new(0)
+        }
+
+        pub fn set_style(&self, _style: ProgressStyle) {}
+        pub fn set_message(&self, _msg: &str) {}
+        pub fn set_prefix(&self, _prefix: &str) {}
+        pub fn set_draw_target(&self, _target: ProgressDrawTarget) {}
+        pub fn println<I: Into<String>>(&self, _msg: I) {}
+
+        pub fn is_hidden(&self) -> bool {
+            true
+        }
+
+        pub fn set_position(&self, pos: u64) {
+            self.position.store(pos, Ordering::Relaxed)
+        }
+
+        pub fn position(&self) -> u64 {
+            self.position.load(Ordering::Relaxed)
+        }
+
+        pub fn is_finished(&self) -> bool {
+            self.finished.load(Ordering::Relaxed)
+        }
+
+        pub fn finish(&self) {
+            self.finished.store(true, Ordering::Relaxed)
+        }
+
+        pub fn finish_and_clear(&self) {
+            self.finish()
+        }
+
+        pub fn finish_with_message(&self, _msg: &str) {
+            self.finish()
+        }
+
+        pub fn abandon(&self) {
+            self.finish()
+        }
+    }
+}
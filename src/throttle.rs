@@ -0,0 +1,144 @@
+//! Limiting the rate of reading data.
+
+use std::cmp::{max, min};
+use std::io;
+use std::io::Read;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Maximum time worth of tokens a single request may take from the bucket.
+/// Keeps the waits short, so the progress is reported smoothly and
+/// the program can be interrupted promptly.
+const MAX_REQUEST_TIME: Duration = Duration::from_millis(100);
+
+/// Limits the rate of consuming a resource, e.g. the number of bytes read per second,
+/// with the token bucket algorithm.
+///
+/// Tokens are added to the bucket at a constant rate, up to the capacity of the bucket.
+/// Consumers take tokens out of the bucket and wait if there were not enough of them.
+/// The bucket can be shared by many threads.
+pub struct TokenBucket {
+    rate: u64,
+    capacity: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    /// Number of tokens available; negative if the consumers took more tokens than available
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket refilled with `rate` tokens per second.
+    ///
+    /// # Panics
+    /// If `rate` is zero.
+    pub fn new(rate: u64) -> TokenBucket {
+        assert!(rate > 0, "Rate must be greater than zero");
+        let capacity = rate as f64 * MAX_REQUEST_TIME.as_secs_f64();
+        TokenBucket {
+            rate,
+            capacity,
+            state: Mutex::new(BucketState {
+                tokens: capacity,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Returns the number of tokens added to the bucket per second
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Returns the maximum number of tokens that should be requested at once,
+    /// so that waiting for them doesn't take too long
+    pub fn max_request(&self) -> usize {
+        max(1, self.capacity as usize)
+    }
+
+    /// Takes `count` tokens out of the bucket.
+    /// Blocks the calling thread until the tokens become available.
+    pub fn acquire(&self, count: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+            state.tokens = (state.tokens + elapsed * self.rate as f64).min(self.capacity);
+            state.last_refill = now;
+            state.tokens -= count as f64;
+            if state.tokens >= 0.0 {
+                Duration::ZERO
+            } else {
+                Duration::from_secs_f64(-state.tokens / self.rate as f64)
+            }
+        };
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+
+    /// Wraps a reader so that each byte read takes one token from this bucket.
+    pub fn reader<R: Read>(&self, inner: R) -> ThrottledReader<'_, R> {
+        ThrottledReader {
+            inner,
+            bucket: self,
+        }
+    }
+}
+
+/// A reader limiting the rate of reading from the underlying reader.
+/// Created by [`TokenBucket::reader`].
+pub struct ThrottledReader<'a, R> {
+    inner: R,
+    bucket: &'a TokenBucket,
+}
+
+impl<R: Read> Read for ThrottledReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = min(buf.len(), self.bucket.max_request());
+        let read = self.inner.read(&mut buf[..len])?;
+        self.bucket.acquire(read);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use std::time::{Duration, Instant};
+
+    use super::TokenBucket;
+
+    #[test]
+    fn full_bucket_does_not_block() {
+        let bucket = TokenBucket::new(1000);
+        let start = Instant::now();
+        bucket.acquire(100);
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn empty_bucket_blocks() {
+        let bucket = TokenBucket::new(1000);
+        let start = Instant::now();
+        bucket.acquire(100);
+        bucket.acquire(100);
+        assert!(start.elapsed() >= Duration::from_millis(90));
+    }
+
+    #[test]
+    fn throttled_reader_reads_all_data() {
+        let bucket = TokenBucket::new(100_000);
+        let data = vec![1u8; 30_000];
+        let mut reader = bucket.reader(data.as_slice());
+        let mut result = Vec::new();
+        let start = Instant::now();
+        reader.read_to_end(&mut result).unwrap();
+        assert_eq!(result, data);
+        // the first 10000 bytes are available immediately, the rest takes 200 ms
+        assert!(start.elapsed() >= Duration::from_millis(190));
+    }
+}
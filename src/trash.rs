@@ -0,0 +1,58 @@
+//! Moving redundant files to the trash or the recycle bin of the operating system,
+//! so they can be restored if removing them turns out to be a mistake.
+
+use std::io;
+
+use crate::lock::FileLock;
+use crate::path::Path;
+
+/// Moves the file to the trash.
+///
+/// Files on a file system without a trash directory of their own are moved to the trash
+/// in the user's home directory by the operating system or the `trash` crate,
+/// so they can still be restored to their original location.
+/// If the file can't be trashed at all, it is left intact and an error is returned.
+pub fn move_to_trash(path: &Path) -> io::Result<()> {
+    let _ = FileLock::new(path)?; // don't touch a locked file
+    trash::delete(path.to_path_buf()).map_err(|e| {
+        io::Error::new(
+            error_kind(&e),
+            format!(
+                "Failed to move {} to trash: {}. The file was left intact",
+                path.display(),
+                e
+            ),
+        )
+    })
+}
+
+/// Returns the kind of the underlying I/O error, if the error came from the file system.
+fn error_kind(e: &trash::Error) -> io::ErrorKind {
+    match e {
+        #[cfg(all(
+            unix,
+            not(target_os = "macos"),
+            not(target_os = "ios"),
+            not(target_os = "android")
+        ))]
+        trash::Error::FileSystem { source, .. } => source.kind(),
+        _ => io::ErrorKind::Other,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn error_kind_of_file_system_error() {
+        let e = trash::Error::FileSystem {
+            path: "file".into(),
+            source: io::Error::from(io::ErrorKind::PermissionDenied),
+        };
+        assert_eq!(error_kind(&e), io::ErrorKind::PermissionDenied);
+        let e = trash::Error::TargetedRoot;
+        assert_eq!(error_kind(&e), io::ErrorKind::Other);
+    }
+}
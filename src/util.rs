@@ -70,19 +70,23 @@ pub mod test {
     use std::fs::{create_dir_all, remove_dir_all, File};
     use std::io::{BufReader, Read, Write};
     use std::path::PathBuf;
+    #[cfg(feature = "reflink")]
     use std::sync::Mutex;
     use std::time::SystemTime;
     use std::{fs, thread};
 
     use super::*;
+    #[cfg(feature = "reflink")]
     use lazy_static::lazy_static;
 
+    #[cfg(feature = "reflink")]
     #[derive(Debug, PartialEq, Eq)]
     enum FsSupportsReflink {
         Untested,
         Supported(bool),
     }
 
+    #[cfg(feature = "reflink")]
     lazy_static! {
         static ref REFLINK_SUPPORTED: Mutex<FsSupportsReflink> =
             Mutex::new(FsSupportsReflink::Untested);
@@ -159,6 +163,7 @@ pub mod test {
         result
     }
 
+    #[cfg(feature = "reflink")]
     pub fn cached_reflink_supported() -> bool {
         let mut guard = REFLINK_SUPPORTED.lock().unwrap();
 
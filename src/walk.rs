@@ -6,8 +6,11 @@ use std::{fs, io};
 use dashmap::DashSet;
 use rayon::Scope;
 
-use crate::log::Log;
-use crate::path::Path;
+use crate::btrfs::is_read_only_subvolume_root;
+use crate::file::FileId;
+use crate::log::{LogMessage, LogSink};
+use crate::path::{Path, PathInterner};
+use crate::pause::PAUSE;
 use crate::selector::PathSelector;
 
 #[derive(Debug)]
@@ -67,16 +70,27 @@ pub struct Walk<'a> {
     pub base_dir: Arc<Path>,
     pub depth: usize,
     pub skip_hidden: bool,
+    /// Skips read-only btrfs subvolumes, e.g. snapshots
+    pub skip_ro_snapshots: bool,
     pub follow_links: bool,
     pub path_selector: PathSelector,
     pub on_visit: &'a (dyn Fn(&Path) + Sync + Send),
-    pub log: Option<&'a Log>,
+    /// Checked before visiting each entry; once it returns true,
+    /// the remaining entries are skipped, so the walk finishes early
+    pub should_stop: &'a (dyn Fn() -> bool + Sync + Send),
+    pub log: Option<&'a dyn LogSink>,
 }
 
-/// Private shared state scoped to a single `run` invocation.
+/// Private shared state scoped to a single traversal root of a `run` invocation.
 struct WalkState<F> {
     pub consumer: F,
-    pub visited: DashSet<u128>,
+    /// Hashes of paths visited from any root
+    pub visited: Arc<DashSet<u128>>,
+    /// Identifiers of directories visited from this root; used to detect symlink cycles
+    pub visited_dirs: DashSet<FileId>,
+    /// Makes the roots and the targets of symbolic links share the parent directories
+    /// with the paths visited earlier; shared by all roots
+    pub interner: Arc<PathInterner>,
 }
 
 impl<'a> Walk<'a> {
@@ -87,9 +101,11 @@ impl<'a> Walk<'a> {
             base_dir: Arc::new(base_dir.clone()),
             depth: usize::MAX,
             skip_hidden: false,
+            skip_ro_snapshots: false,
             follow_links: false,
             path_selector: PathSelector::new(base_dir),
             on_visit: &|_| {},
+            should_stop: &|| false,
             log: None,
         }
     }
@@ -105,19 +121,32 @@ impl<'a> Walk<'a> {
         I: IntoIterator<Item = Path> + Send,
         F: Fn(Path) + Sync + Send,
     {
-        let state = WalkState {
-            consumer,
-            visited: DashSet::new(),
-        };
+        let visited = Arc::new(DashSet::new());
+        let interner = Arc::new(PathInterner::default());
+        let roots: Vec<Path> = roots
+            .into_iter()
+            .map(|p| interner.intern(self.absolute(p)))
+            .collect();
+        let states: Vec<_> = roots
+            .iter()
+            .map(|_| WalkState {
+                consumer: &consumer,
+                visited: visited.clone(),
+                visited_dirs: DashSet::new(),
+                interner: interner.clone(),
+            })
+            .collect();
         rayon::scope(|scope| {
-            for p in roots.into_iter() {
-                let p = self.absolute(p);
+            for (p, state) in roots.into_iter().zip(states.iter()) {
                 match fs::metadata(&p.to_path_buf()) {
-                    Ok(metadata) if metadata.is_dir() && self.depth == 0 => self.log_warn(format!(
-                        "Skipping directory {} because recursive scan is disabled.",
-                        p.display()
-                    )),
-                    _ => scope.spawn(|scope| self.visit_path(p, scope, 0, &state)),
+                    Ok(metadata) if metadata.is_dir() && self.depth == 0 => {
+                        let msg = format!(
+                            "Skipping directory {} because recursive scan is disabled.",
+                            p.display()
+                        );
+                        self.log_warn(LogMessage::new(msg).with_path(&p))
+                    }
+                    _ => scope.spawn(move |scope| self.visit_path(p, scope, 0, state)),
                 }
             }
         });
@@ -136,7 +165,10 @@ impl<'a> Walk<'a> {
     {
         if self.path_selector.matches_dir(&path) {
             Entry::from_path(path.clone())
-                .map_err(|e| self.log_warn(format!("Failed to stat {}: {}", path.display(), e)))
+                .map_err(|e| {
+                    let msg = format!("Failed to stat {}: {}", path.display(), e);
+                    self.log_warn(LogMessage::new(msg).with_path(&path).with_error(&e))
+                })
                 .into_iter()
                 .for_each(|entry| self.visit_entry(entry, scope, level, state))
         }
@@ -157,6 +189,10 @@ impl<'a> Walk<'a> {
         // For progress reporting
         (self.on_visit)(&entry.path);
 
+        if (self.should_stop)() {
+            return;
+        }
+
         // Skip hidden files
         if self.skip_hidden {
             if let Some(name) = entry.path.file_name_cstr() {
@@ -204,14 +240,22 @@ impl<'a> Walk<'a> {
     {
         if self.follow_links {
             match self.resolve_link(path) {
-                Ok(target) => self.visit_path(target, scope, level, state),
-                Err(e) => self.log_warn(format!("Failed to read link {}: {}", path.display(), e)),
+                Ok(target) => {
+                    let target = state.interner.intern(target);
+                    self.visit_path(target, scope, level, state)
+                }
+                Err(e) => {
+                    let msg = format!("Failed to read link {}: {}", path.display(), e);
+                    self.log_warn(LogMessage::new(msg).with_path(path).with_error(&e))
+                }
             }
         }
     }
 
     /// Reads the contents of the directory pointed to by `path`
-    /// and recursively visits each child entry
+    /// and recursively visits each child entry.
+    /// If `follow_links` is set, skips directories already visited from the same root,
+    /// so symbolic links forming a cycle don't cause visiting the same files many times.
     fn visit_dir<'s, 'w, F>(
         &'s self,
         path: Path,
@@ -223,13 +267,39 @@ impl<'a> Walk<'a> {
         's: 'w,
     {
         if level < self.depth && self.path_selector.matches_dir(&path) {
+            PAUSE.wait_while_paused(self.log, || {});
+            if self.skip_ro_snapshots && is_read_only_subvolume_root(&path) {
+                let msg = format!(
+                    "Skipping directory {} because it is a read-only btrfs snapshot",
+                    path.display()
+                );
+                self.log_info(LogMessage::new(msg).with_path(&path));
+                return;
+            }
+            // If the identifier can't be read, we let read_dir report the problem
+            if self.follow_links {
+                if let Ok(id) = FileId::new(&path) {
+                    if !state.visited_dirs.insert(id) {
+                        let msg = format!(
+                            "Skipping directory {} because it was already visited. \
+                            Possible symbolic link cycle.",
+                            path.display()
+                        );
+                        self.log_warn(LogMessage::new(msg).with_path(&path));
+                        return;
+                    }
+                }
+            }
             match std::fs::read_dir(path.to_path_buf()) {
                 Ok(rd) => {
                     for entry in Self::sorted_entries(path, rd) {
                         scope.spawn(move |s| self.visit_entry(entry, s, level + 1, state))
                     }
                 }
-                Err(e) => self.log_warn(format!("Failed to read dir {}: {}", path.display(), e)),
+                Err(e) => {
+                    let msg = format!("Failed to read dir {}: {}", path.display(), e);
+                    self.log_warn(LogMessage::new(msg).with_path(&path).with_error(&e))
+                }
             }
         }
     }
@@ -291,8 +361,16 @@ impl<'a> Walk<'a> {
     }
 
     /// Logs a warning
-    fn log_warn(&self, msg: String) {
-        self.log.iter().for_each(|l| l.warn(&msg))
+    fn log_warn(&self, msg: LogMessage) {
+        if let Some(log) = self.log {
+            log.warn(msg)
+        }
+    }
+
+    fn log_info(&self, msg: LogMessage) {
+        if let Some(log) = self.log {
+            log.info(msg)
+        }
     }
 }
 
@@ -408,6 +486,72 @@ mod test {
         });
     }
 
+    #[test]
+    #[cfg(unix)]
+    fn sym_link_cycles_through_aliased_paths() {
+        with_dir("target/test/walk/8/", |test_root| {
+            use std::os::unix::fs::symlink;
+            let test_root = test_root.canonicalize().unwrap();
+            let real = test_root.join("real");
+            let alias = test_root.join("alias");
+            let dir = real.join("dir");
+            let file = dir.join("file.txt");
+
+            create_dir(&real).unwrap();
+            create_dir(&dir).unwrap();
+            File::create(&file).unwrap();
+            symlink(&real, &alias).unwrap(); // alias -> real
+                                             // alias/dir is the same directory as real/dir, but the paths are different:
+            symlink(alias.join("dir"), dir.join("link")).unwrap();
+
+            let mut walk = Walk::new();
+            walk.follow_links = true;
+            assert_eq!(run_walk(walk, dir), vec![file]);
+        });
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn link_targets_share_parents() {
+        with_dir("target/test/walk/10/", |test_root| {
+            use std::os::unix::fs::symlink;
+            let dir = test_root.join("dir");
+            create_dir(&dir).unwrap();
+            File::create(dir.join("file1.txt")).unwrap();
+            File::create(dir.join("file2.txt")).unwrap();
+            symlink(dir.join("file1.txt"), test_root.join("link1")).unwrap();
+            symlink(dir.join("file2.txt"), test_root.join("link2")).unwrap();
+
+            let mut walk = Walk::new();
+            walk.follow_links = true;
+            let results = Mutex::new(Vec::new());
+            let roots = vec![
+                Path::from(test_root.join("link1")),
+                Path::from(test_root.join("link2")),
+            ];
+            walk.run(roots, |path| results.lock().unwrap().push(path));
+            let results = results.into_inner().unwrap();
+            assert_eq!(results.len(), 2);
+            assert!(Arc::ptr_eq(
+                results[0].parent().unwrap(),
+                results[1].parent().unwrap()
+            ));
+        });
+    }
+
+    #[test]
+    fn stop_early() {
+        with_dir("target/test/walk/9/", |test_root| {
+            let dir = test_root.join("dir");
+            create_dir(&dir).unwrap();
+            File::create(dir.join("file1.txt")).unwrap();
+            File::create(dir.join("file2.txt")).unwrap();
+            let mut walk = Walk::new();
+            walk.should_stop = &|| true;
+            assert_eq!(run_walk(walk, test_root.clone()), Vec::<PathBuf>::new());
+        });
+    }
+
     #[test]
     fn skip_hidden() {
         with_dir("target/test/walk/7/", |test_root| {
@@ -0,0 +1,238 @@
+//! Keeping the report of duplicate files up to date while the files change.
+//!
+//! After the initial scan, changes of files in the input directories are received
+//! from the operating system (inotify on Linux, FSEvents on macOS, ReadDirectoryChangesW
+//! on Windows). Changes are collected for a debounce interval and then the files are
+//! grouped again. The hash cache is always enabled, so only the changed files are hashed again.
+//!
+//! If the file system events cannot be received, e.g. because the event queue overflowed,
+//! the files are periodically rescanned instead.
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::config::{GroupConfig, WatchConfig};
+use crate::error::Error;
+use crate::group::{group_files_with_metrics, write_report_with_metrics};
+use crate::log::Log;
+use crate::path::Path;
+use crate::term::HumanDuration;
+
+/// How often the stop flag is checked
+const POLL_PERIOD: Duration = Duration::from_millis(100);
+
+type EventReceiver = Receiver<notify::Result<Event>>;
+
+/// Groups the files and writes the report to the output file configured in `config.group`,
+/// then updates the report after files change, until `stop` is set.
+///
+/// The report is written to a temporary file in the same directory first, and then
+/// renamed to replace the previous report.
+/// The hash cache is enabled regardless of `config.group.cache`.
+///
+/// # Errors
+/// Returns an error if the output file is not set, if the initial scan fails
+/// or if the report cannot be written.
+pub fn watch_files(mut config: WatchConfig, log: &Log, stop: &AtomicBool) -> Result<(), Error> {
+    config.validate().map_err(Error::config)?;
+    let output = absolute(config.group.output_path().unwrap());
+    let temp = temp_path(&output);
+    // The temporary file has a different name, so the format can't be guessed from it:
+    config.group.format = Some(config.group.output_format());
+    config.group.output = Some(temp.clone());
+    // Only the changed files need to be hashed again on each update:
+    config.group.cache = true;
+    let ignored = [output.clone(), temp.clone()];
+
+    let (sender, receiver) = channel();
+    // The watcher must be kept alive until the end of watching:
+    let watcher = start_watcher(&config.group, sender.clone(), log);
+    let mut degraded = watcher.is_none();
+
+    update_report(&config.group, &temp, &output, log)?;
+    let mut last_update = Instant::now();
+    let mut first_change: Option<Instant> = None;
+
+    while !stop.load(Ordering::Relaxed) {
+        match next_event(&receiver) {
+            Some(Ok(event)) if event.need_rescan() => {
+                if !degraded {
+                    log.warn(format!(
+                        "Some file system events were lost. Switching to full rescans every {}",
+                        HumanDuration(config.rescan_interval)
+                    ));
+                    degraded = true;
+                }
+                first_change.get_or_insert_with(Instant::now);
+            }
+            Some(Ok(event)) if is_change(&event, &ignored) => {
+                first_change.get_or_insert_with(Instant::now);
+            }
+            Some(Ok(_)) | None => {}
+            Some(Err(e)) => {
+                log.warn(format!("Failed to watch files: {}", e));
+                first_change.get_or_insert_with(Instant::now);
+            }
+        }
+
+        let changed = first_change.is_some_and(|t| t.elapsed() >= config.debounce);
+        let rescan_due = degraded && last_update.elapsed() >= config.rescan_interval;
+        if changed || rescan_due {
+            update_report(&config.group, &temp, &output, log)?;
+            last_update = Instant::now();
+            first_change = None;
+        }
+    }
+    drop(watcher);
+    Ok(())
+}
+
+/// Starts watching the input paths recursively.
+/// Returns `None` and logs a warning if any of the paths cannot be watched.
+fn start_watcher(
+    config: &GroupConfig,
+    sender: Sender<notify::Result<Event>>,
+    log: &Log,
+) -> Option<RecommendedWatcher> {
+    let fallback_warning = |e: notify::Error| {
+        log.warn(format!(
+            "Cannot watch changes of files: {}. Falling back to periodic rescans.",
+            e
+        ))
+    };
+    let mut watcher = match notify::recommended_watcher(sender) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            fallback_warning(e);
+            return None;
+        }
+    };
+    for path in config.input_paths() {
+        if let Err(e) = watcher.watch(&path.to_path_buf(), RecursiveMode::Recursive) {
+            fallback_warning(e);
+            return None;
+        }
+    }
+    Some(watcher)
+}
+
+/// Waits for the next event up to the poll period
+fn next_event(receiver: &EventReceiver) -> Option<notify::Result<Event>> {
+    match receiver.recv_timeout(POLL_PERIOD) {
+        Ok(event) => Some(event),
+        Err(RecvTimeoutError::Timeout) => None,
+        // The sender is owned by the caller, so it is never disconnected before returning
+        Err(RecvTimeoutError::Disconnected) => None,
+    }
+}
+
+/// Returns true if the event signals a change of files other than the `ignored` ones.
+/// Reading files, including reading them by fclones itself, doesn't count as a change.
+fn is_change(event: &Event, ignored: &[PathBuf]) -> bool {
+    !matches!(event.kind, EventKind::Access(_)) && event.paths.iter().any(|p| !ignored.contains(p))
+}
+
+/// Groups the files, writes the report to the `temp` file and renames it to `output`
+fn update_report(
+    config: &GroupConfig,
+    temp: &std::path::Path,
+    output: &std::path::Path,
+    log: &Log,
+) -> Result<(), Error> {
+    let (groups, stats, metrics) = group_files_with_metrics(config, log)?;
+    let metrics = config.timings.then_some(&metrics);
+    write_report_with_metrics(config, log, &groups, &stats, metrics)
+        .map_err(|e| Error::io("Failed to write report", Some(&Path::from(temp)), e))?;
+    fs::rename(temp, output)
+        .map_err(|e| Error::io("Failed to replace report", Some(&Path::from(output)), e))?;
+    log.info(format!("Updated report {}", Path::from(output).display()));
+    Ok(())
+}
+
+/// Returns the path of the temporary file the report is written to before it replaces
+/// the previous report. The extension is kept, so a compressed report stays compressed.
+fn temp_path(output: &std::path::Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".fclones-");
+    name.push(output.file_name().unwrap_or_default());
+    output.with_file_name(name)
+}
+
+fn absolute(path: PathBuf) -> PathBuf {
+    std::path::absolute(&path).unwrap_or(path)
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use crate::report::open_report;
+    use crate::util::test::{with_dir, write_file};
+
+    use super::*;
+
+    #[test]
+    fn temp_path_keeps_extension() {
+        assert_eq!(
+            temp_path(std::path::Path::new("/tmp/dupes.json.gz")),
+            PathBuf::from("/tmp/.fclones-dupes.json.gz")
+        );
+    }
+
+    #[test]
+    fn report_is_updated_after_files_change() {
+        with_dir("watch/report_is_updated_after_files_change", |root| {
+            let input = root.join("input");
+            fs::create_dir(&input).unwrap();
+            write_file(&input.join("file1"), "foo");
+            write_file(&input.join("file2"), "foo");
+            let output = root.join("report.json");
+
+            let mut log = Log::new();
+            log.no_progress = true;
+            let mut config = WatchConfig {
+                debounce: Duration::from_millis(100),
+                rescan_interval: Duration::from_secs(1),
+                ..Default::default()
+            };
+            config.group.paths = vec![Path::from(&input)];
+            config.group.output = Some(output.clone());
+
+            let file_count = || -> usize {
+                let file = match fs::File::open(&output) {
+                    Ok(file) => file,
+                    Err(_) => return 0,
+                };
+                let mut reader = open_report(file).unwrap();
+                reader
+                    .read_header()
+                    .unwrap()
+                    .stats
+                    .unwrap()
+                    .total_file_count
+            };
+            let wait_for_file_count = |count: usize| {
+                let start = Instant::now();
+                while file_count() != count && start.elapsed() < Duration::from_secs(20) {
+                    thread::sleep(Duration::from_millis(50));
+                }
+                assert_eq!(file_count(), count);
+            };
+
+            let stop = AtomicBool::new(false);
+            crossbeam_utils::thread::scope(|s| {
+                let handle = s.spawn(|_| watch_files(config, &log, &stop));
+                wait_for_file_count(2);
+                write_file(&input.join("file3"), "foo");
+                wait_for_file_count(3);
+                stop.store(true, Ordering::Relaxed);
+                handle.join().unwrap().unwrap();
+            })
+            .unwrap();
+        });
+    }
+}